@@ -0,0 +1,131 @@
+use crate::{collation::Collation, record::Value};
+
+// The aggregate functions, which fold the rows of a group into one value. A running
+// state is a few values, so it can be kept in a group's entry like any other column
+// https://www.sqlite.org/lang_aggfunc.html
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    // count(*) counts rows, count(X) the rows where X isn't NULL
+    CountRows,
+    Count,
+    // sum() is an INTEGER while it can be, total() always a REAL
+    Sum,
+    Total,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    // The aggregate called `name` with `arg_count` arguments, if any. count() and
+    // count(*) count rows, and with more than one argument min() and max() are scalar
+    // functions
+    pub fn get(name: &str, arg_count: usize, star: bool) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "count" if star || arg_count == 0 => Some(Self::CountRows),
+            "count" => Some(Self::Count),
+            "sum" => Some(Self::Sum),
+            "total" => Some(Self::Total),
+            "avg" => Some(Self::Avg),
+            "min" if arg_count == 1 => Some(Self::Min),
+            "max" if arg_count == 1 => Some(Self::Max),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CountRows | Self::Count => "count",
+            Self::Sum => "sum",
+            Self::Total => "total",
+            Self::Avg => "avg",
+            Self::Min => "min",
+            Self::Max => "max",
+        }
+    }
+
+    // The state before the first row:
+    //   count: [rows]
+    //   sum, total: [sum, whether a REAL or text went in, whether the INTEGER sum overflowed]
+    //   avg: [sum, rows]
+    //   min, max: [value so far]
+    pub fn initial_state(self) -> Vec<Value> {
+        match self {
+            Self::CountRows | Self::Count => vec![Value::I64(0)],
+            Self::Sum | Self::Total => vec![Value::Null, Value::I64(0), Value::I64(0)],
+            Self::Avg => vec![Value::Float(0.0), Value::I64(0)],
+            Self::Min | Self::Max => vec![Value::Null],
+        }
+    }
+
+    pub fn state_width(self) -> usize {
+        self.initial_state().len()
+    }
+
+    // Fold in the argument of one row, NULL is skipped by all but count(*). Returns whether
+    // min() or max() took the value, the row then supplies the group's bare columns. They
+    // compare text under `collation`, that of their argument
+    pub fn step(self, state: &mut [Value], arg: &Value, collation: Collation) -> bool {
+        if arg.is_null() && self != Self::CountRows {
+            return false;
+        }
+        match self {
+            Self::CountRows | Self::Count => state[0] = Value::I64(state[0].to_i64() + 1),
+            Self::Sum | Self::Total => {
+                // Text that looks like an INTEGER counts as one, anything else as a REAL
+                let arg = arg.with_numeric_affinity();
+                let sum = std::mem::replace(&mut state[0], Value::Null);
+                state[0] = match (&sum, &arg) {
+                    (Value::Null, Value::I64(n)) => Value::I64(*n),
+                    (Value::I64(a), Value::I64(b)) => match a.checked_add(*b) {
+                        Some(sum) => Value::I64(sum),
+                        None => {
+                            state[2] = Value::I64(1);
+                            Value::Float(*a as f64 + *b as f64)
+                        }
+                    },
+                    (sum, arg) => {
+                        if !matches!(arg, Value::I64(_)) {
+                            state[1] = Value::I64(1);
+                        }
+                        Value::Float(sum.to_f64() + arg.to_f64())
+                    }
+                };
+            }
+            Self::Avg => {
+                state[0] = Value::Float(state[0].to_f64() + arg.to_f64());
+                state[1] = Value::I64(state[1].to_i64() + 1);
+            }
+            Self::Min | Self::Max => {
+                let replaces = state[0].is_null()
+                    || match self {
+                        Self::Min => collation.compare(arg, &state[0]).is_lt(),
+                        _ => collation.compare(arg, &state[0]).is_gt(),
+                    };
+                if replaces {
+                    state[0] = arg.clone();
+                }
+                return replaces;
+            }
+        }
+        false
+    }
+
+    // The value of the aggregate once every row of the group is in. Over no rows
+    // count() is 0 and the others NULL
+    pub fn finish(self, state: &[Value]) -> anyhow::Result<Value> {
+        Ok(match self {
+            Self::CountRows | Self::Count | Self::Min | Self::Max => state[0].clone(),
+            // Summing only INTEGERs past the 64-bit range is an error, not a REAL
+            Self::Sum if state[2].to_i64() == 1 && state[1].to_i64() == 0 => {
+                anyhow::bail!("integer overflow")
+            }
+            Self::Sum => state[0].clone(),
+            Self::Total => Value::Float(state[0].to_f64()),
+            Self::Avg => match state[1].to_i64() {
+                0 => Value::Null,
+                rows => Value::Float(state[0].to_f64() / rows as f64),
+            },
+        })
+    }
+}
@@ -0,0 +1,993 @@
+use std::cmp::Ordering;
+
+use crate::{
+    collation::KeyOrder,
+    db::{Pager, HEADER_SIZE},
+    page::{
+        index_max_local, local_payload_size, read_overflow, table_leaf_max_local, INDEX_INTERIOR_PAGE_ID,
+        INDEX_LEAF_PAGE_ID, parse_cell_pointers, PageHeader, PAGE_CELL_CONTENT_OFFSET, PAGE_CELL_COUNT_OFFSET,
+        PAGE_INTERIOR_HEADER_SIZE, PAGE_LEAF_HEADER_SIZE, PAGE_RIGHT_MOST_POINTER_OFFSET,
+        TABLE_INTERIOR_PAGE_ID, TABLE_LEAF_PAGE_ID,
+    },
+    error::Error,
+    record::{Record, Value},
+    utils::{read_be_word_at, read_varint, read_varint_i64, write_varint},
+};
+
+const PAGE_FIRST_FREEBLOCK_OFFSET: usize = 1;
+const PAGE_FRAGMENTED_BYTES_COUNT_OFFSET: usize = 7;
+// A cell is given at least this much room, so a freed cell can always hold a freeblock
+const MIN_CELL_SIZE: usize = 4;
+// Past this many fragmented bytes the page is defragmented instead of reusing freeblocks
+const MAX_FRAGMENTED_BYTES: usize = 60;
+
+// A b-tree page of the database file, edited in place the way SQLite does: cells go into
+// a freeblock or the gap below the cell content area, removed cells become freeblocks,
+// and the page is only defragmented when the free space is too scattered to use
+// https://www.sqlite.org/fileformat.html#b_tree_pages
+pub struct BtreePage {
+    pub num: usize,
+    data: Vec<u8>,
+    // Where the page header starts, past the database header on page 1
+    offset: usize,
+    usable_size: usize,
+}
+
+impl BtreePage {
+    pub fn read(pager: &mut Pager, num: usize) -> anyhow::Result<Self> {
+        let data = pager.read_raw_page(num)?;
+        let offset = if num == 1 { HEADER_SIZE } else { 0 };
+        // Cells are found through the header and cell pointers, which have to make sense
+        let header = PageHeader::parse(&data, offset as u16)?;
+        parse_cell_pointers(&data, offset as u16, &header, pager.usable_size())?;
+        Ok(BtreePage {
+            num,
+            data,
+            offset,
+            usable_size: pager.usable_size(),
+        })
+    }
+
+    // A new page from the end of the file (or the freelist), with no cells yet
+    pub fn allocate(pager: &mut Pager, page_type: u8) -> anyhow::Result<Self> {
+        let num = pager.allocate_page()?;
+        let mut page = BtreePage {
+            num,
+            data: vec![0; pager.page_size()],
+            offset: 0,
+            usable_size: pager.usable_size(),
+        };
+        page.rebuild(page_type, &[], None);
+        Ok(page)
+    }
+
+    pub fn write(&self, pager: &mut Pager) -> anyhow::Result<()> {
+        if self.offset == 0 {
+            return pager.write_page(self.num, &self.data);
+        }
+        // The database header may have changed since the page was read, when pages
+        // went on or off the freelist, so the one on disk is kept
+        let mut data = pager.read_raw_page(self.num)?;
+        data[self.offset..].copy_from_slice(&self.data[self.offset..]);
+        pager.write_page(self.num, &data)
+    }
+
+    pub fn page_type(&self) -> u8 {
+        self.data[self.offset]
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.page_type(), TABLE_LEAF_PAGE_ID | INDEX_LEAF_PAGE_ID)
+    }
+
+    fn header_size(&self) -> usize {
+        if self.is_leaf() {
+            PAGE_LEAF_HEADER_SIZE
+        } else {
+            PAGE_INTERIOR_HEADER_SIZE
+        }
+    }
+
+    fn header_word(&self, field: usize) -> usize {
+        read_be_word_at(&self.data, self.offset + field) as usize
+    }
+
+    fn set_header_word(&mut self, field: usize, value: usize) {
+        let at = self.offset + field;
+        self.data[at..at + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.header_word(PAGE_CELL_COUNT_OFFSET)
+    }
+
+    // A content area starting at 65536 is stored as 0
+    fn content_start(&self) -> usize {
+        match self.header_word(PAGE_CELL_CONTENT_OFFSET) {
+            0 => 65_536,
+            start => start,
+        }
+    }
+
+    fn fragmented_bytes(&self) -> usize {
+        self.data[self.offset + PAGE_FRAGMENTED_BYTES_COUNT_OFFSET] as usize
+    }
+
+    fn set_fragmented_bytes(&mut self, count: usize) {
+        // The count saturates rather than wrapping, defragmenting resets it
+        self.data[self.offset + PAGE_FRAGMENTED_BYTES_COUNT_OFFSET] = count.min(255) as u8;
+    }
+
+    fn pointer_end(&self) -> usize {
+        self.offset + self.header_size() + self.cell_count() * 2
+    }
+
+    fn cell_offset(&self, i: usize) -> usize {
+        read_be_word_at(&self.data, self.offset + self.header_size() + i * 2) as usize
+    }
+
+    fn set_cell_offset(&mut self, i: usize, cell_offset: usize) {
+        let at = self.offset + self.header_size() + i * 2;
+        self.data[at..at + 2].copy_from_slice(&(cell_offset as u16).to_be_bytes());
+    }
+
+    // Raw bytes of cell `i`
+    pub fn cell(&self, i: usize) -> anyhow::Result<&[u8]> {
+        let start = self.cell_offset(i);
+        let size = self.cell_size(start)?;
+        match self.data.get(start..start + size) {
+            Some(cell) if start + size <= self.usable_size => Ok(cell),
+            _ => anyhow::bail!(Error::CorruptPage(format!(
+                "cell at offset {} runs past the end of the page",
+                start
+            ))),
+        }
+    }
+
+    // Raw bytes of every cell, in key order
+    pub fn cells(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        (0..self.cell_count())
+            .map(|i| self.cell(i).map(<[u8]>::to_vec))
+            .collect()
+    }
+
+    fn cell_size(&self, start: usize) -> anyhow::Result<usize> {
+        let cell = &self.data[start..];
+        let page_type = self.page_type();
+        if page_type == TABLE_INTERIOR_PAGE_ID {
+            let (n, _) = read_varint(&cell[4..])?;
+            return Ok(4 + n);
+        }
+        let mut size = if self.is_leaf() { 0 } else { 4 };
+        let (n, payload_size) = read_varint(&cell[size..])?;
+        size += n;
+        let max_local = if page_type == TABLE_LEAF_PAGE_ID {
+            let (n, _) = read_varint(&cell[size..])?;
+            size += n;
+            table_leaf_max_local(self.usable_size)
+        } else {
+            index_max_local(self.usable_size)
+        };
+        let payload_size = payload_size as usize;
+        let local = local_payload_size(payload_size, max_local, self.usable_size);
+        Ok(size + local + if local < payload_size { 4 } else { 0 })
+    }
+
+    // Rowid of a table leaf cell, or the key of a table interior cell
+    pub fn row_id(&self, i: usize) -> anyhow::Result<i64> {
+        let cell = &self.data[self.cell_offset(i)..];
+        if self.is_leaf() {
+            let (n, _) = read_varint(cell)?;
+            Ok(read_varint_i64(&cell[n..])?.1)
+        } else {
+            Ok(read_varint_i64(&cell[4..])?.1)
+        }
+    }
+
+    // The whole payload of a leaf or index cell, following its overflow pages
+    pub fn payload(&self, pager: &mut Pager, i: usize) -> anyhow::Result<Vec<u8>> {
+        let cell = self.cell(i)?;
+        let mut start = if self.is_leaf() { 0 } else { 4 };
+        let (n, payload_size) = read_varint(&cell[start..])?;
+        start += n;
+        if self.page_type() == TABLE_LEAF_PAGE_ID {
+            start += read_varint(&cell[start..])?.0;
+        }
+        let payload_size = payload_size as usize;
+        if start + payload_size <= cell.len() {
+            return Ok(cell[start..start + payload_size].to_vec());
+        }
+        // The cell ends with the first overflow page number
+        let overflow_start = cell.len() - 4;
+        let first_page = u32::from_be_bytes(cell[overflow_start..].try_into().unwrap());
+        let usable_size = pager.usable_size();
+        read_overflow(
+            &cell[start..overflow_start],
+            first_page,
+            payload_size,
+            usable_size,
+            &mut |page_num| pager.read_raw_page(page_num),
+        )
+    }
+
+    // The record of an index cell
+    pub fn index_entry(&self, pager: &mut Pager, i: usize) -> anyhow::Result<Vec<Value>> {
+        Ok(Record::parse(&self.payload(pager, i)?)?.values())
+    }
+
+    pub fn right_most(&self) -> Option<u32> {
+        (!self.is_leaf()).then(|| {
+            let at = self.offset + PAGE_RIGHT_MOST_POINTER_OFFSET;
+            u32::from_be_bytes(self.data[at..at + 4].try_into().unwrap())
+        })
+    }
+
+    // Left child of cell `i`, or the right-most child past the last cell
+    pub fn child(&self, i: usize) -> u32 {
+        if i < self.cell_count() {
+            let at = self.cell_offset(i);
+            u32::from_be_bytes(self.data[at..at + 4].try_into().unwrap())
+        } else {
+            self.right_most().unwrap()
+        }
+    }
+
+    pub fn set_child(&mut self, i: usize, page_num: u32) {
+        let at = if i < self.cell_count() {
+            self.cell_offset(i)
+        } else {
+            self.offset + PAGE_RIGHT_MOST_POINTER_OFFSET
+        };
+        self.data[at..at + 4].copy_from_slice(&page_num.to_be_bytes());
+    }
+
+    // Put `cell` at position `i`, returns false when the page has no room for it
+    pub fn insert_cell(&mut self, i: usize, cell: &[u8]) -> bool {
+        let Some(start) = self.allocate_space(cell.len().max(MIN_CELL_SIZE)) else {
+            return false;
+        };
+        self.data[start..start + cell.len()].copy_from_slice(cell);
+        let count = self.cell_count();
+        let pointer = self.offset + self.header_size() + i * 2;
+        let pointers_end = self.pointer_end();
+        self.data.copy_within(pointer..pointers_end, pointer + 2);
+        self.set_header_word(PAGE_CELL_COUNT_OFFSET, count + 1);
+        self.set_cell_offset(i, start);
+        true
+    }
+
+    // Take out cell `i`, its space goes on the freeblock chain
+    pub fn remove_cell(&mut self, i: usize) -> anyhow::Result<()> {
+        let start = self.cell_offset(i);
+        let size = self.cell_size(start)?.max(MIN_CELL_SIZE);
+        let count = self.cell_count();
+        let pointer = self.offset + self.header_size() + i * 2;
+        let pointers_end = self.pointer_end();
+        self.data.copy_within(pointer + 2..pointers_end, pointer);
+        self.set_header_word(PAGE_CELL_COUNT_OFFSET, count - 1);
+        self.free_space_at(start, size);
+        Ok(())
+    }
+
+    // Bytes a new cell could use: the gap, the freeblocks and the fragments
+    pub fn free_space(&self) -> usize {
+        let gap = self.content_start() - self.pointer_end();
+        let freeblocks = self.freeblocks().iter().map(|(_, size)| size).sum::<usize>();
+        gap + freeblocks + self.fragmented_bytes()
+    }
+
+    // `size` bytes for a new cell plus room for its pointer, like allocateSpace()
+    fn allocate_space(&mut self, size: usize) -> Option<usize> {
+        let gap = self.content_start() - self.pointer_end();
+        if gap >= 2 && self.fragmented_bytes() <= MAX_FRAGMENTED_BYTES {
+            if let Some(start) = self.take_freeblock(size) {
+                return Some(start);
+            }
+        }
+        if gap < size + 2 {
+            if self.free_space() < size + 2 {
+                return None;
+            }
+            self.defragment().ok()?;
+        }
+        let start = self.content_start() - size;
+        self.set_header_word(PAGE_CELL_CONTENT_OFFSET, start);
+        Some(start)
+    }
+
+    // (offset, size) of each freeblock, in the order of the chain
+    fn freeblocks(&self) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut next = self.header_word(PAGE_FIRST_FREEBLOCK_OFFSET);
+        while next != 0 && next + 4 <= self.usable_size {
+            let size = read_be_word_at(&self.data, next + 2) as usize;
+            blocks.push((next, size));
+            let following = read_be_word_at(&self.data, next) as usize;
+            // The chain is kept in increasing order, anything else is a broken chain
+            if following <= next {
+                break;
+            }
+            next = following;
+        }
+        blocks
+    }
+
+    fn set_freeblocks(&mut self, blocks: &[(usize, usize)]) {
+        let first = blocks.first().map_or(0, |(start, _)| *start);
+        self.set_header_word(PAGE_FIRST_FREEBLOCK_OFFSET, first);
+        for (i, (start, size)) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).map_or(0, |(next, _)| *next);
+            self.data[*start..*start + 2].copy_from_slice(&(next as u16).to_be_bytes());
+            self.data[*start + 2..*start + 4].copy_from_slice(&(*size as u16).to_be_bytes());
+        }
+    }
+
+    // First fit: the cell goes at the end of the block, a remainder too small to be a
+    // freeblock becomes fragmented bytes
+    fn take_freeblock(&mut self, size: usize) -> Option<usize> {
+        let mut blocks = self.freeblocks();
+        let i = blocks.iter().position(|(_, block_size)| *block_size >= size)?;
+        let (start, block_size) = blocks[i];
+        let remainder = block_size - size;
+        if remainder < MIN_CELL_SIZE {
+            blocks.remove(i);
+            self.set_fragmented_bytes(self.fragmented_bytes() + remainder);
+            self.set_freeblocks(&blocks);
+            return Some(start);
+        }
+        blocks[i].1 = remainder;
+        self.set_freeblocks(&blocks);
+        Some(start + remainder)
+    }
+
+    // Add a freed range to the chain, merging it with the blocks next to it, along with
+    // the fragment between them when it is too small to keep them apart. A block that
+    // ends up at the start of the content area is handed back to the gap
+    fn free_space_at(&mut self, start: usize, size: usize) {
+        self.data[start..start + size].fill(0);
+        let mut blocks = self.freeblocks();
+        blocks.push((start, size));
+        blocks.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        let mut absorbed = 0;
+        for (start, size) in blocks {
+            match merged.last_mut() {
+                Some((last_start, last_size)) if start < *last_start + *last_size + MIN_CELL_SIZE => {
+                    absorbed += start - (*last_start + *last_size);
+                    *last_size = start + size - *last_start;
+                }
+                _ => merged.push((start, size)),
+            }
+        }
+        self.set_fragmented_bytes(self.fragmented_bytes().saturating_sub(absorbed));
+        if let Some(&(first_start, first_size)) = merged.first() {
+            if first_start == self.content_start() {
+                merged.remove(0);
+                self.set_header_word(PAGE_CELL_CONTENT_OFFSET, first_start + first_size);
+            }
+        }
+        self.set_freeblocks(&merged);
+    }
+
+    // Move every cell to the end of the page so all free space is in the gap
+    fn defragment(&mut self) -> anyhow::Result<()> {
+        let cells = self.cells()?;
+        let right_most = self.right_most();
+        self.rebuild(self.page_type(), &cells, right_most);
+        Ok(())
+    }
+
+    // Reset the page to hold just `cells`, laid out from the end of the usable space
+    pub fn rebuild(&mut self, page_type: u8, cells: &[Vec<u8>], right_most: Option<u32>) {
+        let usable_size = self.usable_size;
+        self.data[self.offset..usable_size].fill(0);
+        self.data[self.offset] = page_type;
+        self.set_header_word(PAGE_CELL_CONTENT_OFFSET, usable_size);
+        if let Some(right_most) = right_most {
+            let at = self.offset + PAGE_RIGHT_MOST_POINTER_OFFSET;
+            self.data[at..at + 4].copy_from_slice(&right_most.to_be_bytes());
+        }
+        for (i, cell) in cells.iter().enumerate() {
+            let inserted = self.insert_cell(i, cell);
+            debug_assert!(inserted, "rebuilt page overflows");
+        }
+    }
+
+    // Room for cells on a page with no database header
+    fn capacity(&self) -> usize {
+        self.usable_size - self.header_size()
+    }
+}
+
+// The bytes of a payload that fit on the b-tree page, followed by the number of the
+// first overflow page holding the rest
+fn spill_payload(
+    pager: &mut Pager,
+    payload: &[u8],
+    max_local: usize,
+    cell: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    let usable_size = pager.usable_size();
+    let local = local_payload_size(payload.len(), max_local, usable_size);
+    cell.extend_from_slice(&payload[..local]);
+    if local == payload.len() {
+        return Ok(());
+    }
+    // Each overflow page holds a pointer to the next one and usable size - 4 bytes
+    let chunks = payload[local..].chunks(usable_size - 4).collect::<Vec<_>>();
+    let mut pages = Vec::new();
+    for _ in &chunks {
+        pages.push(pager.allocate_page()?);
+    }
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut buffer = vec![0; pager.page_size()];
+        let next = pages.get(i + 1).copied().unwrap_or(0) as u32;
+        buffer[0..4].copy_from_slice(&next.to_be_bytes());
+        buffer[4..4 + chunk.len()].copy_from_slice(chunk);
+        pager.write_page(pages[i], &buffer)?;
+    }
+    cell.extend_from_slice(&(pages[0] as u32).to_be_bytes());
+    Ok(())
+}
+
+fn table_leaf_cell(pager: &mut Pager, row_id: i64, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut cell = Vec::new();
+    write_varint(payload.len() as u64, &mut cell);
+    write_varint(row_id as u64, &mut cell);
+    let max_local = table_leaf_max_local(pager.usable_size());
+    spill_payload(pager, payload, max_local, &mut cell)?;
+    Ok(cell)
+}
+
+fn index_leaf_cell(pager: &mut Pager, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut cell = Vec::new();
+    write_varint(payload.len() as u64, &mut cell);
+    let max_local = index_max_local(pager.usable_size());
+    spill_payload(pager, payload, max_local, &mut cell)?;
+    Ok(cell)
+}
+
+// An interior cell is the left child followed by the key: a rowid varint in a table
+// b-tree, a whole index leaf cell in an index b-tree
+fn interior_cell(left_child: u32, key: &[u8]) -> Vec<u8> {
+    let mut cell = left_child.to_be_bytes().to_vec();
+    cell.extend_from_slice(key);
+    cell
+}
+
+fn interior_type(page_type: u8) -> u8 {
+    match page_type {
+        TABLE_LEAF_PAGE_ID | TABLE_INTERIOR_PAGE_ID => TABLE_INTERIOR_PAGE_ID,
+        _ => INDEX_INTERIOR_PAGE_ID,
+    }
+}
+
+// Interior pages on the way down from the root, with the child that was taken
+type Path = Vec<(BtreePage, usize)>;
+
+// Descend a table b-tree to the leaf that holds, or would hold, `row_id`
+fn seek_table_leaf(pager: &mut Pager, root: usize, row_id: i64) -> anyhow::Result<(Path, BtreePage)> {
+    let mut path = Vec::new();
+    let mut page = BtreePage::read(pager, root)?;
+    while !page.is_leaf() {
+        // The left child of a cell holds the rowids up to and including its key
+        let (Ok(i) | Err(i)) = search_row_id(&page, row_id)?;
+        let child = page.child(i) as usize;
+        path.push((page, i));
+        page = BtreePage::read(pager, child)?;
+    }
+    Ok((path, page))
+}
+
+// Position of `row_id` among the keys of a table page, `Err` is where it would go
+fn search_row_id(page: &BtreePage, row_id: i64) -> anyhow::Result<Result<usize, usize>> {
+    let (mut low, mut high) = (0, page.cell_count());
+    while low < high {
+        let mid = (low + high) / 2;
+        match page.row_id(mid)?.cmp(&row_id) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Ok(Ok(mid)),
+        }
+    }
+    Ok(Err(low))
+}
+
+// A new b-tree with an empty root page, returns the root page number
+pub fn create_tree(pager: &mut Pager, page_type: u8) -> anyhow::Result<usize> {
+    let root = BtreePage::allocate(pager, page_type)?;
+    root.write(pager)?;
+    Ok(root.num)
+}
+
+// The rows of a table b-tree, from the cell counts of its leaves without reading a record
+pub fn count_rows(pager: &mut Pager, root: usize) -> anyhow::Result<u64> {
+    let page = BtreePage::read(pager, root)?;
+    if page.is_leaf() {
+        return Ok(page.cell_count() as u64);
+    }
+    let mut count = 0;
+    for i in 0..=page.cell_count() {
+        count += count_rows(pager, page.child(i) as usize)?;
+    }
+    Ok(count)
+}
+
+// Every entry of an index b-tree in key order, each passed to `f`
+pub fn for_each_index_entry(
+    pager: &mut Pager,
+    root: usize,
+    f: &mut dyn FnMut(Vec<Value>),
+) -> anyhow::Result<()> {
+    let page = BtreePage::read(pager, root)?;
+    for i in 0..page.cell_count() {
+        // The entries below an interior cell sort before it
+        if !page.is_leaf() {
+            for_each_index_entry(pager, page.child(i) as usize, f)?;
+        }
+        f(page.index_entry(pager, i)?);
+    }
+    if let Some(right_most) = page.right_most() {
+        for_each_index_entry(pager, right_most as usize, f)?;
+    }
+    Ok(())
+}
+
+// Put every page of a b-tree on the freelist, overflow pages included
+pub fn drop_tree(pager: &mut Pager, root: usize) -> anyhow::Result<()> {
+    let page = BtreePage::read(pager, root)?;
+    for i in 0..page.cell_count() {
+        free_overflow(pager, &page, i)?;
+        if !page.is_leaf() {
+            drop_tree(pager, page.child(i) as usize)?;
+        }
+    }
+    if let Some(right_most) = page.right_most() {
+        drop_tree(pager, right_most as usize)?;
+    }
+    pager.free_page(root)
+}
+
+// Add a row to a table b-tree, returns false if the rowid is taken
+pub fn insert_row(pager: &mut Pager, root: usize, row_id: i64, payload: &[u8]) -> anyhow::Result<bool> {
+    let (path, mut leaf) = seek_table_leaf(pager, root, row_id)?;
+    let i = match search_row_id(&leaf, row_id)? {
+        Ok(_) => return Ok(false),
+        Err(i) => i,
+    };
+    let cell = table_leaf_cell(pager, row_id, payload)?;
+    if leaf.insert_cell(i, &cell) {
+        leaf.write(pager)?;
+        return Ok(true);
+    }
+    let mut cells = leaf.cells()?;
+    cells.insert(i, cell);
+    balance(pager, root, path, leaf, cells)?;
+    Ok(true)
+}
+
+// The largest rowid in a table b-tree, None when it is empty
+pub fn last_row_id(pager: &mut Pager, root: usize) -> anyhow::Result<Option<i64>> {
+    let mut page = BtreePage::read(pager, root)?;
+    while let Some(right_most) = page.right_most() {
+        page = BtreePage::read(pager, right_most as usize)?;
+    }
+    match page.cell_count() {
+        0 => Ok(None),
+        count => Ok(Some(page.row_id(count - 1)?)),
+    }
+}
+
+// Position of `entry` among the cells of an index page, `Err` is where it would go. Index
+// entries compare field by field in the order of the indexed columns, the rowid at the
+// end makes each one unique
+fn search_index_page(
+    pager: &mut Pager,
+    page: &BtreePage,
+    entry: &[Value],
+    order: &KeyOrder,
+) -> anyhow::Result<Result<usize, usize>> {
+    let (mut low, mut high) = (0, page.cell_count());
+    while low < high {
+        let mid = (low + high) / 2;
+        match order.compare(&page.index_entry(pager, mid)?, entry) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Ok(Ok(mid)),
+        }
+    }
+    Ok(Err(low))
+}
+
+// Add an entry (the indexed values followed by the rowid) to an index b-tree,
+// returns false if it is already there
+pub fn insert_index_entry(
+    pager: &mut Pager,
+    root: usize,
+    entry: &[Value],
+    order: &KeyOrder,
+) -> anyhow::Result<bool> {
+    let mut path = Vec::new();
+    let mut page = BtreePage::read(pager, root)?;
+    let i = loop {
+        let i = match search_index_page(pager, &page, entry, order)? {
+            Ok(_) => return Ok(false),
+            Err(i) => i,
+        };
+        if page.is_leaf() {
+            break i;
+        }
+        let child = page.child(i) as usize;
+        path.push((page, i));
+        page = BtreePage::read(pager, child)?;
+    };
+    let cell = index_leaf_cell(pager, &Record::serialize(entry))?;
+    if page.insert_cell(i, &cell) {
+        page.write(pager)?;
+        return Ok(true);
+    }
+    let mut cells = page.cells()?;
+    cells.insert(i, cell);
+    balance(pager, root, path, page, cells)?;
+    Ok(true)
+}
+
+// Remove a row from a table b-tree, returns false if there is no such rowid
+pub fn delete_row(pager: &mut Pager, root: usize, row_id: i64) -> anyhow::Result<bool> {
+    let (path, mut leaf) = seek_table_leaf(pager, root, row_id)?;
+    let Ok(i) = search_row_id(&leaf, row_id)? else {
+        return Ok(false);
+    };
+    free_overflow(pager, &leaf, i)?;
+    leaf.remove_cell(i)?;
+    settle(pager, root, path, leaf)?;
+    Ok(true)
+}
+
+// Remove an entry from an index b-tree, returns false if it isn't there
+pub fn delete_index_entry(
+    pager: &mut Pager,
+    root: usize,
+    entry: &[Value],
+    order: &KeyOrder,
+) -> anyhow::Result<bool> {
+    let Some((path, mut page, i)) = seek_index_entry(pager, root, entry, order)? else {
+        return Ok(false);
+    };
+    if page.is_leaf() {
+        free_overflow(pager, &page, i)?;
+        page.remove_cell(i)?;
+        return settle(pager, root, path, page).map(|_| true);
+    }
+
+    // An interior entry is replaced by the one just before it, the last entry of its
+    // left subtree, which comes out of a leaf
+    let mut leaf = BtreePage::read(pager, page.child(i) as usize)?;
+    while let Some(right_most) = leaf.right_most() {
+        leaf = BtreePage::read(pager, right_most as usize)?;
+    }
+    let predecessor = leaf.index_entry(pager, leaf.cell_count() - 1)?;
+    let Some((leaf_path, mut leaf, j)) = seek_index_entry(pager, root, &predecessor, order)? else {
+        return Err(out_of_order(root));
+    };
+    // The cell moves as it is, overflow pages and all
+    let predecessor_cell = leaf.cell(j)?.to_vec();
+    leaf.remove_cell(j)?;
+    settle(pager, root, leaf_path, leaf)?;
+
+    // Rebalancing can move the entry, even down into a leaf, so look it up again. Either
+    // way the predecessor now belongs right where it is
+    let Some((path, mut page, i)) = seek_index_entry(pager, root, entry, order)? else {
+        return Err(out_of_order(root));
+    };
+    free_overflow(pager, &page, i)?;
+    let cell = match page.is_leaf() {
+        true => predecessor_cell,
+        false => interior_cell(page.child(i), &predecessor_cell),
+    };
+    page.remove_cell(i)?;
+    if page.insert_cell(i, &cell) {
+        page.write(pager)?;
+        return Ok(true);
+    }
+    let mut cells = page.cells()?;
+    cells.insert(i, cell);
+    balance(pager, root, path, page, cells)?;
+    Ok(true)
+}
+
+// An entry that was just read from the index can't be found by searching for it when its
+// entries aren't in the order of its columns
+fn out_of_order(root: usize) -> anyhow::Error {
+    anyhow::anyhow!(Error::CorruptPage(format!(
+        "index rooted at page {} is not in the order of its columns",
+        root
+    )))
+}
+
+// The page and position of an index entry, with the path down to it
+fn seek_index_entry(
+    pager: &mut Pager,
+    root: usize,
+    entry: &[Value],
+    order: &KeyOrder,
+) -> anyhow::Result<Option<(Path, BtreePage, usize)>> {
+    let mut path = Vec::new();
+    let mut page = BtreePage::read(pager, root)?;
+    loop {
+        match search_index_page(pager, &page, entry, order)? {
+            Ok(i) => return Ok(Some((path, page, i))),
+            Err(_) if page.is_leaf() => return Ok(None),
+            Err(i) => {
+                let child = page.child(i) as usize;
+                path.push((page, i));
+                page = BtreePage::read(pager, child)?;
+            }
+        }
+    }
+}
+
+// Whether an entry of the index starts with `key`, the values of its columns without
+// the rowid
+pub fn has_index_key(pager: &mut Pager, root: usize, key: &[Value], order: &KeyOrder) -> anyhow::Result<bool> {
+    let mut page = BtreePage::read(pager, root)?;
+    loop {
+        // Entries that start with the key are in the subtree before the first one past it
+        let (mut low, mut high) = (0, page.cell_count());
+        while low < high {
+            let mid = (low + high) / 2;
+            let entry = page.index_entry(pager, mid)?;
+            match order.compare(&entry[..key.len().min(entry.len())], key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(true),
+            }
+        }
+        if page.is_leaf() {
+            return Ok(false);
+        }
+        page = BtreePage::read(pager, page.child(low) as usize)?;
+    }
+}
+
+// Put the pages of a cell's overflow chain on the freelist
+fn free_overflow(pager: &mut Pager, page: &BtreePage, i: usize) -> anyhow::Result<()> {
+    if page.page_type() == TABLE_INTERIOR_PAGE_ID {
+        return Ok(());
+    }
+    let cell = page.cell(i)?;
+    let mut start = if page.is_leaf() { 0 } else { 4 };
+    let (n, payload_size) = read_varint(&cell[start..])?;
+    start += n;
+    if page.page_type() == TABLE_LEAF_PAGE_ID {
+        start += read_varint(&cell[start..])?.0;
+    }
+    if start + payload_size as usize <= cell.len() {
+        return Ok(());
+    }
+    let mut next_page = u32::from_be_bytes(cell[cell.len() - 4..].try_into().unwrap());
+    while next_page != 0 {
+        let buffer = pager.read_raw_page(next_page as usize)?;
+        pager.free_page(next_page as usize)?;
+        next_page = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
+    }
+    Ok(())
+}
+
+// Write a page that lost a cell, rebalancing it with its siblings once it is mostly
+// empty, like SQLite does when more than 2/3 of a page is free
+fn settle(pager: &mut Pager, root: usize, path: Path, page: BtreePage) -> anyhow::Result<()> {
+    let underfull = page.cell_count() == 0 || page.free_space() > page.capacity() * 2 / 3;
+    if page.num == root {
+        if !page.is_leaf() && page.cell_count() == 0 {
+            return balance_shallower(pager, page);
+        }
+        return page.write(pager);
+    }
+    if !underfull {
+        return page.write(pager);
+    }
+    let cells = page.cells()?;
+    balance(pager, root, path, page, cells)
+}
+
+// Give `page` the content `cells` and even it out with up to two of its siblings, the
+// way balance_nonroot() does: the cells of the siblings and the dividers between them are
+// packed into as few pages as they fit in, and the parent gets the new dividers. This
+// splits a page that overflows and merges one that emptied out
+fn balance(
+    pager: &mut Pager,
+    root: usize,
+    mut path: Path,
+    page: BtreePage,
+    cells: Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    if page.num == root {
+        return balance_root(pager, page, cells);
+    }
+    let page_type = page.page_type();
+    let capacity = page.capacity();
+    let (mut parent, i) = path.pop().unwrap();
+    let count = parent.cell_count();
+    let first = match i {
+        0 => 0,
+        i if i == count => count.saturating_sub(2),
+        i => i - 1,
+    };
+    let last = (first + 2).min(count);
+
+    let mut all_cells = Vec::new();
+    let mut siblings = Vec::new();
+    let mut page = Some((page, cells));
+    for j in first..=last {
+        let (sibling, sibling_cells) = match j == i {
+            true => page.take().unwrap(),
+            false => {
+                let sibling = BtreePage::read(pager, parent.child(j) as usize)?;
+                let sibling_cells = sibling.cells()?;
+                (sibling, sibling_cells)
+            }
+        };
+        all_cells.extend(sibling_cells);
+        // The dividers between the siblings come back down, except the copied rowids
+        // of a table leaf. An interior divider takes the right-most child of the page
+        // before it as its left child
+        if j < last && page_type != TABLE_LEAF_PAGE_ID {
+            let divider = parent.cell(j)?;
+            all_cells.push(match sibling.right_most() {
+                Some(right_most) => interior_cell(right_most, &divider[4..]),
+                None => divider[4..].to_vec(),
+            });
+        }
+        siblings.push(sibling);
+    }
+    let right_most = siblings.last().unwrap().right_most();
+    for _ in first..last {
+        parent.remove_cell(first)?;
+    }
+
+    let (groups, dividers, right_mosts) = pack(all_cells, page_type, capacity, right_most)?;
+    let mut page_nums = Vec::new();
+    let mut siblings = siblings.into_iter();
+    for (group, right_most) in groups.iter().zip(&right_mosts) {
+        let mut new_page = match siblings.next() {
+            Some(sibling) => sibling,
+            None => BtreePage::allocate(pager, page_type)?,
+        };
+        new_page.rebuild(page_type, group, *right_most);
+        new_page.write(pager)?;
+        page_nums.push(new_page.num as u32);
+    }
+    for unused in siblings {
+        pager.free_page(unused.num)?;
+    }
+
+    // The parent's pointer past the removed dividers now leads to the last page, the
+    // new dividers go in front of it pointing at the pages before
+    parent.set_child(first, *page_nums.last().unwrap());
+    let parent_cells = dividers
+        .iter()
+        .zip(&page_nums)
+        .map(|(divider, page_num)| interior_cell(*page_num, divider))
+        .collect::<Vec<_>>();
+    for (j, cell) in parent_cells.iter().enumerate() {
+        if !parent.insert_cell(first + j, cell) {
+            let mut cells = parent.cells()?;
+            cells.splice(first + j..first + j, parent_cells[j..].iter().cloned());
+            return balance(pager, root, path, parent, cells);
+        }
+    }
+    settle(pager, root, path, parent)
+}
+
+// A root keeps its page number: when its cells don't fit they all move to new pages
+// below it and the root becomes the interior page above them
+fn balance_root(pager: &mut Pager, mut root: BtreePage, cells: Vec<Vec<u8>>) -> anyhow::Result<()> {
+    let page_type = root.page_type();
+    let right_most = root.right_most();
+    let room = root.usable_size - root.offset - root.header_size();
+    if fits(&cells, room) {
+        root.rebuild(page_type, &cells, right_most);
+        if !root.is_leaf() && root.cell_count() == 0 {
+            return balance_shallower(pager, root);
+        }
+        return root.write(pager);
+    }
+    let (groups, dividers, right_mosts) = pack(cells, page_type, root.capacity(), right_most)?;
+    let mut page_nums = Vec::new();
+    for (group, right_most) in groups.iter().zip(&right_mosts) {
+        let mut new_page = BtreePage::allocate(pager, page_type)?;
+        new_page.rebuild(page_type, group, *right_most);
+        new_page.write(pager)?;
+        page_nums.push(new_page.num as u32);
+    }
+    let root_cells = dividers
+        .iter()
+        .zip(&page_nums)
+        .map(|(divider, page_num)| interior_cell(*page_num, divider))
+        .collect::<Vec<_>>();
+    root.rebuild(interior_type(page_type), &root_cells, page_nums.last().copied());
+    root.write(pager)
+}
+
+// A root left with no cells and a single child takes over the child's content, when it
+// fits, and the tree gets a level shorter
+fn balance_shallower(pager: &mut Pager, mut root: BtreePage) -> anyhow::Result<()> {
+    let child = BtreePage::read(pager, root.right_most().unwrap() as usize)?;
+    let cells = child.cells()?;
+    let room = root.usable_size - root.offset - root.header_size();
+    if !fits(&cells, room) {
+        return root.write(pager);
+    }
+    root.rebuild(child.page_type(), &cells, child.right_most());
+    pager.free_page(child.num)?;
+    if !root.is_leaf() && root.cell_count() == 0 {
+        return balance_shallower(pager, root);
+    }
+    root.write(pager)
+}
+
+fn fits(cells: &[Vec<u8>], room: usize) -> bool {
+    cells.iter().map(|cell| cell.len().max(MIN_CELL_SIZE) + 2).sum::<usize>() <= room
+}
+
+// Cells of one page, dividers between the pages and the right-most child of each page
+type Packing = (Vec<Vec<Vec<u8>>>, Vec<Vec<u8>>, Vec<Option<u32>>);
+
+// Fill pages in turn with `cells`. Between two pages, a table leaf copies its last rowid
+// up as the divider while other pages move a whole cell up into the parent
+fn pack(
+    cells: Vec<Vec<u8>>,
+    page_type: u8,
+    capacity: usize,
+    right_most: Option<u32>,
+) -> anyhow::Result<Packing> {
+    let table_leaf = page_type == TABLE_LEAF_PAGE_ID;
+    let mut groups: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut dividers: Vec<Vec<u8>> = Vec::new();
+    let mut used = 0;
+    for cell in cells {
+        let size = cell.len().max(MIN_CELL_SIZE) + 2;
+        let group = groups.last_mut().unwrap();
+        if used + size <= capacity || group.is_empty() {
+            used += size;
+            group.push(cell);
+            continue;
+        }
+        if table_leaf {
+            let last = group.last().unwrap();
+            let (n, _) = read_varint(last)?;
+            let (len, _) = read_varint(&last[n..])?;
+            dividers.push(last[n..n + len].to_vec());
+            groups.push(vec![cell]);
+            used = size;
+        } else {
+            dividers.push(cell);
+            groups.push(Vec::new());
+            used = 0;
+        }
+    }
+    // Every page needs a cell, take the divider back and move another one up instead
+    if groups.len() > 1 && groups.last().unwrap().is_empty() {
+        let cell = dividers.pop().unwrap();
+        groups.last_mut().unwrap().push(cell);
+        let previous = groups.len() - 2;
+        let divider = groups[previous].pop().unwrap();
+        if groups[previous].is_empty() {
+            anyhow::bail!("cells too large to fit the pages");
+        }
+        dividers.push(divider);
+    }
+    // An interior divider's left child becomes the right-most child of the page before it
+    let mut right_mosts = Vec::new();
+    for divider in dividers.iter_mut() {
+        if page_type == TABLE_LEAF_PAGE_ID || page_type == INDEX_LEAF_PAGE_ID {
+            right_mosts.push(None);
+        } else {
+            right_mosts.push(Some(u32::from_be_bytes(divider[0..4].try_into().unwrap())));
+            *divider = divider[4..].to_vec();
+        }
+    }
+    right_mosts.push(right_most);
+    Ok((groups, dividers, right_mosts))
+}
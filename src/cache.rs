@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::{
+    db::QueryResult,
+    functions,
+    sql::token::{Token, TokenType},
+};
+
+// Results of read-only statements, keyed by their normalized text. The entries belong
+// to one version of the database, they are all dropped once the file change counter
+// or the schema cookie moves
+#[derive(Default)]
+pub struct ResultCache {
+    // (file change counter, schema cookie) the entries were computed against
+    version: Option<(u32, u32)>,
+    results: HashMap<String, QueryResult>,
+}
+
+impl ResultCache {
+    // Drops the entries when the database changed
+    pub fn validate(&mut self, version: (u32, u32)) {
+        if self.version != Some(version) {
+            self.results.clear();
+            self.version = Some(version);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&QueryResult> {
+        self.results.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, result: QueryResult) {
+        self.results.insert(key, result);
+    }
+}
+
+// The text of every statement of a script, with whitespace collapsed and keywords upper
+// cased, so differently formatted statements share an entry. Identifiers keep their case,
+// which `AS` hands on to the names of the result columns.
+// Statements are split at `;` and empty ones skipped, the same way the parser does. None
+// for a statement that calls a function like random(), whose rows differ every run
+pub fn statement_keys(tokens: &[Token]) -> Vec<Option<String>> {
+    let mut keys = Vec::new();
+    let mut words = Vec::new();
+    let mut deterministic = true;
+    for (i, token) in tokens.iter().enumerate() {
+        let call = tokens.get(i + 1).is_some_and(|next| next.token_type == TokenType::LeftParen);
+        if token.token_type == TokenType::Identifier && call && !functions::is_deterministic(&token.lexeme) {
+            deterministic = false;
+        }
+        match token.token_type {
+            TokenType::Semicolon | TokenType::Eof => {
+                if !words.is_empty() {
+                    keys.push(deterministic.then(|| words.join(" ")));
+                    words.clear();
+                }
+                deterministic = true;
+            }
+            // String literals keep their quotes, so 'a' and "a" stay apart
+            TokenType::Identifier | TokenType::String | TokenType::Number => words.push(token.lexeme.clone()),
+            _ => words.push(token.lexeme.to_uppercase()),
+        }
+    }
+    keys
+}
@@ -0,0 +1,142 @@
+use std::cmp::Ordering;
+
+use crate::record::Value;
+
+// How two text values compare. Numbers, blobs and NULL compare the same under all of them
+// https://www.sqlite.org/datatype3.html#collating_sequences
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Collation {
+    // Byte by byte
+    #[default]
+    Binary,
+    // Ignoring the case of the 26 ASCII letters
+    NoCase,
+    // Ignoring trailing spaces
+    Rtrim,
+}
+
+impl Collation {
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name.to_lowercase().as_str() {
+            "binary" => Ok(Self::Binary),
+            "nocase" => Ok(Self::NoCase),
+            "rtrim" => Ok(Self::Rtrim),
+            _ => anyhow::bail!("no such collation sequence: {}", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Binary => "binary",
+            Self::NoCase => "nocase",
+            Self::Rtrim => "rtrim",
+        }
+    }
+
+    pub fn compare(self, a: &Value, b: &Value) -> Ordering {
+        let (Value::String(a), Value::String(b)) = (a, b) else {
+            return a.cmp(b);
+        };
+        match self {
+            Self::Binary => a.cmp(b),
+            Self::NoCase => {
+                let fold = |text: &'_ String| text.bytes().map(|b| b.to_ascii_lowercase()).collect::<Vec<_>>();
+                fold(a).cmp(&fold(b))
+            }
+            Self::Rtrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+        }
+    }
+}
+
+// Keys compare value by value, each under its own collation. Values past the end of
+// `collations`, like the rowid of an index entry, compare byte by byte
+pub fn compare_keys(a: &[Value], b: &[Value], collations: &[Collation]) -> Ordering {
+    KeyOrder::ascending(collations.to_vec()).compare(a, b)
+}
+
+// The order of an index's entries: its columns each under their collation, the DESC ones
+// from largest to smallest, then the rowid
+#[derive(Debug, Clone, Default)]
+pub struct KeyOrder {
+    pub collations: Vec<Collation>,
+    pub descending: Vec<bool>,
+}
+
+impl KeyOrder {
+    pub fn ascending(collations: Vec<Collation>) -> Self {
+        KeyOrder {
+            collations,
+            descending: Vec::new(),
+        }
+    }
+
+    pub fn compare(&self, a: &[Value], b: &[Value]) -> Ordering {
+        a.iter()
+            .zip(b)
+            .enumerate()
+            .map(|(i, (a, b))| {
+                let ordering = self.collations.get(i).copied().unwrap_or_default().compare(a, b);
+                match self.descending.get(i) {
+                    Some(true) => ordering.reverse(),
+                    _ => ordering,
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn nocase_folds_ascii_letters_only() {
+        assert_eq!(Collation::NoCase.compare(&text("ABC"), &text("abc")), Ordering::Equal);
+        assert_eq!(Collation::NoCase.compare(&text("É"), &text("é")), Ordering::Less);
+        // Folded to lower case, so '_' sorts before the letters like in SQLite
+        assert_eq!(Collation::NoCase.compare(&text("_"), &text("A")), Ordering::Less);
+        assert_eq!(Collation::Binary.compare(&text("_"), &text("A")), Ordering::Greater);
+    }
+
+    #[test]
+    fn rtrim_ignores_trailing_spaces_only() {
+        assert_eq!(Collation::Rtrim.compare(&text("a  "), &text("a")), Ordering::Equal);
+        assert_eq!(Collation::Rtrim.compare(&text(" a"), &text("a")), Ordering::Less);
+        assert_eq!(Collation::Rtrim.compare(&text("a\t"), &text("a")), Ordering::Greater);
+    }
+
+    #[test]
+    fn values_other_than_text_ignore_the_collation() {
+        assert_eq!(Collation::NoCase.compare(&Value::I64(1), &Value::Float(1.0)), Ordering::Equal);
+        let (padded, blob) = (Value::Blob(b"a ".to_vec()), Value::Blob(b"a".to_vec()));
+        assert_eq!(Collation::Rtrim.compare(&padded, &blob), Ordering::Greater);
+        assert_eq!(Collation::NoCase.compare(&Value::Null, &text("a")), Ordering::Less);
+    }
+
+    #[test]
+    fn keys_past_the_collations_compare_byte_by_byte() {
+        let collations = [Collation::NoCase];
+        let key = |name: &str, rowid: &str| [text(name), text(rowid)];
+        assert_eq!(compare_keys(&key("A", "x"), &key("a", "x"), &collations), Ordering::Equal);
+        assert_eq!(compare_keys(&key("A", "X"), &key("a", "x"), &collations), Ordering::Less);
+        // A prefix of a key comes before it
+        assert_eq!(compare_keys(&[text("a")], &key("A", "x"), &collations), Ordering::Less);
+    }
+
+    #[test]
+    fn descending_columns_sort_nulls_last_and_the_rowid_still_ascending() {
+        let order = KeyOrder {
+            collations: vec![Collation::Binary],
+            descending: vec![true],
+        };
+        assert_eq!(order.compare(&[Value::I64(2)], &[Value::I64(1)]), Ordering::Less);
+        assert_eq!(order.compare(&[Value::Null], &[text("a")]), Ordering::Greater);
+        let (first, second) = ([Value::I64(1), Value::I64(5)], [Value::I64(1), Value::I64(7)]);
+        assert_eq!(order.compare(&first, &second), Ordering::Less);
+    }
+}
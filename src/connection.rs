@@ -0,0 +1,179 @@
+use std::{
+    io::Write,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    db::{Db, OpenOptions, QueryResult, ScriptError, StatementStats},
+    error::Result,
+    page_cache::CacheStats,
+    params::Params,
+    row::Rows,
+    statement::Statement,
+    stats::BtreeUsage,
+};
+
+// An open database file. Each statement is a transaction of its own unless BEGIN
+// opened one
+pub struct Connection {
+    // Statements and their rows run on it directly
+    pub(crate) db: Db,
+    // Tells connections apart, so a statement isn't stepped on one it didn't start on
+    pub(crate) id: u64,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Connection {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            db: Db::from_file(path)?,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+
+    // Read-only, created when missing or with its cache sized, see `OpenOptions`
+    pub fn open_with(path: impl AsRef<Path>, options: &OpenOptions) -> Result<Self> {
+        Ok(Self {
+            db: Db::open_with(path, options)?,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+
+    // The rows of a single statement, with `params` bound to its parameters
+    pub fn query(&mut self, sql: &str, params: &Params) -> Result<QueryResult> {
+        let mut statement = self.prepare(sql)?;
+        statement.bind_params(params.clone());
+        statement.execute(self)
+    }
+
+    // Like `query`, the rows are read as they are iterated over
+    pub fn query_rows(&mut self, sql: &str, params: &Params) -> Result<Rows<'_>> {
+        let mut statement = self.prepare(sql)?;
+        statement.bind_params(params.clone());
+        statement.query(self)
+    }
+
+    // Run a single statement, returns the number of rows it inserted, updated or deleted
+    pub fn execute(&mut self, sql: &str, params: &Params) -> Result<usize> {
+        self.query(sql, params)?;
+        Ok(self.db.changes())
+    }
+
+    // Every statement of `sql` in order, separated by `;`, with the result of each. Stops at
+    // the first that fails, the ones before it stay done
+    pub fn execute_batch(&mut self, sql: &str, params: &Params) -> Result<Vec<QueryResult>> {
+        Ok(self.db.execute_sql_with(sql, params)?)
+    }
+
+    pub fn prepare(&self, sql: &str) -> Result<Statement> {
+        Statement::prepare(sql)
+    }
+
+    // Every statement of `sql` in order, with the outcome of each. Unlike
+    // `execute_batch`, a statement that fails doesn't stop the ones after it
+    pub fn execute_script(&mut self, sql: &str, params: &Params) -> Vec<Result<QueryResult>> {
+        let results = self.db.execute_script_with(sql, params);
+        results.into_iter().map(|result| Ok(result?)).collect()
+    }
+
+    // Like `execute_script`, with the rows of each statement read as `each` iterates over
+    // them. The next statement starts once `each` returns, whatever rows it left unread
+    pub fn execute_script_rows(&mut self, sql: &str, params: &Params, mut each: impl FnMut(Result<Rows<'_>>)) {
+        for (stmt, key, deep) in self.db.script_stmts(sql, params) {
+            match stmt.and_then(|stmt| self.db.start_script_stmt(stmt, key, deep)) {
+                Ok(stream) => each(Ok(Rows::new(self, stream))),
+                Err(err) => each(Err(err.into())),
+            }
+        }
+    }
+
+    // Every statement of `sql` as one transaction: all of them take effect, or none when
+    // one fails, which the error names
+    pub fn execute_script_atomic(&mut self, sql: &str, params: &Params) -> std::result::Result<Vec<QueryResult>, ScriptError> {
+        self.db.execute_script_atomic_with(sql, params)
+    }
+
+    // Run a script as a unit in parts, between which the caller can do other things, like
+    // the dot commands of the shell. The transaction this opens, unless one is open already,
+    // ends with `finish_script`
+    pub fn begin_script(&mut self) -> bool {
+        self.db.begin_script()
+    }
+
+    // A part of the script begun with `begin_script`, with the result of each statement
+    // handed to `each` as soon as it is done. Stops at the first statement that fails, the
+    // error counts statements and lines from the start of `sql`
+    pub fn execute_script_part(
+        &mut self,
+        sql: &str,
+        params: &Params,
+        each: impl FnMut(QueryResult),
+    ) -> std::result::Result<(), ScriptError> {
+        self.db.execute_script_part(sql, params, each)
+    }
+
+    // Commit the script, or roll it back when it didn't succeed. `opened` is what
+    // `begin_script` returned
+    pub fn finish_script(&mut self, opened: bool, succeeded: bool) -> Result<()> {
+        Ok(self.db.finish_script(opened, succeeded)?)
+    }
+
+    // Make `SELECT *` behave like `SELECT rowid, *`
+    pub fn set_rowid_in_wildcard(&mut self, enabled: bool) {
+        self.db.set_rowid_in_wildcard(enabled);
+    }
+
+    // Serve repeated queries from memory until the database changes
+    pub fn set_result_cache(&mut self, enabled: bool) {
+        self.db.set_result_cache(enabled);
+    }
+
+    // Bytes of rows an ORDER BY holds in memory before it spills to disk
+    pub fn set_sort_memory_limit(&mut self, bytes: usize) {
+        self.db.set_sort_memory_limit(bytes);
+    }
+
+    // Unicode case mapping in upper(), lower() and LIKE instead of ASCII only
+    #[cfg(feature = "unicode-case")]
+    pub fn set_unicode_case(&mut self, enabled: bool) {
+        self.db.set_unicode_case(enabled);
+    }
+
+    // Record the time and page reads of each statement, see `take_statement_stats`
+    pub fn set_timer(&mut self, enabled: bool) {
+        self.db.set_timer(enabled);
+    }
+
+    // The stats of the statements run since the last call, in order
+    pub fn take_statement_stats(&mut self) -> Vec<StatementStats> {
+        self.db.take_statement_stats()
+    }
+
+    // Lookups in the page cache since the connection opened
+    pub fn cache_stats(&self) -> CacheStats {
+        self.db.pager.cache_stats()
+    }
+
+    // The names of the tables in the database, in name order
+    pub fn table_names(&mut self) -> Result<Vec<String>> {
+        Ok(self.db.table_names()?)
+    }
+
+    // The names of the indexes on `table_name`, or on every table, in name order
+    pub fn index_names(&mut self, table_name: Option<&str>) -> Result<Vec<String>> {
+        Ok(self.db.index_names(table_name)?)
+    }
+
+    // The pages and bytes each table and index uses
+    pub fn space_usage(&mut self) -> Result<Vec<BtreeUsage>> {
+        Ok(self.db.space_usage()?)
+    }
+
+    // The database as the SQL that recreates it, like sqlite3's .dump
+    pub fn dump(&mut self, out: &mut dyn Write) -> Result<()> {
+        Ok(self.db.dump(out)?)
+    }
+
+}
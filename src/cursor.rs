@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use crate::{
+    error::Error,
+    db::Pager,
+    page::{Page, TableLeafCell},
+};
+
+// Walks a table b-tree in rowid order, one cell at a time. Each table in a query gets
+// its own cursor, so the same table can be scanned under two aliases at once
+pub struct TableCursor {
+    root_page: usize,
+    started: bool,
+    // Interior pages on the way down to the current leaf, with the next child to visit.
+    // The pages are shared with the pager's cache
+    stack: Vec<(Arc<Page>, usize)>,
+    leaf: Option<(Arc<Page>, usize)>,
+}
+
+impl TableCursor {
+    pub fn new(root_page: usize) -> Self {
+        TableCursor {
+            root_page,
+            started: false,
+            stack: Vec::new(),
+            leaf: None,
+        }
+    }
+
+    pub fn next(&mut self, pager: &mut Pager) -> anyhow::Result<Option<TableLeafCell>> {
+        loop {
+            if let Some((page, i)) = &mut self.leaf {
+                if let Page::TableLeaf(leaf) = page.as_ref() {
+                    if let Some(cell) = leaf.cells.get(*i) {
+                        *i += 1;
+                        return Ok(Some(cell.clone()));
+                    }
+                }
+                self.leaf = None;
+            }
+
+            let page_num = if self.started {
+                let Some(page_num) = self.next_child() else {
+                    return Ok(None);
+                };
+                page_num
+            } else {
+                self.started = true;
+                self.root_page
+            };
+            let page = pager.read_page(page_num)?;
+            match page.as_ref() {
+                Page::TableLeaf(_) => self.leaf = Some((page, 0)),
+                Page::TableInterior(_) => self.stack.push((page, 0)),
+                page => anyhow::bail!(Error::CorruptPage(format!("expected a table page, found {:?}", page.get_page_type()))),
+            }
+        }
+    }
+
+    // The next page to descend into, popping interior pages that are done
+    fn next_child(&mut self) -> Option<usize> {
+        while let Some((page, i)) = self.stack.last_mut() {
+            let Page::TableInterior(interior) = page.as_ref() else {
+                self.stack.pop();
+                continue;
+            };
+            let child = match interior.cells.get(*i) {
+                Some(cell) => cell.left_child,
+                None if *i == interior.cells.len() => interior.header.get_right_most_point(),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            *i += 1;
+            return Some(child as usize);
+        }
+        None
+    }
+}
+
+// Point lookup of a single row by rowid
+pub fn seek_row(
+    pager: &mut Pager,
+    root_page: usize,
+    row_id: i64,
+) -> anyhow::Result<Option<TableLeafCell>> {
+    let mut page_num = root_page;
+    loop {
+        match pager.read_page(page_num)?.as_ref() {
+            Page::TableLeaf(leaf) => {
+                let found = leaf.cells.binary_search_by_key(&row_id, |cell| cell.row_id);
+                return Ok(found.ok().map(|i| leaf.cells[i].clone()));
+            }
+            Page::TableInterior(interior) => {
+                // The left child of a cell holds the rowids up to and including its key,
+                // and the keys are in order
+                let i = interior.cells.partition_point(|cell| cell.row_id < row_id);
+                page_num = match interior.cells.get(i) {
+                    Some(cell) => cell.left_child as usize,
+                    None => interior.header.get_right_most_point() as usize,
+                };
+            }
+            page => anyhow::bail!(Error::CorruptPage(format!("expected a table page, found {:?}", page.get_page_type()))),
+        }
+    }
+}
@@ -1,491 +1,2637 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-    path::Path,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Ok};
 
 use crate::{
-    page::{self, IndexInteriorPage, IndexLeafPage, Page, TableInteriorPage, TableLeafPage},
-    record::Value,
+    aggregate::Aggregate,
+    btree,
+    cache::{self, ResultCache},
+    collation::{Collation, KeyOrder},
+    functions::{self, FunctionContext},
+    integrity::IntegrityCheck,
+    journal,
+    lock::{self, FileLock},
+    page_cache::{self, CacheStats, PageCache},
+    params::Params,
+    planner::{self, collect_conjuncts, ColumnConstraint, Constraints, IndexStats, KeyRange, Lookup, Plan, ProbeRanges},
+    wal::Wal,
+    page::{is_pointer_map_page, Page, TableLeafCell, INDEX_LEAF_PAGE_ID, TABLE_LEAF_PAGE_ID},
+    cursor::{seek_row, TableCursor},
+    ephemeral::EphemeralBtree,
+    eval::{evaluate, is_comparison, literal_value, value_literal},
+    error::Error,
+    record::{Affinity, Record, Value, ValueRef},
+    row::Row,
+    sorter,
     sql::{
-        parser::{self, Expr, Literal, Stmt},
+        keywords,
+        parser::{
+            self, AlterTableStmt, CommonTableExpr, CreateIndexStmt, CreateTableStmt, DeleteStmt, Expr, InsertStmt, Join, JoinKind, Literal, SelectStmt,
+            Stmt, TableReference, UpdateStmt,
+        },
         scanner,
         token::TokenType,
     },
+    stats::BtreeUsage,
     utils::read_be_word_at,
 };
 
+mod vdbe;
+
+use vdbe::Vm;
+
 pub const HEADER_SIZE: usize = 100;
 const HEADER_PREFIX: &[u8] = b"SQLite format 3\0";
 const HEADER_PAGE_SIZE_OFFSET: usize = 16;
+const HEADER_WRITE_VERSION_OFFSET: usize = 18;
+const HEADER_READ_VERSION_OFFSET: usize = 19;
+const HEADER_RESERVED_SPACE_OFFSET: usize = 20;
+const HEADER_CHANGE_COUNTER_OFFSET: usize = 24;
+const HEADER_DATABASE_SIZE_OFFSET: usize = 28;
+pub const HEADER_FREELIST_TRUNK_OFFSET: usize = 32;
+pub const HEADER_FREELIST_COUNT_OFFSET: usize = 36;
+const HEADER_SCHEMA_COOKIE_OFFSET: usize = 40;
+const HEADER_SCHEMA_FORMAT_OFFSET: usize = 44;
+const HEADER_TEXT_ENCODING_OFFSET: usize = 56;
+const HEADER_LARGEST_ROOT_PAGE_OFFSET: usize = 52;
+const HEADER_VERSION_VALID_FOR_OFFSET: usize = 92;
+const HEADER_SQLITE_VERSION_OFFSET: usize = 96;
+// The version of SQLite whose file format new databases are written in
+const SQLITE_VERSION_NUMBER: u32 = 3_045_000;
+const DEFAULT_PAGE_SIZE: u32 = 4096;
 const PAGE_MAX_SIZE: u32 = 65_536;
+const PAGE_MIN_SIZE: u32 = 512;
+// The least a page may leave after its reserved region
+const MIN_USABLE_SIZE: usize = 480;
 
 #[derive(Debug, Clone)]
 pub struct DbHeader {
     pub page_size: u32,
+    // Bytes at the end of each page reserved for extensions
+    pub reserved_space: u8,
+    // Changes go to a write-ahead log rather than straight into the file
+    pub wal_mode: bool,
+    // auto_vacuum or incremental_vacuum, the file then has pointer-map pages
+    pub auto_vacuum: bool,
 }
 impl DbHeader {
     pub fn parse(buffer: &[u8]) -> anyhow::Result<Self> {
         if !buffer.starts_with(HEADER_PREFIX) {
             let prefix = &buffer[..HEADER_PREFIX.len()];
-            anyhow::bail!("Invalid header prefix: {:?}", prefix);
+            anyhow::bail!(Error::CorruptPage(format!("Invalid header prefix: {:?}", prefix)));
         }
         let page_size_raw = read_be_word_at(buffer, HEADER_PAGE_SIZE_OFFSET);
         let page_size = match page_size_raw {
             1 => PAGE_MAX_SIZE,
-            n if n.is_power_of_two() => n as u32,
-            _ => anyhow::bail!("page size is not a power of 2: {}", page_size_raw),
+            n if n.is_power_of_two() && n as u32 >= PAGE_MIN_SIZE => n as u32,
+            _ => anyhow::bail!(Error::CorruptPage(format!(
+                "page size is not a power of 2 from 512 to 65536: {}",
+                page_size_raw
+            ))),
         };
-        Ok(DbHeader { page_size })
+        let reserved_space = buffer[HEADER_RESERVED_SPACE_OFFSET];
+        if (page_size as usize) - (reserved_space as usize) < MIN_USABLE_SIZE {
+            anyhow::bail!(Error::CorruptPage(format!(
+                "{} reserved bytes leave too little of a {} byte page",
+                reserved_space, page_size
+            )));
+        }
+        Ok(DbHeader {
+            page_size,
+            reserved_space,
+            wal_mode: buffer[HEADER_READ_VERSION_OFFSET] == 2,
+            auto_vacuum: buffer[HEADER_LARGEST_ROOT_PAGE_OFFSET..][..4] != [0; 4],
+        })
+    }
+
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.reserved_space as usize
+    }
+}
+
+#[derive(Clone)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Row>,
+}
+
+impl QueryResult {
+    pub fn new(columns: Vec<String>, rows: Vec<Vec<Value>>) -> Self {
+        let names: Arc<[String]> = columns.clone().into();
+        Self {
+            columns,
+            rows: rows.into_iter().map(|values| Row::new(names.clone(), values)).collect(),
+        }
+    }
+
+    // The name of each result column, an alias if it has one or else the expression as written
+    pub fn column_names(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+// What running a statement took, recorded while the timer is on
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatementStats {
+    pub elapsed: Duration,
+    // From the file or the log, pages found in the cache aren't read again
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+// The rows of a statement, taken one at a time with `Db::next_row`
+pub struct RowStream {
+    columns: Arc<[String]>,
+    rows: StreamRows,
+    // Of a stream read off a b-tree while the timer is on, what the pager had done when it
+    // started. Its stats are recorded once it runs out
+    timer: Option<StatsStart>,
+    // The key the result cache keeps the rows under once all of them were read, and the
+    // rows so far
+    cache: Option<(String, Vec<Row>)>,
+}
+
+// When a statement started, with the pager's counts then
+type StatsStart = (Instant, IoStats, CacheStats);
+
+impl RowStream {
+    // The rows of a statement that already ran in full
+    fn buffered(result: QueryResult) -> Self {
+        RowStream {
+            columns: result.columns.into(),
+            rows: StreamRows::Buffered(result.rows.into_iter()),
+            timer: None,
+            cache: None,
+        }
+    }
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+enum StreamRows {
+    // Worked out in full up front: the result of a write or of any other statement
+    Buffered(std::vec::IntoIter<Row>),
+    // A SELECT, its program run up to the next row as the rows are asked for
+    Program(Box<Vm>),
+}
+
+// How `Db::open_with` opens the file, like the flags of sqlite3_open_v2. The default
+// reads and writes a file that must already exist
+// https://www.sqlite.org/c3ref/open.html
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    read_only: bool,
+    create: bool,
+    // Like PRAGMA cache_size, the pager's default when None
+    cache_size: Option<i64>,
+    // Of a database created by opening an empty file
+    page_size: u32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self {
+            read_only: false,
+            create: false,
+            cache_size: None,
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+    // Writes fail with "attempt to write a readonly database"
+    pub fn read_only(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
     }
+    pub fn read_write(&mut self) -> &mut Self {
+        self.read_only = false;
+        self
+    }
+    // Create the file when it doesn't exist, only when it is opened for writing
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+    // A number of pages, or the memory in KiB when negative
+    pub fn cache_size(&mut self, size: i64) -> &mut Self {
+        self.cache_size = Some(size);
+        self
+    }
+    // A power of two from 512 to 65536, only used when the file is empty
+    pub fn page_size(&mut self, size: u32) -> &mut Self {
+        self.page_size = size;
+        self
+    }
+}
+
+// The whole file of a database with no tables: page 1, the header and then an empty
+// sqlite_schema leaf. Write version 1 is the rollback journal, the text is UTF-8
+// https://www.sqlite.org/fileformat.html#the_database_header
+fn empty_database(page_size: u32) -> anyhow::Result<Vec<u8>> {
+    if !page_size.is_power_of_two() || !(PAGE_MIN_SIZE..=PAGE_MAX_SIZE).contains(&page_size) {
+        anyhow::bail!("page size is not a power of 2 from 512 to 65536: {}", page_size);
+    }
+    let mut page = vec![0; page_size as usize];
+    page[..HEADER_PREFIX.len()].copy_from_slice(HEADER_PREFIX);
+    // 65536 doesn't fit in two bytes and is stored as 1
+    let raw_page_size = if page_size == PAGE_MAX_SIZE { 1 } else { page_size as u16 };
+    page[HEADER_PAGE_SIZE_OFFSET..][..2].copy_from_slice(&raw_page_size.to_be_bytes());
+    page[HEADER_WRITE_VERSION_OFFSET] = 1;
+    page[HEADER_READ_VERSION_OFFSET] = 1;
+    // Maximum and minimum embedded payload fractions and the leaf payload fraction,
+    // which must be these
+    page[21..24].copy_from_slice(&[64, 32, 32]);
+    page[HEADER_CHANGE_COUNTER_OFFSET..][..4].copy_from_slice(&1u32.to_be_bytes());
+    page[HEADER_DATABASE_SIZE_OFFSET..][..4].copy_from_slice(&1u32.to_be_bytes());
+    page[HEADER_SCHEMA_FORMAT_OFFSET..][..4].copy_from_slice(&4u32.to_be_bytes());
+    page[HEADER_TEXT_ENCODING_OFFSET..][..4].copy_from_slice(&1u32.to_be_bytes());
+    page[HEADER_VERSION_VALID_FOR_OFFSET..][..4].copy_from_slice(&1u32.to_be_bytes());
+    page[HEADER_SQLITE_VERSION_OFFSET..][..4].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+    // No cells, their content area starts at the end of the page, where 0 means 65536
+    let btree_header = &mut page[HEADER_SIZE..];
+    btree_header[0] = TABLE_LEAF_PAGE_ID;
+    btree_header[5..7].copy_from_slice(&(page_size as u16).to_be_bytes());
+    Ok(page)
+}
+
+// The statement a script stopped at
+#[derive(Debug, thiserror::Error)]
+#[error("near line {line}: {error}")]
+pub struct ScriptError {
+    // Position of the statement in the script, from 1
+    pub statement: usize,
+    pub line: usize,
+    pub error: Error,
 }
 
 pub struct Db {
-    pub header: DbHeader,
-    pub pager: Pager,
-    pub table_schemas: HashMap<String, Schema>,
-    pub index_schemas: HashMap<String, Schema>,
+    pub(crate) header: DbHeader,
+    pub(crate) pager: Pager,
+    pub(crate) table_schemas: HashMap<String, Schema>,
+    // With the indexes SQLite made for UNIQUE and PRIMARY KEY constraints, see
+    // `Schema::is_autoindex`
+    pub(crate) index_schemas: HashMap<String, Schema>,
+    // The schema cookie when the schemas above were read, None until they are read again
+    schema_cookie: Option<u32>,
+    // The file change counter when the pages the pager has cached were last current, None
+    // before the first statement
+    change_counter: Option<u32>,
+    // Emit the rowid ahead of the record fields when expanding `*`
+    pub(crate) rowid_in_wildcard: bool,
+    pub(crate) function_context: FunctionContext,
+    // Bytes of rows an ORDER BY buffers before spilling a sorted run to disk
+    pub(crate) sort_memory_limit: usize,
+    // Results of earlier statements, when caching is on
+    pub(crate) result_cache: Option<ResultCache>,
+    // A transaction opened by BEGIN or SAVEPOINT, as opposed to the one each write runs in
+    explicit_transaction: bool,
+    // Names of the open savepoints, innermost last. Each has its level in the pager
+    savepoints: Vec<String>,
+    // The outermost savepoint opened the transaction, releasing it commits
+    savepoint_transaction: bool,
+    // Tables of the WITH clauses in scope, innermost last
+    common_tables: Vec<CommonTable>,
+    // Rows the last statement inserted, updated or deleted
+    changes: usize,
+    // What each statement took since they were last taken, while the timer is on
+    statement_stats: Option<Vec<StatementStats>>,
+}
+
+// A WITH clause table, with its rows once a query read it
+struct CommonTable {
+    definition: CommonTableExpr,
+    rows: Option<(Vec<Column>, Vec<SourceRow>)>,
+}
+
+// The result column of an IN subquery, its affinity and collation None unless it is a
+// column of one of the subquery's tables
+struct SubqueryColumn {
+    values: Vec<Value>,
+    affinity: Option<Affinity>,
+    collation: Option<Collation>,
 }
 
+// Names that refer to the rowid unless a column of the table shadows them
+const ROWID_ALIASES: [&str; 3] = ["rowid", "oid", "_rowid_"];
+
+// Names of the table of tables, rooted at page 1
+const SCHEMA_TABLES: [&str; 2] = ["sqlite_schema", "sqlite_master"];
+
+// Where ANALYZE keeps what it found for the planner
+const STAT1_TABLE: &str = "sqlite_stat1";
+
+// The largest rowid each AUTOINCREMENT table has used
+const SEQUENCE_TABLE: &str = "sqlite_sequence";
+
 impl Db {
     pub fn from_file(filename: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let mut file = File::open(filename).context("open db file")?;
+        Self::open_with(filename, &OpenOptions::new())
+    }
+    pub fn open_with(filename: impl AsRef<Path>, options: &OpenOptions) -> anyhow::Result<Self> {
+        let filename = filename.as_ref();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .create(options.create && !options.read_only)
+            .open(filename);
+        // Fall back to reading only, writes then fail with the OS error
+        let mut file = match file {
+            Result::Ok(file) => file,
+            Err(_) if !options.read_only && !options.create => File::open(filename).context("open db file")?,
+            Err(error) => return Err(error).context("open db file"),
+        };
+        // A file just created, or left empty, becomes a database with no tables
+        if !options.read_only && file.metadata().context("read db file size")?.len() == 0 {
+            file.write_all(&empty_database(options.page_size)?)
+                .context("write new db file")?;
+            file.sync_all().context("sync db file")?;
+            file.seek(SeekFrom::Start(0)).context("seek to db header")?;
+        }
+        let lock = FileLock::new(&file).context("open db file")?;
+        // Rolling back a commit that was cut short writes to the file, a writer does that
+        // before its first write. A journal whose writer is still at work isn't hot
+        if options.read_only
+            && journal::journal_path(filename).exists()
+            && !lock.reserved_elsewhere().context("check db file locks")?
+        {
+            anyhow::bail!("cannot open a database with a hot journal read-only");
+        }
         let mut header_buffer = [0; HEADER_SIZE];
         file.read_exact(&mut header_buffer)
             .context("read db header")?;
         let header = DbHeader::parse(&header_buffer)?;
-        let pager = Pager::new(file, header.page_size as usize);
-        Ok(Db {
+        let mut pager = Pager::new(file, header.page_size as usize, header.usable_size());
+        pager.set_journal_path(journal::journal_path(filename));
+        pager.set_lock(lock);
+        pager.set_auto_vacuum(header.auto_vacuum);
+        pager.set_read_only(options.read_only);
+        if header.wal_mode {
+            pager.set_wal(Wal::open(filename)?)?;
+        }
+        let mut db = Db {
             header,
             pager,
             table_schemas: HashMap::new(),
             index_schemas: HashMap::new(),
-        })
+            schema_cookie: None,
+            change_counter: None,
+            rowid_in_wildcard: false,
+            function_context: FunctionContext::default(),
+            sort_memory_limit: sorter::DEFAULT_MEMORY_LIMIT,
+            result_cache: None,
+            explicit_transaction: false,
+            savepoints: Vec::new(),
+            savepoint_transaction: false,
+            common_tables: Vec::new(),
+            changes: 0,
+            statement_stats: None,
+        };
+        if let Some(size) = options.cache_size {
+            db.set_cache_size(size);
+        }
+        Ok(db)
+    }
+    // Use Unicode case mapping in upper(), lower() and LIKE instead of ASCII only
+    #[cfg(feature = "unicode-case")]
+    pub fn set_unicode_case(&mut self, enabled: bool) {
+        self.function_context.case_folding = if enabled {
+            crate::functions::CaseFolding::Unicode
+        } else {
+            crate::functions::CaseFolding::Ascii
+        };
+    }
+    pub fn set_sort_memory_limit(&mut self, bytes: usize) {
+        self.sort_memory_limit = bytes;
+    }
+    // Like PRAGMA cache_size: a number of pages, or the memory in KiB when negative
+    pub fn set_cache_size(&mut self, size: i64) {
+        match size {
+            0.. => self.pager.set_cache_size(size as usize),
+            _ => self.pager.set_cache_memory(size.unsigned_abs() as usize * 1024),
+        }
+    }
+    // Make `SELECT *` behave like `SELECT rowid, *`
+    pub fn set_rowid_in_wildcard(&mut self, enabled: bool) {
+        self.rowid_in_wildcard = enabled;
+    }
+    // Serve repeated statements from memory until the database changes
+    pub fn set_result_cache(&mut self, enabled: bool) {
+        self.result_cache = enabled.then(ResultCache::default);
+    }
+    // Record the time and page reads of each statement, see `take_statement_stats`
+    pub fn set_timer(&mut self, enabled: bool) {
+        self.statement_stats = enabled.then(Vec::new);
+    }
+    // The stats of the statements run since the last call, in order
+    pub fn take_statement_stats(&mut self) -> Vec<StatementStats> {
+        self.statement_stats.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+    // Hold the writes of the following statements back until `commit`, `rollback` drops them
+    pub fn begin(&mut self) -> anyhow::Result<()> {
+        if self.explicit_transaction {
+            anyhow::bail!("cannot start a transaction within a transaction");
+        }
+        // Inside a script run as a unit the transaction is already open and now belongs to BEGIN
+        if !self.pager.in_transaction() {
+            self.pager.begin();
+        }
+        self.explicit_transaction = true;
+        Ok(())
+    }
+    // Commits and rollbacks end the whole transaction, savepoints included
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        if !self.explicit_transaction {
+            anyhow::bail!("cannot commit - no transaction is active");
+        }
+        self.pager.commit()?;
+        self.end_transaction();
+        Ok(())
+    }
+    pub fn rollback(&mut self) -> anyhow::Result<()> {
+        if !self.explicit_transaction {
+            anyhow::bail!("cannot rollback - no transaction is active");
+        }
+        self.end_transaction();
+        self.pager.rollback();
+        self.forget_schemas();
+        Ok(())
+    }
+    fn end_transaction(&mut self) {
+        self.explicit_transaction = false;
+        self.savepoints.clear();
+        self.savepoint_transaction = false;
+    }
+    // Mark a point the transaction can be rolled back to, opening the transaction when
+    // there is none
+    pub fn savepoint(&mut self, name: &str) -> anyhow::Result<()> {
+        if !self.explicit_transaction {
+            if !self.pager.in_transaction() {
+                self.pager.begin();
+            }
+            self.explicit_transaction = true;
+            self.savepoint_transaction = true;
+        }
+        self.pager.savepoint();
+        self.savepoints.push(name.to_string());
+        Ok(())
+    }
+    // Forget the savepoint and the ones set after it, keeping their writes. Releasing the
+    // savepoint that opened the transaction commits it
+    pub fn release(&mut self, name: &str) -> anyhow::Result<()> {
+        let level = self.savepoint_level(name)?;
+        if level == 0 && self.savepoint_transaction {
+            return self.commit();
+        }
+        while self.savepoints.len() > level {
+            self.savepoints.pop();
+            self.pager.release_savepoint();
+        }
+        Ok(())
+    }
+    // Undo the writes since the savepoint, which stays open while the ones set after it go
+    pub fn rollback_to(&mut self, name: &str) -> anyhow::Result<()> {
+        let level = self.savepoint_level(name)?;
+        while self.savepoints.len() > level + 1 {
+            self.savepoints.pop();
+            self.pager.release_savepoint();
+        }
+        self.pager.rollback_to_savepoint();
+        self.forget_schemas();
+        Ok(())
+    }
+    // The newest savepoint with the name wins
+    fn savepoint_level(&self, name: &str) -> anyhow::Result<usize> {
+        match self
+            .savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.eq_ignore_ascii_case(name))
+        {
+            Some(level) => Ok(level),
+            None => anyhow::bail!("no such savepoint: {}", name),
+        }
+    }
+    // Like sqlite3_changes, except that statements other than INSERT, UPDATE and DELETE
+    // leave it at 0
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+    // The file change counter and schema cookie, read from disk since another
+    // connection may have written to the database
+    pub fn data_version(&mut self) -> anyhow::Result<(u32, u32)> {
+        let page = self.pager.read_raw_page(1)?;
+        let read_u32 = |offset: usize| u32::from_be_bytes(page[offset..offset + 4].try_into().unwrap());
+        Ok((
+            read_u32(HEADER_CHANGE_COUNTER_OFFSET),
+            read_u32(HEADER_SCHEMA_COOKIE_OFFSET),
+        ))
+    }
+    // Stops at the first statement that fails, every statement takes its parameters from
+    // `params`
+    pub fn execute_sql_with(&mut self, sql: &str, params: &Params) -> anyhow::Result<Vec<QueryResult>> {
+        let mut results = Vec::new();
+        for (stmt, key, deep) in self.script_stmts(sql, params) {
+            results.push(self.run_script_stmt(stmt?, key, deep)?);
+        }
+        Ok(results)
     }
-    pub fn execute_sql(&mut self, sql: &str) -> anyhow::Result<Vec<Vec<Vec<String>>>> {
+    // Runs every statement of `sql` and returns the outcome of each, a statement that
+    // fails to parse or run doesn't stop the ones after it. Every statement takes its
+    // parameters from `params`
+    pub fn execute_script_with(&mut self, sql: &str, params: &Params) -> Vec<anyhow::Result<QueryResult>> {
+        self.script_stmts(sql, params)
+            .into_iter()
+            .map(|(stmt, key, deep)| stmt.and_then(|stmt| self.run_script_stmt(stmt, key, deep)))
+            .collect()
+    }
+    fn run_script_stmt(&mut self, stmt: Stmt, key: Option<String>, deep: bool) -> anyhow::Result<QueryResult> {
+        let mut stream = self.start_script_stmt(stmt, key, deep)?;
+        let mut rows = Vec::new();
+        while let Some(row) = self.next_row(&mut stream)? {
+            rows.push(row);
+        }
+        Ok(QueryResult { columns: stream.columns.to_vec(), rows })
+    }
+    // The statements of a script with `params` bound, each with the key the result cache
+    // keeps its rows under, when it is on, and whether it nests too deep to run on the
+    // stack of the thread, see `parser::SHALLOW_DEPTH`
+    pub fn script_stmts(&self, sql: &str, params: &Params) -> Vec<(anyhow::Result<Stmt>, Option<String>, bool)> {
         let mut scanner = scanner::Scanner::new(sql.to_string());
         let tokens = scanner.scan_tokens();
-        let mut parser = parser::Parser::new(tokens.clone());
-        let stmts = parser.parse().unwrap();
-        let mut result = Vec::new();
-        for stmt in stmts {
-            match stmt {
-                Stmt::Select(columns, from, where_clause) => {
-                    if let Some(table_ref) = from {
-                        // TODO: optimize
-                        if let Some(schema) = self.get_index_schema(&table_ref.name)? {
-                            let query_value = match &where_clause {
-                                Some(Expr::BinaryOp(_, _, where_value)) => {
-                                    match where_value.as_ref() {
-                                        Expr::Literal(name) =>  {
-                                            match name {
-                                                Literal::String(name) => name,
-                                                _ => continue,
-                                            }
-                                        },
-                                        _ => continue,
-                                    }
-                                },
-                                _ => continue,
-                            };
-                            // println!("index schema: {:#?}", schema);
-                            let page = self.read_page(schema.root_page as usize)?;
-                           
-                            let row_ids = self.get_row_ids(&page, &query_value)?;
-                 
-                            if let Some(table_schema) = self.get_table_schema(&table_ref.name)? {
-                                // println!("table_schema: {:#?}", table_schema);
-                                let page = self.read_page(table_schema.root_page as usize)?;
-                                let rows = self.get_rows(&page, &columns, &table_schema, row_ids)?;
-                                result.push(rows); 
-                            }
-                            continue;
-                        }
-                        if let Some(schema) = self.get_table_schema(&table_ref.name)? {
-                            // 索引信息不存在读取page
-                            let page = self.read_page(schema.root_page as usize)?;
-                            let rows = match page {
-                                Page::TableLeaf(leaf_page) => self.query_leaf_page(
-                                    &leaf_page,
-                                    &columns,
-                                    &schema,
-                                    &where_clause,
-                                ),
-                                Page::TableInterior(interior_page) => self.query_interior_page(
-                                    &interior_page,
-                                    &columns,
-                                    &schema,
-                                    &where_clause,
-                                ),
-                                _ => anyhow::bail!(
-                                    "Unknown page type in query: {:?}",
-                                    page.get_page_type()
-                                ),
-                            }?;
-
-                            result.push(rows);
-                        }
-                    }
+        let mut parser = parser::Parser::new(sql, tokens.clone());
+        // The cache is keyed by the text alone, which doesn't tell bound values apart
+        let mut keys = match self.result_cache.is_some() && params.is_empty() {
+            true => cache::statement_keys(tokens),
+            false => Vec::new(),
+        }
+        .into_iter();
+        let stmts = parser.parse_script();
+        stmts
+            .into_iter()
+            .zip(parser.depths())
+            .map(|(stmt, &depth)| {
+                let stmt = stmt.map(|mut stmt| {
+                    bind_parameters(&mut stmt, params);
+                    stmt
+                });
+                (stmt, keys.next().flatten(), depth > parser::SHALLOW_DEPTH)
+            })
+            .collect()
+    }
+    // Start a statement of a script. A query the result cache has is served from it, one
+    // it doesn't have is kept there once all of its rows were read
+    pub fn start_script_stmt(&mut self, stmt: Stmt, key: Option<String>, deep: bool) -> anyhow::Result<RowStream> {
+        // Writes always run
+        let key = match (key, stmt.is_read_only(), self.data_version()) {
+            (Some(key), true, Result::Ok(version)) => {
+                let cache = self.result_cache.as_mut().unwrap();
+                cache.validate(version);
+                if let Some(result) = cache.get(&key) {
+                    return Ok(RowStream::buffered(result.clone()));
+                }
+                Some(key)
+            }
+            _ => None,
+        };
+        let mut stream = self.start_bound_rows(stmt, deep)?;
+        if let Some(key) = key {
+            match &stream.rows {
+                StreamRows::Buffered(rows) => {
+                    let result = QueryResult { columns: stream.columns.to_vec(), rows: rows.as_slice().to_vec() };
+                    self.result_cache.as_mut().unwrap().insert(key, result);
                 }
+                _ => stream.cache = Some((key, Vec::new())),
             }
         }
-        anyhow::Ok(result)
+        Ok(stream)
     }
-
-    fn get_row_ids(&mut self, page: &Page, query_value: &str) -> anyhow::Result<Vec<usize>> {
-        // println!("page type: {:?}", page.get_page_type());
-        match page {
-            Page::IndexLeaf(leaf_page) => {
-                let mut result = Vec::new();
-                for cell in &leaf_page.cells {
-                    let key = cell.record.body[0].value.clone();
-                    if key == Value::String(query_value.to_string()) {
-                        let row_id = match cell.record.body.last().unwrap().value {
-                            Value::I64(i) => i as usize,
-                            _ => anyhow::bail!("Invalid row id"),
-                        };
-                        result.push(row_id);
-                    }
+    // Run a prepared statement with `params` bound, the parsed statement is kept as it
+    // was for the next run. A `deep` one runs on a thread with a larger stack
+    pub fn execute_prepared(&mut self, stmt: &Stmt, params: &Params, deep: bool) -> anyhow::Result<QueryResult> {
+        if deep {
+            return parser::on_deep_stack(|| self.execute_prepared(stmt, params, false));
+        }
+        let mut stmt = stmt.clone();
+        bind_parameters(&mut stmt, params);
+        self.execute_stmt(stmt)
+    }
+    // Start running a prepared statement whose rows are then taken with `next_row`, so
+    // a caller that stops early skips the work for the rest. Only a SELECT is read
+    // lazily, any other statement, or one too `deep` for the thread's stack, runs in full here
+    pub fn start_rows(&mut self, stmt: &Stmt, params: &Params, deep: bool) -> anyhow::Result<RowStream> {
+        if deep {
+            return Ok(RowStream::buffered(self.execute_prepared(stmt, params, true)?));
+        }
+        let mut stmt = stmt.clone();
+        bind_parameters(&mut stmt, params);
+        self.start_bound_rows(stmt, false)
+    }
+    fn start_bound_rows(&mut self, stmt: Stmt, deep: bool) -> anyhow::Result<RowStream> {
+        if deep {
+            return Ok(RowStream::buffered(self.execute_stmt_on_stack(stmt, deep)?));
+        }
+        if let Stmt::Select(select) = &stmt {
+            let start = self.statement_stats.is_some().then(|| self.stats_start());
+            // A stream keeps SHARED until it runs out
+            match self.check_schema_cookie().and_then(|_| self.select_stream(select)) {
+                Result::Ok(mut stream) => {
+                    stream.timer = start;
+                    return Ok(stream);
                 }
-                anyhow::Ok(result)
-            }
-            Page::IndexInterior(interior_page) => {
-                let mut result = Vec::new();
-                for cell in &interior_page.cells {
-                    let key = cell.record.body[0].value.clone();
-                    if key >= Value::String(query_value.to_string()) {
-                        let page = self.read_page(cell.left_child as usize)?; 
-                        let row_ids = self.get_row_ids(&page, query_value)?;
-                        result.extend(row_ids);
-                    }
-                    if key == Value::String(query_value.to_string()) {
-                        let row_id = match cell.record.body.last().unwrap().value {
-                            Value::I64(i) => i as usize,
-                            _ => anyhow::bail!("Invalid row id"),
-                        };
-                       
-                        result.push(row_id);
-                    }
+                Err(err) => {
+                    self.pager.end_read();
+                    return Err(err);
                 }
-                let right_page = self.read_page(interior_page.header.get_right_most_point() as usize)?; 
-                let row_ids = self.get_row_ids(&right_page, query_value)?;
-                result.extend(row_ids);
-                anyhow::Ok(result)
             }
-            Page::TableInterior(interior_page) => {
-                anyhow::bail!("get_row_ids expected an index page, found {:?}", page.get_page_type())
+        }
+        Ok(RowStream::buffered(self.execute_stmt(stmt)?))
+    }
+    pub fn next_row(&mut self, stream: &mut RowStream) -> anyhow::Result<Option<Row>> {
+        let row = self.read_row(stream).inspect_err(|_| self.pager.end_read())?;
+        match (&row, &mut stream.cache) {
+            (Some(row), Some((_, rows))) => rows.push(row.clone()),
+            (Some(_), None) => (),
+            (None, _) => self.finish_stream(stream),
+        }
+        Ok(row)
+    }
+    // Take SHARED again for the next row of a stream whose reader let go of it between
+    // rows, noticing the writes made meanwhile
+    pub fn resume_read(&mut self) -> anyhow::Result<()> {
+        self.check_schema_cookie()
+    }
+    // For a stream dropped before it ran out
+    pub fn end_read(&mut self) {
+        self.pager.end_read();
+    }
+    // Record the stats of a stream that ran out, and cache its rows
+    fn finish_stream(&mut self, stream: &mut RowStream) {
+        self.pager.end_read();
+        if let Some(start) = stream.timer.take() {
+            self.record_stats(start);
+        }
+        if let (Some((key, rows)), Some(cache)) = (stream.cache.take(), self.result_cache.as_mut()) {
+            cache.insert(key, QueryResult { columns: stream.columns.to_vec(), rows });
+        }
+    }
+    fn read_row(&mut self, stream: &mut RowStream) -> anyhow::Result<Option<Row>> {
+        let vm = match &mut stream.rows {
+            StreamRows::Buffered(rows) => return Ok(rows.next()),
+            StreamRows::Program(vm) => vm,
+        };
+        // The statement's WITH clause tables are in scope while it runs
+        let depth = self.common_tables.len();
+        self.common_tables.append(&mut vm.common_tables);
+        let row = vm.step(self);
+        vm.common_tables = self.common_tables.split_off(depth);
+        Ok(row?.map(|values| Row::new(stream.columns.clone(), values)))
+    }
+    // A stream over the rows of `select`, compiled to a program here
+    fn select_stream(&mut self, select: &SelectStmt) -> anyhow::Result<RowStream> {
+        let depth = self.push_common_tables(&select.with)?;
+        let result = self.sources(select).and_then(|sources| {
+            let columns = column_names(select, &sources, self.rowid_in_wildcard);
+            Ok((columns, self.compile(select, sources)?))
+        });
+        let common_tables = self.common_tables.split_off(depth);
+        let (columns, program) = result?;
+        let mut vm = Vm::new(program);
+        vm.common_tables = common_tables;
+        Ok(RowStream {
+            columns: columns.into(),
+            rows: StreamRows::Program(Box::new(vm)),
+            timer: None,
+            cache: None,
+        })
+    }
+    // Runs a script as a unit, for `.read` and scripts piped on stdin: the first statement
+    // that fails stops the script, no results are returned and the writes of the statements
+    // before it are rolled back, unless the script opened a transaction of its own with BEGIN
+    pub fn execute_script_atomic_with(&mut self, sql: &str, params: &Params) -> Result<Vec<QueryResult>, ScriptError> {
+        let opened = self.begin_script();
+        let mut results = Vec::new();
+        let executed = self.execute_script_part(sql, params, |result| results.push(result));
+        let finished = self.finish_script(opened, executed.is_ok());
+        executed?;
+        // The commit comes at the end of the script
+        finished.map_err(|error| ScriptError {
+            statement: results.len(),
+            line: sql.lines().count(),
+            error: error.into(),
+        })?;
+        Result::Ok(results)
+    }
+    // Open the transaction a script runs in, returns false when one is open already and
+    // the script becomes part of it
+    pub fn begin_script(&mut self) -> bool {
+        let opened = !self.pager.in_transaction();
+        if opened {
+            self.pager.begin();
+        }
+        opened
+    }
+    // The statements of a script, or of the part of it between two dot commands, in the
+    // transaction of `begin_script`, each result handed to `each` once its statement is
+    // done. Stops at the first that fails, the error numbers its statement and line from
+    // the start of `sql`
+    pub fn execute_script_part(
+        &mut self,
+        sql: &str,
+        params: &Params,
+        mut each: impl FnMut(QueryResult),
+    ) -> Result<(), ScriptError> {
+        let mut scanner = scanner::Scanner::new(sql.to_string());
+        let tokens = scanner.scan_tokens();
+        let mut parser = parser::Parser::new(sql, tokens.clone());
+        let stmts = parser.parse_script_lines();
+        for (i, ((line, stmt), &depth)) in stmts.into_iter().zip(parser.depths()).enumerate() {
+            let stmt = stmt.map(|mut stmt| {
+                bind_parameters(&mut stmt, params);
+                stmt
+            });
+            match stmt.and_then(|stmt| self.execute_stmt_on_stack(stmt, depth > parser::SHALLOW_DEPTH)) {
+                Result::Ok(result) => each(result),
+                Err(error) => {
+                    return Err(ScriptError {
+                        statement: i + 1,
+                        line,
+                        error: error.into(),
+                    })
+                }
             }
-            Page::TableLeaf(leaf_page) => {
-                anyhow::bail!("get_row_ids expected an index page, found {:?}", page.get_page_type())
+        }
+        Result::Ok(())
+    }
+    // Commit the transaction `begin_script` opened, or roll it back when the script
+    // failed. One BEGIN in the script left open stays open
+    pub fn finish_script(&mut self, opened: bool, succeeded: bool) -> anyhow::Result<()> {
+        if !opened || self.explicit_transaction || !self.pager.in_transaction() {
+            // COMMIT or ROLLBACK in the script may have closed the transaction already
+            return Ok(());
+        }
+        if succeeded {
+            if let Err(error) = self.pager.commit() {
+                self.pager.rollback();
+                self.forget_schemas();
+                return Err(error);
             }
+        } else {
+            self.pager.rollback();
+            self.forget_schemas();
         }
+        Ok(())
     }
-
-    fn get_rows(
-        &mut self,
-        page: &Page,
-        columns: &[Expr],
-        schema: &Schema,
-        row_ids: Vec<usize>,
-    ) -> anyhow::Result<Vec<Vec<String>>> {
-        match page {
-            Page::TableLeaf(leaf_page) => self.get_rows_leaf(leaf_page, columns, schema, row_ids),
-            Page::TableInterior(interior_page) => self.get_rows_interior(interior_page, columns, schema, row_ids),
-            _ => anyhow::bail!("get_row_by_row_id expected a table leaf page, found {:?}", page),
+    // `execute_stmt` on a thread with a larger stack when the statement is too `deep`
+    // for this one's
+    fn execute_stmt_on_stack(&mut self, stmt: Stmt, deep: bool) -> anyhow::Result<QueryResult> {
+        match deep {
+            true => parser::on_deep_stack(|| self.execute_stmt(stmt)),
+            false => self.execute_stmt(stmt),
         }
     }
-
-
-    fn get_rows_leaf(
-        &mut self,
-        leaf_page: &TableLeafPage,
-        columns: &[Expr],
-        schema: &Schema,
-        row_ids: Vec<usize>,
-    ) -> anyhow::Result<Vec<Vec<String>>> {
-       let mut result = Vec::new();
-       let column_names = columns.iter().map(|column| match column {
-            Expr::Identifier(name) => name.clone(),
-            _ => String::new(),
-        }).collect::<Vec<String>>();
-        for cell in &leaf_page.cells {
-            let mut row_map = HashMap::new();
-            for (column, record_body) in schema.columns.iter().zip(cell.record.body.iter()) {
-                // println!("column: {:?}", column);
-                let key = column.name.clone();
-                if column_names.contains(&key) {
-                    let value = &record_body.value;
-                    row_map.insert(key, value);
-                }
-            }
-            let id = match row_map.get("id") {
-                Some(Value::I64(i)) => *i as usize,
-                _ => anyhow::bail!("Invalid row id"),
-            };
-            if !row_ids.contains(&id) {
-                continue;
+    // Each write is a transaction of its own, or a step inside the open one that
+    // is undone on its own when it fails
+    fn execute_stmt(&mut self, stmt: Stmt) -> anyhow::Result<QueryResult> {
+        if self.statement_stats.is_none() {
+            return self.execute_stmt_untimed(stmt);
+        }
+        let start = self.stats_start();
+        let result = self.execute_stmt_untimed(stmt);
+        self.record_stats(start);
+        result
+    }
+    fn stats_start(&self) -> StatsStart {
+        (Instant::now(), self.pager.io_stats(), self.pager.cache_stats())
+    }
+    fn record_stats(&mut self, (start, io, cache): StatsStart) {
+        let (io_after, cache_after) = (self.pager.io_stats(), self.pager.cache_stats());
+        let stats = StatementStats {
+            elapsed: start.elapsed(),
+            pages_read: io_after.pages_read - io.pages_read,
+            pages_written: io_after.pages_written - io.pages_written,
+            cache_hits: cache_after.hits - cache.hits,
+            cache_misses: cache_after.misses - cache.misses,
+        };
+        if let Some(all) = self.statement_stats.as_mut() {
+            all.push(stats);
+        }
+    }
+    fn execute_stmt_untimed(&mut self, stmt: Stmt) -> anyhow::Result<QueryResult> {
+        // Transaction statements manage the savepoints themselves
+        if stmt.is_read_only() || stmt.is_transaction_control() {
+            return self.reading(|db| db.run_stmt(stmt));
+        }
+        // Rolling back a hot journal can change the schema too
+        if let Err(err) = self.pager.lock_for_write() {
+            // A transaction this statement would have opened never started
+            if !self.pager.in_transaction() {
+                self.pager.rollback();
             }
-            let mut row = Vec::new();
-            for column in columns {
-                match column {
-                    Expr::Identifier(name) => {
-                        if let Some(value) = row_map.get(name) {
-                            row.push(match value {
-                                Value::I64(i) => i.to_string(),
-                                Value::String(s) => s.clone(),
-                                _ => anyhow::bail!("Invalid value type"),
-                            });
-                        }
-                    }
-                    
-                    _ => {}
+            return Err(err);
+        }
+        self.check_schema_cookie()?;
+        if self.pager.in_transaction() {
+            self.pager.savepoint();
+            let result = self.run_stmt(stmt);
+            match result {
+                Result::Ok(_) => self.pager.release_savepoint(),
+                Err(_) => {
+                    self.pager.rollback_to_savepoint();
+                    self.pager.release_savepoint();
+                    self.forget_schemas();
                 }
             }
-            result.push(row);
+            return result;
+        }
+        self.pager.begin();
+        match self.run_stmt(stmt).and_then(|result| self.pager.commit().map(|_| result)) {
+            Result::Ok(result) => Ok(result),
+            Err(err) => {
+                self.pager.rollback();
+                self.forget_schemas();
+                Err(err)
+            }
         }
-
-        anyhow::Ok(result)
     }
-
-    fn get_rows_interior(
-        &mut self,
-        interior_page: &TableInteriorPage,
-        columns: &[Expr],
-        schema: &Schema,
-        row_ids: Vec<usize>,
-    ) -> anyhow::Result<Vec<Vec<String>>> {
-        let mut rows = Vec::new();
-        for cell in &interior_page.cells {
-            if row_ids.iter().any(|id| *id < cell.row_id as usize) {
-                let page = self.read_page(cell.left_child as usize)?;
-                let _rows = self.get_rows(&page, columns, schema, row_ids.clone())?;
-                rows.extend(_rows);
+    fn run_stmt(&mut self, stmt: Stmt) -> anyhow::Result<QueryResult> {
+        self.changes = 0;
+        match stmt {
+            Stmt::Select(select) => {
+                let (columns, rows) = self.query(&select)?;
+                Ok(QueryResult::new(columns, rows))
+            }
+            Stmt::Explain(stmt) => self.explain(*stmt),
+            Stmt::Insert(insert) => self.insert(&insert),
+            Stmt::Update(update) => self.update(&update),
+            Stmt::Delete(delete) => self.delete(&delete),
+            Stmt::CreateTable(create) => self.create_table(&create),
+            Stmt::CreateIndex(create) => self.create_index(&create),
+            Stmt::DropTable { name, if_exists } => self.drop_table(&name, if_exists),
+            Stmt::DropIndex { name, if_exists } => self.drop_index(&name, if_exists),
+            Stmt::AlterTable(alter) => self.alter_table(&alter),
+            Stmt::Begin
+            | Stmt::Commit
+            | Stmt::Rollback { .. }
+            | Stmt::Savepoint(_)
+            | Stmt::Release(_) => {
+                match stmt {
+                    Stmt::Begin => self.begin()?,
+                    Stmt::Commit => self.commit()?,
+                    Stmt::Rollback { savepoint: None } => self.rollback()?,
+                    Stmt::Rollback {
+                        savepoint: Some(name),
+                    } => self.rollback_to(&name)?,
+                    Stmt::Savepoint(name) => self.savepoint(&name)?,
+                    Stmt::Release(name) => self.release(&name)?,
+                    _ => unreachable!(),
+                }
+                Ok(QueryResult {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                })
+            }
+            Stmt::Values(values) => {
+                let rows = self.values_rows(&values)?;
+                let columns = values_columns(values[0].len())
+                    .into_iter()
+                    .map(|column| column.name)
+                    .collect();
+                Ok(QueryResult::new(columns, rows.into_iter().map(|row| row.values).collect()))
             }
+            Stmt::Pragma { name, value } => self.pragma(&name, value.as_deref()),
+            Stmt::Analyze(name) => self.analyze(name.as_deref()),
         }
-        let page = self.read_page(interior_page.header.get_right_most_point() as usize)?;
-        let _rows = self.get_rows(&page, columns, schema, row_ids.clone())?;
-        rows.extend(_rows);
-        anyhow::Ok(rows)
     }
 
-    fn query_leaf_page(
-        &mut self,
-        leaf_page: &TableLeafPage,
-        columns: &[Expr],
-        schema: &Schema,
-        where_clause: &Option<Expr>,
-    ) -> anyhow::Result<Vec<Vec<String>>> {
-        let mut result = Vec::new();
-        for cell in &leaf_page.cells {
-            let mut row_map = HashMap::new();
-            for (column, record_body) in schema.columns.iter().zip(cell.record.body.iter()) {
-                let key = column.name.clone();
-                let value = record_body.value.to_string();
-                row_map.insert(key, value);
-            }
-            if !self.where_clause_matches(where_clause, &row_map) {
-                continue;
+    // Like SQLite, a pragma it doesn't know does nothing
+    // https://www.sqlite.org/pragma.html
+    fn pragma(&mut self, name: &str, value: Option<&str>) -> anyhow::Result<QueryResult> {
+        match name {
+            "integrity_check" => {
+                // At most this many problems are listed, 100 unless a positive number is given
+                let max_errors = match value {
+                    None => 100,
+                    Some(value) => match value.parse::<i64>() {
+                        Result::Ok(n) if n > 0 => n as usize,
+                        Result::Ok(_) => 100,
+                        Err(_) => anyhow::bail!("integrity_check of a single table is not supported: {}", value),
+                    },
+                };
+                let rows = self.integrity_check(max_errors)?;
+                let rows = rows.into_iter().map(|row| vec![Value::String(row)]).collect();
+                Ok(QueryResult::new(vec!["integrity_check".to_string()], rows))
             }
-            let mut row = Vec::new();
+            _ => Ok(QueryResult {
+                columns: Vec::new(),
+                rows: Vec::new(),
+            }),
+        }
+    }
 
-            for column in columns {
-                match column {
-                    Expr::Identifier(name) => {
-                        if let Some(value) = row_map.get(name) {
-                            row.push(value.clone());
-                        } else {
-                            row.push("NULL".to_string());
-                        }
-                    }
-                    Expr::FunctionCall(name, args) => {
-                        if let Expr::Identifier(func_name) = name.as_ref() {
-                            match func_name.as_str() {
-                                "count" => {
-                                    let count = leaf_page.cells.len() as i64;
-                                    row.push(count.to_string());
-                                    return Ok(vec![row]);
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    _ => {}
+    fn insert(&mut self, insert: &InsertStmt) -> anyhow::Result<QueryResult> {
+        let Some(schema) = self.get_table_schema(&insert.table)? else {
+            anyhow::bail!(Error::NoSuchTable(insert.table.clone()));
+        };
+        // Position in the record of each value of a row
+        let positions = if insert.columns.is_empty() {
+            (0..schema.columns.len()).collect()
+        } else {
+            let mut positions = Vec::new();
+            for name in &insert.columns {
+                let name = name.to_lowercase();
+                match schema.columns.iter().position(|column| column.name == name) {
+                    Some(i) => positions.push(i),
+                    None => anyhow::bail!("table {} has no column named {}", insert.table, name),
                 }
             }
-            result.push(row);
+            positions
+        };
+        let width = insert.values[0].len();
+        if width != positions.len() {
+            if insert.columns.is_empty() {
+                anyhow::bail!(
+                    "table {} has {} columns but {} values were supplied",
+                    insert.table,
+                    positions.len(),
+                    width
+                );
+            }
+            anyhow::bail!("{} values for {} columns", width, positions.len());
         }
-        Ok(result)
+        let indexes = self.get_index_schemas(&schema.table_name)?;
+        self.check_writable(&schema, &indexes)?;
+        let orders = indexes
+            .iter()
+            .map(|index| index_order(&schema, index))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let root_page = schema.root_page as usize;
+        let rowid_alias = schema.columns.iter().position(Column::is_rowid_alias);
+        let autoincrement = schema.is_autoincrement();
+        let mut sequence = match autoincrement {
+            true => self.sequence(&schema.table_name)?,
+            false => None,
+        };
+        let start_sequence = sequence;
+
+        for row in self.values_rows(&insert.values)? {
+            // Columns left out take their default
+            let mut record = schema.columns.iter().map(|column| column.default.clone()).collect::<Vec<_>>();
+            for (value, position) in row.values.into_iter().zip(&positions) {
+                record[*position] = value;
+            }
+            // The INTEGER PRIMARY KEY is the rowid, its field in the record stays NULL
+            let given_row_id = match rowid_alias.map(|i| std::mem::replace(&mut record[i], Value::Null)) {
+                None | Some(Value::Null) => None,
+                Some(value) => Some(to_row_id(value)?),
+            };
+            check_not_null(&schema, &record)?;
+            let record = stored_record(&schema, record);
+            let row_id = match given_row_id {
+                Some(row_id) => row_id,
+                // An AUTOINCREMENT table doesn't go back to rowids that were deleted
+                None => match btree::last_row_id(&mut self.pager, root_page)?.max(sequence) {
+                    Some(i64::MAX) if autoincrement => anyhow::bail!("database or disk is full"),
+                    Some(i64::MAX) => self.random_row_id(root_page)?,
+                    Some(last) => last + 1,
+                    None => 1,
+                },
+            };
+            if autoincrement {
+                sequence = sequence.max(Some(row_id));
+            }
+            let payload = Record::serialize(&record);
+            if !btree::insert_row(&mut self.pager, root_page, row_id, &payload)? {
+                let column = format!("{}.{}", schema.table_name, schema.rowid_name());
+                anyhow::bail!(Error::Constraint(format!("UNIQUE constraint failed: {}", column)));
+            }
+            for (index, order) in indexes.iter().zip(&orders) {
+                let entry = index_entry(&schema, index, &record, row_id)?;
+                self.check_unique(&schema, index, &entry, order)?;
+                btree::insert_index_entry(&mut self.pager, index.root_page as usize, &entry, order)?;
+            }
+        }
+        if let Some(sequence) = sequence.filter(|_| sequence != start_sequence) {
+            self.set_sequence(&schema.table_name, sequence)?;
+        }
+        self.finish_write()?;
+        self.changes = insert.values.len();
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
     }
-    fn query_interior_page(
-        &mut self,
-        interior_page: &TableInteriorPage,
-        columns: &[Expr],
-        schema: &Schema,
-        where_clause: &Option<Expr>,
-    ) -> anyhow::Result<Vec<Vec<String>>> {
-        let mut result = Vec::new();
-        for cell in &interior_page.cells {
-            let page = self.read_page(cell.left_child as usize)?;
-            match page {
-                Page::TableLeaf(leaf_page) => {
-                    let mut rows =
-                        self.query_leaf_page(&leaf_page, columns, schema, where_clause)?;
-                    result.append(&mut rows);
-                }
-                Page::TableInterior(interior_page) => {
-                    let mut rows =
-                        self.query_interior_page(&interior_page, columns, schema, where_clause)?;
-                    result.append(&mut rows);
+
+    // The matching rows are found the way a SELECT finds them and the new values are
+    // worked out from the old ones first, then each row is taken out of its table and
+    // index b-trees and put back with its new record
+    fn update(&mut self, update: &UpdateStmt) -> anyhow::Result<QueryResult> {
+        let (schema, rows) = self.matching_rows(&update.table, &update.where_clause)?;
+        let indexes = self.get_index_schemas(&schema.table_name)?;
+        self.check_writable(&schema, &indexes)?;
+        let rowid_alias = schema.columns.iter().position(Column::is_rowid_alias);
+        // Position in the record of each assigned column, None for the rowid
+        let mut targets = Vec::new();
+        for (name, _) in &update.assignments {
+            let name = name.to_lowercase();
+            match schema.columns.iter().position(|column| column.name == name) {
+                Some(i) if Some(i) != rowid_alias => targets.push(Some(i)),
+                _ if schema.is_rowid(&name) => targets.push(None),
+                _ => anyhow::bail!(Error::NoSuchColumn(name.clone())),
+            }
+        }
+
+        // (old row, new rowid, new record)
+        let mut changes = Vec::new();
+        for (row, row_map) in &rows {
+            let mut values = row.values.clone();
+            let mut row_id = row.row_id;
+            for (target, (_, expr)) in targets.iter().zip(&update.assignments) {
+                let value = evaluate(expr, row_map, &self.function_context)?;
+                match target {
+                    Some(i) => values[*i] = value,
+                    // Unlike an INSERT, a NULL rowid is a datatype mismatch
+                    None => row_id = to_row_id(value)?,
                 }
-                _ => {}
             }
+            // The rowid alias reads NULL in the record
+            if let Some(i) = rowid_alias {
+                values[i] = Value::Null;
+            }
+            check_not_null(&schema, &values)?;
+            changes.push((row, row_id, stored_record(&schema, values)));
         }
-        let right_page = self.read_page(interior_page.header.get_right_most_point() as usize)?;
-        match right_page {
-            Page::TableLeaf(leaf_page) => {
-                let mut rows =
-                    self.query_leaf_page(&leaf_page, columns, schema, where_clause)?;
-                result.append(&mut rows);
+
+        let root_page = schema.root_page as usize;
+        for (old_row, row_id, record) in changes {
+            if row_id != old_row.row_id && seek_row(&mut self.pager, root_page, row_id)?.is_some() {
+                let column = format!("{}.{}", schema.table_name, schema.rowid_name());
+                anyhow::bail!(Error::Constraint(format!("UNIQUE constraint failed: {}", column)));
             }
-            Page::TableInterior(interior_page) => {
-                let mut rows =
-                    self.query_interior_page(&interior_page, columns, schema, where_clause)?;
-                result.append(&mut rows);
+            self.remove_row(&schema, &indexes, old_row)?;
+            btree::insert_row(&mut self.pager, root_page, row_id, &Record::serialize(&record))?;
+            for index in &indexes {
+                let entry = index_entry(&schema, index, &record, row_id)?;
+                let order = index_order(&schema, index)?;
+                self.check_unique(&schema, index, &entry, &order)?;
+                btree::insert_index_entry(&mut self.pager, index.root_page as usize, &entry, &order)?;
             }
-            _ => {}
         }
-        Ok(result)
+        self.finish_write()?;
+        self.changes = rows.len();
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // The cells of the matching rows come off their pages onto the freeblock chain, a
+    // page left mostly empty is merged with its siblings and emptied pages go on the
+    // freelist, so later inserts reuse the space
+    fn delete(&mut self, delete: &DeleteStmt) -> anyhow::Result<QueryResult> {
+        let (schema, rows) = self.matching_rows(&delete.table, &delete.where_clause)?;
+        let indexes = self.get_index_schemas(&schema.table_name)?;
+        self.check_writable(&schema, &indexes)?;
+        for (row, _) in &rows {
+            self.remove_row(&schema, &indexes, row)?;
+        }
+        self.finish_write()?;
+        self.changes = rows.len();
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
     }
 
-    fn where_clause_matches(
+    // Rows of `table` that satisfy the WHERE clause, with their row maps, all read before
+    // any of them is changed
+    fn matching_rows(
         &mut self,
+        table: &str,
         where_clause: &Option<Expr>,
-        row_map: &HashMap<String, String>,
-    ) -> bool {
-        match where_clause {
-            Some(expr) => self.check(expr, row_map),
-            None => true,
-        }
-    }
-    fn check(&mut self, where_expr: &Expr, row_map: &HashMap<String, String>) -> bool {
-        match where_expr {
-            Expr::BinaryOp(left, op, right) => {
-                let left = if let Expr::Identifier(name) = left.as_ref() {
-                    row_map.get(name).unwrap().to_string()
-                } else {
-                    "".to_string()
-                };
-                let right = match right.as_ref() {
-                    Expr::Identifier(name) => row_map.get(name).unwrap().to_string(),
-                    Expr::Literal(literal) => match literal {
-                        Literal::String(s) => s.to_string(),
-                        Literal::Number(n) => n.to_string(),
-                        Literal::Boolean(b) => b.to_string(),
-                        Literal::Null => "NULL".to_string(),
-                    },
-                    _ => "".to_string(),
-                };
-
-                match op.token_type {
-                    TokenType::Equal => left == right,
-                    _ => false,
-                }
+    ) -> anyhow::Result<(Schema, Vec<MatchingRow>)> {
+        let table = TableReference {
+            name: table.to_string(),
+            alias: None,
+            values: None,
+        };
+        let sources = [self.source(&table, JoinKind::Inner, None)?];
+        let mut where_clause = match where_clause {
+            Some(where_expr) => {
+                reject_aggregates(where_expr)?;
+                Some(self.run_subqueries(where_expr, &sources, None)?)
             }
-            _ => false,
+            None => None,
+        };
+        if let Some(where_expr) = &mut where_clause {
+            apply_affinities(where_expr, &sources);
+            apply_collations(where_expr, &sources);
+        }
+        let mut vm = Vm::new(self.compile_scan(sources[0].clone(), where_clause)?);
+        let mut rows = Vec::new();
+        while let Some(mut values) = vm.step(self)? {
+            let Value::I64(row_id) = values.remove(0) else {
+                unreachable!("a rowid");
+            };
+            let row = SourceRow { row_id, values };
+            let row_map = row_map(&sources, &[Some(row.clone())]);
+            rows.push((row, row_map));
         }
+        let [source] = sources;
+        Ok((source.schema, rows))
     }
 
-    fn read_page(&mut self, page_num: usize) -> anyhow::Result<Page> {
-        self.pager.read_page(page_num).map(|page| page.clone())
-    }
-    fn read_first_page(&mut self) -> anyhow::Result<Page> {
-        self.read_page(1)
+    // Writes here keep a table b-tree, its indexes and its NOT NULL constraints and
+    // AUTOINCREMENT counter the way sqlite3 does, anything more is refused rather than
+    // left for sqlite3 to find broken
+    fn check_writable(&self, schema: &Schema, indexes: &[Schema]) -> anyhow::Result<()> {
+        if schema.is_without_rowid() {
+            anyhow::bail!("writing to a WITHOUT ROWID table is not supported");
+        }
+        if schema.has_check() {
+            anyhow::bail!("writing to a table with CHECK constraints is not supported");
+        }
+        for index in indexes {
+            if index.is_partial() {
+                anyhow::bail!("writing to a table with a partial index is not supported");
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_schemas(&mut self) -> anyhow::Result<()> {
-        let first_page = self.read_first_page()?;
-        let mut table_schemas = HashMap::new();
-        let mut index_schemas = HashMap::new();
-        if let Page::TableLeaf(page) = first_page {
-            for cell in page.cells {
-                // 0: schema_type
-                // 1: schema_name
-                // 2: table_name
-                // 3: rootpage
-                // 4: sql
-                let schema_type = match &cell.record.body.get(0) {
-                    Some(record_body) => match &record_body.value {
-                        Value::String(schema_type) => schema_type.clone(),
-                        _ => continue,
-                    },
-                    None => continue,
-                };
-                let schema_name = match &cell.record.body.get(1).unwrap().value {
-                    Value::String(name) => name.clone(),
-                    _ => continue,
-                };
-                let table_name = match &cell.record.body.get(2).unwrap().value {
-                    Value::String(name) => name.clone(),
-                    _ => continue,
-                };
-                let root_page = match &cell.record.body.get(3).unwrap().value {
-                    Value::I64(n) => *n as i8,
-                    _ => continue,
-                };
-                let sql = match &cell.record.body.get(4).unwrap().value {
-                    Value::String(sql) => sql.clone(),
-                    _ => continue,
-                };
-
-               
-                match schema_type.as_str() {
-                    "table" => {
-                        let columns = parse_create_table_sql(&sql)?;
-                        table_schemas.insert(
-                            table_name.clone(),
-                            Schema {
-                                schema_name,
-                                table_name,
-                                sql,
-                                root_page,
-                                columns,
-                            },
-                        );
-                    }
-                    "index" => {
-                        let columns = parse_create_index_sql(&sql)?;
-                        index_schemas.insert(
-                            table_name.clone(),
-                            Schema {
-                                schema_name,
-                                table_name,
-                                sql,
-                                root_page,
-                                columns,
-                            },
-                        );
-                    }
-                    &_ => todo!(),
-                };
+    // The seq of the table's row in sqlite_sequence, None before its first rowid
+    // Past the largest rowid, like SQLite a few random positive ones are tried for one
+    // that is free
+    fn random_row_id(&mut self, root_page: usize) -> anyhow::Result<i64> {
+        for _ in 0..100 {
+            let row_id = (functions::next_random() >> 2) as i64 + 1;
+            if seek_row(&mut self.pager, root_page, row_id)?.is_none() {
+                return Ok(row_id);
             }
         }
-        self.table_schemas = table_schemas;
-        self.index_schemas = index_schemas;
-        // println!("index_schemas: {:#?}", self.index_schemas);
-        anyhow::Ok(())
+        anyhow::bail!("database or disk is full")
+    }
+    fn sequence(&mut self, table: &str) -> anyhow::Result<Option<i64>> {
+        Ok(self.sequence_row(table)?.map(|(_, sequence)| sequence))
     }
-    pub fn get_index_schema(&mut self, table_name: &str) -> anyhow::Result<Option<Schema>> {
+    fn sequence_row(&mut self, table: &str) -> anyhow::Result<Option<(i64, i64)>> {
         self.get_schemas()?;
-        let index_schema = self.index_schemas.get(table_name);
-        match index_schema {
-            Some(schema) => anyhow::Ok(Some(schema.clone())),
-            _ => anyhow::Ok(None),
+        let Some(root_page) = self.table_schemas.get(SEQUENCE_TABLE).map(|schema| schema.root_page as usize) else {
+            return Ok(None);
+        };
+        let mut cursor = TableCursor::new(root_page);
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            let Some(ValueRef::String(name)) = cell.record.value_ref(0) else {
+                continue;
+            };
+            if name.eq_ignore_ascii_case(table) {
+                let sequence = match cell.record.value_ref(1) {
+                    Some(ValueRef::I64(sequence)) => sequence,
+                    _ => 0,
+                };
+                return Ok(Some((cell.row_id, sequence)));
+            }
         }
+        Ok(None)
     }
-    pub fn get_table_schema(&mut self, table_name: &str) -> anyhow::Result<Option<Schema>> {
+    // The row of an AUTOINCREMENT table is rewritten in place, or added after its first
+    // insert
+    fn set_sequence(&mut self, table: &str, sequence: i64) -> anyhow::Result<()> {
+        self.create_sequence_table()?;
+        let root_page = self.table_schemas[SEQUENCE_TABLE].root_page as usize;
+        let row_id = match self.sequence_row(table)? {
+            Some((row_id, _)) => {
+                btree::delete_row(&mut self.pager, root_page, row_id)?;
+                row_id
+            }
+            None => btree::last_row_id(&mut self.pager, root_page)?.map_or(1, |last| last + 1),
+        };
+        let record = Record::serialize(&[Value::String(table.to_string()), Value::I64(sequence)]);
+        btree::insert_row(&mut self.pager, root_page, row_id, &record)?;
+        Ok(())
+    }
+    // Made along with the first AUTOINCREMENT table, like SQLite does
+    fn create_sequence_table(&mut self) -> anyhow::Result<()> {
+        self.get_schemas()?;
+        if self.table_schemas.contains_key(SEQUENCE_TABLE) {
+            return Ok(());
+        }
+        let root_page = btree::create_tree(&mut self.pager, TABLE_LEAF_PAGE_ID)?;
+        let sql = format!("CREATE TABLE {}(name,seq)", SEQUENCE_TABLE);
+        self.add_schema_row("table", SEQUENCE_TABLE, SEQUENCE_TABLE, root_page, Some(&sql))
+    }
+
+    // A UNIQUE index, or the index of a UNIQUE or PRIMARY KEY constraint, takes no second
+    // entry with the same values, unless one of them is NULL, which equals nothing
+    fn check_unique(&mut self, schema: &Schema, index: &Schema, entry: &[Value], order: &KeyOrder) -> anyhow::Result<()> {
+        if !index.is_unique() && !index.is_autoindex() {
+            return Ok(());
+        }
+        let key = &entry[..index.columns.len()];
+        if key.iter().any(Value::is_null) || !btree::has_index_key(&mut self.pager, index.root_page as usize, key, order)? {
+            return Ok(());
+        }
+        anyhow::bail!(Error::Constraint(unique_failed(schema, index)))
+    }
+
+    // Take a row out of its table b-tree and its entries out of the indexes
+    fn remove_row(&mut self, schema: &Schema, indexes: &[Schema], row: &SourceRow) -> anyhow::Result<()> {
+        let record = stored_record(schema, row.values.clone());
+        for index in indexes {
+            let entry = index_entry(schema, index, &record, row.row_id)?;
+            let order = index_order(schema, index)?;
+            btree::delete_index_entry(&mut self.pager, index.root_page as usize, &entry, &order)?;
+        }
+        btree::delete_row(&mut self.pager, schema.root_page as usize, row.row_id)?;
+        Ok(())
+    }
+
+    // The table gets an empty leaf page as its root and a row in sqlite_schema, which is
+    // where its columns are read back from
+    fn create_table(&mut self, create: &CreateTableStmt) -> anyhow::Result<QueryResult> {
+        let name = &create.table;
+        self.get_schemas()?;
+        if self.table_schemas.keys().any(|table| table.eq_ignore_ascii_case(name)) {
+            if create.if_not_exists {
+                return Ok(QueryResult {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                });
+            }
+            anyhow::bail!("table {} already exists", name);
+        }
+        if self.index_schemas.keys().any(|index| index.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("there is already an index named {}", name);
+        }
+        if name.to_lowercase().starts_with("sqlite_") {
+            anyhow::bail!("object name reserved for internal use: {}", name);
+        }
+        // Their values are computed, so reads would have to evaluate them and writes skip them
+        if create.has_generated_column {
+            anyhow::bail!("generated columns are not supported");
+        }
+        let mut seen = HashSet::new();
+        for column in &create.columns {
+            if !seen.insert(column.to_lowercase()) {
+                anyhow::bail!("duplicate column name: {}", column);
+            }
+        }
+        // The columns are read back from the SQL, so an unknown collation is caught here
+        let columns = parse_create_table_sql(&create.sql)?;
+        let schema = Schema {
+            schema_name: name.clone(),
+            table_name: name.clone(),
+            sql: create.sql.clone(),
+            root_page: 0,
+            columns,
+        };
+        let columns = &schema.columns;
+        let autoincrement = create.sql.to_lowercase().contains("autoincrement");
+        if autoincrement && !columns.iter().any(Column::is_rowid_alias) {
+            anyhow::bail!("AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY");
+        }
+        if autoincrement {
+            self.create_sequence_table()?;
+        }
+        let root_page = btree::create_tree(&mut self.pager, TABLE_LEAF_PAGE_ID)?;
+        self.add_schema_row("table", name, name, root_page, Some(&create.sql))?;
+        // Each UNIQUE and PRIMARY KEY constraint gets an index without SQL of its own, which
+        // sqlite3 expects to find
+        for n in 1..=autoindex_columns(&schema).len() {
+            let root_page = btree::create_tree(&mut self.pager, INDEX_LEAF_PAGE_ID)?;
+            let index = format!("sqlite_autoindex_{}_{}", name, n);
+            self.add_schema_row("index", &index, name, root_page, None)?;
+        }
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // The index gets a row in sqlite_schema and a b-tree holding an entry for every row
+    // of the table, which is built by scanning the table and adding the entries in order
+    fn create_index(&mut self, create: &CreateIndexStmt) -> anyhow::Result<QueryResult> {
+        let name = &create.index;
+        self.get_schemas()?;
+        if self.index_schemas.keys().any(|index| index.eq_ignore_ascii_case(name)) {
+            if create.if_not_exists {
+                return Ok(QueryResult {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                });
+            }
+            anyhow::bail!("index {} already exists", name);
+        }
+        if self.table_schemas.keys().any(|table| table.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("there is already a table named {}", name);
+        }
+        if name.to_lowercase().starts_with("sqlite_") {
+            anyhow::bail!("object name reserved for internal use: {}", name);
+        }
+        let Some(schema) = self.get_table_schema(&create.table)? else {
+            anyhow::bail!(Error::NoSuchTable(format!("main.{}", create.table)));
+        };
+        for (column, _) in &create.columns {
+            if !schema.columns.iter().any(|c| c.name.eq_ignore_ascii_case(column)) {
+                anyhow::bail!(Error::NoSuchColumn(column.to_string()));
+            }
+        }
+        let index = Schema {
+            schema_name: name.clone(),
+            table_name: schema.table_name.clone(),
+            sql: create.sql.clone(),
+            root_page: 0,
+            columns: parse_create_index_sql(&create.sql)?,
+        };
+
+        let mut entries = Vec::new();
+        let mut cursor = TableCursor::new(schema.root_page as usize);
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            let record = stored_record(&schema, row_values(&cell, &schema));
+            entries.push(index_entry(&schema, &index, &record, cell.row_id)?);
+        }
+        let order = index_order(&schema, &index)?;
+        entries.sort_unstable_by(|a, b| order.compare(a, b));
+        if create.unique {
+            let key_len = index.columns.len();
+            let duplicate = entries.windows(2).find(|pair| {
+                let (a, b) = (&pair[0][..key_len], &pair[1][..key_len]);
+                !a.iter().any(Value::is_null) && order.compare(a, b).is_eq()
+            });
+            if duplicate.is_some() {
+                anyhow::bail!(Error::Constraint(unique_failed(&schema, &index)));
+            }
+        }
+        let root_page = btree::create_tree(&mut self.pager, INDEX_LEAF_PAGE_ID)?;
+        for entry in &entries {
+            btree::insert_index_entry(&mut self.pager, root_page, entry, &order)?;
+        }
+        self.add_schema_row("index", name, &schema.table_name, root_page, Some(&create.sql))?;
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // Tables go with their indexes. Their pages go on the freelist, the file doesn't shrink
+    fn drop_table(&mut self, name: &str, if_exists: bool) -> anyhow::Result<QueryResult> {
+        if SCHEMA_TABLES.iter().any(|table| table.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("table {} may not be dropped", name);
+        }
+        self.get_schemas()?;
+        let schema = self
+            .table_schemas
+            .values()
+            .find(|schema| schema.table_name.eq_ignore_ascii_case(name))
+            .cloned();
+        let Some(schema) = schema else {
+            if if_exists {
+                return Ok(QueryResult {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                });
+            }
+            anyhow::bail!(Error::NoSuchTable(name.to_string()));
+        };
+        for index in self.get_index_schemas(&schema.table_name)? {
+            btree::drop_tree(&mut self.pager, index.root_page as usize)?;
+            self.remove_schema_row(&index.schema_name)?;
+        }
+        btree::drop_tree(&mut self.pager, schema.root_page as usize)?;
+        self.remove_schema_row(&schema.schema_name)?;
+        self.remove_stats(&schema.table_name, None)?;
+        if let Some((row_id, _)) = self.sequence_row(&schema.table_name)? {
+            btree::delete_row(&mut self.pager, self.table_schemas[SEQUENCE_TABLE].root_page as usize, row_id)?;
+        }
+        self.schema_changed()?;
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    fn drop_index(&mut self, name: &str, if_exists: bool) -> anyhow::Result<QueryResult> {
+        self.get_schemas()?;
+        let index = self
+            .index_schemas
+            .values()
+            .find(|schema| schema.schema_name.eq_ignore_ascii_case(name))
+            .cloned();
+        let Some(index) = index else {
+            if if_exists {
+                return Ok(QueryResult {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                });
+            }
+            anyhow::bail!("no such index: {}", name);
+        };
+        if index.is_autoindex() {
+            anyhow::bail!("index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped");
+        }
+        btree::drop_tree(&mut self.pager, index.root_page as usize)?;
+        self.remove_schema_row(&index.schema_name)?;
+        self.remove_stats(&index.table_name, Some(&index.schema_name))?;
+        self.schema_changed()?;
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // Counts the rows of each table and, for each of its indexes, how many rows share a
+    // value of its first column, of its first two columns and so on, and writes them to
+    // sqlite_stat1 for the planner. Without a name every table is analyzed, otherwise the
+    // one table or index
+    // https://www.sqlite.org/fileformat2.html#stat1tab
+    fn analyze(&mut self, name: Option<&str>) -> anyhow::Result<QueryResult> {
+        self.get_schemas()?;
+        let mut targets = Vec::new();
+        let index = name.and_then(|name| {
+            self.index_schemas
+                .values()
+                .find(|index| index.schema_name.eq_ignore_ascii_case(name))
+                .cloned()
+        });
+        match (name, index) {
+            (_, Some(index)) => {
+                let table = self.table_schemas.get(&index.table_name).cloned().unwrap();
+                targets.push((table, Some(index)));
+            }
+            // Of the main database as a whole
+            (None, None) => {
+                let mut tables = self
+                    .table_schemas
+                    .values()
+                    .filter(|table| !table.table_name.to_lowercase().starts_with("sqlite_"))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                tables.sort_by(|a, b| a.table_name.cmp(&b.table_name));
+                targets.extend(tables.into_iter().map(|table| (table, None)));
+            }
+            (Some(name), None) if name.eq_ignore_ascii_case("main") => return self.analyze(None),
+            // Only makes sure sqlite_stat1 exists, as .dump has it before the table's rows
+            (Some(name), None) if SCHEMA_TABLES.iter().any(|table| table.eq_ignore_ascii_case(name)) => {}
+            (Some(name), None) => {
+                let table = self
+                    .table_schemas
+                    .values()
+                    .find(|table| table.table_name.eq_ignore_ascii_case(name))
+                    .cloned();
+                let Some(table) = table else {
+                    anyhow::bail!("no such table or index: {}", name);
+                };
+                targets.push((table, None));
+            }
+        }
+        if !self.table_schemas.contains_key(STAT1_TABLE) {
+            let root_page = btree::create_tree(&mut self.pager, TABLE_LEAF_PAGE_ID)?;
+            let sql = format!("CREATE TABLE {}(tbl,idx,stat)", STAT1_TABLE);
+            self.add_schema_row("table", STAT1_TABLE, STAT1_TABLE, root_page, Some(&sql))?;
+        }
+        let stat1_root = self.table_schemas[STAT1_TABLE].root_page as usize;
+
+        for (table, only_index) in targets {
+            self.remove_stats(&table.table_name, only_index.as_ref().map(Schema::name))?;
+            let row_count = btree::count_rows(&mut self.pager, table.root_page as usize)?;
+            // Like SQLite, an empty table gets no row
+            if row_count == 0 {
+                continue;
+            }
+            let indexes = match only_index {
+                Some(index) => vec![index],
+                None => self.get_index_schemas(&table.table_name)?,
+            };
+            let mut rows = Vec::new();
+            if indexes.is_empty() {
+                rows.push((Value::Null, row_count.to_string()));
+            }
+            for index in &indexes {
+                let stat = self.index_stat(&table, index)?;
+                rows.push((Value::String(index.schema_name.clone()), stat));
+            }
+            for (index, stat) in rows {
+                let record = Record::serialize(&[
+                    Value::String(table.table_name.clone()),
+                    index,
+                    Value::String(stat),
+                ]);
+                let row_id = btree::last_row_id(&mut self.pager, stat1_root)?.map_or(1, |last| last + 1);
+                btree::insert_row(&mut self.pager, stat1_root, row_id, &record)?;
+            }
+        }
+        self.finish_write()?;
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // The stat column of an index's row in sqlite_stat1: the number of entries, then for
+    // each prefix of its columns the entries divided by the distinct values of the
+    // prefix, rounded up. Entries come in key order, so each one that differs from the
+    // one before it in a column starts a new distinct value of that prefix and the ones
+    // after it
+    fn index_stat(&mut self, table: &Schema, index: &Schema) -> anyhow::Result<String> {
+        let width = index.columns.len();
+        let collations = index.columns.iter().map(|column| table.collation(&column.name)).collect::<Vec<_>>();
+        let mut entries = 0u64;
+        let mut distinct = vec![0u64; width];
+        let mut previous: Option<Vec<Value>> = None;
+        btree::for_each_index_entry(&mut self.pager, index.root_page as usize, &mut |entry| {
+            entries += 1;
+            let first_change = match &previous {
+                Some(previous) => (0..width)
+                    .find(|&i| collations[i].compare(&previous[i], &entry[i]) != Ordering::Equal)
+                    .unwrap_or(width),
+                None => 0,
+            };
+            distinct[first_change..].iter_mut().for_each(|count| *count += 1);
+            previous = Some(entry);
+        })?;
+        let mut stat = entries.to_string();
+        for count in distinct {
+            stat.push_str(&format!(" {}", entries.div_ceil(count.max(1))));
+        }
+        Ok(stat)
+    }
+
+    // Take the rows of a table, or of one of its indexes, out of sqlite_stat1
+    fn remove_stats(&mut self, table: &str, index: Option<&str>) -> anyhow::Result<()> {
+        let Some(stat1) = self.table_schemas.get(STAT1_TABLE).cloned() else {
+            return Ok(());
+        };
+        let root_page = stat1.root_page as usize;
+        let mut cursor = TableCursor::new(root_page);
+        let mut row_ids = Vec::new();
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            let values = row_values(&cell, &stat1);
+            let is_table = matches!(&values[0], Value::String(tbl) if tbl.eq_ignore_ascii_case(table));
+            let is_index = match index {
+                Some(index) => matches!(&values[1], Value::String(idx) if idx.eq_ignore_ascii_case(index)),
+                None => true,
+            };
+            if is_table && is_index {
+                row_ids.push(cell.row_id);
+            }
+        }
+        for row_id in row_ids {
+            btree::delete_row(&mut self.pager, root_page, row_id)?;
+        }
+        Ok(())
+    }
+
+    // Like SQLite, only the stored CREATE TABLE changes: the definition goes in before its
+    // closing parenthesis, and rows written before read the column's default
+    fn alter_table(&mut self, alter: &AlterTableStmt) -> anyhow::Result<QueryResult> {
+        let Some(schema) = self.get_table_schema(&alter.table)? else {
+            anyhow::bail!(Error::NoSuchTable(alter.table.clone()));
+        };
+        if schema.columns.iter().any(|column| column.name.eq_ignore_ascii_case(&alter.column)) {
+            anyhow::bail!("duplicate column name: {}", alter.column);
+        }
+        let mut columns = parse_create_table_sql(&format!("({})", alter.definition))?;
+        let Some(column) = columns.pop() else {
+            anyhow::bail!("near \"{}\": syntax error", alter.definition);
+        };
+        let definition = alter.definition.to_lowercase();
+        if column.primary_key {
+            anyhow::bail!("Cannot add a PRIMARY KEY column");
+        }
+        if definition.contains("unique") {
+            anyhow::bail!("Cannot add a UNIQUE column");
+        }
+        if alter.generated {
+            anyhow::bail!("generated columns are not supported");
+        }
+        if definition.contains("not null") && column.default.is_null() {
+            anyhow::bail!("Cannot add a NOT NULL column with default value NULL");
+        }
+        let Some(end) = schema.sql.rfind(')') else {
+            anyhow::bail!("malformed CREATE TABLE for {}", schema.table_name);
+        };
+        let sql = format!("{}, {}{}", &schema.sql[..end], alter.definition, &schema.sql[end..]);
+        self.replace_schema_sql(&schema.schema_name, &sql)?;
+        // Records of different lengths need file format 2, a default other than NULL 3
+        let format: u32 = if column.default.is_null() { 2 } else { 3 };
+        let mut page = self.pager.read_raw_page(1)?;
+        let current = u32::from_be_bytes(page[HEADER_SCHEMA_FORMAT_OFFSET..][..4].try_into().unwrap());
+        if current < format {
+            page[HEADER_SCHEMA_FORMAT_OFFSET..][..4].copy_from_slice(&format.to_be_bytes());
+            self.pager.write_page(1, &page)?;
+        }
+        self.schema_changed()?;
+        Ok(QueryResult {
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    // Point the sqlite_schema row of a table at new CREATE TABLE text
+    fn replace_schema_sql(&mut self, name: &str, sql: &str) -> anyhow::Result<()> {
+        let mut cursor = TableCursor::new(1);
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            let mut values = cell.record.values();
+            if values.get(1) != Some(&Value::String(name.to_string())) {
+                continue;
+            }
+            values[4] = Value::String(sql.to_string());
+            btree::delete_row(&mut self.pager, 1, cell.row_id)?;
+            btree::insert_row(&mut self.pager, 1, cell.row_id, &Record::serialize(&values))?;
+            return Ok(());
+        }
+        anyhow::bail!(Error::NoSuchTable(name.to_string()))
+    }
+
+    // Add a row to sqlite_schema, an automatic index has NULL for its SQL
+    fn add_schema_row(
+        &mut self,
+        schema_type: &str,
+        name: &str,
+        table_name: &str,
+        root_page: usize,
+        sql: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let record = Record::serialize(&[
+            Value::String(schema_type.to_string()),
+            Value::String(name.to_string()),
+            Value::String(table_name.to_string()),
+            Value::I64(root_page as i64),
+            sql.map_or(Value::Null, |sql| Value::String(sql.to_string())),
+        ]);
+        let row_id = btree::last_row_id(&mut self.pager, 1)?.map_or(1, |last| last + 1);
+        btree::insert_row(&mut self.pager, 1, row_id, &record)?;
+        self.schema_changed()
+    }
+
+    // Take the row of a table or index out of sqlite_schema
+    fn remove_schema_row(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut cursor = TableCursor::new(1);
+        let mut row_ids = Vec::new();
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            if let Some(ValueRef::String(row_name)) = cell.record.value_ref(1) {
+                if row_name == name {
+                    row_ids.push(cell.row_id);
+                }
+            }
+        }
+        for row_id in row_ids {
+            btree::delete_row(&mut self.pager, 1, row_id)?;
+        }
+        Ok(())
+    }
+
+    // Bump the schema cookie, so connections that read the schema before know to read it
+    // again, and read it again here
+    fn schema_changed(&mut self) -> anyhow::Result<()> {
+        let mut page = self.pager.read_raw_page(1)?;
+        let cookie = u32::from_be_bytes(page[HEADER_SCHEMA_COOKIE_OFFSET..][..4].try_into().unwrap());
+        page[HEADER_SCHEMA_COOKIE_OFFSET..][..4].copy_from_slice(&cookie.wrapping_add(1).to_be_bytes());
+        self.pager.write_page(1, &page)?;
+        self.finish_write()?;
+        self.forget_schemas();
+        self.get_schemas()
+    }
+
+    // Every write bumps the file change counter so other connections, and the result
+    // cache, know the database changed, and records the new database size
+    fn finish_write(&mut self) -> anyhow::Result<()> {
+        let mut page = self.pager.read_raw_page(1)?;
+        let counter = u32::from_be_bytes(page[HEADER_CHANGE_COUNTER_OFFSET..][..4].try_into().unwrap());
+        let counter = counter.wrapping_add(1);
+        // This connection's own write leaves its cached pages current
+        self.change_counter = Some(counter);
+        let counter = counter.to_be_bytes();
+        page[HEADER_CHANGE_COUNTER_OFFSET..][..4].copy_from_slice(&counter);
+        // The size is only trusted when the version-valid-for number matches the counter
+        page[HEADER_VERSION_VALID_FOR_OFFSET..][..4].copy_from_slice(&counter);
+        let page_count = self.pager.page_count() as u32;
+        page[HEADER_DATABASE_SIZE_OFFSET..][..4].copy_from_slice(&page_count.to_be_bytes());
+        self.pager.write_page(1, &page)
+    }
+
+    // The tables a SELECT reads, in join order
+    fn sources(&mut self, select: &SelectStmt) -> anyhow::Result<Vec<Source>> {
+        let Some(from) = &select.from else {
+            anyhow::bail!("SELECT without FROM is not supported");
+        };
+        let mut sources = vec![self.source(from, JoinKind::Inner, None)?];
+        for join in &select.joins {
+            let mut source = self.source(&join.table, join.kind, join.on.clone())?;
+            source.using = join_columns(join, &sources, &source.schema)?;
+            sources.push(source);
+        }
+        for i in 0..sources.len() {
+            if let Some(mut on) = sources[i].on.take() {
+                apply_affinities(&mut on, &sources);
+                apply_collations(&mut on, &sources);
+                sources[i].on = Some(on);
+            }
+        }
+        for column in &select.columns {
+            if let Expr::TableWildcard(table) = &column.expr {
+                wildcard_source(&sources, table)?;
+            }
+        }
+        Ok(sources)
+    }
+
+    fn source(
+        &mut self,
+        table: &TableReference,
+        kind: JoinKind,
+        on: Option<Expr>,
+    ) -> anyhow::Result<Source> {
+        if let Some(values) = &table.values {
+            let schema = Schema {
+                schema_name: "table".to_string(),
+                table_name: table.name.clone(),
+                sql: String::new(),
+                root_page: 0,
+                columns: values_columns(values[0].len()),
+            };
+            return Ok(Source {
+                table: table.clone(),
+                schema,
+                kind,
+                on,
+                using: Vec::new(),
+                rows: Some(self.values_rows(values)?),
+            });
+        }
+        let common_table = self
+            .common_tables
+            .iter()
+            .rposition(|common_table| common_table.definition.name.eq_ignore_ascii_case(&table.name));
+        if let Some(i) = common_table {
+            let (columns, rows) = self.common_table_rows(i)?;
+            let schema = Schema {
+                schema_name: "table".to_string(),
+                table_name: table.name.clone(),
+                sql: String::new(),
+                root_page: 0,
+                columns,
+            };
+            return Ok(Source {
+                table: table.clone(),
+                schema,
+                kind,
+                on,
+                using: Vec::new(),
+                rows: Some(rows),
+            });
+        }
+        let Some(schema) = self.get_table_schema(&table.name)? else {
+            anyhow::bail!(Error::NoSuchTable(table.name.clone()));
+        };
+        Ok(Source {
+            table: table.clone(),
+            schema,
+            kind,
+            on,
+            using: Vec::new(),
+            rows: None,
+        })
+    }
+
+    // A VALUES list evaluates to rows numbered from 1, like rowids
+    fn values_rows(&self, values: &[Vec<Expr>]) -> anyhow::Result<Vec<SourceRow>> {
+        let no_columns = HashMap::new();
+        let mut rows = Vec::new();
+        for (i, exprs) in values.iter().enumerate() {
+            let values = exprs
+                .iter()
+                .map(|expr| evaluate(expr, &no_columns, &self.function_context))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            rows.push(SourceRow {
+                row_id: i as i64 + 1,
+                values,
+            });
+        }
+        Ok(rows)
+    }
+
+    // A negative LIMIT is no limit, and a negative OFFSET skips nothing
+    fn limit_offset(&self, select: &SelectStmt) -> anyhow::Result<(Option<usize>, usize)> {
+        let limit = match &select.limit {
+            Some(expr) => usize::try_from(self.limit_value(expr)?).ok(),
+            None => None,
+        };
+        let offset = match &select.offset {
+            Some(expr) => self.limit_value(expr)?.max(0) as usize,
+            None => 0,
+        };
+        Ok((limit, offset))
+    }
+    // LIMIT and OFFSET take a whole number, or text that reads as one
+    fn limit_value(&self, expr: &Expr) -> anyhow::Result<i64> {
+        let value = evaluate(expr, &HashMap::new(), &self.function_context)?;
+        match value.with_numeric_affinity() {
+            Value::I64(n) => Ok(n),
+            Value::Float(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Ok(n as i64),
+            _ => anyhow::bail!(Error::TypeMismatch("datatype mismatch".to_string())),
+        }
+    }
+
+    // `expr` with its subqueries run: EXISTS becomes 1 or 0, and IN (SELECT ...) a list of
+    // the values the subquery returned. Without an `outer` row, the subqueries that refer
+    // to one are left for each row to run
+    fn run_subqueries(
+        &mut self,
+        expr: &Expr,
+        sources: &[Source],
+        outer: Option<&HashMap<String, Value>>,
+    ) -> anyhow::Result<Expr> {
+        Ok(match expr {
+            Expr::Exists(select) | Expr::InSelect(_, select) => {
+                let mut select = select.as_ref().clone();
+                if self.bind_outer(&mut select, &mut Vec::new(), outer)? && outer.is_none() {
+                    return Ok(expr.clone());
+                }
+                let Expr::InSelect(left, _) = expr else {
+                    // EXISTS only needs the first row
+                    select.limit.get_or_insert(Expr::Literal(Literal::Integer(1)));
+                    let (_, rows) = self.query(&select)?;
+                    return Ok(Expr::Literal(Literal::Integer(!rows.is_empty() as i64)));
+                };
+                let mut left = self.run_subqueries(left, sources, outer)?;
+                let column = self.subquery_column(&select)?;
+                // Compared like `left = column`, left first when it comes to the collation
+                let left_affinity = expr_affinity(&left, sources);
+                let collation = operand_collation(&left, sources)?.or(column.collation).unwrap_or_default();
+                apply_affinity(&mut left, comparison_affinity(column.affinity, left_affinity));
+                let value_affinity = comparison_affinity(left_affinity, column.affinity);
+                let mut rows = EphemeralBtree::with_collations(vec![collation]);
+                for value in column.values {
+                    let value = match value_affinity {
+                        Some(affinity) => value.with_affinity(affinity),
+                        None => value,
+                    };
+                    rows.insert(&[value])?;
+                }
+                Expr::InRows(Box::new(left), Arc::new(rows))
+            }
+            Expr::BinaryOp(left, op, right) => Expr::BinaryOp(
+                Box::new(self.run_subqueries(left, sources, outer)?),
+                op.clone(),
+                Box::new(self.run_subqueries(right, sources, outer)?),
+            ),
+            Expr::UnaryOp(op, operand) => {
+                Expr::UnaryOp(op.clone(), Box::new(self.run_subqueries(operand, sources, outer)?))
+            }
+            Expr::Aliased(expr, alias) => {
+                Expr::Aliased(Box::new(self.run_subqueries(expr, sources, outer)?), alias.clone())
+            }
+            Expr::FunctionCall(name, args) => Expr::FunctionCall(
+                name.clone(),
+                args.iter()
+                    .map(|arg| self.run_subqueries(arg, sources, outer))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Expr::InList(left, list) => Expr::InList(
+                Box::new(self.run_subqueries(left, sources, outer)?),
+                list.iter()
+                    .map(|item| self.run_subqueries(item, sources, outer))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Expr::Between(value, low, high) => Expr::Between(
+                Box::new(self.run_subqueries(value, sources, outer)?),
+                Box::new(self.run_subqueries(low, sources, outer)?),
+                Box::new(self.run_subqueries(high, sources, outer)?),
+            ),
+            Expr::Case(operand, branches, else_result) => {
+                let mut run = |expr: &Expr| self.run_subqueries(expr, sources, outer);
+                let operand = operand.as_deref().map(&mut run).transpose()?.map(Box::new);
+                let mut ran = Vec::new();
+                for (when, then) in branches {
+                    ran.push((run(when)?, run(then)?));
+                }
+                let else_result = else_result.as_deref().map(&mut run).transpose()?.map(Box::new);
+                Expr::Case(operand, ran, else_result)
+            }
+            Expr::Cast(expr, type_name) => {
+                Expr::Cast(Box::new(self.run_subqueries(expr, sources, outer)?), type_name.clone())
+            }
+            Expr::Collate(expr, name) => Expr::Collate(Box::new(self.run_subqueries(expr, sources, outer)?), name.clone()),
+            Expr::Affinity(expr, affinity) => Expr::Affinity(Box::new(self.run_subqueries(expr, sources, outer)?), *affinity),
+            expr => expr.clone(),
+        })
+    }
+
+    // The subqueries of the select list, GROUP BY, HAVING and ORDER BY. Those that don't
+    // refer to the row are run here, the others are taken out, leaving a name no column
+    // can have in their place, under which each row's value goes into its row map
+    fn row_subqueries(&mut self, select: &mut SelectStmt, sources: &[Source]) -> anyhow::Result<Vec<(String, Expr)>> {
+        let mut subqueries = Vec::new();
+        let exprs = select
+            .columns
+            .iter_mut()
+            .map(|column| &mut column.expr)
+            .chain(&mut select.group_by)
+            .chain(&mut select.having)
+            .chain(select.order_by.iter_mut().map(|term| &mut term.expr));
+        for expr in exprs.filter(|expr| contains(expr, &is_subquery)) {
+            *expr = self.run_subqueries(expr, sources, None)?;
+            take_subqueries(expr, &mut subqueries);
+        }
+        Ok(subqueries)
+    }
+
+    // Whether `select`, or a subquery in it, refers to columns of an enclosing query,
+    // which are replaced with their values in `outer` when there is one. `scopes` has the
+    // names the enclosing subqueries, up to `select`, give meaning to
+    fn bind_outer(
+        &mut self,
+        select: &mut SelectStmt,
+        scopes: &mut Vec<Scope>,
+        outer: Option<&HashMap<String, Value>>,
+    ) -> anyhow::Result<bool> {
+        let mut with = std::mem::take(&mut select.with);
+        let depth = self.common_tables.len();
+        let refers_outer = self.bind_with(&mut with, select, scopes, outer);
+        self.common_tables.truncate(depth);
+        select.with = with;
+        refers_outer
+    }
+    // The tables of the WITH clause first, each with the ones before it in scope
+    fn bind_with(
+        &mut self,
+        with: &mut [CommonTableExpr],
+        select: &mut SelectStmt,
+        scopes: &mut Vec<Scope>,
+        outer: Option<&HashMap<String, Value>>,
+    ) -> anyhow::Result<bool> {
+        let mut refers_outer = false;
+        for table in with.iter_mut() {
+            refers_outer |= self.bind_outer(&mut table.select, scopes, outer)?;
+            self.push_common_tables(std::slice::from_ref(table))?;
+        }
+        let sources = self.sources(select)?;
+        scopes.push(Scope::new(select, &sources));
+        let mut result = Ok(());
+        select_exprs_mut(select, &mut |expr| {
+            if result.is_ok() {
+                match self.bind_expr(expr, scopes, outer) {
+                    Result::Ok(refers) => refers_outer |= refers,
+                    Err(err) => result = Err(err),
+                }
+            }
+        });
+        scopes.pop();
+        result?;
+        // Each compounded SELECT has tables of its own
+        for part in &mut select.compound {
+            refers_outer |= self.bind_outer(&mut part.select, scopes, outer)?;
+        }
+        Ok(refers_outer)
+    }
+    fn bind_expr(
+        &mut self,
+        expr: &mut Expr,
+        scopes: &mut Vec<Scope>,
+        outer: Option<&HashMap<String, Value>>,
+    ) -> anyhow::Result<bool> {
+        match expr {
+            Expr::Identifier(_) | Expr::QualifiedIdentifier(..) => {
+                if scopes.iter().any(|scope| scope.resolves(expr)) {
+                    return Ok(false);
+                }
+                let value = row_map_key(expr).and_then(|key| outer?.get(&key).cloned());
+                if let Some(value) = value {
+                    *expr = Expr::Literal(value_literal(value));
+                }
+                Ok(true)
+            }
+            Expr::Exists(select) => self.bind_outer(select, scopes, outer),
+            Expr::InSelect(left, select) => {
+                let left = self.bind_expr(left, scopes, outer)?;
+                Ok(self.bind_outer(select, scopes, outer)? || left)
+            }
+            expr => {
+                let mut refers_outer = false;
+                for child in children_mut(expr) {
+                    refers_outer |= self.bind_expr(child, scopes, outer)?;
+                }
+                Ok(refers_outer)
+            }
+        }
+    }
+
+    // The values of a subquery's one result column, with the affinity and collation the
+    // column has when it is a column of one of its tables
+    fn subquery_column(&mut self, select: &SelectStmt) -> anyhow::Result<SubqueryColumn> {
+        let depth = self.push_common_tables(&select.with)?;
+        let result = self.sources(select).and_then(|sources| {
+            let columns = column_names(select, &sources, self.rowid_in_wildcard);
+            if columns.len() != 1 {
+                anyhow::bail!("sub-select returns {} columns - expected 1", columns.len());
+            }
+            let expr = match &select.columns[0].expr {
+                Expr::Aliased(expr, _) => expr,
+                expr => expr,
+            };
+            let (affinity, collation) = (expr_affinity(expr, &sources), operand_collation(expr, &sources)?);
+            Ok(SubqueryColumn {
+                affinity,
+                collation,
+                values: self.select_rows(select, sources)?.into_iter().flatten().collect(),
+            })
+        });
+        self.common_tables.truncate(depth);
+        result
+    }
+
+    // Rows of a SELECT with the names of its result columns
+    fn query(&mut self, select: &SelectStmt) -> anyhow::Result<(Vec<String>, Vec<Vec<Value>>)> {
+        let depth = self.push_common_tables(&select.with)?;
+        let result = self.sources(select).and_then(|sources| {
+            let columns = column_names(select, &sources, self.rowid_in_wildcard);
+            Ok((columns, self.select_rows(select, sources)?))
+        });
+        self.common_tables.truncate(depth);
+        result
+    }
+
+    // All the rows of a SELECT, its program run to the end
+    fn select_rows(&mut self, select: &SelectStmt, sources: Vec<Source>) -> anyhow::Result<Vec<Vec<Value>>> {
+        let mut vm = Vm::new(self.compile(select, sources)?);
+        let mut rows = Vec::new();
+        while let Some(row) = vm.step(self)? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    // The program a SELECT compiles to, one row per op like sqlite3 lists them
+    fn explain(&mut self, stmt: Stmt) -> anyhow::Result<QueryResult> {
+        let Stmt::Select(select) = stmt else {
+            anyhow::bail!("EXPLAIN is only supported for SELECT");
+        };
+        let depth = self.push_common_tables(&select.with)?;
+        let program = self.sources(&select).and_then(|sources| self.compile(&select, sources));
+        self.common_tables.truncate(depth);
+        let program = program?;
+        let rows = program
+            .ops
+            .iter()
+            .zip(&program.comments)
+            .enumerate()
+            .map(|(addr, (op, comment))| {
+                let (opcode, p1, p2, p3, p4) = op.explain();
+                vec![
+                    Value::I64(addr as i64),
+                    Value::String(opcode.to_string()),
+                    Value::I64(p1),
+                    Value::I64(p2),
+                    Value::I64(p3),
+                    p4.map_or(Value::Null, Value::String),
+                    Value::I64(0),
+                    comment.clone().map_or(Value::Null, Value::String),
+                ]
+            })
+            .collect();
+        let columns = ["addr", "opcode", "p1", "p2", "p3", "p4", "p5", "comment"];
+        Ok(QueryResult::new(columns.map(String::from).to_vec(), rows))
+    }
+
+    // Put the tables of a WITH clause in scope, returns the depth to truncate the
+    // scope back to once the statement is done
+    fn push_common_tables(&mut self, with: &[CommonTableExpr]) -> anyhow::Result<usize> {
+        for (i, table) in with.iter().enumerate() {
+            if with[..i].iter().any(|earlier| earlier.name.eq_ignore_ascii_case(&table.name)) {
+                anyhow::bail!("duplicate WITH table name: {}", table.name);
+            }
+        }
+        let depth = self.common_tables.len();
+        self.common_tables.extend(with.iter().map(|table| CommonTable {
+            definition: table.clone(),
+            rows: None,
+        }));
+        Ok(depth)
+    }
+
+    // The columns and rows of the WITH clause table at `i`, run the first time it is read.
+    // Only the tables before it are in scope, so it can't refer to itself
+    fn common_table_rows(&mut self, i: usize) -> anyhow::Result<(Vec<Column>, Vec<SourceRow>)> {
+        if let Some(rows) = &self.common_tables[i].rows {
+            return Ok(rows.clone());
+        }
+        let later = self.common_tables.split_off(i);
+        let definition = &later[0].definition;
+        let result = self.query(&definition.select);
+        self.common_tables.extend(later);
+        let (names, rows) = result?;
+        let definition = &self.common_tables[i].definition;
+        let names = match definition.columns.is_empty() {
+            true => names,
+            false if definition.columns.len() == names.len() => definition.columns.clone(),
+            false => anyhow::bail!(
+                "table {} has {} values for {} columns",
+                definition.name,
+                names.len(),
+                definition.columns.len()
+            ),
+        };
+        let columns = names
+            .into_iter()
+            .map(|name| Column {
+                name: name.to_lowercase(),
+                type_name: String::new(),
+                affinity: Affinity::Blob,
+                collation: Collation::Binary,
+                primary_key: false,
+                not_null: false,
+                default: Value::Null,
+            })
+            .collect();
+        let rows = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, values)| SourceRow {
+                row_id: i as i64 + 1,
+                values,
+            })
+            .collect();
+        let table = (columns, rows);
+        self.common_tables[i].rows = Some(table.clone());
+        Ok(table)
+    }
+
+    // USING columns, and ON clause or early WHERE terms of the form `table.column =
+    // <expression over the outer tables>` or comparing a column with a literal, ANDed
+    // together, on the rowid or the leading columns of an index, can be answered with a
+    // lookup per outer row instead of a scan of the whole table
+    fn probe_join(
+        &mut self,
+        source: &Source,
+        where_terms: &[&Expr],
+        outer_row_map: &HashMap<String, Value>,
+    ) -> anyhow::Result<Option<Vec<i64>>> {
+        let plan = self.join_plan(source, where_terms, outer_row_map)?;
+        self.run_plan(plan)
+    }
+
+    fn join_plan(
+        &mut self,
+        source: &Source,
+        where_terms: &[&Expr],
+        outer_row_map: &HashMap<String, Value>,
+    ) -> anyhow::Result<Plan> {
+        let mut terms = where_terms.to_vec();
+        if let Some(on) = &source.on {
+            collect_conjuncts(on, &mut terms);
+        }
+        let mut constraints = planner::term_constraints(&terms, source.table.qualifier(), false);
+        for column in &source.using {
+            if let Some(value) = outer_row_map.get(column) {
+                constraints
+                    .entry(column.clone())
+                    .or_insert_with(ColumnConstraint::new)
+                    .add(ProbeRanges::Values(vec![value.clone()]));
+            }
+        }
+        for term in terms {
+            let Expr::BinaryOp(left, op, right) = term else {
+                continue;
+            };
+            if op.token_type != TokenType::Equal {
+                continue;
+            }
+            for (inner, outer) in [(left, right), (right, left)] {
+                let Expr::QualifiedIdentifier(table, column) = inner.as_ref() else {
+                    continue;
+                };
+                if !table.eq_ignore_ascii_case(source.table.qualifier()) {
+                    continue;
+                }
+                // Fails when the expression refers to the table being probed
+                if let Result::Ok(value) = evaluate(outer, outer_row_map, &self.function_context) {
+                    constraints
+                        .entry(column.to_lowercase())
+                        .or_insert_with(ColumnConstraint::new)
+                        .add(ProbeRanges::Values(vec![value]));
+                }
+            }
+        }
+        self.plan(&source.schema, &[constraints])
+    }
+
+    // OR-expansion: a WHERE clause made of terms joined by OR, each constraining the
+    // rowid or indexed columns with `column = literal`, `column IN (literals)`, `<`,
+    // `<=`, `>`, `>=` or BETWEEN, ANDed together, can be answered with a lookup per term
+    // and the union of the rowids instead of a full table scan, when the planner
+    // estimates that is cheaper.
+    // In a join, only the terms qualified with this table count
+    fn probe_row_ids(
+        &mut self,
+        source: &Source,
+        is_only_table: bool,
+        where_clause: Option<&Expr>,
+    ) -> anyhow::Result<Option<Vec<i64>>> {
+        let Some(where_expr) = where_clause else {
+            return Ok(None);
+        };
+        let terms = planner::where_constraints(where_expr, source.table.qualifier(), is_only_table);
+        let plan = self.plan(&source.schema, &terms)?;
+        self.run_plan(plan)
+    }
+
+    // The number of rows in a table, as the last ANALYZE counted them or else estimated
+    fn row_estimate(&mut self, table_schema: &Schema) -> anyhow::Result<f64> {
+        match self.table_stats(&table_schema.table_name)? {
+            Some((row_count, _)) => Ok(row_count),
+            None => self.estimate_row_count(table_schema.root_page as usize),
+        }
+    }
+
+    fn plan(&mut self, table_schema: &Schema, terms: &[Constraints]) -> anyhow::Result<Plan> {
+        let indexes = self.get_index_schemas(&table_schema.table_name)?;
+        let (row_count, stats) = match self.table_stats(&table_schema.table_name)? {
+            Some((row_count, stats)) => (row_count, stats),
+            None => (self.estimate_row_count(table_schema.root_page as usize)?, IndexStats::new()),
+        };
+        Ok(planner::plan(table_schema, &indexes, row_count, &stats, terms))
+    }
+
+    // The number of rows of `table` and the stats of its indexes that the last ANALYZE
+    // wrote to sqlite_stat1, None if it has no row there
+    fn table_stats(&mut self, table: &str) -> anyhow::Result<Option<(f64, IndexStats)>> {
+        let Some(stat1) = self.table_schemas.get(STAT1_TABLE).cloned() else {
+            return Ok(None);
+        };
+        let mut row_count = None;
+        let mut stats = IndexStats::new();
+        let mut cursor = TableCursor::new(stat1.root_page as usize);
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            let values = row_values(&cell, &stat1);
+            let (Value::String(tbl), Value::String(stat)) = (&values[0], &values[2]) else {
+                continue;
+            };
+            if !tbl.eq_ignore_ascii_case(table) {
+                continue;
+            }
+            // Any words after the numbers, like "unordered", are hints this planner ignores
+            let mut numbers = stat.split_whitespace().map_while(|word| word.parse::<f64>().ok());
+            row_count = numbers.next().or(row_count);
+            if let Value::String(index) = &values[1] {
+                stats.insert(index.to_lowercase(), numbers.collect());
+            }
+        }
+        Ok(row_count.map(|row_count| (row_count, stats)))
+    }
+
+    // The rowids a plan finds, deduplicated and sorted so the table is walked once, in
+    // order. None for a full scan
+    fn run_plan(&mut self, plan: Plan) -> anyhow::Result<Option<Vec<i64>>> {
+        let Plan::Lookups(lookups) = plan else {
+            return Ok(None);
+        };
+        let mut row_ids = Vec::new();
+        for lookup in lookups {
+            match lookup {
+                Lookup::RowIds(lookup_row_ids) => row_ids.extend(lookup_row_ids),
+                Lookup::IndexRanges { root_page, ranges } => {
+                    let page = self.read_page(root_page)?;
+                    for range in &ranges {
+                        row_ids.extend(self.get_row_ids(&page, range)?);
+                    }
+                }
+            }
+        }
+        row_ids.sort_unstable();
+        row_ids.dedup();
+        Ok(Some(row_ids))
+    }
+
+    // A guess at the number of rows in a table b-tree from its leftmost path: the
+    // fan-out of each interior page on the way down times the cells of the first leaf
+    fn estimate_row_count(&mut self, root_page: usize) -> anyhow::Result<f64> {
+        let mut estimate = 1.0;
+        let mut page_num = root_page;
+        loop {
+            page_num = match self.read_page(page_num)?.as_ref() {
+                Page::TableInterior(interior) => {
+                    estimate *= (interior.cells.len() + 1) as f64;
+                    match interior.cells.first() {
+                        Some(cell) => cell.left_child as usize,
+                        None => interior.header.get_right_most_point() as usize,
+                    }
+                }
+                Page::TableLeaf(leaf) => return Ok(estimate * leaf.cells.len().max(1) as f64),
+                page => anyhow::bail!(Error::CorruptPage(format!("expected a table page, found {:?}", page.get_page_type()))),
+            };
+        }
+    }
+
+    // Rowids of the index entries in `range`. Only the subtrees that
+    // can hold such keys are read: a child is skipped while its separator key is below
+    // the range, and the walk stops at the first key past it
+    fn get_row_ids(&mut self, page: &Page, range: &KeyRange) -> anyhow::Result<Vec<i64>> {
+        let mut result = Vec::new();
+        self.collect_row_ids(page, range, &mut result)?;
+        Ok(result)
+    }
+    // Returns false once a key past the range was seen
+    fn collect_row_ids(
+        &mut self,
+        page: &Page,
+        range: &KeyRange,
+        result: &mut Vec<i64>,
+    ) -> anyhow::Result<bool> {
+        match page {
+            Page::IndexLeaf(leaf_page) => {
+                let start = leaf_page
+                    .cells
+                    .partition_point(|cell| !range.above_lower(&cell.record));
+                for cell in &leaf_page.cells[start..] {
+                    if !range.below_upper(&cell.record) {
+                        return Ok(false);
+                    }
+                    result.push(index_row_id(&cell.record)?);
+                }
+                Ok(true)
+            }
+            Page::IndexInterior(interior_page) => {
+                for cell in &interior_page.cells {
+                    if !range.above_lower(&cell.record) {
+                        continue;
+                    }
+                    let page = self.read_page(cell.left_child as usize)?;
+                    if !self.collect_row_ids(&page, range, result)? || !range.below_upper(&cell.record) {
+                        return Ok(false);
+                    }
+                    result.push(index_row_id(&cell.record)?);
+                }
+                let right_page = self.read_page(interior_page.header.get_right_most_point() as usize)?;
+                self.collect_row_ids(&right_page, range, result)
+            }
+            Page::TableInterior(_) | Page::TableLeaf(_) => {
+                anyhow::bail!(Error::CorruptPage(format!(
+                    "get_row_ids expected an index page, found {:?}",
+                    page.get_page_type()
+                )))
+            }
+        }
+    }
+
+    fn read_page(&mut self, page_num: usize) -> anyhow::Result<Arc<Page>> {
+        self.pager.read_page(page_num)
+    }
+
+    // sqlite_schema is read and its CREATE statements parsed once, then again only after
+    // the schema cookie changed: by a write of this connection, a rollback, or another
+    // connection, which a statement checks for before it runs
+    pub fn get_schemas(&mut self) -> anyhow::Result<()> {
+        if self.schema_cookie.is_some() {
+            return Ok(());
+        }
+        let mut table_schemas = HashMap::new();
+        let mut index_schemas = HashMap::new();
+        // Name, table and root page of the indexes for UNIQUE and PRIMARY KEY constraints,
+        // whose columns come from their table's SQL once it was read
+        let mut autoindexes = Vec::new();
+        // sqlite_schema is a table b-tree rooted at page 1, which grows past it like any other
+        let mut cursor = TableCursor::new(1);
+        while let Some(cell) = cursor.next(&mut self.pager)? {
+            // 0: schema_type
+            // 1: schema_name
+            // 2: table_name
+            // 3: rootpage
+            // 4: sql
+            let schema_type = match cell.record.value_ref(0) {
+                Some(ValueRef::String(schema_type)) => schema_type.to_string(),
+                _ => continue,
+            };
+            let schema_name = match cell.record.value_ref(1) {
+                Some(ValueRef::String(name)) => name.to_string(),
+                _ => continue,
+            };
+            let table_name = match cell.record.value_ref(2) {
+                Some(ValueRef::String(name)) => name.to_string(),
+                _ => continue,
+            };
+            let root_page = match cell.record.value_ref(3) {
+                Some(ValueRef::I64(n)) => n as u32,
+                _ => continue,
+            };
+            let sql = match cell.record.value_ref(4) {
+                Some(ValueRef::String(sql)) => sql.to_string(),
+                _ => {
+                    if schema_type == "index" {
+                        autoindexes.push((schema_name, table_name, root_page));
+                    }
+                    continue;
+                }
+            };
+
+            match schema_type.as_str() {
+                "table" => {
+                    let schema = Schema::parse(&schema_type, schema_name, table_name.clone(), sql, root_page)?;
+                    table_schemas.insert(table_name, schema);
+                }
+                "index" => {
+                    let schema = Schema::parse(&schema_type, schema_name.clone(), table_name, sql, root_page)?;
+                    index_schemas.insert(schema_name, schema);
+                }
+                // Views and triggers have no b-tree of their own, they are left out
+                _ => {}
+            };
+        }
+        self.table_schemas = table_schemas;
+        for (schema_name, table_name, root_page) in autoindexes {
+            let columns = self
+                .table_schemas
+                .get(&table_name)
+                .map(|table| autoindex_constraint(table, &schema_name))
+                .unwrap_or_default();
+            let schema = Schema {
+                schema_name: schema_name.clone(),
+                table_name,
+                sql: String::new(),
+                root_page,
+                columns: columns.into_iter().map(|(name, _)| index_column(name)).collect(),
+            };
+            index_schemas.insert(schema_name, schema);
+        }
+        self.index_schemas = index_schemas;
+        self.schema_cookie = Some(self.data_version()?.1);
+        anyhow::Ok(())
+    }
+    // Drops the schemas read at an older cookie, they are read again when next needed
+    // The file change counter as this connection last saw it: it moves with each of its
+    // own writes, and with another connection's once a statement noticed it
+    pub fn change_counter(&self) -> Option<u32> {
+        self.change_counter
+    }
+    // Another connection may have written to the file since the last statement. The
+    // pages cached before are stale once the change counter moved, and the schemas once
+    // the schema cookie did
+    fn check_schema_cookie(&mut self) -> anyhow::Result<()> {
+        self.pager.lock_for_read()?;
+        let (counter, cookie) = self.data_version()?;
+        if self.change_counter != Some(counter) {
+            self.pager.clear_cache()?;
+        }
+        self.change_counter = Some(counter);
+        if self.schema_cookie.is_some_and(|seen| seen != cookie) {
+            self.forget_schemas();
+        }
+        Ok(())
+    }
+    fn forget_schemas(&mut self) {
+        self.schema_cookie = None;
+    }
+    // Run `read` with SHARED held on the file, and the schemas up to date
+    fn reading<T>(&mut self, read: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let result = self.check_schema_cookie().and_then(|_| read(self));
+        self.pager.end_read();
+        result
+    }
+    // Page usage of sqlite_schema and every table and index b-tree
+    pub fn space_usage(&mut self) -> anyhow::Result<Vec<BtreeUsage>> {
+        self.reading(|db| {
+            db.get_schemas()?;
+            let mut btrees = vec![("sqlite_schema".to_string(), 1, false)];
+            for schema in db.table_schemas.values() {
+                btrees.push((schema.schema_name.clone(), schema.root_page, false));
+            }
+            for schema in db.index_schemas.values() {
+                btrees.push((schema.schema_name.clone(), schema.root_page, true));
+            }
+            btrees[1..].sort();
+
+            let usable_size = db.header.usable_size();
+            let mut result = Vec::new();
+            for (name, root_page, is_index) in btrees {
+                let mut usage = BtreeUsage::new(&name, is_index);
+                usage.collect(&mut db.pager, root_page as usize, usable_size)?;
+                result.push(usage);
+            }
+            anyhow::Ok(result)
+        })
+    }
+    // "ok", or the problems with the file: pages that are laid out wrong, in the wrong
+    // place or used twice or not at all
+    pub fn integrity_check(&mut self, max_errors: usize) -> anyhow::Result<Vec<String>> {
+        self.reading(|db| {
+            db.get_schemas()?;
+            let tables = db.table_schemas.values().chain(db.index_schemas.values());
+            let mut roots: Vec<usize> = tables.map(|schema| schema.root_page as usize).collect();
+            roots.push(1);
+            roots.sort_unstable();
+            roots.dedup();
+            IntegrityCheck::new(&mut db.pager, db.header.auto_vacuum, max_errors).run(&roots)
+        })
+    }
+    // The database as SQL that sqlite3 can replay, like its .dump: each table followed
+    // by an INSERT for each of its rows, then the indexes
+    pub fn dump(&mut self, out: &mut dyn Write) -> anyhow::Result<()> {
+        self.reading(|db| {
+            db.get_schemas()?;
+            // Type, name and SQL of each entry of sqlite_schema, in the order they were created
+            let mut entries = Vec::new();
+            let mut cursor = TableCursor::new(1);
+            while let Some(cell) = cursor.next(&mut db.pager)? {
+                let text = |i| match cell.record.value_ref(i) {
+                    Some(ValueRef::String(text)) => Some(text.to_string()),
+                    _ => None,
+                };
+                if let (Some(kind), Some(name), Some(sql)) = (text(0), text(1), text(4)) {
+                    entries.push((kind, name, sql));
+                }
+            }
+
+            writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+            writeln!(out, "BEGIN TRANSACTION;")?;
+            // Views go with the tables, like sqlite3 does
+            for (kind, name, sql) in entries.iter().filter(|(kind, _, _)| kind == "table" || kind == "view") {
+                match name.as_str() {
+                    _ if kind == "view" => {
+                        writeln!(out, "{};", sql)?;
+                        continue;
+                    }
+                    // Created by ANALYZE and AUTOINCREMENT, not by CREATE TABLE
+                    "sqlite_stat1" => writeln!(out, "ANALYZE sqlite_schema;")?,
+                    "sqlite_sequence" => writeln!(out, "DELETE FROM sqlite_sequence;")?,
+                    name if name.starts_with("sqlite_") => continue,
+                    // sqlite3 does the same for a quoted name
+                    _ if sql.starts_with("CREATE TABLE \"") || sql.starts_with("CREATE TABLE '") => {
+                        writeln!(out, "CREATE TABLE IF NOT EXISTS {};", &sql["CREATE TABLE ".len()..])?
+                    }
+                    _ => writeln!(out, "{};", sql)?,
+                }
+                let Some(schema) = db.table_schemas.get(name).cloned() else {
+                    continue;
+                };
+                let table = quote_identifier(name);
+                let mut cursor = TableCursor::new(schema.root_page as usize);
+                while let Some(cell) = cursor.next(&mut db.pager)? {
+                    let values = row_values(&cell, &schema).iter().map(Value::to_sql_literal).collect::<Vec<_>>();
+                    writeln!(out, "INSERT INTO {} VALUES({});", table, values.join(","))?;
+                }
+            }
+            for (_, _, sql) in entries.iter().filter(|(kind, _, _)| kind == "index" || kind == "trigger") {
+                writeln!(out, "{};", sql)?;
+            }
+            writeln!(out, "COMMIT;")?;
+            Ok(())
+        })
+    }
+    // Every index of the table, ordered by root page
+    pub fn get_index_schemas(&mut self, table_name: &str) -> anyhow::Result<Vec<Schema>> {
+        self.get_schemas()?;
+        let mut indexes = self
+            .index_schemas
+            .values()
+            .filter(|schema| schema.table_name.eq_ignore_ascii_case(table_name))
+            .cloned()
+            .collect::<Vec<_>>();
+        indexes.sort_by_key(|schema| schema.root_page);
+        Ok(indexes)
+    }
+    // The names of the tables, but not SQLite's own, in name order
+    pub fn table_names(&mut self) -> anyhow::Result<Vec<String>> {
+        self.reading(|db| {
+            db.get_schemas()?;
+            let mut names = db
+                .table_schemas
+                .values()
+                .map(|schema| schema.table_name.clone())
+                .filter(|name| !name.to_lowercase().starts_with("sqlite_"))
+                .collect::<Vec<_>>();
+            names.sort();
+            Ok(names)
+        })
+    }
+    // The names of the indexes on `table_name`, or on every table, in name order
+    pub fn index_names(&mut self, table_name: Option<&str>) -> anyhow::Result<Vec<String>> {
+        self.reading(|db| {
+            db.get_schemas()?;
+            let mut names = db
+                .index_schemas
+                .values()
+                .filter(|schema| table_name.map_or(true, |name| schema.table_name.eq_ignore_ascii_case(name)))
+                .map(|schema| schema.schema_name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            Ok(names)
+        })
+    }
+    pub fn get_table_schema(&mut self, table_name: &str) -> anyhow::Result<Option<Schema>> {
         self.get_schemas()?;
         let table_schema = self.table_schemas.get(table_name);
         match table_schema {
@@ -495,42 +2641,1226 @@ impl Db {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Schema {
-    schema_name: String,
-    table_name: String,
-    sql: String,
-    root_page: i8,
-    columns: Vec<Column>,
+#[derive(Debug, Clone)]
+pub struct Schema {
+    schema_name: String,
+    pub table_name: String,
+    pub sql: String,
+    pub root_page: u32,
+    pub columns: Vec<Column>,
+}
+impl Schema {
+    // A table or an index from its row in sqlite_schema
+    pub(crate) fn parse(
+        schema_type: &str,
+        schema_name: String,
+        table_name: String,
+        sql: String,
+        root_page: u32,
+    ) -> anyhow::Result<Self> {
+        let columns = match schema_type {
+            "index" => parse_create_index_sql(&sql)?,
+            _ => parse_create_table_sql(&sql)?,
+        };
+        Ok(Schema { schema_name, table_name, sql, root_page, columns })
+    }
+    // Its name in sqlite_schema, an index's own name rather than its table's
+    pub fn name(&self) -> &str {
+        &self.schema_name
+    }
+    // The INTEGER PRIMARY KEY column, if any, stands in for the rowid
+    fn rowid_name(&self) -> &str {
+        match self.columns.iter().find(|column| column.is_rowid_alias()) {
+            Some(column) => &column.name,
+            None => "rowid",
+        }
+    }
+    // How sqlite3 names a result column that refers to `name`
+    fn declared_name(&self, name: &str) -> Option<String> {
+        let name = name.to_lowercase();
+        if self.is_rowid(&name) {
+            return Some(self.rowid_name().to_string());
+        }
+        self.columns
+            .iter()
+            .find(|column| column.name == name)
+            .map(|column| column.name.clone())
+    }
+    // The collation of column `name`, the rowid's is BINARY
+    pub fn collation(&self, name: &str) -> Collation {
+        let name = name.to_lowercase();
+        self.columns
+            .iter()
+            .find(|column| column.name == name)
+            .map_or(Collation::Binary, |column| column.collation)
+    }
+    // The affinity of column `name`, the rowid's is INTEGER
+    fn affinity(&self, name: &str) -> Option<Affinity> {
+        let name = name.to_lowercase();
+        match self.columns.iter().find(|column| column.name == name) {
+            Some(column) => Some(column.affinity),
+            None if ROWID_ALIASES.contains(&name.as_str()) => Some(Affinity::Integer),
+            None => None,
+        }
+    }
+    pub fn is_rowid(&self, name: &str) -> bool {
+        match self.columns.iter().find(|column| column.name == name) {
+            Some(column) => column.is_rowid_alias(),
+            None => ROWID_ALIASES.contains(&name),
+        }
+    }
+    // A table whose rows are kept in an index b-tree by their PRIMARY KEY
+    fn is_without_rowid(&self) -> bool {
+        let sql = self.sql.to_lowercase();
+        let options = sql.rfind(')').map_or("", |end| &sql[end + 1..]);
+        options.split(|c: char| c.is_whitespace() || c == ',').any(|word| word == "without")
+    }
+    // A CHECK constraint on one of the columns or on the table
+    fn has_check(&self) -> bool {
+        let sql = self.sql.to_lowercase();
+        sql.match_indices("check").any(|(i, _)| {
+            let before = sql[..i].chars().next_back();
+            before.is_some_and(|c| c.is_whitespace() || c == ',' || c == '(')
+                && sql[i + 5..].trim_start().starts_with('(')
+        })
+    }
+    // Rowids of an AUTOINCREMENT table are never reused, the largest one is in sqlite_sequence
+    fn is_autoincrement(&self) -> bool {
+        self.sql.to_lowercase().contains("autoincrement")
+    }
+    // Of an index: made by SQLite for a UNIQUE or PRIMARY KEY constraint of its table,
+    // which leaves its SQL NULL
+    fn is_autoindex(&self) -> bool {
+        self.sql.is_empty()
+    }
+    // Of an index: CREATE UNIQUE INDEX
+    fn is_unique(&self) -> bool {
+        self.sql.to_lowercase().split_whitespace().nth(1) == Some("unique")
+    }
+    // Of an index: entries only for the rows its WHERE clause holds for
+    fn is_partial(&self) -> bool {
+        self.sql.to_lowercase().split(|c: char| !c.is_alphanumeric() && c != '_').any(|word| word == "where")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    type_name: String,
+    // The affinity the declared type gives the column
+    affinity: Affinity,
+    // How its text compares, from its COLLATE constraint
+    collation: Collation,
+    primary_key: bool,
+    not_null: bool,
+    // DEFAULT value, which older records that lack the column read as
+    default: Value,
+}
+
+impl Column {
+    // An INTEGER PRIMARY KEY column is stored as NULL in the record and reads the rowid
+    fn is_rowid_alias(&self) -> bool {
+        self.primary_key && self.type_name == "integer"
+    }
+}
+
+
+// The collation of each result column, as its expression would sort: a column's own, or
+// the one an outer COLLATE names. Wildcards give those of the columns they expand to
+fn result_collations(select: &SelectStmt, sources: &[Source], rowid_in_wildcard: bool) -> anyhow::Result<Vec<Collation>> {
+    let mut collations = Vec::new();
+    for column in &select.columns {
+        match &column.expr {
+            Expr::Wildcard => {
+                if rowid_in_wildcard {
+                    collations.push(Collation::Binary);
+                }
+                for source in sources {
+                    collations.extend(source.wildcard_columns().map(|i| source.schema.columns[i].collation));
+                }
+            }
+            Expr::TableWildcard(table) => {
+                let (_, source) = wildcard_source(sources, table)?;
+                collations.extend(source.schema.columns.iter().map(|column| column.collation));
+            }
+            Expr::Aliased(expr, _) => collations.push(order_collation(expr, sources)?),
+            expr => collations.push(order_collation(expr, sources)?),
+        }
+    }
+    Ok(collations)
+}
+
+// A NULL in a NOT NULL column fails the statement. `record` has NULL for the INTEGER
+// PRIMARY KEY, which then gets a new rowid
+fn check_not_null(schema: &Schema, record: &[Value]) -> anyhow::Result<()> {
+    for (value, column) in record.iter().zip(&schema.columns) {
+        if column.not_null && !column.is_rowid_alias() && value.is_null() {
+            anyhow::bail!(Error::Constraint(format!(
+                "NOT NULL constraint failed: {}.{}",
+                schema.table_name, column.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// A value stored into the rowid has to be a whole number
+fn to_row_id(value: Value) -> anyhow::Result<i64> {
+    match value.to_numeric() {
+        Value::I64(row_id) => Ok(row_id),
+        Value::Float(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Ok(n as i64),
+        _ => anyhow::bail!(Error::TypeMismatch("datatype mismatch".to_string())),
+    }
+}
+
+// Column values as they are stored in the record, each converted by its column's
+// affinity. An INTEGER PRIMARY KEY is NULL there
+fn stored_record(schema: &Schema, values: Vec<Value>) -> Vec<Value> {
+    values
+        .into_iter()
+        .zip(&schema.columns)
+        .map(|(value, column)| match column.is_rowid_alias() {
+            true => Value::Null,
+            false => value.with_affinity(column.affinity),
+        })
+        .collect()
+}
+
+// The entry of a table row in one of its indexes: the indexed columns, then the rowid.
+// `record` is the row as stored, with NULL for an INTEGER PRIMARY KEY
+fn index_entry(schema: &Schema, index: &Schema, record: &[Value], row_id: i64) -> anyhow::Result<Vec<Value>> {
+    let mut entry = Vec::new();
+    for column in &index.columns {
+        let Some(i) = schema.columns.iter().position(|c| c.name == column.name) else {
+            anyhow::bail!(Error::NoSuchColumn(column.name.clone()));
+        };
+        entry.push(match schema.columns[i].is_rowid_alias() {
+            true => Value::I64(row_id),
+            false => record.get(i).cloned().unwrap_or(Value::Null),
+        });
+    }
+    entry.push(Value::I64(row_id));
+    Ok(entry)
+}
+
+// A name as it can be written in SQL, in double quotes unless it is a plain word
+fn quote_identifier(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && keywords::get(name).is_none();
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+// Column values of a table row, an INTEGER PRIMARY KEY column reads the rowid
+fn row_values(cell: &TableLeafCell, schema: &Schema) -> Vec<Value> {
+    schema
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| match cell.record.value_ref(i) {
+            Some(value) if column.is_rowid_alias() && value.is_null() => Value::I64(cell.row_id),
+            // A REAL with no fractional part may be written to disk as an INTEGER
+            Some(ValueRef::I64(n)) if column.affinity == Affinity::Real => Value::Float(n as f64),
+            Some(value) => value.to_owned(),
+            // Columns added by ALTER TABLE are missing from older records
+            None => column.default.clone(),
+        })
+        .collect()
+}
+
+// One table of the FROM clause and how it is joined to the ones before it
+#[derive(Clone)]
+struct Source {
+    table: TableReference,
+    schema: Schema,
+    kind: JoinKind,
+    on: Option<Expr>,
+    // Columns of a NATURAL or USING join, shared with the tables before this one
+    using: Vec<String>,
+    // The rows of a VALUES list, which has no b-tree
+    rows: Option<Vec<SourceRow>>,
+}
+
+// The position of the last table a term refers to, None when one of its columns is
+// ambiguous or unknown, or it has a subquery to run
+fn term_level(term: &Expr, sources: &[Source]) -> Option<usize> {
+    if contains(term, &is_subquery) {
+        return None;
+    }
+    let mut columns = Vec::new();
+    collect_columns(term, &mut columns);
+    let mut level = 0;
+    for column in columns {
+        let owner = match column {
+            Expr::QualifiedIdentifier(table, _) => sources
+                .iter()
+                .position(|source| source.table.qualifier().eq_ignore_ascii_case(table))?,
+            Expr::Identifier(name) => {
+                let mut owners = sources.iter().enumerate().filter(|(_, source)| {
+                    source.schema.columns.iter().any(|column| column.name.eq_ignore_ascii_case(name))
+                });
+                match (owners.next(), owners.next()) {
+                    (Some((owner, _)), None) => owner,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        level = level.max(owner);
+    }
+    Some(level)
+}
+
+// Like SQLite, an operand compared with a column first takes the column's affinity: an
+// INTEGER column equals '5' where it holds 5, a TEXT column equals 5 where it holds '5'.
+// Between two columns only a numeric one passes its affinity on. The values of an IN
+// list take the affinity of the left side, a BETWEEN compares with each bound, and its
+// value takes an affinity only when both comparisons agree on it
+// https://www.sqlite.org/datatype3.html#type_conversions_prior_to_comparison
+fn apply_affinities(expr: &mut Expr, sources: &[Source]) {
+    match expr {
+        Expr::BinaryOp(left, op, right)
+            if is_comparison(&op.token_type) || op.token_type == TokenType::Is =>
+        {
+            let (left_affinity, right_affinity) = (expr_affinity(left, sources), expr_affinity(right, sources));
+            apply_affinity(right, comparison_affinity(left_affinity, right_affinity));
+            apply_affinity(left, comparison_affinity(right_affinity, left_affinity));
+        }
+        Expr::InList(left, list) => {
+            let affinity = expr_affinity(left, sources).filter(|affinity| *affinity != Affinity::Blob);
+            list.iter_mut().for_each(|item| apply_affinity(item, affinity));
+        }
+        Expr::Between(value, low, high) => {
+            let affinity = expr_affinity(value, sources);
+            let (low_affinity, high_affinity) = (expr_affinity(low, sources), expr_affinity(high, sources));
+            let value_affinity = comparison_affinity(low_affinity, affinity);
+            apply_affinity(low, comparison_affinity(affinity, low_affinity));
+            apply_affinity(high, comparison_affinity(affinity, high_affinity));
+            if value_affinity == comparison_affinity(high_affinity, affinity) {
+                apply_affinity(value, value_affinity);
+            }
+        }
+        _ => (),
+    }
+    for child in children_mut(expr) {
+        apply_affinities(child, sources);
+    }
+}
+
+// The affinity an operand with affinity `own` takes before it is compared with one that
+// has `other`, None when it is compared as it is. A column without a type has BLOB
+// affinity, which converts nothing but still counts as one
+fn comparison_affinity(other: Option<Affinity>, own: Option<Affinity>) -> Option<Affinity> {
+    match (other, own) {
+        (_, Some(Affinity::Integer | Affinity::Real | Affinity::Numeric)) => None,
+        (Some(Affinity::Integer | Affinity::Real | Affinity::Numeric), _) => Some(Affinity::Numeric),
+        (Some(Affinity::Text), None) => Some(Affinity::Text),
+        _ => None,
+    }
+}
+
+// The equalities a hash join on the table at `level` groups its rows by: USING columns,
+// and ON clause or early WHERE terms comparing one of its columns with an expression over
+// the outer tables, which are the ones `outer_row_map` has
+fn hash_join_keys(
+    sources: &[Source],
+    level: usize,
+    early_terms: &[&Expr],
+    outer_row_map: &HashMap<String, Value>,
+) -> Vec<HashJoinKey> {
+    let source = &sources[level];
+    let mut terms = early_terms.to_vec();
+    if let Some(on) = &source.on {
+        collect_conjuncts(on, &mut terms);
+    }
+    let qualifier = source.table.qualifier();
+    let position = |name: &str| source.schema.columns.iter().position(|column| column.name == name);
+    // A column of this table, unqualified only when no outer table has one by the name,
+    // with the affinity it takes for the comparison
+    let inner_column = |expr: &Expr| {
+        let (column, affinity) = match strip_collate(expr) {
+            Expr::Affinity(column, affinity) => (strip_collate(column), Some(*affinity)),
+            column => (column, None),
+        };
+        let i = match column {
+            Expr::QualifiedIdentifier(table, name) if table.eq_ignore_ascii_case(qualifier) => {
+                position(&name.to_lowercase())
+            }
+            Expr::Identifier(name) if !outer_row_map.contains_key(&name.to_lowercase()) => {
+                position(&name.to_lowercase())
+            }
+            _ => None,
+        };
+        i.map(|i| (i, affinity))
+    };
+    let is_outer = |expr: &Expr| {
+        let is_inner = |expr: &Expr| {
+            is_subquery(expr) || row_map_key(expr).is_some_and(|key| !outer_row_map.contains_key(&key))
+        };
+        !contains(expr, &is_inner)
+    };
+    let mut keys = Vec::new();
+    for column in &source.using {
+        if let Some(i) = position(column) {
+            let (outer_affinity, inner_affinity) = using_affinities(sources, level, column);
+            let mut outer = Expr::Identifier(column.clone());
+            apply_affinity(&mut outer, outer_affinity);
+            keys.push(HashJoinKey { position: i, affinity: inner_affinity, outer });
+        }
+    }
+    for term in terms {
+        let Expr::BinaryOp(left, op, right) = term else {
+            continue;
+        };
+        if op.token_type != TokenType::Equal {
+            continue;
+        }
+        for (inner, outer) in [(left, right), (right, left)] {
+            if let Some((position, affinity)) = inner_column(inner) {
+                if is_outer(outer) {
+                    let outer = strip_collate(outer).clone();
+                    keys.push(HashJoinKey { position, affinity, outer });
+                    break;
+                }
+            }
+        }
+    }
+    keys
+}
+
+// The affinities the two columns a USING clause compares take, the one on the left of the
+// table at `level` and that table's
+fn using_affinities(sources: &[Source], level: usize, column: &str) -> (Option<Affinity>, Option<Affinity>) {
+    let left = sources[..level].iter().find_map(|source| source.schema.affinity(column));
+    let right = sources[level].schema.affinity(column);
+    (comparison_affinity(right, left), comparison_affinity(left, right))
+}
+
+// The affinity of a column reference or a CAST, other expressions have none
+fn expr_affinity(expr: &Expr, sources: &[Source]) -> Option<Affinity> {
+    match expr {
+        Expr::Cast(_, type_name) => Some(Affinity::from_type_name(type_name)),
+        Expr::Collate(expr, _) => expr_affinity(expr, sources),
+        Expr::Affinity(_, affinity) => Some(*affinity),
+        expr => column_schema(expr, sources).and_then(|(schema, name)| schema.affinity(name)),
+    }
+}
+
+// The schema of the table a column reference is to, with the column's name. An
+// unqualified name has to belong to just one table
+fn column_schema<'a>(expr: &'a Expr, sources: &'a [Source]) -> Option<(&'a Schema, &'a str)> {
+    match expr {
+        Expr::Identifier(name) => {
+            let mut schemas = sources
+                .iter()
+                .map(|source| &source.schema)
+                .filter(|schema| schema.affinity(name).is_some());
+            match (schemas.next(), schemas.next()) {
+                (Some(schema), None) => Some((schema, name)),
+                _ => None,
+            }
+        }
+        Expr::QualifiedIdentifier(table, name) => sources
+            .iter()
+            .find(|source| source.table.qualifier().eq_ignore_ascii_case(table))
+            .map(|source| (&source.schema, name.as_str())),
+        _ => None,
+    }
+}
+
+// A comparison uses the collation an operand names with COLLATE, the left one first, and
+// otherwise that of a column operand, again the left one first, even when it is BINARY.
+// The column is wrapped in a COLLATE with its own collation here, so evaluating the
+// comparison only has to look at its operands
+fn apply_collations(expr: &mut Expr, sources: &[Source]) {
+    let is_collate = |expr: &Expr| matches!(expr, Expr::Collate(..));
+    match expr {
+        Expr::BinaryOp(left, op, right)
+            if (is_comparison(&op.token_type) || op.token_type == TokenType::Is)
+                && !is_collate(left)
+                && !is_collate(right) =>
+        {
+            for operand in [left, right] {
+                if apply_collation(operand, sources) {
+                    break;
+                }
+            }
+        }
+        Expr::InList(left, _) | Expr::Between(left, ..) | Expr::Case(Some(left), ..) if !is_collate(left) => {
+            apply_collation(left, sources);
+        }
+        _ => (),
+    }
+    for child in children_mut(expr) {
+        apply_collations(child, sources);
+    }
+}
+
+// Wraps a column with a collation other than BINARY in its COLLATE, returns whether the
+// operand is a column and so decides the collation. A BINARY one is left as it is, that
+// is what a comparison without a COLLATE uses
+fn apply_collation(operand: &mut Expr, sources: &[Source]) -> bool {
+    let column = match &*operand {
+        Expr::Affinity(column, _) => column,
+        column => column,
+    };
+    let Some((schema, name)) = column_schema(column, sources) else {
+        return false;
+    };
+    let collation = schema.collation(name);
+    if collation == Collation::Binary {
+        return true;
+    }
+    let column = std::mem::replace(operand, Expr::Wildcard);
+    *operand = Expr::Collate(Box::new(column), collation.name().to_string());
+    true
+}
+
+// The collation a comparison operand asks for: its COLLATE, or a column's own
+fn operand_collation(expr: &Expr, sources: &[Source]) -> anyhow::Result<Option<Collation>> {
+    match expr {
+        Expr::Collate(_, name) => Collation::from_name(name).map(Some),
+        Expr::Affinity(expr, _) => operand_collation(expr, sources),
+        expr => Ok(column_schema(expr, sources).map(|(schema, name)| schema.collation(name))),
+    }
+}
+
+// The collation an ORDER BY term sorts by: its COLLATE, or a column's own
+fn order_collation(expr: &Expr, sources: &[Source]) -> anyhow::Result<Collation> {
+    match expr {
+        Expr::Collate(_, name) => Collation::from_name(name),
+        expr => Ok(column_schema(expr, sources).map_or(Collation::Binary, |(schema, name)| schema.collation(name))),
+    }
+}
+
+// A numeric affinity turns text that reads as a number into that number, TEXT turns a
+// number into text. A literal is converted here, any other operand when it is evaluated.
+// The affinity goes inside a COLLATE, which has to stay on top for the comparison to see it
+fn apply_affinity(operand: &mut Expr, affinity: Option<Affinity>) {
+    let Some(affinity) = affinity else {
+        return;
+    };
+    if let Expr::Collate(inner, _) = operand {
+        return apply_affinity(inner, Some(affinity));
+    }
+    let Expr::Literal(literal) = operand else {
+        let inner = std::mem::replace(operand, Expr::Wildcard);
+        *operand = Expr::Affinity(Box::new(inner), affinity);
+        return;
+    };
+    let value = literal_value(literal);
+    let value = match (affinity, &value) {
+        (Affinity::Integer | Affinity::Real | Affinity::Numeric, _) => value.with_numeric_affinity(),
+        (Affinity::Text, Value::I64(_) | Value::Float(_)) => Value::String(value.to_text()),
+        _ => return,
+    };
+    *literal = value_literal(value);
+}
+
+// The table `table.*` expands, by its alias or else its name
+fn wildcard_source<'a>(sources: &'a [Source], table: &str) -> anyhow::Result<(usize, &'a Source)> {
+    match sources.iter().enumerate().find(|(_, source)| table.eq_ignore_ascii_case(source.table.qualifier())) {
+        Some(found) => Ok(found),
+        None => anyhow::bail!(Error::NoSuchTable(table.to_string())),
+    }
+}
+
+impl Source {
+    // A column of its own by that name, or the rowid under one of its aliases
+    fn has_column(&self, name: &str) -> bool {
+        let mut names = self.schema.columns.iter().map(|column| column.name.as_str()).chain(ROWID_ALIASES);
+        names.any(|column| column.eq_ignore_ascii_case(name))
+    }
+    // Positions of the columns `*` expands to, a join column only shows up once
+    fn wildcard_columns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.schema
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| !self.using.contains(&column.name))
+            .map(|(i, _)| i)
+    }
+}
+
+// The columns a NATURAL or USING join compares, each has to exist on both sides
+fn join_columns(join: &Join, left: &[Source], right: &Schema) -> anyhow::Result<Vec<String>> {
+    let in_left = |name: &str| {
+        left.iter()
+            .any(|source| source.schema.columns.iter().any(|column| column.name == name))
+    };
+    if join.natural {
+        return Ok(right
+            .columns
+            .iter()
+            .map(|column| column.name.clone())
+            .filter(|name| in_left(name))
+            .collect());
+    }
+    let mut columns = Vec::new();
+    for name in &join.using {
+        let name = name.to_lowercase();
+        if !in_left(&name) || !right.columns.iter().any(|column| column.name == name) {
+            anyhow::bail!("cannot join using column {} - column not present in both tables", name);
+        }
+        columns.push(name);
+    }
+    Ok(columns)
+}
+
+// The rows of a joined table by the values of the columns its equalities compare
+struct HashJoin {
+    keys: Vec<HashJoinKey>,
+    rows: HashMap<Vec<HashKey>, Vec<SourceRow>>,
+}
+
+// A column of the joined table, the affinity its values take before they are compared,
+// and the expression over the outer tables it equals, which applies its own
+struct HashJoinKey {
+    position: usize,
+    affinity: Option<Affinity>,
+    outer: Expr,
+}
+
+impl HashJoinKey {
+    fn outer(&self, outer_row_map: &HashMap<String, Value>, ctx: &FunctionContext) -> anyhow::Result<Option<HashKey>> {
+        Ok(HashKey::new(&evaluate(&self.outer, outer_row_map, ctx)?))
+    }
+    fn inner(&self, row: &SourceRow) -> Option<HashKey> {
+        let value = &row.values[self.position];
+        match self.affinity {
+            Some(affinity) => HashKey::new(&value.with_affinity(affinity)),
+            None => HashKey::new(value),
+        }
+    }
+}
+
+impl HashJoin {
+    // The rows whose keys may equal the outer row's, the join terms still decide
+    fn candidates(
+        &self,
+        outer_row_map: &HashMap<String, Value>,
+        ctx: &FunctionContext,
+    ) -> anyhow::Result<&[SourceRow]> {
+        let mut key = Vec::new();
+        for join_key in &self.keys {
+            match join_key.outer(outer_row_map, ctx)? {
+                Some(part) => key.push(part),
+                None => return Ok(&[]),
+            }
+        }
+        Ok(self.rows.get(&key).map_or(&[], Vec::as_slice))
+    }
+}
+
+// A value as a hash join groups it, after both sides took the affinity of the comparison.
+// Values that compare equal under any collation get the same key, so text is keyed by
+// its lowercase without trailing spaces and an integer as the REAL it equals. Some values
+// that aren't equal share a key too, which the join terms then tell apart
+#[derive(PartialEq, Eq, Hash)]
+enum HashKey {
+    Number(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl HashKey {
+    // None for NULL
+    fn new(value: &Value) -> Option<Self> {
+        // 0.0 and -0.0 are equal
+        let number = |n: f64| Self::Number((n + 0.0).to_bits());
+        Some(match value {
+            Value::Null => return None,
+            Value::I64(n) => number(*n as f64),
+            Value::Float(n) => number(*n),
+            Value::String(s) => Self::Text(s.trim_end_matches(' ').to_ascii_lowercase()),
+            Value::Blob(b) => Self::Blob(b.clone()),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct SourceRow {
+    row_id: i64,
+    values: Vec<Value>,
+}
+
+// VALUES columns are named column1, column2, ...
+fn values_columns(count: usize) -> Vec<Column> {
+    (1..=count)
+        .map(|i| Column {
+            name: format!("column{}", i),
+            type_name: String::new(),
+            affinity: Affinity::Blob,
+            collation: Collation::Binary,
+            primary_key: false,
+            not_null: false,
+            default: Value::Null,
+        })
+        .collect()
+}
+
+// A row picked by the WHERE clause of an UPDATE or DELETE, with its row map
+type MatchingRow = (SourceRow, HashMap<String, Value>);
+
+// Column name -> value of a joined row, including the rowid under its aliases.
+// Every column is reachable as `table.column`, and as just `column` unless several
+// tables have one by that name. `None` rows are the NULL rows of a LEFT JOIN
+fn row_map(sources: &[Source], rows: &[Option<SourceRow>]) -> HashMap<String, Value> {
+    let mut row_map = HashMap::new();
+    // Unqualified name -> the source it belongs to
+    let mut owners = HashMap::new();
+    let mut ambiguous = Vec::new();
+    for (i, (source, row)) in sources.iter().zip(rows).enumerate() {
+        let qualifier = source.table.qualifier().to_lowercase();
+        let row_id = row.as_ref().map_or(Value::Null, |row| Value::I64(row.row_id));
+        let aliases = ROWID_ALIASES.iter().map(|alias| (alias.to_string(), row_id.clone()));
+        let columns = source.schema.columns.iter().enumerate().map(|(j, column)| {
+            let value = row.as_ref().map_or(Value::Null, |row| row.values[j].clone());
+            (column.name.clone(), value)
+        });
+        // Columns come last so they shadow the rowid aliases
+        for (name, value) in aliases.chain(columns) {
+            row_map.insert(format!("{}.{}", qualifier, name), value.clone());
+            // A join column is shared with the left side, it is only NULL when both are
+            if source.using.contains(&name) {
+                if matches!(row_map.get(&name), None | Some(Value::Null)) {
+                    row_map.insert(name, value);
+                }
+                continue;
+            }
+            if *owners.entry(name.clone()).or_insert(i) != i {
+                ambiguous.push(name.clone());
+            }
+            row_map.insert(name, value);
+        }
+    }
+    for name in ambiguous {
+        row_map.remove(&name);
+    }
+    row_map
+}
+
+// Like SQLite, a statement fails on a column name that more than one of the tables has,
+// not counting the join columns shared with a table before them, or on a table name or
+// alias that more than one of them goes by
+fn check_ambiguous(expr: &Expr, sources: &[Source]) -> anyhow::Result<()> {
+    let (owners, name) = match expr {
+        Expr::Identifier(name) => {
+            let owners = sources
+                .iter()
+                .filter(|source| !source.using.iter().any(|column| column.eq_ignore_ascii_case(name)))
+                .filter(|source| source.has_column(name))
+                .count();
+            (owners, name.clone())
+        }
+        Expr::QualifiedIdentifier(table, _) | Expr::TableWildcard(table) => {
+            let owners = sources
+                .iter()
+                .filter(|source| source.table.qualifier().eq_ignore_ascii_case(table))
+                .count();
+            let name = match expr {
+                Expr::QualifiedIdentifier(table, name) => format!("{}.{}", table, name),
+                _ => format!("{}.*", table),
+            };
+            (owners, name)
+        }
+        _ => (0, String::new()),
+    };
+    if owners > 1 {
+        anyhow::bail!("ambiguous column name: {}", name);
+    }
+    children(expr).into_iter().try_for_each(|child| check_ambiguous(child, sources))
+}
+
+// Whether `expr` or an expression inside it satisfies `predicate`
+fn contains(expr: &Expr, predicate: &dyn Fn(&Expr) -> bool) -> bool {
+    if predicate(expr) {
+        return true;
+    }
+    match expr {
+        Expr::BinaryOp(left, _, right) => contains(left, predicate) || contains(right, predicate),
+        Expr::UnaryOp(_, operand)
+        | Expr::Aliased(operand, _)
+        | Expr::Cast(operand, _)
+        | Expr::Collate(operand, _)
+        | Expr::Affinity(operand, _) => contains(operand, predicate),
+        Expr::FunctionCall(_, args) => args.iter().any(|arg| contains(arg, predicate)),
+        Expr::InList(left, list) => {
+            contains(left, predicate) || list.iter().any(|item| contains(item, predicate))
+        }
+        Expr::Between(value, low, high) => {
+            contains(value, predicate) || contains(low, predicate) || contains(high, predicate)
+        }
+        Expr::Case(operand, branches, else_result) => {
+            operand.iter().chain(else_result).any(|expr| contains(expr, predicate))
+                || branches
+                    .iter()
+                    .any(|(when, then)| contains(when, predicate) || contains(then, predicate))
+        }
+        // A subquery's expressions belong to another query
+        Expr::InSelect(left, _) | Expr::InRows(left, _) => contains(left, predicate),
+        Expr::Identifier(_)
+        | Expr::QualifiedIdentifier(..)
+        | Expr::Literal(_)
+        | Expr::Wildcard
+        | Expr::TableWildcard(_)
+        | Expr::Parameter(..)
+        | Expr::Exists(_) => false,
+    }
+}
+
+// The column references in `expr`, outside of aggregate calls
+fn collect_columns<'a>(expr: &'a Expr, columns: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Identifier(_) | Expr::QualifiedIdentifier(..) => columns.push(expr),
+        expr if is_aggregate_call(expr) => (),
+        Expr::BinaryOp(left, _, right) => {
+            collect_columns(left, columns);
+            collect_columns(right, columns);
+        }
+        Expr::UnaryOp(_, operand) | Expr::Aliased(operand, _) => collect_columns(operand, columns),
+        Expr::FunctionCall(_, args) => args.iter().for_each(|arg| collect_columns(arg, columns)),
+        Expr::InList(left, list) => {
+            collect_columns(left, columns);
+            list.iter().for_each(|item| collect_columns(item, columns));
+        }
+        Expr::Between(value, low, high) => {
+            collect_columns(value, columns);
+            collect_columns(low, columns);
+            collect_columns(high, columns);
+        }
+        Expr::Case(operand, branches, else_result) => {
+            operand.iter().for_each(|operand| collect_columns(operand, columns));
+            for (when, then) in branches {
+                collect_columns(when, columns);
+                collect_columns(then, columns);
+            }
+            else_result.iter().for_each(|else_result| collect_columns(else_result, columns));
+        }
+        Expr::InSelect(left, _)
+        | Expr::InRows(left, _)
+        | Expr::Cast(left, _)
+        | Expr::Collate(left, _)
+        | Expr::Affinity(left, _) => collect_columns(left, columns),
+        Expr::Literal(_) | Expr::Wildcard | Expr::TableWildcard(_) | Expr::Parameter(..) | Expr::Exists(_) => (),
+    }
+}
+
+// The key a column reference is found under in a row map
+// The operand of a COLLATE, whose value is the same
+fn strip_collate(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Collate(expr, _) => strip_collate(expr),
+        expr => expr,
+    }
 }
-#[derive(Debug, Clone)]
-pub struct Column {
-    name: String,
-    type_name: String,
+
+fn row_map_key(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(name) => Some(name.to_lowercase()),
+        Expr::QualifiedIdentifier(table, name) => Some(format!("{}.{}", table, name).to_lowercase()),
+        _ => None,
+    }
+}
+
+// `expr` with every call to one of `aggregates` replaced by its value for the group
+fn replace_aggregates(expr: &Expr, aggregates: &[(Aggregate, Expr)], values: &[Value]) -> Expr {
+    let replace = |expr: &Expr| Box::new(replace_aggregates(expr, aggregates, values));
+    if let Some(i) = aggregates.iter().position(|(_, call)| call == expr) {
+        return Expr::Literal(value_literal(values[i].clone()));
+    }
+    match expr {
+        Expr::BinaryOp(left, op, right) => Expr::BinaryOp(replace(left), op.clone(), replace(right)),
+        Expr::UnaryOp(op, operand) => Expr::UnaryOp(op.clone(), replace(operand)),
+        Expr::Aliased(expr, alias) => Expr::Aliased(replace(expr), alias.clone()),
+        Expr::FunctionCall(name, args) => Expr::FunctionCall(
+            name.clone(),
+            args.iter().map(|arg| replace_aggregates(arg, aggregates, values)).collect(),
+        ),
+        Expr::InList(left, list) => Expr::InList(
+            replace(left),
+            list.iter().map(|item| replace_aggregates(item, aggregates, values)).collect(),
+        ),
+        Expr::Between(value, low, high) => Expr::Between(replace(value), replace(low), replace(high)),
+        Expr::Case(operand, branches, else_result) => Expr::Case(
+            operand.as_deref().map(replace),
+            branches
+                .iter()
+                .map(|(when, then)| {
+                    (replace_aggregates(when, aggregates, values), replace_aggregates(then, aggregates, values))
+                })
+                .collect(),
+            else_result.as_deref().map(replace),
+        ),
+        Expr::Cast(expr, type_name) => Expr::Cast(replace(expr), type_name.clone()),
+        Expr::Collate(expr, name) => Expr::Collate(replace(expr), name.clone()),
+        Expr::Affinity(expr, affinity) => Expr::Affinity(replace(expr), *affinity),
+        expr => expr.clone(),
+    }
+}
+
+// The aggregate `expr` calls, if it is a call to one
+pub fn aggregate_call(expr: &Expr) -> Option<Aggregate> {
+    let Expr::FunctionCall(name, args) = expr else {
+        return None;
+    };
+    let Expr::Identifier(name) = name.as_ref() else {
+        return None;
+    };
+    Aggregate::get(name, args.len(), args.as_slice() == [Expr::Wildcard])
+}
+
+// A SELECT of count(*) alone from a table and nothing else, answered by counting the
+// cells of the table's leaves
+fn counts_all(select: &SelectStmt, sources: &[Source]) -> bool {
+    let is_count = match select.columns.as_slice() {
+        [column] => match &column.expr {
+            Expr::Aliased(expr, _) => aggregate_call(expr) == Some(Aggregate::CountRows),
+            expr => aggregate_call(expr) == Some(Aggregate::CountRows),
+        },
+        _ => false,
+    };
+    let is_bare = select.where_clause.is_none()
+        && select.group_by.is_empty()
+        && select.having.is_none()
+        && select.order_by.is_empty()
+        && select.limit.is_none()
+        && select.offset.is_none();
+    matches!(sources, [source] if is_count && is_bare && source.rows.is_none())
+}
+
+fn is_aggregate_call(expr: &Expr) -> bool {
+    aggregate_call(expr).is_some()
+}
+
+// The aggregate calls in `expr` that aren't in `aggregates` yet. An aggregate can't
+// take another one as its argument
+fn collect_aggregates(expr: &Expr, aggregates: &mut Vec<(Aggregate, Expr)>) -> anyhow::Result<()> {
+    let Some(aggregate) = aggregate_call(expr) else {
+        return children(expr)
+            .into_iter()
+            .try_for_each(|child| collect_aggregates(child, aggregates));
+    };
+    let Expr::FunctionCall(_, args) = expr else {
+        unreachable!()
+    };
+    if aggregate != Aggregate::CountRows && args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function {}()", aggregate.name());
+    }
+    if args.iter().any(|arg| contains(arg, &is_aggregate_call)) {
+        anyhow::bail!("misuse of aggregate function {}()", aggregate.name());
+    }
+    if !aggregates.iter().any(|(_, call)| call == expr) {
+        aggregates.push((aggregate, expr.clone()));
+    }
+    Ok(())
+}
+
+// Aggregates belong in the select list, HAVING and ORDER BY only, not in WHERE or GROUP BY
+fn reject_aggregates(expr: &Expr) -> anyhow::Result<()> {
+    let mut aggregates = Vec::new();
+    collect_aggregates(expr, &mut aggregates)?;
+    match aggregates.first() {
+        Some((aggregate, _)) => anyhow::bail!("misuse of aggregate function {}()", aggregate.name()),
+        None => Ok(()),
+    }
+}
+
+fn is_subquery(expr: &Expr) -> bool {
+    matches!(expr, Expr::Exists(_) | Expr::InSelect(..))
+}
+
+// Replace the subqueries in `expr` with column names of their own, like "\0subquery 0",
+// and add them to `taken` under those names
+fn take_subqueries(expr: &mut Expr, taken: &mut Vec<(String, Expr)>) {
+    if is_subquery(expr) {
+        let name = format!("\0subquery {}", taken.len());
+        let subquery = std::mem::replace(expr, Expr::Identifier(name.clone()));
+        taken.push((name, subquery));
+        return;
+    }
+    for child in children_mut(expr) {
+        take_subqueries(child, taken);
+    }
+}
+
+// The operands of an expression, but not the expressions of a subquery in it
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::BinaryOp(left, _, right) => vec![left, right],
+        Expr::UnaryOp(_, operand)
+        | Expr::Aliased(operand, _)
+        | Expr::InSelect(operand, _)
+        | Expr::InRows(operand, _)
+        | Expr::Cast(operand, _)
+        | Expr::Collate(operand, _)
+        | Expr::Affinity(operand, _) => vec![operand],
+        Expr::FunctionCall(_, args) => args.iter().collect(),
+        Expr::InList(left, list) => std::iter::once(left.as_ref()).chain(list).collect(),
+        Expr::Between(value, low, high) => vec![value, low, high],
+        Expr::Case(operand, branches, else_result) => operand
+            .iter()
+            .map(|operand| operand.as_ref())
+            .chain(branches.iter().flat_map(|(when, then)| [when, then]))
+            .chain(else_result.iter().map(|else_result| else_result.as_ref()))
+            .collect(),
+        Expr::Identifier(_)
+        | Expr::QualifiedIdentifier(..)
+        | Expr::Literal(_)
+        | Expr::Wildcard
+        | Expr::TableWildcard(_)
+        | Expr::Parameter(..)
+        | Expr::Exists(_) => Vec::new(),
+    }
+}
+
+fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::BinaryOp(left, _, right) => vec![left, right],
+        Expr::UnaryOp(_, operand)
+        | Expr::Aliased(operand, _)
+        | Expr::InSelect(operand, _)
+        | Expr::InRows(operand, _)
+        | Expr::Cast(operand, _)
+        | Expr::Collate(operand, _)
+        | Expr::Affinity(operand, _) => vec![operand],
+        Expr::FunctionCall(_, args) => args.iter_mut().collect(),
+        Expr::InList(left, list) => std::iter::once(left.as_mut()).chain(list).collect(),
+        Expr::Between(value, low, high) => vec![value, low, high],
+        Expr::Case(operand, branches, else_result) => operand
+            .iter_mut()
+            .map(|operand| operand.as_mut())
+            .chain(branches.iter_mut().flat_map(|(when, then)| [when, then]))
+            .chain(else_result.iter_mut().map(|else_result| else_result.as_mut()))
+            .collect(),
+        Expr::Identifier(_)
+        | Expr::QualifiedIdentifier(..)
+        | Expr::Literal(_)
+        | Expr::Wildcard
+        | Expr::TableWildcard(_)
+        | Expr::Parameter(..)
+        | Expr::Exists(_) => Vec::new(),
+    }
+}
+
+// Every expression of a SELECT statement
+fn select_exprs_mut(select: &mut SelectStmt, f: &mut dyn FnMut(&mut Expr)) {
+    select.columns.iter_mut().for_each(|column| f(&mut column.expr));
+    select.joins.iter_mut().filter_map(|join| join.on.as_mut()).for_each(&mut *f);
+    select.where_clause.iter_mut().for_each(&mut *f);
+    select.group_by.iter_mut().for_each(&mut *f);
+    select.having.iter_mut().for_each(&mut *f);
+    select.order_by.iter_mut().for_each(|term| f(&mut term.expr));
+    select.limit.iter_mut().chain(&mut select.offset).for_each(f);
+}
+
+// A name in WHERE, GROUP BY or HAVING that is no column of the tables but the alias of
+// a result column stands for the aliased expression, like in SQLite. ORDER BY sorts by
+// the result column itself, see `order_by_slots`
+fn resolve_aliases(select: &mut SelectStmt, sources: &[Source]) {
+    let aliases = select
+        .columns
+        .iter()
+        .filter_map(|column| match &column.expr {
+            Expr::Aliased(expr, alias) => Some((alias.to_lowercase(), expr.as_ref().clone())),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    if aliases.is_empty() {
+        return;
+    }
+    let is_column = |name: &str| {
+        ROWID_ALIASES.contains(&name)
+            || sources.iter().any(|source| source.schema.columns.iter().any(|column| column.name == name))
+    };
+    fn substitute(expr: &mut Expr, aliases: &HashMap<String, Expr>, is_column: &dyn Fn(&str) -> bool) {
+        if let Expr::Identifier(name) = expr {
+            let name = name.to_lowercase();
+            if let Some(aliased) = aliases.get(&name).filter(|_| !is_column(&name)) {
+                *expr = aliased.clone();
+            }
+            return;
+        }
+        for child in children_mut(expr) {
+            substitute(child, aliases, is_column);
+        }
+    }
+    let clauses = select.where_clause.iter_mut().chain(&mut select.group_by).chain(&mut select.having);
+    clauses.for_each(|expr| substitute(expr, &aliases, &is_column));
+}
+
+// Put the values bound to the parameters of `stmt` in their place, as literals
+fn bind_parameters(stmt: &mut Stmt, params: &Params) {
+    let bind = |expr: &mut Expr| bind_expr(expr, params);
+    match stmt {
+        Stmt::Select(select) => bind_select(select, params),
+        Stmt::Values(rows) => rows.iter_mut().flatten().for_each(bind),
+        Stmt::Insert(insert) => insert.values.iter_mut().flatten().for_each(bind),
+        Stmt::Update(update) => {
+            let assignments = update.assignments.iter_mut().map(|(_, expr)| expr);
+            assignments.chain(&mut update.where_clause).for_each(bind);
+        }
+        Stmt::Delete(delete) => delete.where_clause.iter_mut().for_each(bind),
+        Stmt::Explain(stmt) => bind_parameters(stmt, params),
+        _ => (),
+    }
+}
+
+fn bind_select(select: &mut SelectStmt, params: &Params) {
+    select_exprs_mut(select, &mut |expr| bind_expr(expr, params));
+    let tables = select.from.iter_mut().chain(select.joins.iter_mut().map(|join| &mut join.table));
+    tables
+        .filter_map(|table| table.values.as_mut())
+        .flatten()
+        .flatten()
+        .for_each(|expr| bind_expr(expr, params));
+    let selects = select.with.iter_mut().map(|cte| &mut cte.select);
+    selects
+        .chain(select.compound.iter_mut().map(|compound| &mut compound.select))
+        .for_each(|select| bind_select(select, params));
+}
+
+fn bind_expr(expr: &mut Expr, params: &Params) {
+    match expr {
+        Expr::Parameter(index, name) => {
+            let value = params.get(*index, name.as_deref());
+            *expr = Expr::Literal(value_literal(value));
+            return;
+        }
+        Expr::InSelect(_, select) | Expr::Exists(select) => bind_select(select, params),
+        _ => (),
+    }
+    children_mut(expr).into_iter().for_each(|child| bind_expr(child, params));
+}
+
+// The names the tables and result columns of a query give meaning to, any other column
+// reference in it is to an enclosing query
+struct Scope {
+    qualifiers: HashSet<String>,
+    columns: HashSet<String>,
+}
+
+impl Scope {
+    fn new(select: &SelectStmt, sources: &[Source]) -> Self {
+        let qualifiers = sources
+            .iter()
+            .map(|source| source.table.qualifier().to_lowercase())
+            .collect();
+        let table_columns = sources.iter().flat_map(|source| &source.schema.columns);
+        let aliases = select.columns.iter().filter_map(|column| match &column.expr {
+            Expr::Aliased(_, alias) => Some(alias.to_lowercase()),
+            _ => None,
+        });
+        let columns = table_columns
+            .map(|column| column.name.clone())
+            .chain(ROWID_ALIASES.iter().map(|alias| alias.to_string()))
+            .chain(aliases)
+            .collect();
+        Self {
+            qualifiers,
+            columns,
+        }
+    }
+    fn resolves(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Identifier(name) => self.columns.contains(&name.to_lowercase()),
+            Expr::QualifiedIdentifier(table, _) => self.qualifiers.contains(&table.to_lowercase()),
+            _ => false,
+        }
+    }
+}
+
+// Result column names the way sqlite3 reports them: the alias, the declared name of a
+// column reference, or else the expression's source text
+fn column_names(select: &SelectStmt, sources: &[Source], rowid_in_wildcard: bool) -> Vec<String> {
+    let mut names = Vec::new();
+    for column in &select.columns {
+        let declared = match &column.expr {
+            Expr::Aliased(_, alias) => Some(alias.clone()),
+            Expr::Wildcard => {
+                if rowid_in_wildcard && sources.len() == 1 {
+                    names.push(sources[0].schema.rowid_name().to_string());
+                }
+                for source in sources {
+                    names.extend(
+                        source
+                            .wildcard_columns()
+                            .map(|i| source.schema.columns[i].name.clone()),
+                    );
+                }
+                continue;
+            }
+            // Join columns included
+            Expr::TableWildcard(table) => {
+                if let Result::Ok((_, source)) = wildcard_source(sources, table) {
+                    names.extend(source.schema.columns.iter().map(|column| column.name.clone()));
+                }
+                continue;
+            }
+            Expr::Identifier(name) => sources
+                .iter()
+                .find_map(|source| source.schema.declared_name(name)),
+            Expr::QualifiedIdentifier(table, name) => sources
+                .iter()
+                .find(|source| table.eq_ignore_ascii_case(source.table.qualifier()))
+                .and_then(|source| source.schema.declared_name(name)),
+            _ => None,
+        };
+        names.push(declared.unwrap_or_else(|| column.text.clone()));
+    }
+    names
 }
 
+// The rowid is the last column of an index entry
+fn index_row_id(record: &Record) -> anyhow::Result<i64> {
+    match record.value_ref(record.len() - 1) {
+        Some(ValueRef::I64(row_id)) => Ok(row_id),
+        _ => anyhow::bail!("Invalid row id"),
+    }
+}
 
 fn parse_create_table_sql(sql: &str) -> anyhow::Result<Vec<Column>> {
     let mut columns = vec![];
-    let sql = sql.to_lowercase();
     if let Some(start) = sql.find("(") {
         if let Some(end) = sql.rfind(")") {
-            let column_defs = &sql[start + 1..end];
-            for column_def in column_defs.split(",") {
-                let column = column_def.trim();
+            for column_def in split_definitions(&sql[start + 1..end]) {
+                let default = column_default(column_def);
+                let column = column_def.trim().to_lowercase();
                 if column.starts_with('"') {
                     let parts = column.split('"').collect::<Vec<&str>>();
+                    let type_name = type_name(parts[2]);
                     columns.push(Column {
                         name: parts[1].to_string(),
-                        type_name: parts[2].trim().to_string(),
+                        affinity: Affinity::from_type_name(&type_name),
+                        type_name,
+                        collation: column_collation(&column)?,
+                        primary_key: column.contains("primary key"),
+                        not_null: column.contains("not null"),
+                        default,
                     });
                     continue;
                 }
                 let parts = column.split_whitespace().collect::<Vec<&str>>();
-                if parts.len() >= 2 {
+                // The type is optional, table constraints aren't columns
+                let is_constraint = parts.first().is_some_and(|word| {
+                    let word = word.split('(').next().unwrap_or_default();
+                    ["constraint", "primary", "unique", "check", "foreign"].contains(&word)
+                });
+                if !parts.is_empty() && !is_constraint {
+                    let type_name = type_name(&parts[1..].join(" "));
                     columns.push(Column {
                         name: parts[0].to_string(),
-                        type_name: parts[1].to_string(),
+                        affinity: Affinity::from_type_name(&type_name),
+                        type_name,
+                        collation: column_collation(&column)?,
+                        primary_key: column.contains("primary key"),
+                        not_null: column.contains("not null"),
+                        default,
                     });
                 }
             }
@@ -539,6 +3869,142 @@ fn parse_create_table_sql(sql: &str) -> anyhow::Result<Vec<Column>> {
     anyhow::Ok(columns)
 }
 
+// Column and constraint definitions, split at the commas that aren't inside parentheses
+// or quotes
+fn split_definitions(text: &str) -> Vec<&str> {
+    let mut definitions = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                definitions.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    definitions.push(&text[start..]);
+    definitions
+}
+
+// The DEFAULT of a column definition when it is a literal, NULL otherwise
+// The collation named by a column's COLLATE constraint, BINARY without one
+fn column_collation(column_def: &str) -> anyhow::Result<Collation> {
+    let mut words = column_def.split_whitespace();
+    match words.by_ref().position(|word| word.eq_ignore_ascii_case("collate")) {
+        Some(_) => {
+            let name = words.next().unwrap_or_default();
+            Collation::from_name(name.trim_matches(|c: char| !c.is_alphanumeric() && c != '_'))
+        }
+        None => Ok(Collation::Binary),
+    }
+}
+
+// The message a write that would repeat the values of a UNIQUE index fails with
+fn unique_failed(table: &Schema, index: &Schema) -> String {
+    let columns = index
+        .columns
+        .iter()
+        .map(|column| format!("{}.{}", table.table_name, column.name))
+        .collect::<Vec<_>>();
+    format!("UNIQUE constraint failed: {}", columns.join(", "))
+}
+
+// The order of the entries of `index`: each column under the index column's own COLLATE,
+// or else the table column's, and from largest to smallest when it is DESC
+fn index_order(table: &Schema, index: &Schema) -> anyhow::Result<KeyOrder> {
+    if index.is_autoindex() {
+        return Ok(KeyOrder {
+            collations: index.columns.iter().map(|column| table.collation(&column.name)).collect(),
+            descending: autoindex_constraint(table, &index.schema_name).iter().map(|(_, descending)| *descending).collect(),
+        });
+    }
+    let sql = index.sql.to_lowercase();
+    let (Some(start), Some(end)) = (sql.find('('), sql.rfind(')')) else {
+        return Ok(KeyOrder::default());
+    };
+    let mut order = KeyOrder::default();
+    for column_def in sql[start + 1..end].split(',') {
+        let collation = match column_def.contains("collate") {
+            true => column_collation(column_def)?,
+            false => table.collation(column_def.split_whitespace().next().unwrap_or_default()),
+        };
+        order.collations.push(collation);
+        order.descending.push(column_def.split_whitespace().last() == Some("desc"));
+    }
+    Ok(order)
+}
+
+// The declared type at the start of what follows a column's name, the words up to its
+// first constraint: "unsigned big int not null" is "unsigned big int"
+fn type_name(rest: &str) -> String {
+    const CONSTRAINTS: [&str; 10] =
+        ["constraint", "primary", "not", "null", "unique", "check", "default", "collate", "references", "generated"];
+    rest.split_whitespace()
+        .take_while(|word| !CONSTRAINTS.contains(word) && !word.starts_with("as("))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn column_default(column_def: &str) -> Value {
+    let lower = column_def.to_ascii_lowercase();
+    let words = lower.match_indices("default").filter(|(i, _)| {
+        let before = lower[..*i].chars().next_back();
+        let after = lower[i + 7..].chars().next();
+        before.is_some_and(char::is_whitespace) && !after.is_some_and(|c| c.is_alphanumeric() || c == '_')
+    });
+    let Some((i, _)) = words.last() else {
+        return Value::Null;
+    };
+    let text = column_def[i + 7..].trim_start();
+    if let Some(quoted) = text.strip_prefix('\'') {
+        // '' stands for a quote inside the string
+        let mut value = String::new();
+        let mut chars = quoted.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if chars.peek() != Some(&'\'') {
+                    break;
+                }
+                chars.next();
+            }
+            value.push(c);
+        }
+        return Value::String(value);
+    }
+    // x'0A1B', two hex digits to a byte
+    let blob = text.strip_prefix(['x', 'X']).and_then(|text| text.strip_prefix('\''));
+    if let Some((digits, _)) = blob.and_then(|blob| blob.split_once('\'')) {
+        let bytes = (0..digits.len() / 2).map(|i| u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16));
+        return bytes.collect::<Result<_, _>>().map_or(Value::Null, Value::Blob);
+    }
+    let literal = text.split_whitespace().next().unwrap_or_default();
+    let literal = literal.trim_start_matches('(').trim_end_matches(')');
+    if let Result::Ok(n) = literal.parse::<i64>() {
+        return Value::I64(n);
+    }
+    if let Some(hex) = literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")) {
+        if let Result::Ok(n) = u64::from_str_radix(hex, 16) {
+            return Value::I64(n as i64);
+        }
+    }
+    if let Result::Ok(n) = literal.parse::<f64>() {
+        return Value::Float(n);
+    }
+    match literal.to_ascii_lowercase().as_str() {
+        "true" => Value::I64(1),
+        "false" => Value::I64(0),
+        _ => Value::Null,
+    }
+}
+
 // "CREATE INDEX idx_companies_country\n\ton companies (country)"
 fn parse_create_index_sql(sql: &str) -> anyhow::Result<Vec<Column>> {
     let mut columns = vec![];
@@ -548,44 +4014,645 @@ fn parse_create_index_sql(sql: &str) -> anyhow::Result<Vec<Column>> {
             let column_defs = &sql[start + 1..end];
             for column_def in column_defs.split(",") {
                 let parts = column_def.split_whitespace().collect::<Vec<&str>>();
-                columns.push(Column {
-                    name: parts[0].to_string(),
-                    type_name: "".to_string(),
-                });
+                columns.push(index_column(parts[0].to_string()));
             }
         }
     }
     anyhow::Ok(columns)
 }
+
+// A column of an index, which only has a name of its own
+fn index_column(name: String) -> Column {
+    Column {
+        name,
+        type_name: "".to_string(),
+        affinity: Affinity::Blob,
+        collation: Collation::Binary,
+        primary_key: false,
+        not_null: false,
+        default: Value::Null,
+    }
+}
+
+// The columns of the constraint an automatic index is for, sqlite_autoindex_<table>_<N>
+// being for the Nth one that needs one
+fn autoindex_constraint(table: &Schema, index: &str) -> Vec<(String, bool)> {
+    let n = index.rsplit('_').next().and_then(|n| n.parse::<usize>().ok());
+    n.and_then(|n| autoindex_columns(table).into_iter().nth(n.wrapping_sub(1))).unwrap_or_default()
+}
+
+// The columns of each UNIQUE and PRIMARY KEY constraint of `table` that SQLite makes an
+// index for, each with whether it is DESC, in the order it numbers them: the constraints
+// of each column, then those of the table. An INTEGER PRIMARY KEY is the rowid, a
+// constraint on the same columns as an earlier one shares its index, and a WITHOUT ROWID
+// table is its PRIMARY KEY
+fn autoindex_columns(table: &Schema) -> Vec<Vec<(String, bool)>> {
+    let sql = table.sql.to_lowercase();
+    let (Some(start), Some(end)) = (sql.find('('), sql.rfind(')')) else {
+        return Vec::new();
+    };
+    let without_rowid = table.is_without_rowid();
+    let mut constraints: Vec<Vec<(String, bool)>> = Vec::new();
+    let mut column = 0;
+    for definition in split_definitions(&sql[start + 1..end]) {
+        let words = definition.split_whitespace().collect::<Vec<_>>();
+        let first = words.first().map_or("", |word| word.split('(').next().unwrap_or_default());
+        if !["constraint", "primary", "unique", "check", "foreign"].contains(&first) {
+            let Some(def) = table.columns.get(column) else {
+                break;
+            };
+            column += 1;
+            let primary_key = def.primary_key && !def.is_rowid_alias() && !without_rowid;
+            if primary_key || words.contains(&"unique") {
+                let descending = primary_key && words.windows(2).any(|pair| pair == ["key", "desc"]);
+                constraints.push(vec![(def.name.clone(), descending)]);
+            }
+        } else if words.iter().any(|word| word.starts_with("primary") || word.starts_with("unique")) {
+            let primary_key = !words.iter().any(|word| word.starts_with("unique"));
+            let (Some(open), Some(close)) = (definition.find('('), definition.rfind(')')) else {
+                continue;
+            };
+            let names = definition[open + 1..close]
+                .split(',')
+                .filter_map(|column_def| {
+                    let name = column_def.split_whitespace().next()?;
+                    let name = name.trim_matches(|c| c == '"' || c == '`' || c == '[' || c == ']').to_string();
+                    Some((name, column_def.split_whitespace().last() == Some("desc")))
+                })
+                .collect::<Vec<_>>();
+            let rowid = names.len() == 1 && table.columns.iter().any(|c| c.name == names[0].0 && c.type_name == "integer");
+            if primary_key && (rowid || without_rowid) {
+                continue;
+            }
+            constraints.push(names);
+        }
+    }
+    let mut distinct: Vec<Vec<(String, bool)>> = Vec::new();
+    for columns in constraints {
+        let names = |columns: &[(String, bool)]| columns.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+        if !distinct.iter().any(|earlier| names(earlier) == names(&columns)) {
+            distinct.push(columns);
+        }
+    }
+    distinct
+}
+// The result column each ORDER BY term sorts by, as an index into the row: `ORDER BY 2`
+// the second one, and `ORDER BY x` the one aliased `x`, before any table column x.
+// Other terms are expressions evaluated on each row
+fn order_by_slots(
+    select: &SelectStmt,
+    column_starts: &[usize],
+    row_width: usize,
+) -> anyhow::Result<Vec<Option<usize>>> {
+    let mut slots = Vec::new();
+    for (i, term) in select.order_by.iter().enumerate() {
+        // `ORDER BY 1 COLLATE NOCASE` still names the column
+        let slot = match strip_collate(&term.expr) {
+            Expr::Literal(Literal::Integer(n)) => {
+                if *n < 1 || *n > row_width as i64 {
+                    anyhow::bail!(
+                        "{} ORDER BY term out of range - should be between 1 and {}",
+                        nth(i + 1),
+                        row_width
+                    );
+                }
+                Some(*n as usize - 1)
+            }
+            Expr::Identifier(name) => select
+                .columns
+                .iter()
+                .zip(column_starts)
+                .find_map(|(column, start)| match &column.expr {
+                    Expr::Aliased(_, alias) if alias.eq_ignore_ascii_case(name) => Some(*start),
+                    _ => None,
+                }),
+            _ => None,
+        };
+        slots.push(slot);
+    }
+    Ok(slots)
+}
+
+// The result column each ORDER BY term of a compound SELECT sorts by, which it has to
+// name by position or by its name in one of the SELECTs, the leftmost first.
+// `names` has the result column names of each SELECT
+fn compound_order_slots(select: &SelectStmt, names: &[Vec<String>]) -> anyhow::Result<Vec<Option<usize>>> {
+    let width = names[0].len();
+    let mut slots = Vec::new();
+    for (i, term) in select.order_by.iter().enumerate() {
+        let slot = match &term.expr {
+            Expr::Literal(Literal::Integer(n)) => {
+                if *n < 1 || *n > width as i64 {
+                    anyhow::bail!(
+                        "{} ORDER BY term out of range - should be between 1 and {}",
+                        nth(i + 1),
+                        width
+                    );
+                }
+                Some(*n as usize - 1)
+            }
+            Expr::Identifier(name) | Expr::QualifiedIdentifier(_, name) => names.iter().find_map(|names| {
+                names.iter().position(|column| column.eq_ignore_ascii_case(name))
+            }),
+            _ => None,
+        };
+        let Some(slot) = slot else {
+            anyhow::bail!("{} ORDER BY term does not match any column in the result set", nth(i + 1));
+        };
+        slots.push(Some(slot));
+    }
+    Ok(slots)
+}
+
+// The result column each GROUP BY term groups by, like `order_by_slots`: `GROUP BY 2` the
+// second one. Other terms are expressions evaluated on each row
+fn group_by_slots(select: &SelectStmt, column_starts: &[usize], row_width: usize) -> anyhow::Result<Vec<Option<usize>>> {
+    let mut slots = Vec::new();
+    for (i, expr) in select.group_by.iter().enumerate() {
+        let Expr::Literal(Literal::Integer(n)) = expr else {
+            slots.push(None);
+            continue;
+        };
+        if *n < 1 || *n > row_width as i64 {
+            anyhow::bail!(
+                "{} GROUP BY term out of range - should be between 1 and {}",
+                nth(i + 1),
+                row_width
+            );
+        }
+        let slot = *n as usize - 1;
+        // Its value is only known once the group is complete
+        let column = column_starts.iter().position(|start| *start == slot).map(|i| &select.columns[i].expr);
+        if column.is_some_and(|expr| contains(expr, &is_aggregate_call)) {
+            anyhow::bail!("aggregate functions are not allowed in the GROUP BY clause");
+        }
+        slots.push(Some(slot));
+    }
+    Ok(slots)
+}
+
+// 1st, 2nd, 3rd, 4th, ..., 11th, 12th, 13th, ..., 21st
+fn nth(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
 pub struct Pager<I: std::fmt::Debug + Read + Seek = std::fs::File> {
     input: I,
     page_size: usize,
-    pages: HashMap<usize, Page>,
+    usable_size: usize,
+    // Pages in the file, including the ones allocated by this connection
+    page_count: usize,
+    pages: PageCache,
+    // Pages written by the open transaction, they only reach the file on commit
+    dirty: Option<HashMap<usize, Vec<u8>>>,
+    // Page count when the transaction began
+    committed_page_count: usize,
+    // Points inside the transaction it can be rolled back to, innermost last
+    savepoints: Vec<Savepoint>,
+    journal_path: Option<PathBuf>,
+    // Committed pages newer than the ones in the file
+    wal: Option<Wal>,
+    // Pointer-map pages are interleaved with the b-tree pages
+    auto_vacuum: bool,
+    // Opened without write access, every write fails
+    read_only: bool,
+    // The locks shared with other processes writing the file
+    lock: Option<FileLock>,
+    io_stats: IoStats,
+}
+
+// Pages the pager read from and wrote to the file or the log since it was opened
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoStats {
+    pub pages_read: u64,
+    pub pages_written: u64,
+}
+
+// The page count when the savepoint was set, and what each page written since held
+// before, None for a page the transaction hadn't written yet
+#[derive(Default)]
+struct Savepoint {
+    page_count: usize,
+    pages: HashMap<usize, Option<Vec<u8>>>,
 }
 
 impl<I: Read + Seek + std::fmt::Debug> Pager<I> {
-    pub fn new(input: I, page_size: usize) -> Self {
+    pub fn new(mut input: I, page_size: usize, usable_size: usize) -> Self {
+        let file_size = input.seek(SeekFrom::End(0)).unwrap_or(0) as usize;
         Self {
             input,
             page_size,
+            usable_size,
+            page_count: file_size / page_size,
+            pages: PageCache::new(page_cache::DEFAULT_CAPACITY),
+            dirty: None,
+            committed_page_count: 0,
+            savepoints: Vec::new(),
+            journal_path: None,
+            wal: None,
+            auto_vacuum: false,
+            read_only: false,
+            lock: None,
+            io_stats: IoStats::default(),
+        }
+    }
+    pub fn set_auto_vacuum(&mut self, auto_vacuum: bool) {
+        self.auto_vacuum = auto_vacuum;
+    }
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+    // Read pages through the log, the database then has the size of its last commit
+    pub fn set_wal(&mut self, wal: Wal) -> anyhow::Result<()> {
+        self.wal = Some(wal);
+        self.wal_changed()
+    }
+    // Whether the log has commits the file doesn't, the pages are read from there then
+    fn in_wal(&self) -> bool {
+        self.wal.as_ref().is_some_and(|wal| wal.page_count().is_some())
+    }
+    fn wal_changed(&mut self) -> anyhow::Result<()> {
+        self.pages.clear();
+        self.page_count = match self.wal.as_ref().and_then(Wal::page_count) {
+            Some(page_count) => page_count,
+            None => self.input.seek(SeekFrom::End(0)).context("read db file size")? as usize / self.page_size,
+        };
+        self.committed_page_count = self.page_count;
+        Ok(())
+    }
+    // Where commits write the rollback journal, without one nothing is journaled
+    pub fn set_journal_path(&mut self, path: PathBuf) {
+        self.journal_path = Some(path);
+    }
+    // Without a lock other processes aren't kept out while this one writes
+    pub fn set_lock(&mut self, lock: FileLock) {
+        self.lock = Some(lock);
+    }
+    pub fn in_transaction(&self) -> bool {
+        self.dirty.is_some()
+    }
+    // Keep writes in memory until `commit`
+    pub fn begin(&mut self) {
+        self.dirty = Some(HashMap::new());
+        self.committed_page_count = self.page_count;
+    }
+    // Drop every write of the transaction, the file never saw them
+    pub fn rollback(&mut self) {
+        if let Some(lock) = self.lock.as_mut() {
+            let _ = lock.unlock();
+        }
+        if self.dirty.take().is_none() {
+            return;
+        }
+        self.savepoints.clear();
+        self.page_count = self.committed_page_count;
+        self.pages.clear();
+    }
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(Savepoint {
+            page_count: self.page_count,
             pages: HashMap::new(),
+        });
+    }
+    // Keep the writes since the innermost savepoint, the one around it can still undo them
+    pub fn release_savepoint(&mut self) {
+        let Some(savepoint) = self.savepoints.pop() else {
+            return;
+        };
+        if let Some(outer) = self.savepoints.last_mut() {
+            for (page_num, page) in savepoint.pages {
+                outer.pages.entry(page_num).or_insert(page);
+            }
+        }
+    }
+    // Undo the writes since the innermost savepoint, which stays in place
+    pub fn rollback_to_savepoint(&mut self) {
+        let (Some(savepoint), Some(dirty)) = (self.savepoints.last_mut(), self.dirty.as_mut()) else {
+            return;
+        };
+        for (page_num, page) in savepoint.pages.drain() {
+            match page {
+                Some(page) => dirty.insert(page_num, page),
+                None => dirty.remove(&page_num),
+            };
         }
+        self.page_count = savepoint.page_count;
+        self.pages.clear();
+    }
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+    pub fn usable_size(&self) -> usize {
+        self.usable_size
     }
-    pub fn read_page(&mut self, page_num: usize) -> anyhow::Result<&Page> {
-        if self.pages.contains_key(&page_num) {
-            return Ok(self.pages.get(&page_num).unwrap());
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+    // Pages are shared with the cache, holding one is as cheap as holding a reference
+    pub fn read_page(&mut self, page_num: usize) -> anyhow::Result<Arc<Page>> {
+        if let Some(page) = self.pages.get(page_num) {
+            return Ok(page);
         }
-        let page = self.load_page(page_num)?;
+        let page = Arc::new(self.load_page(page_num)?);
         self.pages.insert(page_num, page.clone());
-        Ok(self.pages.get(&page_num).unwrap())
+        Ok(page)
     }
-    fn load_page(&mut self, page_num: usize) -> anyhow::Result<Page> {
+    // Most parsed pages kept in memory at once
+    pub fn set_cache_size(&mut self, pages: usize) {
+        self.pages.set_capacity(pages);
+    }
+    // Size the cache to a memory budget instead
+    pub fn set_cache_memory(&mut self, bytes: usize) {
+        self.pages.set_capacity(bytes / self.page_size);
+    }
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pages.stats()
+    }
+    pub fn io_stats(&self) -> IoStats {
+        self.io_stats
+    }
+    // Forget the parsed pages, and the size of the file, after it was changed behind our
+    // back. Pages this connection wrote itself are kept
+    pub fn clear_cache(&mut self) -> anyhow::Result<()> {
+        self.pages.clear();
+        if !self.in_wal() && self.dirty.as_ref().map_or(true, HashMap::is_empty) {
+            let file_size = self.input.seek(SeekFrom::End(0)).context("read db file size")? as usize;
+            self.page_count = file_size / self.page_size;
+            self.committed_page_count = self.page_count;
+        }
+        Ok(())
+    }
+    pub fn read_raw_page(&mut self, page_num: usize) -> anyhow::Result<Vec<u8>> {
+        if page_num == 0 || page_num > self.page_count {
+            anyhow::bail!(Error::CorruptPage(format!(
+                "page {} is out of range, the database has {} pages",
+                page_num, self.page_count
+            )));
+        }
+        if let Some(page) = self.dirty.as_ref().and_then(|dirty| dirty.get(&page_num)) {
+            return Ok(page.clone());
+        }
+        self.io_stats.pages_read += 1;
+        if let Some(wal) = self.wal.as_mut() {
+            if let Some(page) = wal.read_page(page_num)? {
+                return Ok(page);
+            }
+        }
         let offset = page_num.saturating_sub(1) * self.page_size;
         self.input
             .seek(SeekFrom::Start(offset as u64))
             .context("seek to page start")?;
         let mut buffer = vec![0; self.page_size];
         self.input.read_exact(&mut buffer).context("read page")?;
-        Ok(Page::parse(&buffer, page_num)?)
+        Ok(buffer)
+    }
+    fn load_page(&mut self, page_num: usize) -> anyhow::Result<Page> {
+        if self.auto_vacuum && is_pointer_map_page(page_num, self.usable_size) {
+            anyhow::bail!(Error::CorruptPage(format!(
+                "page {} is a pointer-map page, not a b-tree page",
+                page_num
+            )));
+        }
+        // The cells of the page borrow from this one copy of it
+        let buffer = Arc::from(self.read_raw_page(page_num)?);
+        let usable_size = self.usable_size;
+        Page::parse(&buffer, page_num, usable_size, &mut |overflow_page| {
+            self.read_raw_page(overflow_page)
+        })
+    }
+}
+
+impl<I: Read + Write + Seek + std::fmt::Debug> Pager<I> {
+    // Pages are written straight through to the file, unless a transaction is open
+    pub fn write_page(&mut self, page_num: usize, buffer: &[u8]) -> anyhow::Result<()> {
+        if self.read_only {
+            anyhow::bail!("attempt to write a readonly database");
+        }
+        // The file is behind the log until a checkpoint, writing it would lose committed changes
+        if self.in_wal() {
+            anyhow::bail!("writing to a database with a write-ahead log is not supported");
+        }
+        // Moving and allocating pages would have to keep the pointer map up to date
+        if self.auto_vacuum {
+            anyhow::bail!("writing to an auto_vacuum database is not supported");
+        }
+        if let Some(dirty) = self.dirty.as_mut() {
+            if let Some(savepoint) = self.savepoints.last_mut() {
+                let before = dirty.get(&page_num).cloned();
+                savepoint.pages.entry(page_num).or_insert(before);
+            }
+            dirty.insert(page_num, buffer.to_vec());
+            self.pages.remove(page_num);
+            self.page_count = self.page_count.max(page_num);
+            return Ok(());
+        }
+        self.io_stats.pages_written += 1;
+        let offset = page_num.saturating_sub(1) * self.page_size;
+        self.input
+            .seek(SeekFrom::Start(offset as u64))
+            .context("seek to page start")?;
+        self.input.write_all(buffer).context("write page")?;
+        self.pages.remove(page_num);
+        self.page_count = self.page_count.max(page_num);
+        Ok(())
+    }
+    // A zeroed page, off the freelist when it has one or else at the end of the file
+    pub fn allocate_page(&mut self) -> anyhow::Result<usize> {
+        let trunk = self.header_u32(HEADER_FREELIST_TRUNK_OFFSET)? as usize;
+        let page_num = if trunk == 0 {
+            self.page_count + 1
+        } else {
+            // Leaves come off the end of the first trunk, an empty trunk is used itself
+            let mut buffer = self.read_raw_page(trunk)?;
+            let leaf_count = u32::from_be_bytes(buffer[4..8].try_into().unwrap()) as usize;
+            // A trunk holds at most this many after its next trunk and count
+            if leaf_count > self.usable_size / 4 - 2 {
+                anyhow::bail!(Error::CorruptPage(format!(
+                    "freelist trunk page {} has {} leaves, more than fit on it",
+                    trunk, leaf_count
+                )));
+            }
+            let page_num = if leaf_count == 0 {
+                let next_trunk = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
+                self.set_header_u32(HEADER_FREELIST_TRUNK_OFFSET, next_trunk)?;
+                trunk
+            } else {
+                let at = 8 + (leaf_count - 1) * 4;
+                let leaf = u32::from_be_bytes(buffer[at..at + 4].try_into().unwrap());
+                // Page 1 is the schema's, past the end is no page at all
+                if leaf < 2 || leaf as usize > self.page_count {
+                    anyhow::bail!(Error::CorruptPage(format!(
+                        "freelist leaf page {} of trunk {} is out of range, the database has {} pages",
+                        leaf, trunk, self.page_count
+                    )));
+                }
+                buffer[4..8].copy_from_slice(&(leaf_count as u32 - 1).to_be_bytes());
+                self.write_page(trunk, &buffer)?;
+                leaf as usize
+            };
+            let count = self.header_u32(HEADER_FREELIST_COUNT_OFFSET)?;
+            self.set_header_u32(HEADER_FREELIST_COUNT_OFFSET, count.saturating_sub(1))?;
+            page_num
+        };
+        self.write_page(page_num, &vec![0; self.page_size])?;
+        Ok(page_num)
+    }
+    // Put a page that is no longer used on the freelist, as a leaf of the first trunk or
+    // as a new trunk when that one is full
+    // https://www.sqlite.org/fileformat.html#the_freelist
+    pub fn free_page(&mut self, page_num: usize) -> anyhow::Result<()> {
+        let trunk = self.header_u32(HEADER_FREELIST_TRUNK_OFFSET)? as usize;
+        // SQLite itself never fills a trunk past this, older versions read no further
+        let max_leaves = self.usable_size / 4 - 8;
+        let mut buffer = match trunk {
+            0 => None,
+            trunk => Some(self.read_raw_page(trunk)?),
+        };
+        match buffer.as_mut() {
+            Some(buffer) if (u32::from_be_bytes(buffer[4..8].try_into().unwrap()) as usize) < max_leaves => {
+                let leaf_count = u32::from_be_bytes(buffer[4..8].try_into().unwrap()) as usize;
+                let at = 8 + leaf_count * 4;
+                buffer[at..at + 4].copy_from_slice(&(page_num as u32).to_be_bytes());
+                buffer[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                self.write_page(trunk, buffer)?;
+            }
+            _ => {
+                let mut buffer = vec![0; self.page_size];
+                buffer[0..4].copy_from_slice(&(trunk as u32).to_be_bytes());
+                self.write_page(page_num, &buffer)?;
+                self.set_header_u32(HEADER_FREELIST_TRUNK_OFFSET, page_num as u32)?;
+            }
+        }
+        let count = self.header_u32(HEADER_FREELIST_COUNT_OFFSET)?;
+        self.set_header_u32(HEADER_FREELIST_COUNT_OFFSET, count + 1)
+    }
+    fn header_u32(&mut self, offset: usize) -> anyhow::Result<u32> {
+        let buffer = self.read_raw_page(1)?;
+        Ok(u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap()))
+    }
+    fn set_header_u32(&mut self, offset: usize, value: u32) -> anyhow::Result<()> {
+        let mut buffer = self.read_raw_page(1)?;
+        buffer[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        self.write_page(1, &buffer)
+    }
+}
+
+impl Pager<File> {
+    // Take SHARED before a statement reads the file, so no other process overwrites it
+    // meanwhile. A journal nobody holds RESERVED for is rolled back first, the pages it
+    // holds are the file as it was before a commit that was cut short
+    pub fn lock_for_read(&mut self) -> anyhow::Result<()> {
+        let Some(lock) = self.lock.as_mut() else {
+            return self.refresh_wal();
+        };
+        if lock.level() >= lock::Level::Shared {
+            return Ok(());
+        }
+        if !lock.shared().context("lock db file")? {
+            anyhow::bail!("database is locked");
+        }
+        let hot_journal = match &self.journal_path {
+            Some(path) => path.exists() && !lock.reserved_elsewhere().context("check db file locks")?,
+            None => false,
+        };
+        if let (true, Some(path)) = (hot_journal, &self.journal_path) {
+            if self.read_only {
+                let _ = lock.unlock();
+                anyhow::bail!("cannot read a database with a hot journal read-only");
+            }
+            // Readers of the file would see it change under them
+            if !lock.exclusive().context("lock db file")? {
+                let _ = lock.unlock();
+                anyhow::bail!("database is locked");
+            }
+            let recovered = journal::recover(&mut self.input, path);
+            lock.downgrade().context("unlock db file")?;
+            recovered?;
+            let file_size = self.input.seek(SeekFrom::End(0)).context("read db file size")? as usize;
+            self.page_count = file_size / self.page_size;
+            self.committed_page_count = self.page_count;
+            self.pages.clear();
+        }
+        self.refresh_wal()
+    }
+    // Each read sees the commits made to the log up to its start
+    fn refresh_wal(&mut self) -> anyhow::Result<()> {
+        let changed = match self.wal.as_mut() {
+            Some(wal) => wal.refresh()?,
+            None => false,
+        };
+        match changed {
+            true => self.wal_changed(),
+            false => Ok(()),
+        }
+    }
+    // Let go of SHARED once a statement is done reading, unless a transaction goes on
+    pub fn end_read(&mut self) {
+        if self.in_transaction() {
+            return;
+        }
+        if let Some(lock) = self.lock.as_mut().filter(|lock| lock.level() == lock::Level::Shared) {
+            let _ = lock.unlock();
+        }
+    }
+    // Take RESERVED before the first write of a transaction, so no other process writes
+    // one at the same time
+    pub fn lock_for_write(&mut self) -> anyhow::Result<()> {
+        if self.read_only || self.lock.as_ref().map_or(true, |lock| lock.level() >= lock::Level::Reserved) {
+            return Ok(());
+        }
+        self.lock_for_read()?;
+        let lock = self.lock.as_mut().unwrap();
+        if !lock.reserved().context("lock db file")? {
+            let _ = lock.unlock();
+            anyhow::bail!("database is locked");
+        }
+        Ok(())
+    }
+    // The original content of every page the transaction overwrites goes to the journal,
+    // which is synced before the pages are written and deleted once they are synced too.
+    // A crash in between leaves the journal behind for the next open to roll back
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        if self.dirty.is_none() {
+            return Ok(());
+        }
+        // Readers of the file must be gone before it is overwritten, the transaction stays
+        // open when they aren't so the commit can be tried again
+        if let Some(lock) = self.lock.as_mut() {
+            if !lock.exclusive().context("lock db file")? {
+                anyhow::bail!("database is locked");
+            }
+        }
+        let dirty = self.dirty.take().unwrap_or_default();
+        self.savepoints.clear();
+        let mut page_nums = dirty.keys().copied().collect::<Vec<_>>();
+        page_nums.sort_unstable();
+        let journal_path = self.journal_path.clone();
+        if let Some(path) = &journal_path {
+            let mut originals = Vec::new();
+            let committed_page_count = self.committed_page_count;
+            for &page_num in page_nums.iter().filter(|n| **n <= committed_page_count) {
+                originals.push((page_num, self.read_raw_page(page_num)?));
+            }
+            journal::write(path, self.page_size, self.committed_page_count, &originals)?;
+        }
+        for page_num in page_nums {
+            self.write_page(page_num, &dirty[&page_num])?;
+        }
+        self.input.sync_all().context("sync db file")?;
+        if let Some(path) = &journal_path {
+            journal::remove(path)?;
+        }
+        if let Some(lock) = self.lock.as_mut() {
+            lock.unlock().context("unlock db file")?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,1849 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use super::{
+    apply_affinities, apply_collations, check_ambiguous, collect_aggregates, collect_columns, column_names,
+    compound_order_slots, contains, counts_all, group_by_slots, hash_join_keys, is_aggregate_call, is_subquery,
+    order_by_slots, order_collation, reject_aggregates, replace_aggregates, resolve_aliases, result_collations,
+    row_map, row_map_key, row_values, select_exprs_mut, term_level, using_affinities, wildcard_source, CommonTable,
+    Db, HashJoin, HashKey, Source, SourceRow,
+};
+use crate::{
+    aggregate::Aggregate,
+    btree,
+    collation::Collation,
+    cursor::{seek_row, TableCursor},
+    ephemeral::EphemeralBtree,
+    eval::{compare, evaluate, truth},
+    planner::{self, collect_conjuncts, Plan},
+    record::{Affinity, Value},
+    sorter::{SortedRows, Sorter},
+    sql::parser::{CompoundOp, Expr, JoinKind, SelectStmt},
+};
+
+// Every SELECT is compiled to a program for a register machine, the way SQLite's VDBE
+// runs statements, and the program is run a step at a time for each row asked for.
+// A cursor walks each table of the FROM clause, nested in join order: over its whole
+// b-tree, over the rows an index or rowid lookup finds, or over the rows a hash table
+// holds for the current outer row. The innermost loop checks the WHERE clause and
+// computes the result columns into registers, which go out through ResultRow, or
+// through ephemeral tables and a sorter for DISTINCT, GROUP BY, ORDER BY and compound
+// SELECTs first. Expressions themselves are evaluated on the row whole, see eval.rs
+// https://www.sqlite.org/opcode.html
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    // Jump to the setup at the end, which jumps back to the op after this one
+    Init { target: usize },
+    Goto { target: usize },
+    Halt,
+    Integer { value: i64, dest: usize },
+    Null { dest: usize },
+    Copy { src: usize, dest: usize },
+    // Jump when the register holds a true value, or unless it does. NULL is neither
+    If { reg: usize, target: usize },
+    IfNot { reg: usize, target: usize },
+    // When the register is above 0, decrement it and jump
+    IfPos { reg: usize, target: usize },
+    // Decrement the register, and jump once it reaches 0
+    DecrJumpZero { reg: usize, target: usize },
+    // The value of `expr` for the current rows of the first `tables` tables of a FROM
+    // clause, whose first table `cursor` reads. Subqueries that refer to the row run for it
+    Eval { expr: Rc<Expr>, cursor: usize, tables: usize, dest: usize },
+    // The register's value goes in every row map under `name`, for a subquery taken out
+    // of the select list, see `Db::row_subqueries`
+    Bind { reg: usize, name: String },
+    OpenRead { cursor: usize, root_page: usize },
+    // A cursor over the rows of a VALUES list or WITH clause table
+    OpenRows { cursor: usize },
+    // To the first row, or jump to `target` when there is none
+    Rewind { cursor: usize, target: usize },
+    // To the first of the rows an index or rowid lookup finds: of the WHERE clause for
+    // the first table, and of the join terms and the current outer rows for later ones
+    Lookup { cursor: usize, target: usize },
+    // To the first of the rows with the join keys of the current outer rows, from a hash
+    // table of the whole table built the first time
+    HashLookup { cursor: usize, target: usize },
+    // The same for the first of two tables, whose hash table is keyed by the expressions
+    // the second table's columns equal and looked up with the second table's current row
+    OuterHashLookup { cursor: usize, target: usize },
+    // To the next row and jump to `target`, or fall through after the last
+    Next { cursor: usize, target: usize },
+    // The NULL row of a LEFT JOIN, or of the tables before a RIGHT JOIN's unmatched rows
+    NullRow { cursor: usize },
+    Column { cursor: usize, column: usize, dest: usize },
+    Rowid { cursor: usize, dest: usize },
+    // A column that tables after this one join on, shared with them for `*`
+    UsingColumn { cursor: usize, column: usize, dest: usize },
+    // Jump unless the row's USING columns equal those of the tables before it
+    Using { cursor: usize, target: usize },
+    // Note that the row matched the rows before it, for the rows of a RIGHT JOIN that
+    // never did
+    MarkMatched { cursor: usize },
+    IfMatched { cursor: usize, target: usize },
+    // The number of rows in the table, counted off its leaves
+    Count { cursor: usize, dest: usize },
+    // An in-memory index keyed by a whole row, with the collation of each field, or a
+    // list of rows without one
+    OpenEphemeral { cursor: usize, collations: Option<Vec<Collation>> },
+    // Jump when the index has the key in the registers from `start`, or when it hasn't
+    Found { cursor: usize, start: usize, count: usize, target: usize },
+    NotFound { cursor: usize, start: usize, count: usize, target: usize },
+    // Add the row in the registers from `start`, unless the index already has it
+    IdxInsert { cursor: usize, start: usize, count: usize },
+    SorterOpen { cursor: usize, descending: Vec<bool>, collations: Vec<Collation> },
+    // Sort the row from `start` by the keys from `keys`
+    SorterInsert { cursor: usize, keys: usize, key_count: usize, start: usize, count: usize },
+    SorterSort { cursor: usize, target: usize },
+    SorterNext { cursor: usize, target: usize },
+    // Step the aggregates of the row's group, see `Aggregation`, with the group keys, the
+    // aggregate arguments, the ORDER BY keys, the bare columns and the row in the
+    // registers from `start`
+    AggStep { cursor: usize, start: usize, aggregation: usize },
+    // Without GROUP BY, the one group there is even when no row was stepped
+    AggInit { cursor: usize, aggregation: usize },
+    // The finished aggregates of the current group, then its ORDER BY keys, bare columns
+    // and row, into the registers from `dest`
+    AggFinal { cursor: usize, dest: usize, aggregation: usize },
+    // The value of an expression over a finished group, unpacked from `start`
+    AggEval { expr: Rc<Expr>, start: usize, aggregation: usize, dest: usize },
+    // The `count` registers from `start` are the next row of the result
+    ResultRow { start: usize, count: usize },
+}
+
+impl Op {
+    // The opcode and its P1 to P4 operands, as EXPLAIN lists them
+    pub fn explain(&self) -> (&'static str, i64, i64, i64, Option<String>) {
+        let n = |n: usize| n as i64;
+        let collations = |collations: &[Collation]| {
+            let names = collations.iter().map(|collation| collation.name().to_uppercase()).collect::<Vec<_>>();
+            format!("k({},{})", collations.len(), names.join(","))
+        };
+        match self {
+            Op::Init { target } => ("Init", 0, n(*target), 0, None),
+            Op::Goto { target } => ("Goto", 0, n(*target), 0, None),
+            Op::Halt => ("Halt", 0, 0, 0, None),
+            Op::Integer { value, dest } => ("Integer", *value, n(*dest), 0, None),
+            Op::Null { dest } => ("Null", 0, n(*dest), 0, None),
+            Op::Copy { src, dest } => ("Copy", n(*src), n(*dest), 0, None),
+            Op::If { reg, target } => ("If", n(*reg), n(*target), 0, None),
+            Op::IfNot { reg, target } => ("IfNot", n(*reg), n(*target), 1, None),
+            Op::IfPos { reg, target } => ("IfPos", n(*reg), n(*target), 1, None),
+            Op::DecrJumpZero { reg, target } => ("DecrJumpZero", n(*reg), n(*target), 0, None),
+            Op::Eval { cursor, tables, dest, .. } => ("Eval", n(*cursor), n(*tables), n(*dest), None),
+            Op::Bind { reg, name } => ("Bind", n(*reg), 0, 0, Some(name.trim_start_matches('\0').to_string())),
+            Op::OpenRead { cursor, root_page } => ("OpenRead", n(*cursor), n(*root_page), 0, None),
+            Op::OpenRows { cursor } => ("OpenRows", n(*cursor), 0, 0, None),
+            Op::Rewind { cursor, target } => ("Rewind", n(*cursor), n(*target), 0, None),
+            Op::Lookup { cursor, target } => ("Lookup", n(*cursor), n(*target), 0, None),
+            Op::HashLookup { cursor, target } => ("HashLookup", n(*cursor), n(*target), 0, None),
+            Op::OuterHashLookup { cursor, target } => ("OuterHashLookup", n(*cursor), n(*target), 0, None),
+            Op::Next { cursor, target } => ("Next", n(*cursor), n(*target), 0, None),
+            Op::NullRow { cursor } => ("NullRow", n(*cursor), 0, 0, None),
+            Op::Column { cursor, column, dest } => ("Column", n(*cursor), n(*column), n(*dest), None),
+            Op::Rowid { cursor, dest } => ("Rowid", n(*cursor), n(*dest), 0, None),
+            Op::UsingColumn { cursor, column, dest } => ("UsingColumn", n(*cursor), n(*column), n(*dest), None),
+            Op::Using { cursor, target } => ("Using", n(*cursor), n(*target), 0, None),
+            Op::MarkMatched { cursor } => ("MarkMatched", n(*cursor), 0, 0, None),
+            Op::IfMatched { cursor, target } => ("IfMatched", n(*cursor), n(*target), 0, None),
+            Op::Count { cursor, dest } => ("Count", n(*cursor), n(*dest), 0, None),
+            Op::OpenEphemeral { cursor, collations: keys } => {
+                let count = keys.as_ref().map_or(0, Vec::len);
+                ("OpenEphemeral", n(*cursor), n(count), 0, keys.as_deref().map(collations))
+            }
+            Op::Found { cursor, start, count, target } => {
+                ("Found", n(*cursor), n(*target), n(*start), Some(count.to_string()))
+            }
+            Op::NotFound { cursor, start, count, target } => {
+                ("NotFound", n(*cursor), n(*target), n(*start), Some(count.to_string()))
+            }
+            Op::IdxInsert { cursor, start, count } => ("IdxInsert", n(*cursor), n(*start), n(*count), None),
+            Op::SorterOpen { cursor, descending, collations: keys } => {
+                ("SorterOpen", n(*cursor), n(descending.len()), 0, Some(collations(keys)))
+            }
+            Op::SorterInsert { cursor, keys, key_count, start, count } => {
+                ("SorterInsert", n(*cursor), n(*start), n(*count), Some(format!("keys {}..{}", keys, keys + key_count)))
+            }
+            Op::SorterSort { cursor, target } => ("SorterSort", n(*cursor), n(*target), 0, None),
+            Op::SorterNext { cursor, target } => ("SorterNext", n(*cursor), n(*target), 0, None),
+            Op::AggStep { cursor, start, aggregation } => ("AggStep", n(*cursor), n(*start), n(*aggregation), None),
+            Op::AggInit { cursor, aggregation } => ("AggInit", n(*cursor), 0, n(*aggregation), None),
+            Op::AggFinal { cursor, dest, aggregation } => ("AggFinal", n(*cursor), n(*dest), n(*aggregation), None),
+            Op::AggEval { start, aggregation, dest, .. } => ("AggEval", n(*start), n(*dest), n(*aggregation), None),
+            Op::ResultRow { start, count } => ("ResultRow", n(*start), n(*count), 0, None),
+        }
+    }
+
+    fn set_target(&mut self, to: usize) {
+        match self {
+            Op::Init { target }
+            | Op::Goto { target }
+            | Op::If { target, .. }
+            | Op::IfNot { target, .. }
+            | Op::IfPos { target, .. }
+            | Op::DecrJumpZero { target, .. }
+            | Op::Rewind { target, .. }
+            | Op::Lookup { target, .. }
+            | Op::HashLookup { target, .. }
+            | Op::OuterHashLookup { target, .. }
+            | Op::Next { target, .. }
+            | Op::Using { target, .. }
+            | Op::IfMatched { target, .. }
+            | Op::Found { target, .. }
+            | Op::NotFound { target, .. }
+            | Op::SorterSort { target, .. }
+            | Op::SorterNext { target, .. } => *target = to,
+            _ => unreachable!("{:?} doesn't jump", self),
+        }
+    }
+}
+
+pub struct Program {
+    pub ops: Vec<Op>,
+    // What each op is for, the COMMENT column of EXPLAIN
+    pub comments: Vec<Option<String>>,
+    registers: usize,
+    cursors: usize,
+    from_clauses: Vec<FromClause>,
+    // Of each cursor that reads a table, its FROM clause and its position there
+    tables: Vec<Option<(usize, usize)>>,
+    aggregations: Vec<Aggregation>,
+}
+
+impl Program {
+    fn table(&self, cursor: usize) -> (&FromClause, usize) {
+        let (from, level) = self.tables[cursor].expect("a table cursor");
+        (&self.from_clauses[from], level)
+    }
+}
+
+// The tables of a SELECT, read by the cursors from `first` on in join order, with the
+// WHERE clause that plans the lookups on the first one, and the terms of it checked as
+// soon as the tables they refer to have their rows, see `early_terms`
+struct FromClause {
+    sources: Vec<Source>,
+    first: usize,
+    where_clause: Option<Expr>,
+    early_terms: Vec<Vec<Expr>>,
+}
+
+impl FromClause {
+    fn new(sources: Vec<Source>, first: usize, where_clause: Option<Expr>) -> Self {
+        let early_terms = early_terms(&sources, where_clause.as_ref());
+        FromClause {
+            sources,
+            first,
+            where_clause,
+            early_terms,
+        }
+    }
+
+    fn early_terms(&self, level: usize) -> Vec<&Expr> {
+        self.early_terms[level].iter().collect()
+    }
+}
+
+// The ANDed terms of the WHERE clause of a join by the first table in the join order
+// after which they can be checked: a combination of rows that fails one is dropped there
+// instead of being joined with every row of the tables after it.
+// Only a join of inner joins has early terms, as an outer join has to tell apart rows
+// without a match from rows the WHERE clause drops
+fn early_terms(sources: &[Source], where_clause: Option<&Expr>) -> Vec<Vec<Expr>> {
+    let mut early_terms = vec![Vec::new(); sources.len()];
+    let is_inner = sources.iter().all(|source| source.kind == JoinKind::Inner);
+    // A single table has its WHERE clause checked once per row anyway
+    if let Some(where_expr) = where_clause.filter(|_| is_inner && sources.len() > 1) {
+        let mut terms = Vec::new();
+        collect_conjuncts(where_expr, &mut terms);
+        for term in terms {
+            if let Some(level) = term_level(term, sources) {
+                early_terms[level].push(term.clone());
+            }
+        }
+    }
+    early_terms
+}
+
+// The aggregates of a SELECT and the layout of its groups' entries in their ephemeral
+// index: group key -> [aggregate states..., ORDER BY keys..., bare columns..., row...]
+struct Aggregation {
+    aggregates: Vec<(Aggregate, Expr)>,
+    // Of each aggregate's argument, for min() and max()
+    collations: Vec<Collation>,
+    group_len: usize,
+    order_len: usize,
+    // The columns HAVING and the expressions around aggregates refer to, taken from the
+    // first row of the group like bare columns in the select list. With a single min()
+    // or max(), like SQLite they come from the row that holds its value
+    bare_columns: Vec<Expr>,
+    from_extreme_row: bool,
+    row_width: usize,
+}
+
+impl Aggregation {
+    fn states_len(&self) -> usize {
+        self.aggregates.iter().map(|(aggregate, _)| aggregate.state_width()).sum()
+    }
+}
+
+// How the loop over a table finds its rows
+#[derive(Clone, Copy)]
+enum Loop {
+    Scan,
+    Lookup,
+    Hash,
+}
+
+// Where the rows of a SELECT go: out of the program, or into an ephemeral table for a
+// compound SELECT to combine them. DISTINCT drops a row the index has seen, and ORDER BY
+// holds all of them in the sorter before OFFSET and LIMIT apply
+#[derive(Clone, Default)]
+struct Output {
+    distinct: Option<usize>,
+    sorter: Option<usize>,
+    // The result column each ORDER BY term sorts by, if it names one
+    order_slots: Vec<Option<usize>>,
+    // Registers counting down the rows still to skip and to return
+    offset: Option<usize>,
+    limit: Option<usize>,
+    into: Option<usize>,
+}
+
+// Code for the loop body, run for each combination of rows that passes the WHERE clause
+type Body<'a> = dyn FnMut(&mut Compiler) -> anyhow::Result<()> + 'a;
+
+struct Compiler {
+    ops: Vec<Op>,
+    comments: Vec<Option<String>>,
+    registers: usize,
+    tables: Vec<Option<(usize, usize)>>,
+    from_clauses: Vec<FromClause>,
+    aggregations: Vec<Aggregation>,
+    // Run once before the first op, the Init op jumps there, with their comments
+    setup: Vec<(Op, String)>,
+    // Jumps to the Halt at the end
+    to_halt: Vec<usize>,
+}
+
+impl Compiler {
+    fn new() -> Self {
+        let mut compiler = Compiler {
+            ops: Vec::new(),
+            comments: Vec::new(),
+            registers: 0,
+            tables: Vec::new(),
+            from_clauses: Vec::new(),
+            aggregations: Vec::new(),
+            setup: Vec::new(),
+            to_halt: Vec::new(),
+        };
+        compiler.emit(Op::Init { target: 0 });
+        compiler
+    }
+
+    fn alloc(&mut self, count: usize) -> usize {
+        self.registers += count;
+        self.registers - count
+    }
+
+    fn cursor(&mut self) -> usize {
+        self.tables.push(None);
+        self.tables.len() - 1
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.comments.push(None);
+        self.ops.len() - 1
+    }
+
+    // Describe the op just emitted
+    fn note(&mut self, comment: impl Into<String>) {
+        *self.comments.last_mut().unwrap() = Some(comment.into());
+    }
+
+    fn here(&self) -> usize {
+        self.ops.len()
+    }
+
+    fn patch(&mut self, jumps: &[usize], target: usize) {
+        for &jump in jumps {
+            self.ops[jump].set_target(target);
+        }
+    }
+
+    fn patch_here(&mut self, jumps: &[usize]) {
+        self.patch(jumps, self.here());
+    }
+
+    fn eval(&mut self, expr: &Expr, from: &FromClause, tables: usize, comment: impl Into<String>) -> usize {
+        let dest = self.alloc(1);
+        self.emit(Op::Eval {
+            expr: Rc::new(expr.clone()),
+            cursor: from.first,
+            tables,
+            dest,
+        });
+        self.note(comment);
+        dest
+    }
+
+    // Cursors for the tables of a FROM clause, which is added once its code is compiled
+    fn open_tables(&mut self, sources: &[Source]) -> usize {
+        let first = self.tables.len();
+        for (level, source) in sources.iter().enumerate() {
+            let cursor = self.cursor();
+            self.tables[cursor] = Some((self.from_clauses.len(), level));
+            match source.rows {
+                Some(_) => self.emit(Op::OpenRows { cursor }),
+                None => self.emit(Op::OpenRead {
+                    cursor,
+                    root_page: source.schema.root_page as usize,
+                }),
+            };
+            self.note(source.table.qualifier());
+        }
+        first
+    }
+
+    fn open_ephemeral(&mut self, collations: Option<Vec<Collation>>, comment: &str) -> usize {
+        let cursor = self.cursor();
+        self.emit(Op::OpenEphemeral { cursor, collations });
+        self.note(comment);
+        cursor
+    }
+
+    // The output of a SELECT of its own, with the counters of its LIMIT and OFFSET set
+    // before the first op
+    fn open_output(
+        &mut self,
+        distinct: Option<Vec<Collation>>,
+        descending: Vec<bool>,
+        order_collations: Vec<Collation>,
+        order_slots: Vec<Option<usize>>,
+        (limit, offset): (Option<usize>, usize),
+    ) -> Output {
+        let distinct = distinct.map(|collations| self.open_ephemeral(Some(collations), "DISTINCT"));
+        let sorter = (!descending.is_empty()).then(|| {
+            let cursor = self.cursor();
+            self.emit(Op::SorterOpen {
+                cursor,
+                descending,
+                collations: order_collations,
+            });
+            self.note("ORDER BY");
+            cursor
+        });
+        let mut counter = |value: usize, comment: &str| {
+            let dest = self.alloc(1);
+            let value = value.min(i64::MAX as usize) as i64;
+            self.setup.push((Op::Integer { value, dest }, comment.to_string()));
+            dest
+        };
+        let limit = limit.map(|limit| counter(limit, "LIMIT"));
+        let offset = (offset > 0).then(|| counter(offset, "OFFSET"));
+        Output {
+            distinct,
+            sorter,
+            order_slots,
+            offset,
+            limit,
+            into: None,
+        }
+    }
+
+    // Without ORDER BY, rows come out in the order they arrive, so the program ends as soon
+    // as the LIMIT has its rows, and `LIMIT 0` reads nothing
+    fn check_limit(&mut self, output: &Output) {
+        if let (None, Some(reg)) = (output.sorter, output.limit) {
+            let jump = self.emit(Op::IfNot { reg, target: 0 });
+            self.note("LIMIT 0");
+            self.to_halt.push(jump);
+        }
+    }
+
+    // Send on the row of `width` registers from `row`, with the ORDER BY keys from `keys`
+    // of which those naming a result column are filled in here
+    fn emit_row(&mut self, output: &Output, row: usize, width: usize, keys: usize) {
+        let mut skip = Vec::new();
+        if let Some(cursor) = output.distinct {
+            skip.push(self.emit(Op::Found {
+                cursor,
+                start: row,
+                count: width,
+                target: 0,
+            }));
+            self.emit(Op::IdxInsert {
+                cursor,
+                start: row,
+                count: width,
+            });
+        }
+        match output.sorter {
+            Some(cursor) => {
+                for (j, slot) in output.order_slots.iter().enumerate() {
+                    if let Some(i) = slot {
+                        self.emit(Op::Copy {
+                            src: row + i,
+                            dest: keys + j,
+                        });
+                    }
+                }
+                self.emit(Op::SorterInsert {
+                    cursor,
+                    keys,
+                    key_count: output.order_slots.len(),
+                    start: row,
+                    count: width,
+                });
+            }
+            None => self.deliver(output, row, width, &mut skip),
+        }
+        self.patch_here(&skip);
+    }
+
+    fn deliver(&mut self, output: &Output, row: usize, width: usize, skip: &mut Vec<usize>) {
+        if let Some(reg) = output.offset {
+            skip.push(self.emit(Op::IfPos { reg, target: 0 }));
+        }
+        match output.into {
+            Some(cursor) => self.emit(Op::IdxInsert {
+                cursor,
+                start: row,
+                count: width,
+            }),
+            None => self.emit(Op::ResultRow { start: row, count: width }),
+        };
+        if let Some(reg) = output.limit {
+            let jump = self.emit(Op::DecrJumpZero { reg, target: 0 });
+            self.to_halt.push(jump);
+        }
+    }
+
+    // The rows of an ORDER BY, out of the sorter once all are in. Sorted rows can only be
+    // cut by the LIMIT once all are sorted
+    fn emit_sorted(&mut self, output: &Output, width: usize) {
+        let Some(cursor) = output.sorter else {
+            return;
+        };
+        if let Some(reg) = output.limit {
+            let jump = self.emit(Op::IfNot { reg, target: 0 });
+            self.to_halt.push(jump);
+        }
+        let row = self.alloc(width);
+        let sort = self.emit(Op::SorterSort { cursor, target: 0 });
+        let top = self.here();
+        for column in 0..width {
+            self.emit(Op::Column {
+                cursor,
+                column,
+                dest: row + column,
+            });
+        }
+        let mut skip = Vec::new();
+        self.deliver(output, row, width, &mut skip);
+        self.patch_here(&skip);
+        self.emit(Op::SorterNext { cursor, target: top });
+        self.patch_here(&[sort]);
+    }
+
+    // Add the rows of ephemeral table `from` to `to`, with `filter` only those `right`
+    // has, or only those it doesn't
+    fn copy_rows(&mut self, from: usize, to: usize, width: usize, filter: Option<(usize, bool)>) {
+        let row = self.alloc(width);
+        let rewind = self.emit(Op::Rewind { cursor: from, target: 0 });
+        let top = self.here();
+        for column in 0..width {
+            self.emit(Op::Column {
+                cursor: from,
+                column,
+                dest: row + column,
+            });
+        }
+        let skip = filter.map(|(right, found)| {
+            let (cursor, start, count, target) = (right, row, width, 0);
+            match found {
+                true => self.emit(Op::NotFound { cursor, start, count, target }),
+                false => self.emit(Op::Found { cursor, start, count, target }),
+            }
+        });
+        self.emit(Op::IdxInsert {
+            cursor: to,
+            start: row,
+            count: width,
+        });
+        self.patch_here(&Vec::from_iter(skip));
+        self.emit(Op::Next { cursor: from, target: top });
+        self.patch_here(&[rewind]);
+    }
+
+    // The loop over the table at `level` and the ones after it, then `body` for each
+    // combination of their rows that passes the join terms and the WHERE clause
+    fn emit_loop(&mut self, from: &FromClause, level: usize, loops: &[Loop], body: &mut Body) -> anyhow::Result<()> {
+        let Some(source) = from.sources.get(level) else {
+            let mut skip = Vec::new();
+            if let Some(where_expr) = &from.where_clause {
+                let reg = self.eval(where_expr, from, from.sources.len(), "WHERE");
+                skip.push(self.emit(Op::IfNot { reg, target: 0 }));
+            }
+            body(self)?;
+            self.patch_here(&skip);
+            return Ok(());
+        };
+        let cursor = from.first + level;
+        // LEFT and FULL JOIN keep the outer row, with NULLs for this table, when no row
+        // of it matched
+        let matched = source.kind.keeps_left().then(|| self.alloc(1));
+        if let Some(dest) = matched {
+            self.emit(Op::Integer { value: 0, dest });
+        }
+        let open = match loops[level] {
+            Loop::Scan => self.emit(Op::Rewind { cursor, target: 0 }),
+            Loop::Lookup => self.emit(Op::Lookup { cursor, target: 0 }),
+            Loop::Hash => self.emit(Op::HashLookup { cursor, target: 0 }),
+        };
+        let top = self.here();
+        let to_next = self.join_checks(from, level);
+        if let Some(dest) = matched {
+            self.emit(Op::Integer { value: 1, dest });
+        }
+        if source.kind.keeps_right() {
+            self.emit(Op::MarkMatched { cursor });
+        }
+        let inner = self.here();
+        self.emit_loop(from, level + 1, loops, body)?;
+        self.patch_here(&to_next);
+        self.emit(Op::Next { cursor, target: top });
+        self.patch_here(&[open]);
+        if let Some(reg) = matched {
+            let done = self.emit(Op::If { reg, target: 0 });
+            self.emit(Op::NullRow { cursor });
+            self.note("LEFT JOIN");
+            self.emit(Op::Integer { value: 1, dest: reg });
+            self.emit(Op::Goto { target: inner });
+            self.patch_here(&[done]);
+        }
+        Ok(())
+    }
+
+    // Whether the row of the table at `level` matches the rows before it: its USING
+    // columns, ON clause and the WHERE terms that can already be checked. The jumps
+    // taken when it doesn't
+    fn join_checks(&mut self, from: &FromClause, level: usize) -> Vec<usize> {
+        let source = &from.sources[level];
+        let cursor = from.first + level;
+        let mut to_next = Vec::new();
+        if !source.using.is_empty() {
+            to_next.push(self.emit(Op::Using { cursor, target: 0 }));
+        }
+        if let Some(on) = &source.on {
+            let reg = self.eval(on, from, level + 1, "ON");
+            to_next.push(self.emit(Op::IfNot { reg, target: 0 }));
+        }
+        for term in &from.early_terms[level] {
+            let reg = self.eval(term, from, level + 1, "WHERE term");
+            to_next.push(self.emit(Op::IfNot { reg, target: 0 }));
+        }
+        to_next
+    }
+
+    fn finish(mut self) -> Program {
+        let halt = self.emit(Op::Halt);
+        let to_halt = std::mem::take(&mut self.to_halt);
+        self.patch(&to_halt, halt);
+        let setup = self.here();
+        self.ops[0].set_target(setup);
+        for (op, comment) in std::mem::take(&mut self.setup) {
+            self.emit(op);
+            self.note(comment);
+        }
+        self.emit(Op::Goto { target: 1 });
+        Program {
+            ops: self.ops,
+            comments: self.comments,
+            registers: self.registers,
+            cursors: self.tables.len(),
+            from_clauses: self.from_clauses,
+            tables: self.tables,
+            aggregations: self.aggregations,
+        }
+    }
+}
+
+impl Db {
+    // The program for `select` over its tables, with the WITH clause tables in scope.
+    // Subqueries that don't refer to the row run here, like WITH clause tables that
+    // are read
+    pub(super) fn compile(&mut self, select: &SelectStmt, sources: Vec<Source>) -> anyhow::Result<Program> {
+        let mut compiler = Compiler::new();
+        match select.compound.is_empty() {
+            true => self.compile_core(&mut compiler, select, sources, None)?,
+            false => self.compile_compound(&mut compiler, select, sources)?,
+        }
+        Ok(compiler.finish())
+    }
+
+    // The program for the rows of the table an UPDATE or DELETE changes: the rowid and
+    // the columns of each row the WHERE clause holds for
+    pub(super) fn compile_scan(&mut self, source: Source, where_clause: Option<Expr>) -> anyhow::Result<Program> {
+        let mut c = Compiler::new();
+        let width = source.schema.columns.len();
+        let sources = vec![source];
+        let first = c.open_tables(&sources);
+        let from = FromClause::new(sources, first, where_clause);
+        let row = c.alloc(width + 1);
+        self.compile_loops(&mut c, &from, &mut |c| {
+            c.emit(Op::Rowid { cursor: first, dest: row });
+            for column in 0..width {
+                c.emit(Op::Column {
+                    cursor: first,
+                    column,
+                    dest: row + 1 + column,
+                });
+            }
+            c.emit(Op::ResultRow {
+                start: row,
+                count: width + 1,
+            });
+            Ok(())
+        })?;
+        c.from_clauses.push(from);
+        Ok(c.finish())
+    }
+
+    // A SELECT and the ones compounded with it: UNION ALL adds the rows on the right,
+    // while UNION, INTERSECT and EXCEPT return distinct rows, in order like SQLite's
+    // temporary index gives them. The ORDER BY and LIMIT then apply to the combined rows.
+    // The SELECTs after the last operator other than UNION ALL send their rows straight
+    // to the output
+    fn compile_compound(&mut self, c: &mut Compiler, select: &SelectStmt, sources: Vec<Source>) -> anyhow::Result<()> {
+        let mut names = vec![column_names(select, &sources, self.rowid_in_wildcard)];
+        let width = names[0].len();
+        let mut part_sources = Vec::new();
+        for part in &select.compound {
+            let sources = self.sources(&part.select)?;
+            let part_names = column_names(&part.select, &sources, self.rowid_in_wildcard);
+            if part_names.len() != width {
+                let op = match part.op {
+                    CompoundOp::Union => "UNION",
+                    CompoundOp::UnionAll => "UNION ALL",
+                    CompoundOp::Intersect => "INTERSECT",
+                    CompoundOp::Except => "EXCEPT",
+                };
+                anyhow::bail!(
+                    "SELECTs to the left and right of {} do not have the same number of result columns",
+                    op
+                );
+            }
+            names.push(part_names);
+            part_sources.push(sources);
+        }
+        let order_slots = compound_order_slots(select, &names)?;
+        // The ORDER BY of a compound select names result columns, which have no collation
+        let order_collations = select
+            .order_by
+            .iter()
+            .map(|term| order_collation(&term.expr, &[]))
+            .collect::<anyhow::Result<_>>()?;
+        let descending = select.order_by.iter().map(|term| term.descending).collect();
+        let limit_offset = self.limit_offset(select)?;
+        let output = c.open_output(None, descending, order_collations, order_slots, limit_offset);
+        c.check_limit(&output);
+        let mut core = select.clone();
+        core.compound.clear();
+        core.order_by.clear();
+        (core.limit, core.offset) = (None, None);
+        let mut parts = select.compound.iter().zip(part_sources);
+        let Some(last) = select.compound.iter().rposition(|part| part.op != CompoundOp::UnionAll) else {
+            self.compile_core(c, &core, sources, Some(&output))?;
+            for (part, sources) in parts {
+                self.compile_core(c, &part.select, sources, Some(&output))?;
+            }
+            c.emit_sorted(&output, width);
+            return Ok(());
+        };
+
+        let binary = || Some(vec![Collation::Binary; width]);
+        let mut is_index = select.compound[0].op == CompoundOp::Union;
+        let mut rows = c.open_ephemeral(if is_index { binary() } else { None }, "compound rows");
+        let into = |cursor| Output {
+            into: Some(cursor),
+            ..Output::default()
+        };
+        self.compile_core(c, &core, sources, Some(&into(rows)))?;
+        for (part, sources) in parts.by_ref().take(last + 1) {
+            match part.op {
+                CompoundOp::Union | CompoundOp::UnionAll => {
+                    let wants_index = part.op == CompoundOp::Union;
+                    if wants_index != is_index {
+                        let next = c.open_ephemeral(if wants_index { binary() } else { None }, "compound rows");
+                        c.copy_rows(rows, next, width, None);
+                        (rows, is_index) = (next, wants_index);
+                    }
+                    self.compile_core(c, &part.select, sources, Some(&into(rows)))?;
+                }
+                CompoundOp::Intersect | CompoundOp::Except => {
+                    let right = c.open_ephemeral(binary(), "compound right rows");
+                    self.compile_core(c, &part.select, sources, Some(&into(right)))?;
+                    let next = c.open_ephemeral(binary(), "compound rows");
+                    c.copy_rows(rows, next, width, Some((right, part.op == CompoundOp::Intersect)));
+                    (rows, is_index) = (next, true);
+                }
+            }
+        }
+        let row = c.alloc(width);
+        let keys = c.alloc(output.order_slots.len());
+        let rewind = c.emit(Op::Rewind { cursor: rows, target: 0 });
+        let top = c.here();
+        for column in 0..width {
+            c.emit(Op::Column {
+                cursor: rows,
+                column,
+                dest: row + column,
+            });
+        }
+        c.emit_row(&output, row, width, keys);
+        c.emit(Op::Next { cursor: rows, target: top });
+        c.patch_here(&[rewind]);
+        for (part, sources) in parts {
+            self.compile_core(c, &part.select, sources, Some(&output))?;
+        }
+        c.emit_sorted(&output, width);
+        Ok(())
+    }
+
+    // One SELECT of a compound, or a SELECT on its own when there is no `target` output
+    fn compile_core(
+        &mut self,
+        c: &mut Compiler,
+        select: &SelectStmt,
+        sources: Vec<Source>,
+        target: Option<&Output>,
+    ) -> anyhow::Result<()> {
+        // The rowid only goes in front of `*` for a single table
+        let rowid_in_wildcard = self.rowid_in_wildcard && sources.len() == 1;
+        let output_for = |output: Output| match target {
+            Some(target) => Output {
+                distinct: output.distinct,
+                ..target.clone()
+            },
+            None => output,
+        };
+        if counts_all(select, &sources) {
+            let output = c.open_output(None, Vec::new(), Vec::new(), Vec::new(), (None, 0));
+            let output = output_for(output);
+            let first = c.open_tables(&sources);
+            let row = c.alloc(1);
+            c.emit(Op::Count { cursor: first, dest: row });
+            c.note("count(*)");
+            c.from_clauses.push(FromClause::new(sources, first, None));
+            let keys = c.alloc(output.order_slots.len());
+            c.emit_row(&output, row, 1, keys);
+            return Ok(());
+        }
+        let mut bound = select.clone();
+        resolve_aliases(&mut bound, &sources);
+        select_exprs_mut(&mut bound, &mut |expr| {
+            apply_affinities(expr, &sources);
+            apply_collations(expr, &sources);
+        });
+        let row_subqueries = self.row_subqueries(&mut bound, &sources)?;
+        let select = &bound;
+        // Position of each select list item in the result row
+        let wildcard_width = sources
+            .iter()
+            .map(|source| source.wildcard_columns().count())
+            .sum::<usize>()
+            + rowid_in_wildcard as usize;
+        let mut column_starts = Vec::new();
+        let mut row_width = 0;
+        for column in &select.columns {
+            column_starts.push(row_width);
+            row_width += match &column.expr {
+                Expr::Wildcard => wildcard_width,
+                Expr::TableWildcard(table) => wildcard_source(&sources, table)?.1.schema.columns.len(),
+                _ => 1,
+            };
+        }
+        let order_slots = order_by_slots(select, &column_starts, row_width)?;
+        let group_slots = group_by_slots(select, &column_starts, row_width)?;
+        // A term naming a result column has nothing to look up in the tables
+        let order_terms = select.order_by.iter().zip(&order_slots).filter(|(_, slot)| slot.is_none());
+        let exprs = select
+            .columns
+            .iter()
+            .map(|column| &column.expr)
+            .chain(sources.iter().filter_map(|source| source.on.as_ref()))
+            .chain(&select.where_clause)
+            .chain(&select.group_by)
+            .chain(&select.having)
+            .chain(order_terms.map(|(term, _)| &term.expr));
+        for expr in exprs {
+            check_ambiguous(expr, &sources)?;
+        }
+        // The aggregate calls of the select list, HAVING and ORDER BY, each with a running
+        // state in the group's entry
+        let outputs = select
+            .columns
+            .iter()
+            .map(|column| &column.expr)
+            .chain(&select.having)
+            .chain(select.order_by.iter().map(|term| &term.expr));
+        select.group_by.iter().try_for_each(reject_aggregates)?;
+        let mut aggregates = Vec::new();
+        for expr in outputs.clone() {
+            collect_aggregates(expr, &mut aggregates)?;
+        }
+        // An aggregate turns the query into one over each group, or over the whole table
+        let is_aggregate = !select.group_by.is_empty() || !aggregates.is_empty();
+        if select.having.is_some() && !is_aggregate {
+            anyhow::bail!("HAVING clause on a non-aggregate query");
+        }
+        let mut bare_columns = Vec::new();
+        for expr in outputs.filter(|expr| contains(expr, &is_aggregate_call)).chain(&select.having) {
+            collect_columns(expr, &mut bare_columns);
+        }
+        let bare_columns = bare_columns.into_iter().cloned().collect::<Vec<_>>();
+        let result_collations = result_collations(select, &sources, rowid_in_wildcard)?;
+        // Text equal under the collation of the key's column is one group
+        let group_collations = select
+            .group_by
+            .iter()
+            .zip(&group_slots)
+            .map(|(expr, slot)| match slot {
+                Some(i) => Ok(result_collations[*i]),
+                None => order_collation(expr, &sources),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let aggregate_collations = aggregates
+            .iter()
+            .map(|(_, call)| match call {
+                Expr::FunctionCall(_, args) if !args.is_empty() => order_collation(&args[0], &sources),
+                _ => Ok(Collation::Binary),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // A term that names a result column sorts by that column's collation
+        let order_collations = select
+            .order_by
+            .iter()
+            .zip(&order_slots)
+            .map(|(term, slot)| match (&term.expr, slot) {
+                (Expr::Collate(..), _) | (_, None) => order_collation(&term.expr, &sources),
+                (_, Some(i)) => Ok(result_collations[*i]),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let descending = select.order_by.iter().map(|term| term.descending).collect();
+        let limit_offset = self.limit_offset(select)?;
+        let distinct = select.distinct.then_some(result_collations);
+        let output = c.open_output(distinct, descending, order_collations, order_slots.clone(), limit_offset);
+        let output = output_for(output);
+        if target.is_none() {
+            c.check_limit(&output);
+        }
+        let where_clause = match &select.where_clause {
+            Some(where_expr) => {
+                reject_aggregates(where_expr)?;
+                Some(self.run_subqueries(where_expr, &sources, None)?)
+            }
+            None => None,
+        };
+
+        let first = c.open_tables(&sources);
+        let from = FromClause::new(sources, first, where_clause);
+        let sources = &from.sources;
+        let group_len = select.group_by.len();
+        let order_len = output.order_slots.len();
+        let aggregation = Aggregation {
+            aggregates,
+            collations: aggregate_collations,
+            group_len,
+            order_len,
+            bare_columns,
+            from_extreme_row: false,
+            row_width,
+        };
+        let aggregation = Aggregation {
+            from_extreme_row: matches!(
+                aggregation.aggregates.as_slice(),
+                [(Aggregate::Min | Aggregate::Max, _)]
+            ),
+            ..aggregation
+        };
+        let groups = is_aggregate.then(|| c.open_ephemeral(Some(group_collations), "GROUP BY"));
+        // The registers of a row: [group keys | aggregate arguments | ORDER BY keys | bare
+        // columns | row], as AggStep takes them
+        let aggregates_len = aggregation.aggregates.len();
+        let block = c.alloc(group_len + aggregates_len + order_len + aggregation.bare_columns.len() + row_width);
+        let (args, keys) = (block + group_len, block + group_len + aggregates_len);
+        let bare = keys + order_len;
+        let row = bare + aggregation.bare_columns.len();
+        let aggregation_id = c.aggregations.len();
+
+        self.compile_loops(c, &from, &mut |c| {
+            for (name, subquery) in &row_subqueries {
+                let reg = c.eval(subquery, &from, sources.len(), "subquery");
+                c.emit(Op::Bind {
+                    reg,
+                    name: name.clone(),
+                });
+            }
+            let mut dest = row;
+            for (i, column) in select.columns.iter().enumerate() {
+                match &column.expr {
+                    Expr::Wildcard => {
+                        if rowid_in_wildcard {
+                            c.emit(Op::Rowid { cursor: first, dest });
+                            dest += 1;
+                        }
+                        for (j, source) in sources.iter().enumerate() {
+                            for column in source.wildcard_columns() {
+                                let cursor = first + j;
+                                let name = &source.schema.columns[column].name;
+                                // A column later tables join on shows the shared value,
+                                // which a RIGHT or FULL JOIN fills in from the right side
+                                match sources[j + 1..].iter().any(|later| later.using.contains(name)) {
+                                    true => c.emit(Op::UsingColumn { cursor, column, dest }),
+                                    false => c.emit(Op::Column { cursor, column, dest }),
+                                };
+                                dest += 1;
+                            }
+                        }
+                    }
+                    Expr::TableWildcard(table) => {
+                        let (j, source) = wildcard_source(sources, table)?;
+                        for column in 0..source.schema.columns.len() {
+                            c.emit(Op::Column {
+                                cursor: first + j,
+                                column,
+                                dest,
+                            });
+                            dest += 1;
+                        }
+                    }
+                    // Filled in once the group is complete
+                    expr if contains(expr, &is_aggregate_call) => {
+                        c.emit(Op::Null { dest });
+                        dest += 1;
+                    }
+                    expr => {
+                        c.emit(Op::Eval {
+                            expr: Rc::new(expr.clone()),
+                            cursor: first,
+                            tables: sources.len(),
+                            dest,
+                        });
+                        c.note(format!("result column {}", i + 1));
+                        dest += 1;
+                    }
+                }
+            }
+            for (j, (term, slot)) in select.order_by.iter().zip(&order_slots).enumerate() {
+                // Result column references are resolved against the finished row
+                if slot.is_none() && !contains(&term.expr, &is_aggregate_call) {
+                    c.emit(Op::Eval {
+                        expr: Rc::new(term.expr.clone()),
+                        cursor: first,
+                        tables: sources.len(),
+                        dest: keys + j,
+                    });
+                    c.note(format!("ORDER BY term {}", j + 1));
+                }
+            }
+            let Some(groups) = groups else {
+                c.emit_row(&output, row, row_width, keys);
+                return Ok(());
+            };
+            for (j, (expr, slot)) in select.group_by.iter().zip(&group_slots).enumerate() {
+                match slot {
+                    Some(i) => c.emit(Op::Copy {
+                        src: row + i,
+                        dest: block + j,
+                    }),
+                    None => c.emit(Op::Eval {
+                        expr: Rc::new(expr.clone()),
+                        cursor: first,
+                        tables: sources.len(),
+                        dest: block + j,
+                    }),
+                };
+                c.note(format!("GROUP BY term {}", j + 1));
+            }
+            for (j, (aggregate, call)) in aggregation.aggregates.iter().enumerate() {
+                match call {
+                    Expr::FunctionCall(_, call_args) if *aggregate != Aggregate::CountRows => c.emit(Op::Eval {
+                        expr: Rc::new(call_args[0].clone()),
+                        cursor: first,
+                        tables: sources.len(),
+                        dest: args + j,
+                    }),
+                    _ => c.emit(Op::Null { dest: args + j }),
+                };
+                c.note(format!("{}() argument", aggregate.name()));
+            }
+            for (j, column) in aggregation.bare_columns.iter().enumerate() {
+                c.emit(Op::Eval {
+                    expr: Rc::new(column.clone()),
+                    cursor: first,
+                    tables: sources.len(),
+                    dest: bare + j,
+                });
+                c.note("bare column");
+            }
+            c.emit(Op::AggStep {
+                cursor: groups,
+                start: block,
+                aggregation: aggregation_id,
+            });
+            Ok(())
+        })?;
+
+        if let Some(groups) = groups {
+            // Without GROUP BY an aggregate yields one row, even over an empty table
+            if group_len == 0 {
+                c.emit(Op::AggInit {
+                    cursor: groups,
+                    aggregation: aggregation_id,
+                });
+            }
+            let values = c.alloc(aggregates_len + order_len + aggregation.bare_columns.len() + row_width);
+            let keys = values + aggregates_len;
+            let row = keys + order_len + aggregation.bare_columns.len();
+            let rewind = c.emit(Op::Rewind { cursor: groups, target: 0 });
+            let top = c.here();
+            c.emit(Op::AggFinal {
+                cursor: groups,
+                dest: values,
+                aggregation: aggregation_id,
+            });
+            let finish = |c: &mut Compiler, expr: &Expr, dest: usize, comment: String| {
+                c.emit(Op::AggEval {
+                    expr: Rc::new(expr.clone()),
+                    start: values,
+                    aggregation: aggregation_id,
+                    dest,
+                });
+                c.note(comment);
+            };
+            let mut skip = Vec::new();
+            if let Some(having) = &select.having {
+                let reg = c.alloc(1);
+                finish(c, having, reg, "HAVING".to_string());
+                skip.push(c.emit(Op::IfNot { reg, target: 0 }));
+            }
+            for (i, (column, start)) in select.columns.iter().zip(&column_starts).enumerate() {
+                if contains(&column.expr, &is_aggregate_call) {
+                    finish(c, &column.expr, row + start, format!("result column {}", i + 1));
+                }
+            }
+            for (j, (term, slot)) in select.order_by.iter().zip(&order_slots).enumerate() {
+                if slot.is_none() && contains(&term.expr, &is_aggregate_call) {
+                    finish(c, &term.expr, keys + j, format!("ORDER BY term {}", j + 1));
+                }
+            }
+            c.emit_row(&output, row, row_width, keys);
+            c.patch_here(&skip);
+            c.emit(Op::Next { cursor: groups, target: top });
+            c.patch_here(&[rewind]);
+            c.aggregations.push(aggregation);
+        }
+        c.from_clauses.push(from);
+        if target.is_none() {
+            c.emit_sorted(&output, row_width);
+        }
+        Ok(())
+    }
+
+    // The nested loops over the tables of `from`, with `body` inside for each combination
+    // of rows that passes the WHERE clause
+    fn compile_loops(&mut self, c: &mut Compiler, from: &FromClause, body: &mut Body) -> anyhow::Result<()> {
+        // A term without columns, like one comparing values bound from the row of an
+        // enclosing query, is false for every row when it is false at all
+        let mut terms = Vec::new();
+        if let Some(where_expr) = &from.where_clause {
+            collect_conjuncts(where_expr, &mut terms);
+        }
+        let is_constant = |term: &Expr| !contains(term, &|expr| is_subquery(expr) || row_map_key(expr).is_some());
+        let mut to_end = Vec::new();
+        for term in terms.into_iter().filter(|term| is_constant(term)) {
+            let reg = c.eval(term, from, 0, "constant WHERE term");
+            to_end.push(c.emit(Op::IfNot { reg, target: 0 }));
+        }
+        if self.is_outer_hash_join(from)? {
+            // The second table is scanned once, each of its rows looking up the rows of
+            // the first it joins
+            let (outer, inner) = (from.first, from.first + 1);
+            let rewind = c.emit(Op::Rewind { cursor: inner, target: 0 });
+            let inner_top = c.here();
+            let lookup = c.emit(Op::OuterHashLookup { cursor: outer, target: 0 });
+            let outer_top = c.here();
+            let to_next = c.join_checks(from, 1);
+            c.emit_loop(from, 2, &[], body)?;
+            c.patch_here(&to_next);
+            c.emit(Op::Next { cursor: outer, target: outer_top });
+            c.patch_here(&[lookup]);
+            c.emit(Op::Next { cursor: inner, target: inner_top });
+            c.patch_here(&[rewind]);
+            c.patch_here(&to_end);
+            return Ok(());
+        }
+        let loops = (0..from.sources.len())
+            .map(|level| self.loop_kind(from, level))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        c.emit_loop(from, 0, &loops, body)?;
+
+        // RIGHT and FULL JOIN: rows that never matched come last, with NULLs for the
+        // tables before them, and still go through the joins after them
+        for (level, source) in from.sources.iter().enumerate() {
+            if !source.kind.keeps_right() {
+                continue;
+            }
+            let cursor = from.first + level;
+            for before in from.first..cursor {
+                c.emit(Op::NullRow { cursor: before });
+            }
+            let rewind = c.emit(Op::Rewind { cursor, target: 0 });
+            c.note("RIGHT JOIN");
+            let top = c.here();
+            let matched = c.emit(Op::IfMatched { cursor, target: 0 });
+            c.emit_loop(from, level + 1, &loops, body)?;
+            c.patch_here(&[matched]);
+            c.emit(Op::Next { cursor, target: top });
+            c.patch_here(&[rewind]);
+        }
+        c.patch_here(&to_end);
+        Ok(())
+    }
+
+    // A table with rows an index or rowid lookup finds is looked up, one with no index to
+    // probe but joined on equalities with the tables before it is read once into a hash
+    // table by those columns, each outer row then looking up its candidates instead of
+    // scanning the whole table again, and any other is scanned
+    fn loop_kind(&mut self, from: &FromClause, level: usize) -> anyhow::Result<Loop> {
+        let source = &from.sources[level];
+        // A VALUES list has no b-tree to probe
+        if source.rows.is_some() {
+            return Ok(Loop::Scan);
+        }
+        if level == 0 {
+            let Some(where_expr) = &from.where_clause else {
+                return Ok(Loop::Scan);
+            };
+            let is_only_table = from.sources.len() == 1;
+            let terms = planner::where_constraints(where_expr, source.table.qualifier(), is_only_table);
+            return Ok(match self.plan(&source.schema, &terms)? {
+                Plan::Lookups(_) => Loop::Lookup,
+                Plan::FullScan => Loop::Scan,
+            });
+        }
+        // Whether there is a lookup, or which terms are keys, doesn't depend on the
+        // values of the outer rows
+        let null_rows = vec![None; level];
+        let null_row_map = row_map(&from.sources[..level], &null_rows);
+        let early_terms = from.early_terms(level);
+        if let Plan::Lookups(_) = self.join_plan(source, &early_terms, &null_row_map)? {
+            return Ok(Loop::Lookup);
+        }
+        match hash_join_keys(&from.sources, level, &early_terms, &null_row_map).is_empty() {
+            true => Ok(Loop::Scan),
+            false => Ok(Loop::Hash),
+        }
+    }
+
+    // A hash join builds on the smaller side. When the second of two tables in an inner
+    // join is expected to have more rows than the first, and has no index to probe, the
+    // first is read into the hash table instead and the second is scanned once
+    fn is_outer_hash_join(&mut self, from: &FromClause) -> anyhow::Result<bool> {
+        let [outer, inner] = from.sources.as_slice() else {
+            return Ok(false);
+        };
+        if inner.kind != JoinKind::Inner || outer.rows.is_some() || inner.rows.is_some() {
+            return Ok(false);
+        }
+        let null_row_map = row_map(&from.sources[..1], &[None]);
+        let early_terms = from.early_terms(1);
+        let keys = hash_join_keys(&from.sources, 1, &early_terms, &null_row_map);
+        if keys.is_empty() || matches!(self.join_plan(inner, &early_terms, &null_row_map)?, Plan::Lookups(_)) {
+            return Ok(false);
+        }
+        Ok(self.row_estimate(&outer.schema)? < self.row_estimate(&inner.schema)?)
+    }
+}
+
+// A program running, one step per row of its result
+pub struct Vm {
+    program: Rc<Program>,
+    pc: usize,
+    registers: Vec<Value>,
+    cursors: Vec<Cursor>,
+    // The current row of each table cursor, None for a NULL row
+    rows: Vec<Option<SourceRow>>,
+    // Of each table cursor of a RIGHT or FULL JOIN, the rowids that matched some row
+    // before them
+    matched: Vec<HashSet<i64>>,
+    hash_joins: Vec<Option<HashJoin>>,
+    // The values of the subqueries taken out of the select list for the current row
+    bindings: HashMap<String, Value>,
+    // The row map of the first cursor and the number of tables it was last built for,
+    // until one of their rows changes
+    row_map: Option<(usize, usize, HashMap<String, Value>)>,
+    // The tables of the statement's WITH clause, in scope while it runs
+    pub(super) common_tables: Vec<CommonTable>,
+}
+
+enum Cursor {
+    Closed,
+    Table(Walk),
+    Ephemeral(Ephemeral),
+    Sorter(Sorting),
+}
+
+// How a table cursor goes through its rows
+enum Walk {
+    Scan(TableCursor),
+    // The rows of a VALUES list or WITH clause table, from the one at this position
+    Rows(usize),
+    RowIds(std::vec::IntoIter<i64>),
+    Candidates(std::vec::IntoIter<SourceRow>),
+    Done,
+}
+
+// The rows an ephemeral index holds, read in order from the last Rewind. Without an index,
+// the list of rows added
+struct Ephemeral {
+    index: Option<EphemeralBtree>,
+    rows: Vec<Vec<Value>>,
+    position: usize,
+}
+
+enum Sorting {
+    Open(Sorter),
+    Sorted(SortedRows, Option<Vec<Value>>),
+}
+
+impl Vm {
+    pub fn new(program: Program) -> Self {
+        let cursors = program.cursors;
+        Vm {
+            registers: vec![Value::Null; program.registers],
+            cursors: (0..cursors).map(|_| Cursor::Closed).collect(),
+            rows: vec![None; cursors],
+            matched: vec![HashSet::new(); cursors],
+            hash_joins: (0..cursors).map(|_| None).collect(),
+            program: Rc::new(program),
+            pc: 0,
+            bindings: HashMap::new(),
+            row_map: None,
+            common_tables: Vec::new(),
+        }
+    }
+
+    // Run up to the next result row, None once the program halted
+    pub fn step(&mut self, db: &mut Db) -> anyhow::Result<Option<Vec<Value>>> {
+        let program = Rc::clone(&self.program);
+        loop {
+            let op = &program.ops[self.pc];
+            self.pc += 1;
+            match op {
+                Op::Init { target } | Op::Goto { target } => self.pc = *target,
+                Op::Halt => {
+                    self.pc -= 1;
+                    return Ok(None);
+                }
+                Op::Integer { value, dest } => self.registers[*dest] = Value::I64(*value),
+                Op::Null { dest } => self.registers[*dest] = Value::Null,
+                Op::Copy { src, dest } => self.registers[*dest] = self.registers[*src].clone(),
+                Op::If { reg, target } => {
+                    if truth(&self.registers[*reg]) == Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Op::IfNot { reg, target } => {
+                    if truth(&self.registers[*reg]) != Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Op::IfPos { reg, target } => {
+                    if let Value::I64(n) = &mut self.registers[*reg] {
+                        if *n > 0 {
+                            *n -= 1;
+                            self.pc = *target;
+                        }
+                    }
+                }
+                Op::DecrJumpZero { reg, target } => {
+                    if let Value::I64(n) = &mut self.registers[*reg] {
+                        *n -= 1;
+                        if *n == 0 {
+                            self.pc = *target;
+                        }
+                    }
+                }
+                Op::Eval { expr, cursor, tables, dest } => {
+                    let (from, _) = program.table(*cursor);
+                    let ctx = db.function_context;
+                    let row_map = self.row_map(&program, *cursor, *tables);
+                    // Subqueries that refer to the row run again for every row
+                    let value = match contains(expr, &is_subquery) {
+                        true => evaluate(&db.run_subqueries(expr, &from.sources, Some(row_map))?, row_map, &ctx)?,
+                        false => evaluate(expr, row_map, &ctx)?,
+                    };
+                    self.registers[*dest] = value;
+                }
+                Op::Bind { reg, name } => {
+                    self.bindings.insert(name.clone(), self.registers[*reg].clone());
+                    self.row_map = None;
+                }
+                Op::OpenRead { cursor, .. } | Op::OpenRows { cursor } => {
+                    self.cursors[*cursor] = Cursor::Table(Walk::Done);
+                }
+                Op::Rewind { cursor, target } => {
+                    let has_row = match &mut self.cursors[*cursor] {
+                        Cursor::Table(walk) => {
+                            *walk = scan(&program, *cursor);
+                            self.advance(db, &program, *cursor)?
+                        }
+                        Cursor::Ephemeral(ephemeral) => {
+                            if let Some(index) = &ephemeral.index {
+                                ephemeral.rows = index.entries()?;
+                            }
+                            ephemeral.position = 0;
+                            !ephemeral.rows.is_empty()
+                        }
+                        _ => unreachable!("Rewind of a sorter"),
+                    };
+                    if !has_row {
+                        self.pc = *target;
+                    }
+                }
+                Op::Lookup { cursor, target } => {
+                    let (from, level) = program.table(*cursor);
+                    let source = &from.sources[level];
+                    let row_ids = match level {
+                        0 => db.probe_row_ids(source, from.sources.len() == 1, from.where_clause.as_ref())?,
+                        _ => {
+                            let outer_row_map = self.row_map(&program, from.first, level);
+                            db.probe_join(source, &from.early_terms(level), outer_row_map)?
+                        }
+                    };
+                    self.cursors[*cursor] = Cursor::Table(match row_ids {
+                        Some(row_ids) => Walk::RowIds(row_ids.into_iter()),
+                        None => scan(&program, *cursor),
+                    });
+                    if !self.advance(db, &program, *cursor)? {
+                        self.pc = *target;
+                    }
+                }
+                Op::HashLookup { cursor, target } => {
+                    let (from, level) = program.table(*cursor);
+                    let outer_row_map = self.row_map(&program, from.first, level).clone();
+                    if self.hash_joins[*cursor].is_none() {
+                        let keys = hash_join_keys(&from.sources, level, &from.early_terms(level), &outer_row_map);
+                        let mut rows: HashMap<Vec<HashKey>, Vec<SourceRow>> = HashMap::new();
+                        for row in all_rows(db, &from.sources[level])? {
+                            // NULL equals nothing
+                            let key = keys.iter().map(|key| key.inner(&row)).collect::<Option<Vec<_>>>();
+                            if let Some(key) = key {
+                                rows.entry(key).or_default().push(row);
+                            }
+                        }
+                        self.hash_joins[*cursor] = Some(HashJoin { keys, rows });
+                    }
+                    let hash_join = self.hash_joins[*cursor].as_ref().unwrap();
+                    let candidates = hash_join.candidates(&outer_row_map, &db.function_context)?.to_vec();
+                    self.cursors[*cursor] = Cursor::Table(Walk::Candidates(candidates.into_iter()));
+                    if !self.advance(db, &program, *cursor)? {
+                        self.pc = *target;
+                    }
+                }
+                Op::OuterHashLookup { cursor, target } => {
+                    if self.hash_joins[*cursor].is_none() {
+                        let hash_join = self.outer_hash_join(db, &program, *cursor)?;
+                        self.hash_joins[*cursor] = Some(hash_join);
+                    }
+                    let hash_join = self.hash_joins[*cursor].as_ref().unwrap();
+                    let inner_row = self.rows[*cursor + 1].as_ref().expect("the second table's row");
+                    let key = hash_join.keys.iter().map(|key| key.inner(inner_row)).collect::<Option<Vec<_>>>();
+                    let candidates = key.and_then(|key| hash_join.rows.get(&key)).cloned().unwrap_or_default();
+                    self.cursors[*cursor] = Cursor::Table(Walk::Candidates(candidates.into_iter()));
+                    if !self.advance(db, &program, *cursor)? {
+                        self.pc = *target;
+                    }
+                }
+                Op::Next { cursor, target } => {
+                    let has_row = match &mut self.cursors[*cursor] {
+                        Cursor::Table(_) => self.advance(db, &program, *cursor)?,
+                        Cursor::Ephemeral(ephemeral) => {
+                            ephemeral.position += 1;
+                            ephemeral.position < ephemeral.rows.len()
+                        }
+                        _ => unreachable!("Next of a sorter"),
+                    };
+                    if has_row {
+                        self.pc = *target;
+                    }
+                }
+                Op::NullRow { cursor } => {
+                    self.rows[*cursor] = None;
+                    self.row_map = None;
+                }
+                Op::Column { cursor, column, dest } => {
+                    let value = match &self.cursors[*cursor] {
+                        Cursor::Table(_) => self.rows[*cursor].as_ref().map(|row| row.values[*column].clone()),
+                        Cursor::Ephemeral(ephemeral) => Some(ephemeral.rows[ephemeral.position][*column].clone()),
+                        Cursor::Sorter(Sorting::Sorted(_, Some(row))) => Some(row[*column].clone()),
+                        _ => unreachable!("Column of a cursor without a row"),
+                    };
+                    self.registers[*dest] = value.unwrap_or(Value::Null);
+                }
+                Op::Rowid { cursor, dest } => {
+                    let row_id = self.rows[*cursor].as_ref().map(|row| row.row_id);
+                    self.registers[*dest] = row_id.map_or(Value::Null, Value::I64);
+                }
+                Op::UsingColumn { cursor, column, dest } => {
+                    let (from, level) = program.table(*cursor);
+                    let name = &from.sources[level].schema.columns[*column].name;
+                    let shared = self.row_map(&program, from.first, from.sources.len()).get(name).cloned();
+                    let own = self.rows[*cursor].as_ref().map(|row| row.values[*column].clone());
+                    self.registers[*dest] = shared.or(own).unwrap_or(Value::Null);
+                }
+                Op::Using { cursor, target } => {
+                    if !self.using_matched(&program, *cursor) {
+                        self.pc = *target;
+                    }
+                }
+                Op::MarkMatched { cursor } => {
+                    if let Some(row) = &self.rows[*cursor] {
+                        self.matched[*cursor].insert(row.row_id);
+                    }
+                }
+                Op::IfMatched { cursor, target } => {
+                    let row_id = self.rows[*cursor].as_ref().map(|row| row.row_id);
+                    if row_id.is_some_and(|row_id| self.matched[*cursor].contains(&row_id)) {
+                        self.pc = *target;
+                    }
+                }
+                Op::Count { cursor, dest } => {
+                    let (from, level) = program.table(*cursor);
+                    let root_page = from.sources[level].schema.root_page as usize;
+                    self.registers[*dest] = Value::I64(btree::count_rows(&mut db.pager, root_page)? as i64);
+                }
+                Op::OpenEphemeral { cursor, collations } => {
+                    self.cursors[*cursor] = Cursor::Ephemeral(Ephemeral {
+                        index: collations.clone().map(EphemeralBtree::with_collations),
+                        rows: Vec::new(),
+                        position: 0,
+                    });
+                }
+                Op::Found { cursor, start, count, target } | Op::NotFound { cursor, start, count, target } => {
+                    let Cursor::Ephemeral(Ephemeral { index: Some(index), .. }) = &self.cursors[*cursor] else {
+                        unreachable!("Found in a cursor without an index");
+                    };
+                    let found = index.get(&self.registers[*start..*start + *count])?.is_some();
+                    if found == matches!(op, Op::Found { .. }) {
+                        self.pc = *target;
+                    }
+                }
+                Op::IdxInsert { cursor, start, count } => {
+                    let Cursor::Ephemeral(ephemeral) = &mut self.cursors[*cursor] else {
+                        unreachable!("IdxInsert into a cursor that isn't ephemeral");
+                    };
+                    let row = &self.registers[*start..*start + *count];
+                    match &mut ephemeral.index {
+                        Some(index) => {
+                            index.insert(row)?;
+                        }
+                        None => ephemeral.rows.push(row.to_vec()),
+                    }
+                }
+                Op::SorterOpen {
+                    cursor,
+                    descending,
+                    collations,
+                } => {
+                    let sorter = Sorter::new(descending.clone(), collations.clone(), db.sort_memory_limit);
+                    self.cursors[*cursor] = Cursor::Sorter(Sorting::Open(sorter));
+                }
+                Op::SorterInsert {
+                    cursor,
+                    keys,
+                    key_count,
+                    start,
+                    count,
+                } => {
+                    let Cursor::Sorter(Sorting::Open(sorter)) = &mut self.cursors[*cursor] else {
+                        unreachable!("SorterInsert into a sorted cursor");
+                    };
+                    let keys = self.registers[*keys..*keys + *key_count].to_vec();
+                    sorter.push(keys, self.registers[*start..*start + *count].to_vec())?;
+                }
+                Op::SorterSort { cursor, target } => {
+                    let cursor_state = std::mem::replace(&mut self.cursors[*cursor], Cursor::Closed);
+                    let Cursor::Sorter(Sorting::Open(sorter)) = cursor_state else {
+                        unreachable!("SorterSort of a sorted cursor");
+                    };
+                    let mut rows = sorter.finish()?;
+                    let row = rows.next().transpose()?;
+                    if row.is_none() {
+                        self.pc = *target;
+                    }
+                    self.cursors[*cursor] = Cursor::Sorter(Sorting::Sorted(rows, row));
+                }
+                Op::SorterNext { cursor, target } => {
+                    let Cursor::Sorter(Sorting::Sorted(rows, row)) = &mut self.cursors[*cursor] else {
+                        unreachable!("SorterNext of an unsorted cursor");
+                    };
+                    *row = rows.next().transpose()?;
+                    if row.is_some() {
+                        self.pc = *target;
+                    }
+                }
+                Op::AggStep {
+                    cursor,
+                    start,
+                    aggregation,
+                } => {
+                    let aggregation = &program.aggregations[*aggregation];
+                    let Cursor::Ephemeral(Ephemeral { index: Some(groups), .. }) = &mut self.cursors[*cursor] else {
+                        unreachable!("AggStep without an index of groups");
+                    };
+                    agg_step(groups, aggregation, &self.registers[*start..])?;
+                }
+                Op::AggInit { cursor, aggregation } => {
+                    let aggregation = &program.aggregations[*aggregation];
+                    let Cursor::Ephemeral(Ephemeral { index: Some(groups), .. }) = &mut self.cursors[*cursor] else {
+                        unreachable!("AggInit without an index of groups");
+                    };
+                    if groups.is_empty() {
+                        let mut entry = aggregation
+                            .aggregates
+                            .iter()
+                            .flat_map(|(aggregate, _)| aggregate.initial_state())
+                            .collect::<Vec<_>>();
+                        let rest = aggregation.order_len + aggregation.bare_columns.len() + aggregation.row_width;
+                        entry.resize(entry.len() + rest, Value::Null);
+                        groups.insert(&entry)?;
+                    }
+                }
+                Op::AggFinal {
+                    cursor,
+                    dest,
+                    aggregation,
+                } => {
+                    let aggregation = &program.aggregations[*aggregation];
+                    let Cursor::Ephemeral(ephemeral) = &self.cursors[*cursor] else {
+                        unreachable!("AggFinal without groups");
+                    };
+                    let entry = &ephemeral.rows[ephemeral.position];
+                    let mut values = Vec::new();
+                    let mut state = aggregation.group_len;
+                    for (aggregate, _) in &aggregation.aggregates {
+                        values.push(aggregate.finish(&entry[state..state + aggregate.state_width()])?);
+                        state += aggregate.state_width();
+                    }
+                    values.extend(entry[state..].iter().cloned());
+                    self.registers[*dest..*dest + values.len()].clone_from_slice(&values);
+                }
+                Op::AggEval {
+                    expr,
+                    start,
+                    aggregation,
+                    dest,
+                } => {
+                    let aggregation = &program.aggregations[*aggregation];
+                    let values = &self.registers[*start..*start + aggregation.aggregates.len()];
+                    let bare_start = *start + aggregation.aggregates.len() + aggregation.order_len;
+                    let bare_values = &self.registers[bare_start..bare_start + aggregation.bare_columns.len()];
+                    let row_map = aggregation
+                        .bare_columns
+                        .iter()
+                        .filter_map(row_map_key)
+                        .zip(bare_values.iter().cloned())
+                        .collect();
+                    let expr = replace_aggregates(expr, &aggregation.aggregates, values);
+                    self.registers[*dest] = evaluate(&expr, &row_map, &db.function_context)?;
+                }
+                Op::ResultRow { start, count } => return Ok(Some(self.registers[*start..*start + *count].to_vec())),
+            }
+        }
+    }
+
+    // Move the table cursor to its next row, false when it has none left
+    fn advance(&mut self, db: &mut Db, program: &Program, cursor: usize) -> anyhow::Result<bool> {
+        let (from, level) = program.table(cursor);
+        let source = &from.sources[level];
+        let Cursor::Table(walk) = &mut self.cursors[cursor] else {
+            unreachable!("a table cursor");
+        };
+        let row = loop {
+            match walk {
+                Walk::Scan(table_cursor) => {
+                    break table_cursor.next(&mut db.pager)?.map(|cell| SourceRow {
+                        row_id: cell.row_id,
+                        values: row_values(&cell, &source.schema),
+                    })
+                }
+                Walk::Rows(position) => {
+                    *position += 1;
+                    break source.rows.as_ref().and_then(|rows| rows.get(*position - 1)).cloned();
+                }
+                Walk::RowIds(row_ids) => {
+                    let Some(row_id) = row_ids.next() else {
+                        break None;
+                    };
+                    if let Some(cell) = seek_row(&mut db.pager, source.schema.root_page as usize, row_id)? {
+                        break Some(SourceRow {
+                            row_id,
+                            values: row_values(&cell, &source.schema),
+                        });
+                    }
+                }
+                Walk::Candidates(rows) => break rows.next(),
+                Walk::Done => break None,
+            }
+        };
+        if row.is_none() {
+            *walk = Walk::Done;
+        }
+        self.rows[cursor] = row;
+        self.row_map = None;
+        Ok(self.rows[cursor].is_some())
+    }
+
+    // Column name -> value of the current rows of the first `tables` tables from `first`,
+    // see `row_map`, with the values the subqueries bound
+    fn row_map(&mut self, program: &Program, first: usize, tables: usize) -> &HashMap<String, Value> {
+        let is_cached = matches!(&self.row_map, Some((cursor, count, _)) if (*cursor, *count) == (first, tables));
+        if !is_cached {
+            let (from, _) = program.table(first);
+            let mut map = row_map(&from.sources[..tables], &self.rows[first..first + tables]);
+            map.extend(self.bindings.iter().map(|(name, value)| (name.clone(), value.clone())));
+            self.row_map = Some((first, tables, map));
+        }
+        &self.row_map.as_ref().unwrap().2
+    }
+
+    // USING columns are compared with the column of the same name on the left
+    fn using_matched(&mut self, program: &Program, cursor: usize) -> bool {
+        let (from, level) = program.table(cursor);
+        let source = &from.sources[level];
+        let outer_row_map = self.row_map(program, from.first, level);
+        let lefts = source.using.iter().map(|column| outer_row_map.get(column).cloned()).collect::<Vec<_>>();
+        let row_map = self.row_map(program, from.first, level + 1);
+        let qualifier = source.table.qualifier().to_lowercase();
+        source.using.iter().zip(lefts).all(|(column, left)| {
+            let right = row_map.get(&format!("{}.{}", qualifier, column));
+            let (left_affinity, right_affinity) = using_affinities(&from.sources, level, column);
+            let with = |value: &Value, affinity: Option<Affinity>| match affinity {
+                Some(affinity) => value.with_affinity(affinity),
+                None => value.clone(),
+            };
+            match (left, right) {
+                (Some(left), Some(right)) => {
+                    let (left, right) = (with(&left, left_affinity), with(right, right_affinity));
+                    compare(&left, &right, Collation::Binary) == Some(Ordering::Equal)
+                }
+                _ => false,
+            }
+        })
+    }
+
+    // The rows of the first of two tables, each passing the WHERE terms on it alone, keyed
+    // by the expressions the second table's columns equal
+    fn outer_hash_join(&mut self, db: &mut Db, program: &Program, cursor: usize) -> anyhow::Result<HashJoin> {
+        let (from, _) = program.table(cursor);
+        let outer = &from.sources[0];
+        let null_row_map = row_map(&from.sources[..1], &[None]);
+        let keys = hash_join_keys(&from.sources, 1, &from.early_terms(1), &null_row_map);
+        let outer_rows = match db.probe_row_ids(outer, false, from.where_clause.as_ref())? {
+            Some(row_ids) => {
+                let mut rows = Vec::new();
+                for row_id in row_ids {
+                    if let Some(cell) = seek_row(&mut db.pager, outer.schema.root_page as usize, row_id)? {
+                        let values = row_values(&cell, &outer.schema);
+                        rows.push(SourceRow { row_id, values });
+                    }
+                }
+                rows
+            }
+            None => all_rows(db, outer)?,
+        };
+        let mut rows = HashMap::<_, Vec<_>>::new();
+        'outer: for row in outer_rows {
+            let outer_row_map = row_map(&from.sources[..1], &[Some(row.clone())]);
+            for term in &from.early_terms[0] {
+                if truth(&evaluate(term, &outer_row_map, &db.function_context)?) != Some(true) {
+                    continue 'outer;
+                }
+            }
+            let mut key = Vec::new();
+            for join_key in &keys {
+                // NULL equals nothing
+                match join_key.outer(&outer_row_map, &db.function_context)? {
+                    Some(part) => key.push(part),
+                    None => continue 'outer,
+                }
+            }
+            rows.entry(key).or_default().push(row);
+        }
+        Ok(HashJoin { keys, rows })
+    }
+}
+
+// A walk over every row of the table `cursor` reads
+fn scan(program: &Program, cursor: usize) -> Walk {
+    let (from, level) = program.table(cursor);
+    let source = &from.sources[level];
+    match source.rows {
+        Some(_) => Walk::Rows(0),
+        None => Walk::Scan(TableCursor::new(source.schema.root_page as usize)),
+    }
+}
+
+fn all_rows(db: &mut Db, source: &Source) -> anyhow::Result<Vec<SourceRow>> {
+    if let Some(rows) = &source.rows {
+        return Ok(rows.clone());
+    }
+    let mut cursor = TableCursor::new(source.schema.root_page as usize);
+    let mut rows = Vec::new();
+    while let Some(cell) = cursor.next(&mut db.pager)? {
+        let values = row_values(&cell, &source.schema);
+        rows.push(SourceRow {
+            row_id: cell.row_id,
+            values,
+        });
+    }
+    Ok(rows)
+}
+
+// Step the aggregates of the group whose key leads `registers`, laid out like AggStep takes
+// them. The group takes the ORDER BY keys, bare columns and row of its first row, or of
+// the row holding the value of its one min() or max()
+fn agg_step(groups: &mut EphemeralBtree, aggregation: &Aggregation, registers: &[Value]) -> anyhow::Result<()> {
+    let group_len = aggregation.group_len;
+    let group = &registers[..group_len];
+    let args = &registers[group_len..group_len + aggregation.aggregates.len()];
+    let rest_len = aggregation.order_len + aggregation.bare_columns.len() + aggregation.row_width;
+    let rest_start = group_len + aggregation.aggregates.len();
+    let (mut entry, is_new) = match groups.get(group)? {
+        Some(entry) => (entry, false),
+        None => {
+            let states = aggregation.aggregates.iter().flat_map(|(aggregate, _)| aggregate.initial_state());
+            (group.iter().cloned().chain(states).collect::<Vec<_>>(), true)
+        }
+    };
+    let mut takes_row = is_new;
+    let mut start = group_len;
+    for (((aggregate, _), arg), collation) in aggregation.aggregates.iter().zip(args).zip(&aggregation.collations) {
+        let state = &mut entry[start..start + aggregate.state_width()];
+        takes_row |= aggregate.step(state, arg, *collation) && aggregation.from_extreme_row;
+        start += aggregate.state_width();
+    }
+    if takes_row {
+        entry.truncate(group_len + aggregation.states_len());
+        entry.extend(registers[rest_start..rest_start + rest_len].iter().cloned());
+    }
+    groups.replace(&entry)
+}
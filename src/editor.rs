@@ -0,0 +1,285 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Reads the lines typed at the shell prompt, with the cursor keys to move along the
+// line and step through the history. The terminal is put in raw mode with stty while a
+// line is read, without stty lines are read as they are
+pub struct LineEditor {
+    history: Vec<String>,
+    // Each entry is appended as it is added, so other sessions see it too
+    history_path: Option<PathBuf>,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    // Ctrl-U
+    KillLine,
+    // Ctrl-C
+    Interrupt,
+    // Ctrl-D
+    Eof,
+    Ignored,
+}
+
+impl LineEditor {
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let history = history_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().filter(|line| !line.is_empty()).map(unescape).collect())
+            .unwrap_or_default();
+        LineEditor { history, history_path }
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    // A statement over several lines is kept as it was typed, on one line of the file
+    pub fn add_history(&mut self, entry: &str) {
+        let entry = entry.trim().to_string();
+        if entry.is_empty() || self.history.last() == Some(&entry) {
+            return;
+        }
+        if let Some(path) = &self.history_path {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", escape(&entry));
+            }
+        }
+        self.history.push(entry);
+    }
+
+    // The next line without its newline, None at the end of the input. Ctrl-C gives
+    // an Interrupted error
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let Some(_raw) = RawMode::enter() else {
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()));
+        };
+
+        let mut line = Vec::<char>::new();
+        let mut cursor = 0;
+        // The history entry shown, history.len() for the line being typed
+        let mut shown = self.history.len();
+        let mut typed = Vec::new();
+        loop {
+            let key = read_key()?;
+            match key {
+                Key::Enter => {
+                    print!("\r\n");
+                    return Ok(Some(line.into_iter().collect()));
+                }
+                Key::Eof if line.is_empty() => {
+                    print!("\r\n");
+                    return Ok(None);
+                }
+                Key::Interrupt => {
+                    print!("^C\r\n");
+                    return Err(io::Error::from(io::ErrorKind::Interrupted));
+                }
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete | Key::Eof if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillLine => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                // Up steps back through the history, Down forward to the line being typed
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up if shown > 0 => shown - 1,
+                        Key::Down if shown < self.history.len() => shown + 1,
+                        _ => continue,
+                    };
+                    if shown == self.history.len() {
+                        typed = line.clone();
+                    }
+                    shown = next;
+                    line = match self.history.get(shown) {
+                        Some(entry) => entry.chars().collect(),
+                        None => typed.clone(),
+                    };
+                    cursor = line.len();
+                }
+                _ => continue,
+            }
+            redraw(prompt, &line, cursor)?;
+        }
+    }
+}
+
+fn redraw(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    // An entry of the history over several lines is shown on one, a space for each break
+    let text = line.iter().map(|&c| if c == '\n' || c == '\r' { ' ' } else { c }).collect::<String>();
+    // Back to the start, the prompt and the line, clear what is left of the old one
+    print!("\r{}{}\x1b[K", prompt, text);
+    let after = line.len() - cursor;
+    if after > 0 {
+        print!("\x1b[{}D", after);
+    }
+    io::stdout().flush()
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match io::stdin().read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn read_key() -> io::Result<Key> {
+    let Some(byte) = read_byte()? else {
+        return Ok(Key::Eof);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x15 => Key::KillLine,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        // ESC [ A and the like
+        0x1b => match (read_byte()?, read_byte()?) {
+            (Some(b'['), Some(b'A')) => Key::Up,
+            (Some(b'['), Some(b'B')) => Key::Down,
+            (Some(b'['), Some(b'C')) => Key::Right,
+            (Some(b'['), Some(b'D')) => Key::Left,
+            (Some(b'['), Some(b'H')) => Key::Home,
+            (Some(b'['), Some(b'F')) => Key::End,
+            (Some(b'['), Some(b'3')) => match read_byte()? {
+                Some(b'~') => Key::Delete,
+                _ => Key::Ignored,
+            },
+            _ => Key::Ignored,
+        },
+        byte if byte < 0x20 => Key::Ignored,
+        byte => {
+            // The rest of a multi-byte character
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            while bytes.len() < len {
+                match read_byte()? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Ignored,
+            }
+        }
+    };
+    Ok(key)
+}
+
+// An entry as a line of the history file, its line breaks and backslashes escaped
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// The entry a line of the history file holds, see `escape`
+fn unescape(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            entry.push(c);
+            continue;
+        }
+        entry.push(match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some(c) => c,
+            None => '\\',
+        });
+    }
+    entry
+}
+
+// The terminal settings from before raw mode, put back when dropped
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enter() -> Option<Self> {
+        let saved = stty(&["-g"])?;
+        // Byte by byte, not echoed, and Ctrl-C read as a key instead of a signal
+        stty(&["-icanon", "-echo", "-isig", "-icrnl", "min", "1"])?;
+        Some(RawMode { saved: saved.trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+// Runs stty on the terminal, its output if it succeeded
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = File::open("/dev/tty").ok()?;
+    let output = Command::new("stty").args(args).stdin(tty).stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_over_several_lines_read_back_as_typed() {
+        let entries = [
+            "SELECT 1 -- the first\nFROM t",
+            "SELECT 'a\nb', \"c  d\"\tFROM [e  f]\r\nWHERE x = 'it''s  '",
+            "SELECT '\\n', '\\' FROM t\\",
+        ];
+        for entry in entries {
+            let line = escape(entry);
+            assert!(!line.contains(['\n', '\r']), "{}", line);
+            assert_eq!(unescape(&line), entry);
+        }
+        // Lines written before entries were escaped read back as they are
+        assert_eq!(unescape("SELECT 'a  b' FROM t"), "SELECT 'a  b' FROM t");
+    }
+}
@@ -0,0 +1,429 @@
+use std::cmp::Ordering;
+
+use crate::{
+    page::{
+        index_max_local, local_payload_size, read_overflow, PageHeader, INDEX_INTERIOR_PAGE_ID,
+        INDEX_LEAF_PAGE_ID, PAGE_CELL_CONTENT_OFFSET, PAGE_CELL_COUNT_OFFSET,
+        PAGE_INTERIOR_HEADER_SIZE, PAGE_LEAF_HEADER_SIZE, PAGE_RIGHT_MOST_POINTER_OFFSET,
+    },
+    collation::{compare_keys, Collation},
+    record::{Record, Value},
+    utils::{read_be_word_at, read_varint, write_varint},
+};
+
+const PAGE_SIZE: usize = 65_536;
+// Page 1 would start with the database header, so numbering starts at 2
+const FIRST_PAGE: u32 = 2;
+
+// In-memory index b-tree, like the ephemeral tables SQLite opens for DISTINCT and
+// GROUP BY. Pages use the on-disk index page format and cells are decoded with the
+// regular index cell parsers, so pages could be written out to a temp file as they are.
+// Each entry is a record whose first `key_len` fields are the key, the rest is
+// state carried along with it.
+#[derive(Debug, PartialEq)]
+pub struct EphemeralBtree {
+    key_len: usize,
+    // Of each key field, text that compares equal under them is the same key
+    collations: Vec<Collation>,
+    pages: Vec<Vec<u8>>,
+    root: u32,
+}
+
+enum Insert {
+    Present,
+    Done,
+    // The page was split, `right_page` holds the entries after `separator`,
+    // a leaf cell that moves up into the parent
+    Split { separator: Vec<u8>, right_page: u32 },
+}
+
+impl EphemeralBtree {
+    // One key field for each collation
+    pub fn with_collations(collations: Vec<Collation>) -> Self {
+        let root = build_page(INDEX_LEAF_PAGE_ID, &[], None);
+        EphemeralBtree {
+            key_len: collations.len(),
+            collations,
+            pages: vec![root],
+            root: FIRST_PAGE,
+        }
+    }
+
+    // Add the entry unless one with the same key exists, returns whether it was added
+    pub fn insert(&mut self, entry: &[Value]) -> anyhow::Result<bool> {
+        self.insert_root(entry, false)
+    }
+
+    // Add the entry, overwriting the one with the same key if any
+    pub fn replace(&mut self, entry: &[Value]) -> anyhow::Result<()> {
+        self.insert_root(entry, true)?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[Value]) -> anyhow::Result<Option<Vec<Value>>> {
+        let mut page_num = self.root;
+        loop {
+            let page = self.page(page_num);
+            match self.search(page, key)? {
+                Ok(i) => return Ok(Some(self.cell_entry(page, i)?)),
+                Err(_) if is_leaf(page) => return Ok(None),
+                Err(i) => page_num = child(page, i),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        cell_count(self.page(self.root)) == 0
+    }
+
+    // All entries in key order
+    pub fn entries(&self) -> anyhow::Result<Vec<Vec<Value>>> {
+        let mut entries = Vec::new();
+        self.collect_entries(self.root, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect_entries(&self, page_num: u32, entries: &mut Vec<Vec<Value>>) -> anyhow::Result<()> {
+        let page = self.page(page_num);
+        let count = cell_count(page);
+        for i in 0..count {
+            // Interior cells hold entries of their own, after everything in their left child
+            if !is_leaf(page) {
+                self.collect_entries(child(page, i), entries)?;
+            }
+            entries.push(self.cell_entry(page, i)?);
+        }
+        if !is_leaf(page) {
+            self.collect_entries(child(page, count), entries)?;
+        }
+        Ok(())
+    }
+
+    fn insert_root(&mut self, entry: &[Value], overwrite: bool) -> anyhow::Result<bool> {
+        let cell = self.leaf_cell(entry);
+        match self.insert_into(self.root, entry, &cell, overwrite)? {
+            Insert::Present => Ok(false),
+            Insert::Done => Ok(true),
+            Insert::Split {
+                separator,
+                right_page,
+            } => {
+                // The tree grows a level: a new root pointing at both halves
+                let cell = interior_cell(self.root, &separator);
+                let root = build_page(INDEX_INTERIOR_PAGE_ID, &[cell], Some(right_page));
+                self.root = self.push_page(root);
+                Ok(true)
+            }
+        }
+    }
+
+    fn insert_into(
+        &mut self,
+        page_num: u32,
+        entry: &[Value],
+        cell: &[u8],
+        overwrite: bool,
+    ) -> anyhow::Result<Insert> {
+        let page = self.page(page_num);
+        let leaf = is_leaf(page);
+        match self.search(page, entry)? {
+            Ok(_) if !overwrite => Ok(Insert::Present),
+            Ok(i) => {
+                let new_cell = if leaf {
+                    cell.to_vec()
+                } else {
+                    interior_cell(child(page, i), cell)
+                };
+                remove_cell(self.page_mut(page_num), i);
+                self.insert_cell(page_num, i, new_cell)
+            }
+            Err(i) if leaf => self.insert_cell(page_num, i, cell.to_vec()),
+            Err(i) => {
+                let child_page = child(page, i);
+                match self.insert_into(child_page, entry, cell, overwrite)? {
+                    Insert::Split {
+                        separator,
+                        right_page,
+                    } => {
+                        // The child keeps the entries before the separator
+                        set_child(self.page_mut(page_num), i, right_page);
+                        let new_cell = interior_cell(child_page, &separator);
+                        self.insert_cell(page_num, i, new_cell)
+                    }
+                    insert => Ok(insert),
+                }
+            }
+        }
+    }
+
+    // Put the cell at position `i`, splitting the page if it doesn't fit
+    fn insert_cell(&mut self, page_num: u32, i: usize, cell: Vec<u8>) -> anyhow::Result<Insert> {
+        let page = self.page_mut(page_num);
+        if free_space(page) < cell.len() + 2 {
+            // Reclaim the space of removed cells
+            *page = build_page(page[0], &cells(page)?, right_most(page));
+        }
+        if free_space(page) >= cell.len() + 2 {
+            place_cell(page, i, &cell);
+            return Ok(Insert::Done);
+        }
+
+        let page_type = page[0];
+        let right_most = right_most(page);
+        let mut cells = cells(page)?;
+        cells.insert(i, cell);
+        // Split by bytes rather than by count so both halves are sure to fit
+        let total = cells.iter().map(Vec::len).sum::<usize>();
+        let mut mid = 0;
+        let mut left_bytes = 0;
+        while left_bytes + cells[mid].len() < total / 2 {
+            left_bytes += cells[mid].len();
+            mid += 1;
+        }
+        let separator = cells.remove(mid);
+        let (left, right) = cells.split_at(mid);
+        // The separator's left child becomes the right-most child of the left half
+        let (left_right_most, separator) = match right_most {
+            Some(_) => (Some(read_u32(&separator)), separator[4..].to_vec()),
+            None => (None, separator),
+        };
+        *page = build_page(page_type, left, left_right_most);
+        let right_page = build_page(page_type, right, right_most);
+        let right_page = self.push_page(right_page);
+        Ok(Insert::Split {
+            separator,
+            right_page,
+        })
+    }
+
+    // Binary search the page for the key, `Err` holds the child to descend into
+    fn search(&self, page: &[u8], key: &[Value]) -> anyhow::Result<Result<usize, usize>> {
+        let (mut low, mut high) = (0, cell_count(page));
+        while low < high {
+            let mid = (low + high) / 2;
+            let entry = self.cell_entry(page, mid)?;
+            let len = self.key_len.min(key.len());
+            match compare_keys(&entry[..len], &key[..len], &self.collations) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    // A record of the entry, the part past what fits on a b-tree page spilled to overflow
+    // pages like in a b-tree on disk. Those of an entry that is replaced are not reused
+    fn leaf_cell(&mut self, entry: &[Value]) -> Vec<u8> {
+        let payload = Record::serialize(entry);
+        let mut cell = Vec::new();
+        write_varint(payload.len() as u64, &mut cell);
+        let (local, _) = local_size(payload.len());
+        cell.extend_from_slice(&payload[..local]);
+        if local == payload.len() {
+            return cell;
+        }
+        // Each overflow page holds a pointer to the next one and page size - 4 bytes, the
+        // pages of the chain are pushed one after the other
+        let first_page = self.pages.len() as u32 + FIRST_PAGE;
+        cell.extend_from_slice(&first_page.to_be_bytes());
+        let chunks = payload[local..].chunks(PAGE_SIZE - 4).collect::<Vec<_>>();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = if i + 1 < chunks.len() { first_page + i as u32 + 1 } else { 0 };
+            let mut page = vec![0; PAGE_SIZE];
+            page[0..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+            self.push_page(page);
+        }
+        cell
+    }
+
+    fn cell_entry(&self, page: &[u8], i: usize) -> anyhow::Result<Vec<Value>> {
+        let cell = &page[cell_offset(page, i)..];
+        // Interior cells start with the left child
+        let cell = if is_leaf(page) { cell } else { &cell[4..] };
+        let (n, payload_size) = read_varint(cell)?;
+        let payload_size = payload_size as usize;
+        let (local, spilled) = local_size(payload_size);
+        if !spilled {
+            return Ok(Record::parse(&cell[n..n + local])?.values());
+        }
+        let first_page = read_u32(&cell[n + local..]);
+        let mut read_page = |page_num: usize| Ok(self.page(page_num as u32).to_vec());
+        let payload = read_overflow(&cell[n..n + local], first_page, payload_size, PAGE_SIZE, &mut read_page)?;
+        Ok(Record::parse(&payload)?.values())
+    }
+
+    fn page(&self, page_num: u32) -> &[u8] {
+        &self.pages[(page_num - FIRST_PAGE) as usize]
+    }
+
+    fn page_mut(&mut self, page_num: u32) -> &mut Vec<u8> {
+        &mut self.pages[(page_num - FIRST_PAGE) as usize]
+    }
+
+    fn push_page(&mut self, page: Vec<u8>) -> u32 {
+        self.pages.push(page);
+        self.pages.len() as u32 - 1 + FIRST_PAGE
+    }
+}
+
+// The bytes of a payload kept in its cell, and whether the rest spilled to overflow pages
+fn local_size(payload_size: usize) -> (usize, bool) {
+    let local = local_payload_size(payload_size, index_max_local(PAGE_SIZE), PAGE_SIZE);
+    (local, local < payload_size)
+}
+
+fn interior_cell(left_child: u32, leaf_cell: &[u8]) -> Vec<u8> {
+    let mut cell = left_child.to_be_bytes().to_vec();
+    cell.extend_from_slice(leaf_cell);
+    cell
+}
+
+fn is_leaf(page: &[u8]) -> bool {
+    page[0] == INDEX_LEAF_PAGE_ID
+}
+
+fn header_size(page: &[u8]) -> usize {
+    if is_leaf(page) {
+        PAGE_LEAF_HEADER_SIZE
+    } else {
+        PAGE_INTERIOR_HEADER_SIZE
+    }
+}
+
+fn cell_count(page: &[u8]) -> usize {
+    read_be_word_at(page, PAGE_CELL_COUNT_OFFSET) as usize
+}
+
+fn cell_offset(page: &[u8], i: usize) -> usize {
+    read_be_word_at(page, header_size(page) + i * 2) as usize
+}
+
+// Raw bytes of every cell, in key order
+fn cells(page: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let child_size = if is_leaf(page) { 0 } else { 4 };
+    (0..cell_count(page))
+        .map(|i| {
+            let start = cell_offset(page, i);
+            let (n, payload_size) = read_varint(&page[start + child_size..])?;
+            // A spilled payload ends with the first overflow page
+            let (local, spilled) = local_size(payload_size as usize);
+            let end = start + child_size + n + local + if spilled { 4 } else { 0 };
+            Ok(page[start..end].to_vec())
+        })
+        .collect()
+}
+
+// Left child of cell `i`, or the right-most child past the last cell
+fn child(page: &[u8], i: usize) -> u32 {
+    if i < cell_count(page) {
+        read_u32(&page[cell_offset(page, i)..])
+    } else {
+        read_u32(&page[PAGE_RIGHT_MOST_POINTER_OFFSET..])
+    }
+}
+
+fn set_child(page: &mut [u8], i: usize, page_num: u32) {
+    let offset = if i < cell_count(page) {
+        cell_offset(page, i)
+    } else {
+        PAGE_RIGHT_MOST_POINTER_OFFSET
+    };
+    page[offset..offset + 4].copy_from_slice(&page_num.to_be_bytes());
+}
+
+fn right_most(page: &[u8]) -> Option<u32> {
+    (!is_leaf(page)).then(|| child(page, cell_count(page)))
+}
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    u32::from_be_bytes(buffer[0..4].try_into().unwrap())
+}
+
+// Gap between the cell pointer array and the cell content area
+fn free_space(page: &[u8]) -> usize {
+    let header = PageHeader::parse(page, 0).unwrap();
+    let pointers_end = header.get_header_size() + cell_count(page) * 2;
+    header.get_cell_content_offset() as usize - pointers_end
+}
+
+fn place_cell(page: &mut [u8], i: usize, cell: &[u8]) {
+    let header = PageHeader::parse(page, 0).unwrap();
+    let count = cell_count(page);
+    let content_offset = header.get_cell_content_offset() as usize - cell.len();
+    page[content_offset..content_offset + cell.len()].copy_from_slice(cell);
+    let pointer = header.get_header_size() + i * 2;
+    let pointers_end = header.get_header_size() + count * 2;
+    page.copy_within(pointer..pointers_end, pointer + 2);
+    page[pointer..pointer + 2].copy_from_slice(&(content_offset as u16).to_be_bytes());
+    set_header(page, count + 1, content_offset);
+}
+
+// Drop the pointer to cell `i`, its bytes stay behind until the page is rebuilt
+fn remove_cell(page: &mut [u8], i: usize) {
+    let header = PageHeader::parse(page, 0).unwrap();
+    let count = cell_count(page);
+    let pointer = header.get_header_size() + i * 2;
+    let pointers_end = header.get_header_size() + count * 2;
+    page.copy_within(pointer + 2..pointers_end, pointer);
+    set_header(page, count - 1, header.get_cell_content_offset() as usize);
+}
+
+fn set_header(page: &mut [u8], cell_count: usize, content_offset: usize) {
+    page[PAGE_CELL_COUNT_OFFSET..PAGE_CELL_COUNT_OFFSET + 2]
+        .copy_from_slice(&(cell_count as u16).to_be_bytes());
+    // A content area starting at 65536 is stored as 0
+    page[PAGE_CELL_CONTENT_OFFSET..PAGE_CELL_CONTENT_OFFSET + 2]
+        .copy_from_slice(&(content_offset as u16).to_be_bytes());
+}
+
+// Lay the cells out from the end of a fresh page
+fn build_page(page_type: u8, cells: &[Vec<u8>], right_most: Option<u32>) -> Vec<u8> {
+    let mut page = vec![0; PAGE_SIZE];
+    page[0] = page_type;
+    if let Some(right_most) = right_most {
+        page[PAGE_RIGHT_MOST_POINTER_OFFSET..PAGE_RIGHT_MOST_POINTER_OFFSET + 4]
+            .copy_from_slice(&right_most.to_be_bytes());
+    }
+    set_header(&mut page, 0, PAGE_SIZE);
+    for (i, cell) in cells.iter().enumerate() {
+        place_cell(&mut page, i, cell);
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(fill: u8, len: usize) -> Value {
+        Value::Blob(vec![fill; len])
+    }
+
+    #[test]
+    fn large_entries_spill_to_overflow_pages() {
+        let mut btree = EphemeralBtree::with_collations(vec![Collation::Binary]);
+        // Past what a 64KB page keeps in a cell, and long enough to take several pages
+        for fill in [3, 1, 2] {
+            assert!(btree.insert(&[blob(fill, 100_000), Value::I64(fill as i64)]).unwrap());
+            assert!(btree.insert(&[blob(fill, 300_000), Value::I64(fill as i64 * 10)]).unwrap());
+        }
+        assert!(!btree.insert(&[blob(2, 100_000), Value::Null]).unwrap());
+        btree.replace(&[blob(2, 300_000), Value::I64(-1)]).unwrap();
+        assert_eq!(btree.get(&[blob(2, 300_000)]).unwrap(), Some(vec![blob(2, 300_000), Value::I64(-1)]));
+        let states = btree.entries().unwrap().into_iter().map(|entry| entry[1].clone()).collect::<Vec<_>>();
+        let expected = [1, 10, 2, -1, 3, 30].map(Value::I64);
+        assert_eq!(states, expected);
+    }
+
+    #[test]
+    fn splits_keep_every_entry_in_order() {
+        let mut btree = EphemeralBtree::with_collations(vec![Collation::Binary]);
+        for i in (0..2000).rev() {
+            btree.insert(&[Value::I64(i), Value::String("x".repeat(i as usize % 40_000))]).unwrap();
+        }
+        let keys = btree.entries().unwrap().into_iter().map(|entry| entry[0].clone()).collect::<Vec<_>>();
+        assert_eq!(keys, (0..2000).map(Value::I64).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,59 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::sql::error::ParseError;
+
+// What the library's calls fail with, by kind. The engine itself works with anyhow
+// errors, the kinds below are raised inside it as one of these and picked back out at
+// the boundary, anything else becomes `Other` with its message
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    // The file isn't a database, or a page of it doesn't read as one
+    #[error("{0}")]
+    CorruptPage(String),
+    #[error("no such table: {0}")]
+    NoSuchTable(String),
+    #[error("no such column: {0}")]
+    NoSuchColumn(String),
+    // A value of the wrong storage class, like text for a rowid or read as an i64
+    #[error("{0}")]
+    TypeMismatch(String),
+    // A UNIQUE constraint failed
+    #[error("{0}")]
+    Constraint(String),
+    // A call the library doesn't accept, like binding a parameter the statement doesn't have
+    #[error("{0}")]
+    Misuse(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<ParseError>() {
+            Ok(error) => return Error::Parse(error),
+            Err(error) => error,
+        };
+        // The message of the context an I/O error is wrapped in says what was being done
+        if error.downcast_ref::<io::Error>().is_some() {
+            let message = error.to_string();
+            let io_error = error.downcast::<io::Error>().unwrap();
+            return match io_error.to_string() == message {
+                true => Error::Io(io_error),
+                false => Error::Io(io::Error::new(io_error.kind(), format!("{}: {}", message, io_error))),
+            };
+        }
+        Error::Other(error.to_string())
+    }
+}
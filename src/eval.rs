@@ -0,0 +1,356 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{
+    collation::Collation,
+    db::aggregate_call,
+    error::Error,
+    functions::{self, FunctionContext},
+    record::{Affinity, Value},
+    sql::{
+        parser::{Expr, Literal},
+        token::TokenType,
+    },
+};
+
+// Expressions evaluated on typed values the way SQLite does: its comparisons, arithmetic,
+// three-valued logic and function calls
+// https://www.sqlite.org/lang_expr.html
+
+// Whether `where_expr` holds for the row, NULL doesn't
+pub fn check(where_expr: &Expr, row_map: &HashMap<String, Value>, ctx: &FunctionContext) -> anyhow::Result<bool> {
+    Ok(truth(&evaluate(where_expr, row_map, ctx)?) == Some(true))
+}
+
+// A value is true when it is a number other than 0, text is read as a number. NULL is
+// neither true nor false
+pub fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        value => Some(value.to_f64() != 0.0),
+    }
+}
+
+pub fn is_comparison(op: &TokenType) -> bool {
+    matches!(
+        op,
+        TokenType::Equal
+            | TokenType::NotEqual
+            | TokenType::Less
+            | TokenType::LessEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+    )
+}
+
+// Comparing with NULL is never true, otherwise values of different storage classes
+// order NULL, numbers, text, blobs
+pub fn compare(left: &Value, right: &Value, collation: Collation) -> Option<Ordering> {
+    if left.is_null() || right.is_null() {
+        return None;
+    }
+    Some(collation.compare(left, right))
+}
+
+// The collation of a comparison between two operands, see apply_collations in db.rs
+fn comparison_collation(left: &Expr, right: &Expr) -> anyhow::Result<Collation> {
+    match (left, right) {
+        (Expr::Collate(_, name), _) | (_, Expr::Collate(_, name)) => Collation::from_name(name),
+        _ => Ok(Collation::Binary),
+    }
+}
+
+// The value of `expr` for one row, with the row's columns by name in `row_map`. Select
+// lists, WHERE, ON, HAVING and ORDER BY all evaluate through here
+pub fn evaluate(
+    expr: &Expr,
+    row_map: &HashMap<String, Value>,
+    ctx: &FunctionContext,
+) -> anyhow::Result<Value> {
+    match expr {
+        // Column names are case-insensitive
+        Expr::Identifier(name) => match row_map.get(&name.to_lowercase()) {
+            Some(value) => Ok(value.clone()),
+            None => anyhow::bail!(Error::NoSuchColumn(name.clone())),
+        },
+        Expr::QualifiedIdentifier(table, name) => {
+            let key = format!("{}.{}", table, name).to_lowercase();
+            match row_map.get(&key) {
+                Some(value) => Ok(value.clone()),
+                None => anyhow::bail!(Error::NoSuchColumn(format!("{}.{}", table, name))),
+            }
+        }
+        Expr::Aliased(expr, _) => evaluate(expr, row_map, ctx),
+        Expr::Literal(literal) => Ok(literal_value(literal)),
+        // NULL is unknown: FALSE AND NULL is FALSE, TRUE OR NULL is TRUE, otherwise an
+        // unknown side makes the result NULL. The right side is only evaluated when needed
+        Expr::BinaryOp(left, op, right) if matches!(op.token_type, TokenType::And | TokenType::Or) => {
+            let decisive = op.token_type == TokenType::Or;
+            let left = truth(&evaluate(left, row_map, ctx)?);
+            if left == Some(decisive) {
+                return Ok(Value::I64(decisive as i64));
+            }
+            let right = truth(&evaluate(right, row_map, ctx)?);
+            Ok(match (left, right) {
+                (_, Some(right)) if right == decisive => Value::I64(decisive as i64),
+                (Some(_), Some(_)) => Value::I64(!decisive as i64),
+                _ => Value::Null,
+            })
+        }
+        // Like = except that NULL IS NULL is true, and NULL IS anything else false
+        Expr::BinaryOp(left, op, right) if op.token_type == TokenType::Is => {
+            let collation = comparison_collation(left, right)?;
+            let left = evaluate(left, row_map, ctx)?;
+            let right = evaluate(right, row_map, ctx)?;
+            let same = match (left.is_null(), right.is_null()) {
+                (false, false) => collation.compare(&left, &right).is_eq(),
+                (left_null, right_null) => left_null && right_null,
+            };
+            Ok(Value::I64(same as i64))
+        }
+        Expr::BinaryOp(left_expr, op, right_expr) => {
+            let left = evaluate(left_expr, row_map, ctx)?;
+            let right = evaluate(right_expr, row_map, ctx)?;
+            if is_comparison(&op.token_type) {
+                // 1 or 0, NULL when either side is NULL
+                let collation = comparison_collation(left_expr, right_expr)?;
+                return Ok(compare(&left, &right, collation).map_or(Value::Null, |ordering| {
+                    let holds = match op.token_type {
+                        TokenType::Equal => ordering.is_eq(),
+                        TokenType::NotEqual => ordering.is_ne(),
+                        TokenType::Less => ordering.is_lt(),
+                        TokenType::LessEqual => ordering.is_le(),
+                        TokenType::Greater => ordering.is_gt(),
+                        _ => ordering.is_ge(),
+                    };
+                    Value::I64(holds as i64)
+                }));
+            }
+            match op.token_type {
+                TokenType::Plus
+                | TokenType::Minus
+                | TokenType::Star
+                | TokenType::Slash
+                | TokenType::Percent => Ok(arithmetic(&op.token_type, &left, &right)),
+                TokenType::Ampersand
+                | TokenType::Pipe
+                | TokenType::ShiftLeft
+                | TokenType::ShiftRight => Ok(bitwise(&op.token_type, &left, &right)),
+                _ => anyhow::bail!("Unsupported operator: {}", op.lexeme),
+            }
+        }
+        // NULL when nothing matches but the list has a NULL, which might have matched. An
+        // empty list holds nothing, not even an unknown value
+        Expr::InList(left, list) => {
+            let collation = comparison_collation(left, left)?;
+            let left = evaluate(left, row_map, ctx)?;
+            if list.is_empty() {
+                return Ok(Value::I64(0));
+            }
+            if left.is_null() {
+                return Ok(Value::Null);
+            }
+            let mut has_null = false;
+            for item in list {
+                let item = evaluate(item, row_map, ctx)?;
+                match compare(&left, &item, collation) {
+                    Some(Ordering::Equal) => return Ok(Value::I64(1)),
+                    Some(_) => (),
+                    None => has_null = true,
+                }
+            }
+            Ok(if has_null { Value::Null } else { Value::I64(0) })
+        }
+        // The same, looked up among a subquery's rows
+        Expr::InRows(left, rows) => {
+            let left = evaluate(left, row_map, ctx)?;
+            if rows.is_empty() {
+                return Ok(Value::I64(0));
+            }
+            if left.is_null() {
+                return Ok(Value::Null);
+            }
+            Ok(if rows.get(&[left])?.is_some() {
+                Value::I64(1)
+            } else if rows.get(&[Value::Null])?.is_some() {
+                Value::Null
+            } else {
+                Value::I64(0)
+            })
+        }
+        // value >= low AND value <= high
+        Expr::Between(value_expr, low, high) => {
+            let value = evaluate(value_expr, row_map, ctx)?;
+            let collation = comparison_collation(value_expr, low)?;
+            let above = compare(&value, &evaluate(low, row_map, ctx)?, collation).map(Ordering::is_ge);
+            let collation = comparison_collation(value_expr, high)?;
+            let below = compare(&value, &evaluate(high, row_map, ctx)?, collation).map(Ordering::is_le);
+            Ok(match (above, below) {
+                (Some(false), _) | (_, Some(false)) => Value::I64(0),
+                (Some(true), Some(true)) => Value::I64(1),
+                _ => Value::Null,
+            })
+        }
+        Expr::UnaryOp(op, operand) => match (&op.token_type, evaluate(operand, row_map, ctx)?) {
+            (_, Value::Null) => Ok(Value::Null),
+            (TokenType::Tilde, value) => Ok(Value::I64(!value.to_i64())),
+            (TokenType::Minus, value) => Ok(match value.to_numeric() {
+                // -(-9223372036854775808) doesn't fit and becomes a REAL
+                Value::I64(n) => n.checked_neg().map_or(Value::Float(-(n as f64)), Value::I64),
+                Value::Float(n) => Value::Float(-n),
+                value => value,
+            }),
+            // A no-op, text stays text
+            (TokenType::Plus, value) => Ok(value),
+            (TokenType::Not, value) => Ok(Value::I64((truth(&value) == Some(false)) as i64)),
+            _ => anyhow::bail!("Unsupported operator: {}", op.lexeme),
+        },
+        Expr::Case(operand_expr, branches, else_result) => {
+            let operand = match operand_expr {
+                Some(operand) => Some(evaluate(operand, row_map, ctx)?),
+                None => None,
+            };
+            for (when, then) in branches {
+                let matched = match (&operand, operand_expr) {
+                    // A NULL operand matches no WHEN
+                    (Some(operand), Some(operand_expr)) => {
+                        let collation = comparison_collation(operand_expr, when)?;
+                        compare(operand, &evaluate(when, row_map, ctx)?, collation) == Some(Ordering::Equal)
+                    }
+                    _ => check(when, row_map, ctx)?,
+                };
+                if matched {
+                    return evaluate(then, row_map, ctx);
+                }
+            }
+            match else_result {
+                Some(else_result) => evaluate(else_result, row_map, ctx),
+                None => Ok(Value::Null),
+            }
+        }
+        Expr::Cast(expr, type_name) => {
+            Ok(evaluate(expr, row_map, ctx)?.cast(Affinity::from_type_name(type_name)))
+        }
+        // The collation only matters to the comparison the value goes into
+        Expr::Collate(expr, name) => {
+            Collation::from_name(name)?;
+            evaluate(expr, row_map, ctx)
+        }
+        Expr::Affinity(expr, affinity) => Ok(evaluate(expr, row_map, ctx)?.with_affinity(*affinity)),
+        Expr::FunctionCall(name, args) => {
+            // Aggregates are replaced by their value before the expressions around them
+            // are evaluated, so one found here is somewhere it can't be, like WHERE
+            if let Some(aggregate) = aggregate_call(expr) {
+                anyhow::bail!("misuse of aggregate function {}()", aggregate.name());
+            }
+            let Expr::Identifier(name) = name.as_ref() else {
+                anyhow::bail!("Invalid function name: {:?}", name);
+            };
+            // coalesce() and ifnull() stop at the first argument that isn't NULL, the ones
+            // after it aren't evaluated
+            let lowercase = name.to_lowercase();
+            if lowercase == "coalesce" || lowercase == "ifnull" {
+                if args.len() < 2 || (lowercase == "ifnull" && args.len() != 2) {
+                    anyhow::bail!("wrong number of arguments to function {}()", lowercase);
+                }
+                for arg in args {
+                    let value = evaluate(arg, row_map, ctx)?;
+                    if !value.is_null() {
+                        return Ok(value);
+                    }
+                }
+                return Ok(Value::Null);
+            }
+            let Some(function) = functions::get(name) else {
+                anyhow::bail!("no such function: {}", name);
+            };
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, row_map, ctx))
+                .collect::<anyhow::Result<Vec<Value>>>()?;
+            function(ctx, &args)
+        }
+        // Run before the expression around them is evaluated, except where they can't be
+        Expr::Exists(_) | Expr::InSelect(..) => anyhow::bail!("subqueries are not supported here"),
+        // Bound before the statement runs, one without a value is NULL
+        Expr::Parameter(..) => Ok(Value::Null),
+        Expr::Wildcard | Expr::TableWildcard(_) => anyhow::bail!("near \"*\": syntax error"),
+    }
+}
+
+// SQLite's arithmetic: INTEGER operands give an INTEGER unless the result overflows,
+// a REAL operand makes the result REAL, and dividing by zero gives NULL
+fn arithmetic(op: &TokenType, left: &Value, right: &Value) -> Value {
+    let result = match (left.to_numeric(), right.to_numeric()) {
+        (Value::Null, _) | (_, Value::Null) => Value::Null,
+        (Value::I64(a), Value::I64(b)) => match op {
+            TokenType::Plus => a.checked_add(b).map_or(Value::Float(a as f64 + b as f64), Value::I64),
+            TokenType::Minus => a.checked_sub(b).map_or(Value::Float(a as f64 - b as f64), Value::I64),
+            TokenType::Star => a.checked_mul(b).map_or(Value::Float(a as f64 * b as f64), Value::I64),
+            _ if b == 0 => Value::Null,
+            TokenType::Slash => a.checked_div(b).map_or(Value::Float(a as f64 / b as f64), Value::I64),
+            // i64::MIN % -1 overflows, but is 0 all the same
+            _ => Value::I64(a.checked_rem(b).unwrap_or(0)),
+        },
+        (a, b) => {
+            let (a, b) = (a.to_f64(), b.to_f64());
+            match op {
+                TokenType::Plus => Value::Float(a + b),
+                TokenType::Minus => Value::Float(a - b),
+                TokenType::Star => Value::Float(a * b),
+                TokenType::Slash if b == 0.0 => Value::Null,
+                TokenType::Slash => Value::Float(a / b),
+                // The remainder of REALs is taken on their integer parts
+                _ => match (a as i64, b as i64) {
+                    (_, 0) => Value::Null,
+                    (a, b) => Value::Float(a.checked_rem(b).unwrap_or(0) as f64),
+                },
+            }
+        }
+    };
+    match result {
+        Value::Float(n) if n.is_nan() => Value::Null,
+        result => result,
+    }
+}
+
+// Bitwise operators work on the operands converted to INTEGER
+fn bitwise(op: &TokenType, left: &Value, right: &Value) -> Value {
+    if left.is_null() || right.is_null() {
+        return Value::Null;
+    }
+    let (a, b) = (left.to_i64(), right.to_i64());
+    // A negative shift goes the other way
+    let (left_shift, amount) = match op {
+        TokenType::Ampersand => return Value::I64(a & b),
+        TokenType::Pipe => return Value::I64(a | b),
+        TokenType::ShiftLeft => (b >= 0, b.unsigned_abs()),
+        _ => (b < 0, b.unsigned_abs()),
+    };
+    Value::I64(match (left_shift, amount) {
+        (true, 64..) => 0,
+        (true, amount) => a << amount,
+        // Right shifts keep the sign
+        (false, 64..) => if a < 0 { -1 } else { 0 },
+        (false, amount) => a >> amount,
+    })
+}
+
+pub fn literal_value(literal: &Literal) -> Value {
+    match literal {
+        Literal::String(s) => Value::String(s.clone()),
+        Literal::Integer(n) => Value::I64(*n),
+        Literal::Real(n) => Value::Float(*n),
+        Literal::Blob(b) => Value::Blob(b.clone()),
+        Literal::Null => Value::Null,
+    }
+}
+
+pub fn value_literal(value: Value) -> Literal {
+    match value {
+        Value::Null => Literal::Null,
+        Value::I64(n) => Literal::Integer(n),
+        Value::Float(n) => Literal::Real(n),
+        Value::String(s) => Literal::String(s),
+        Value::Blob(b) => Literal::Blob(b),
+    }
+}
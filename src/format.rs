@@ -0,0 +1,418 @@
+use anyhow::bail;
+use codecrafters_sqlite::Value;
+use std::str::FromStr;
+
+// How result rows are printed, the modes of sqlite3's .mode
+// https://sqlite.org/cli.html#changing_output_formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // Values separated by |
+    List,
+    Csv,
+    // Left-aligned columns as wide as their widest value, a line under the header
+    Column,
+    // An array of objects, one per row
+    Json,
+    // Columns in an ASCII-art box
+    Table,
+    Markdown,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::List => "list",
+            Mode::Csv => "csv",
+            Mode::Column => "column",
+            Mode::Json => "json",
+            Mode::Table => "table",
+            Mode::Markdown => "markdown",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        let mode = match name.to_ascii_lowercase().as_str() {
+            "list" => Mode::List,
+            "csv" => Mode::Csv,
+            "column" => Mode::Column,
+            "json" => Mode::Json,
+            "table" => Mode::Table,
+            "markdown" => Mode::Markdown,
+            _ => bail!("mode should be one of: column csv json list markdown table"),
+        };
+        Ok(mode)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Format {
+    pub mode: Mode,
+    // None leaves it to the mode: on for columns, off for list and csv
+    pub header: Option<bool>,
+    // What NULL shows as, except in JSON
+    pub null_value: String,
+    // Of each column from the first in the column modes, from .width. 0 fits the column
+    // to its values, a negative width right-aligns it
+    pub widths: Vec<i32>,
+}
+
+impl Format {
+    pub fn new(mode: Mode, header: Option<bool>) -> Self {
+        Format {
+            mode,
+            header,
+            null_value: String::new(),
+            widths: Vec::new(),
+        }
+    }
+
+    // Tables always have one, JSON has the names in every object instead
+    pub fn shows_header(&self) -> bool {
+        match self.mode {
+            Mode::Table | Mode::Markdown => true,
+            Mode::Json => false,
+            Mode::Column => self.header.unwrap_or(true),
+            Mode::List | Mode::Csv => self.header.unwrap_or(false),
+        }
+    }
+
+    // The lines of a result with `columns`, a row at a time as it is read. Like sqlite3,
+    // nothing at all when there are no rows, not even the header
+    pub fn lines_for(&self, columns: &[String]) -> Lines<'_> {
+        Lines {
+            format: self,
+            columns: columns.to_vec(),
+            first: true,
+            held: Vec::new(),
+            last_object: None,
+        }
+    }
+
+    // The header line of the modes that separate fields by `separator`. `field` has the
+    // text of the name or value and whether it is text, to quote it
+    fn separated_header(&self, columns: &[String], separator: &str, field: fn(String, bool) -> String) -> String {
+        let names = columns.iter().map(|name| field(name.clone(), true));
+        names.collect::<Vec<_>>().join(separator)
+    }
+
+    fn separated_row(&self, row: &[Value], separator: &str, field: fn(String, bool) -> String) -> String {
+        let fields = row.iter().map(|value| match value {
+            // Never quoted
+            Value::Null => self.null_value.clone(),
+            Value::String(_) | Value::Blob(_) => field(value.to_text(), true),
+            value => field(value.to_text(), false),
+        });
+        fields.collect::<Vec<_>>().join(separator)
+    }
+
+    fn cell_text(&self, value: &Value) -> String {
+        match value {
+            Value::Null => self.null_value.clone(),
+            value => value.to_text(),
+        }
+    }
+
+    // Each column padded to its width from .width, or else to its widest value, header
+    // included. A value too wide for its column wraps onto more lines, as does one with
+    // a newline in it
+    fn aligned(&self, columns: &[String], rows: &[&[Value]]) -> Vec<String> {
+        let header = self.shows_header();
+        let widths = (0..columns.len())
+            .map(|i| match self.widths.get(i) {
+                Some(&width) if width != 0 => width.unsigned_abs() as usize,
+                _ => {
+                    let name = if header { columns[i].chars().count() } else { 0 };
+                    rows.iter()
+                        .flat_map(|row| self.cell_text(&row[i]).split('\n').map(|line| line.chars().count()).collect::<Vec<_>>())
+                        .fold(name, usize::max)
+                }
+            })
+            .collect::<Vec<_>>();
+        // A negative width right-aligns the column
+        let right = (0..columns.len())
+            .map(|i| self.widths.get(i).is_some_and(|&width| width < 0))
+            .collect::<Vec<_>>();
+        // The lines of each cell of each row
+        let cells = rows
+            .iter()
+            .map(|row| {
+                let cells = row.iter().zip(&widths).map(|(value, &width)| wrap(&self.cell_text(value), width));
+                cells.collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        // The lines of a row, each cell padded to its width
+        let row_lines = |row: &[Vec<String>]| {
+            let height = row.iter().map(Vec::len).max().unwrap_or(1);
+            (0..height)
+                .map(|line| {
+                    let texts = row.iter().zip(&widths).zip(&right).map(|((cell, &width), &right)| {
+                        align(cell.get(line).map_or("", String::as_str), width, right)
+                    });
+                    texts.collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        // Rows are set apart once one of them takes more than a line
+        let wrapped = cells.iter().flatten().any(|cell| cell.len() > 1);
+        let names = columns
+            .iter()
+            .zip(&widths)
+            .map(|(name, &width)| name.chars().take(width).collect::<String>());
+        let rule = |corner: &str| {
+            let dashes = widths.iter().map(|width| "-".repeat(width + 2)).collect::<Vec<_>>();
+            format!("{}{}{}", corner, dashes.join(corner), corner)
+        };
+        // | a | b |
+        let boxed = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+
+        let mut lines = Vec::with_capacity(cells.len() + 4);
+        match self.mode {
+            Mode::Column => {
+                if header {
+                    let names = names.zip(&widths).zip(&right).map(|((name, &width), &right)| align(&name, width, right));
+                    lines.push(names.collect::<Vec<_>>().join("  "));
+                    let dashes = widths.iter().map(|&width| "-".repeat(width));
+                    lines.push(dashes.collect::<Vec<_>>().join("  "));
+                }
+                for (i, row) in cells.iter().enumerate() {
+                    if wrapped && i > 0 {
+                        lines.push(String::new());
+                    }
+                    lines.extend(row_lines(row).into_iter().map(|texts| texts.join("  ")));
+                }
+            }
+            Mode::Table | Mode::Markdown => {
+                let table = self.mode == Mode::Table;
+                if table {
+                    lines.push(rule("+"));
+                }
+                lines.push(boxed(names.zip(&widths).map(|(name, &width)| center(&name, width)).collect()));
+                lines.push(rule(if table { "+" } else { "|" }));
+                for (i, row) in cells.iter().enumerate() {
+                    if table && wrapped && i > 0 {
+                        lines.push(rule("+"));
+                    }
+                    lines.extend(row_lines(row).into_iter().map(boxed));
+                }
+                if table {
+                    lines.push(rule("+"));
+                }
+            }
+            _ => unreachable!(),
+        }
+        lines
+    }
+}
+
+// The lines of a result as its rows come, from `Format::lines_for`. The column modes
+// size their columns to every value, so they hold the rows back until `finish`
+pub struct Lines<'a> {
+    format: &'a Format,
+    columns: Vec<String>,
+    // Before the first row, which the header goes with
+    first: bool,
+    // Of the column modes
+    held: Vec<Vec<Value>>,
+    // JSON ends the last object with ] instead of a comma, so each waits for the next
+    last_object: Option<String>,
+}
+
+impl Lines<'_> {
+    pub fn row(&mut self, row: &[Value]) -> Vec<String> {
+        let first = std::mem::replace(&mut self.first, false);
+        let format = self.format;
+        let separated = |separator, field| {
+            let mut lines = Vec::with_capacity(2);
+            if first && format.shows_header() {
+                lines.push(format.separated_header(&self.columns, separator, field));
+            }
+            lines.push(format.separated_row(row, separator, field));
+            lines
+        };
+        match format.mode {
+            Mode::List => separated("|", |text, _| text),
+            Mode::Csv => {
+                // sqlite3 ends CSV lines with CRLF
+                let mut lines = separated(",", csv_field);
+                lines.iter_mut().for_each(|line| line.push('\r'));
+                lines
+            }
+            Mode::Json => {
+                let object = json_object(&self.columns, row);
+                let open = if first { "[" } else { "" };
+                let last = self.last_object.replace(format!("{}{}", open, object));
+                last.map(|last| vec![last + ","]).unwrap_or_default()
+            }
+            Mode::Column | Mode::Table | Mode::Markdown => {
+                self.held.push(row.to_vec());
+                Vec::new()
+            }
+        }
+    }
+
+    // The lines still to come after the last row
+    pub fn finish(self) -> Vec<String> {
+        match self.format.mode {
+            Mode::List | Mode::Csv => Vec::new(),
+            Mode::Json => self.last_object.map(|last| vec![last + "]"]).unwrap_or_default(),
+            Mode::Column | Mode::Table | Mode::Markdown if self.held.is_empty() => Vec::new(),
+            Mode::Column | Mode::Table | Mode::Markdown => {
+                let rows = self.held.iter().map(Vec::as_slice).collect::<Vec<_>>();
+                self.format.aligned(&self.columns, &rows)
+            }
+        }
+    }
+}
+
+// The lines of `text` no wider than `width`
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        let chars = line.chars().collect::<Vec<_>>();
+        if chars.len() <= width {
+            lines.push(line.to_string());
+            continue;
+        }
+        lines.extend(chars.chunks(width.max(1)).map(|chunk| chunk.iter().collect::<String>()));
+    }
+    lines
+}
+
+fn align(text: &str, width: usize, right: bool) -> String {
+    let space = " ".repeat(width.saturating_sub(text.chars().count()));
+    if right {
+        space + text
+    } else {
+        format!("{}{}", text, space)
+    }
+}
+
+fn center(text: &str, width: usize) -> String {
+    let space = width.saturating_sub(text.chars().count());
+    format!("{}{}{}", " ".repeat(space / 2), text, " ".repeat(space - space / 2))
+}
+
+// Quoted when it is empty or has a quote, a comma, white space or anything outside of
+// printable ASCII, like sqlite3. Numbers never are
+fn csv_field(text: String, is_text: bool) -> String {
+    let needs_quotes = text.is_empty()
+        || text.chars().any(|c| matches!(c, '"' | '\'' | ',') || c <= ' ' || c >= '\x7f');
+    if is_text && needs_quotes {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// [{"a":1,"b":"x"},
+// {"a":2,"b":null}]
+fn json_object(columns: &[String], row: &[Value]) -> String {
+    let members = columns.iter().zip(row.iter()).map(|(name, value)| {
+        let value = match value {
+            Value::Null => "null".to_string(),
+            Value::I64(_) | Value::Float(_) => value.to_text(),
+            value => json_string(&value.to_text()),
+        };
+        format!("{}:{}", json_string(name), value)
+    });
+    format!("{{{}}}", members.collect::<Vec<_>>().join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x08' => quoted.push_str("\\b"),
+            '\x0c' => quoted.push_str("\\f"),
+            c if c < ' ' || c == '\x7f' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: &Format, columns: &[&str], rows: &[Vec<Value>]) -> Vec<String> {
+        let columns = columns.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let mut lines = format.lines_for(&columns);
+        let mut rendered = rows.iter().flat_map(|row| lines.row(row)).collect::<Vec<_>>();
+        rendered.extend(lines.finish());
+        rendered
+    }
+
+    // A number, a NULL and text that needs quoting in some modes
+    fn sample(mode: Mode) -> Vec<String> {
+        let rows = [
+            vec![Value::I64(1), Value::String("a,b".to_string())],
+            vec![Value::Null, Value::String("say \"hi\"".to_string())],
+            vec![Value::Float(2.5), Value::String(String::new())],
+        ];
+        render(&Format::new(mode, Some(true)), &["n", "s"], &rows)
+    }
+
+    #[test]
+    fn separated_modes_quote_what_they_need_to() {
+        assert_eq!(sample(Mode::List), ["n|s", "1|a,b", "|say \"hi\"", "2.5|"]);
+        assert_eq!(sample(Mode::Csv), ["n,s\r", "1,\"a,b\"\r", ",\"say \"\"hi\"\"\"\r", "2.5,\"\"\r"]);
+        assert_eq!(
+            sample(Mode::Json),
+            ["[{\"n\":1,\"s\":\"a,b\"},", "{\"n\":null,\"s\":\"say \\\"hi\\\"\"},", "{\"n\":2.5,\"s\":\"\"}]"]
+        );
+    }
+
+    #[test]
+    fn column_modes_pad_to_the_widest_value() {
+        let column = ["n    s       ", "---  --------", "1    a,b     ", "     say \"hi\"", "2.5          "];
+        assert_eq!(sample(Mode::Column), column);
+        let table = [
+            "+-----+----------+",
+            "|  n  |    s     |",
+            "+-----+----------+",
+            "| 1   | a,b      |",
+            "|     | say \"hi\" |",
+            "| 2.5 |          |",
+            "+-----+----------+",
+        ];
+        assert_eq!(sample(Mode::Table), table);
+        let markdown = &table[1..6].iter().map(|line| line.replace('+', "|")).collect::<Vec<_>>();
+        assert_eq!(&sample(Mode::Markdown), markdown);
+        // Widths count characters, not bytes
+        let rows = [vec![Value::String("ünï".to_string()), Value::String("a".to_string())]];
+        let lines = render(&Format::new(Mode::Table, None), &["long_name", "b"], &rows);
+        assert_eq!(lines[3], "| ünï       | a |");
+    }
+
+    #[test]
+    fn set_widths_wrap_and_right_align() {
+        let mut format = Format::new(Mode::Column, None);
+        format.null_value = "-".to_string();
+        format.widths = vec![-4, 3];
+        let rows = [
+            vec![Value::I64(1), Value::String("abcdefg".to_string())],
+            vec![Value::Null, Value::String("x".to_string())],
+        ];
+        let lines = render(&format, &["n", "s"], &rows);
+        assert_eq!(lines, ["   n  s  ", "----  ---", "   1  abc", "      def", "      g  ", "", "   -  x  "]);
+    }
+
+    #[test]
+    fn no_rows_print_nothing() {
+        for mode in [Mode::List, Mode::Csv, Mode::Column, Mode::Json, Mode::Table, Mode::Markdown] {
+            assert!(render(&Format::new(mode, Some(true)), &["a"], &[]).is_empty(), "{}", mode.name());
+        }
+    }
+}
@@ -0,0 +1,936 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+};
+
+use crate::record::Value;
+
+pub type ScalarFunction = fn(&FunctionContext, &[Value]) -> anyhow::Result<Value>;
+
+// Per-connection settings visible to scalar functions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FunctionContext {
+    pub case_folding: CaseFolding,
+}
+
+// How upper(), lower() and LIKE treat letter case
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CaseFolding {
+    // Stock SQLite: only the 26 ASCII letters have an upper and lower case
+    #[default]
+    Ascii,
+    // Full Unicode case mapping, like SQLite built with the ICU extension
+    #[cfg(feature = "unicode-case")]
+    Unicode,
+}
+
+impl CaseFolding {
+    pub fn to_upper(self, text: &str) -> String {
+        match self {
+            CaseFolding::Ascii => text.to_ascii_uppercase(),
+            #[cfg(feature = "unicode-case")]
+            CaseFolding::Unicode => text.to_uppercase(),
+        }
+    }
+
+    pub fn to_lower(self, text: &str) -> String {
+        match self {
+            CaseFolding::Ascii => text.to_ascii_lowercase(),
+            #[cfg(feature = "unicode-case")]
+            CaseFolding::Unicode => text.to_lowercase(),
+        }
+    }
+
+    fn fold(self, c: char) -> char {
+        match self {
+            CaseFolding::Ascii => c.to_ascii_lowercase(),
+            #[cfg(feature = "unicode-case")]
+            CaseFolding::Unicode => c.to_lowercase().next().unwrap_or(c),
+        }
+    }
+}
+
+// https://www.sqlite.org/lang_corefunc.html
+static SCALAR_FUNCTIONS: LazyLock<HashMap<&'static str, ScalarFunction>> = LazyLock::new(|| {
+    HashMap::from([
+        ("printf", printf as ScalarFunction),
+        ("format", printf),
+        ("length", length),
+        ("substr", substr),
+        ("substring", substr),
+        ("trim", trim),
+        ("ltrim", ltrim),
+        ("rtrim", rtrim),
+        ("replace", replace),
+        ("instr", instr),
+        ("unicode", unicode),
+        ("char", char),
+        ("upper", upper),
+        ("lower", lower),
+        ("like", like),
+        ("abs", abs),
+        ("round", round),
+        ("sign", sign),
+        ("min", min),
+        ("max", max),
+        ("nullif", nullif),
+        ("typeof", type_of),
+        ("random", random),
+        ("randomblob", randomblob),
+    ])
+});
+
+pub fn get(name: &str) -> Option<ScalarFunction> {
+    SCALAR_FUNCTIONS.get(name.to_lowercase().as_str()).copied()
+}
+
+// The functions that can return something else each time with the same arguments, like
+// the ones SQLite registers without SQLITE_DETERMINISTIC
+const NON_DETERMINISTIC: [&str; 2] = ["random", "randomblob"];
+
+pub fn is_deterministic(name: &str) -> bool {
+    !NON_DETERMINISTIC.iter().any(|function| function.eq_ignore_ascii_case(name))
+}
+
+fn text_arg(args: &[Value], index: usize) -> Option<String> {
+    match args.get(index) {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(value.to_text()),
+    }
+}
+
+// Characters in text, stopping at the first NUL, and bytes in a blob
+fn length(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function length()");
+    }
+    Ok(match &args[0] {
+        Value::Null => Value::Null,
+        Value::Blob(bytes) => Value::I64(bytes.len() as i64),
+        value => Value::I64(value.to_text().chars().take_while(|&c| c != '\0').count() as i64),
+    })
+}
+
+// substr(X, Y[, Z]): Z characters of X from the Y-th, counting from 1. A negative Y counts
+// from the end and a negative Z takes the characters before Y instead. A blob is cut
+// into bytes
+fn substr(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() < 2 || args.len() > 3 {
+        anyhow::bail!("wrong number of arguments to function substr()");
+    }
+    if args.iter().any(Value::is_null) {
+        return Ok(Value::Null);
+    }
+    let text = match &args[0] {
+        Value::Blob(_) => None,
+        value => Some(value.to_text().chars().collect::<Vec<_>>()),
+    };
+    let len = match (&text, &args[0]) {
+        (Some(chars), _) => chars.len() as i64,
+        (None, Value::Blob(bytes)) => bytes.len() as i64,
+        _ => unreachable!(),
+    };
+    // The same steps as SQLite's substrFunc()
+    let mut start = args[1].to_i64();
+    let (mut count, before) = match args.get(2) {
+        Some(count) => (count.to_i64().saturating_abs(), count.to_i64() < 0),
+        None => (i64::MAX, false),
+    };
+    if start < 0 {
+        start += len;
+        if start < 0 {
+            count = (count + start).max(0);
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if count > 0 {
+        count -= 1;
+    }
+    if before {
+        start -= count;
+        if start < 0 {
+            count += start;
+            start = 0;
+        }
+    }
+    let start = start.min(len) as usize;
+    let end = start.saturating_add(count.max(0) as usize).min(len as usize);
+    Ok(match (text, &args[0]) {
+        (Some(chars), _) => Value::String(chars[start..end].iter().collect()),
+        (None, Value::Blob(bytes)) => Value::Blob(bytes[start..end].to_vec()),
+        _ => unreachable!(),
+    })
+}
+
+fn trim(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    trim_with(args, "trim", |text, chars| text.trim_matches(chars))
+}
+
+fn ltrim(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    trim_with(args, "ltrim", |text, chars| text.trim_start_matches(chars))
+}
+
+fn rtrim(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    trim_with(args, "rtrim", |text, chars| text.trim_end_matches(chars))
+}
+
+// X[, Y]: remove any characters that appear in Y (spaces by default)
+fn trim_with(
+    args: &[Value],
+    name: &str,
+    trim: impl for<'a> Fn(&'a str, &[char]) -> &'a str,
+) -> anyhow::Result<Value> {
+    if args.is_empty() || args.len() > 2 {
+        anyhow::bail!("wrong number of arguments to function {}()", name);
+    }
+    let Some(text) = text_arg(args, 0) else {
+        return Ok(Value::Null);
+    };
+    let chars = if args.len() == 2 {
+        match text_arg(args, 1) {
+            Some(chars) => chars.chars().collect::<Vec<char>>(),
+            None => return Ok(Value::Null),
+        }
+    } else {
+        vec![' ']
+    };
+    Ok(Value::String(trim(&text, &chars).to_string()))
+}
+
+fn upper(ctx: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function upper()");
+    }
+    Ok(text_arg(args, 0).map_or(Value::Null, |text| {
+        Value::String(ctx.case_folding.to_upper(&text))
+    }))
+}
+
+fn lower(ctx: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function lower()");
+    }
+    Ok(text_arg(args, 0).map_or(Value::Null, |text| {
+        Value::String(ctx.case_folding.to_lower(&text))
+    }))
+}
+
+// replace(X, Y, Z): every Y in X becomes Z. An empty Y leaves X as it is
+fn replace(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 3 {
+        anyhow::bail!("wrong number of arguments to function replace()");
+    }
+    let (Some(text), Some(from)) = (text_arg(args, 0), text_arg(args, 1)) else {
+        return Ok(Value::Null);
+    };
+    if from.is_empty() {
+        return Ok(args[0].clone());
+    }
+    Ok(text_arg(args, 2).map_or(Value::Null, |to| Value::String(text.replace(&from, &to))))
+}
+
+// instr(X, Y): the position of the first Y in X counting from 1, or 0 when there is none.
+// Between two blobs the position is in bytes, otherwise in characters
+fn instr(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 2 {
+        anyhow::bail!("wrong number of arguments to function instr()");
+    }
+    let position = match (&args[0], &args[1]) {
+        (Value::Null, _) | (_, Value::Null) => return Ok(Value::Null),
+        (Value::Blob(haystack), Value::Blob(needle)) => match needle.len() {
+            0 => Some(0),
+            len => haystack.windows(len).position(|window| window == needle.as_slice()),
+        },
+        (haystack, needle) => {
+            let haystack = haystack.to_text();
+            haystack
+                .find(&needle.to_text())
+                .map(|offset| haystack[..offset].chars().count())
+        }
+    };
+    Ok(Value::I64(position.map_or(0, |position| position as i64 + 1)))
+}
+
+// like(X, Y[, Z]) is `Y LIKE X ESCAPE Z`
+fn like(ctx: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() < 2 || args.len() > 3 {
+        anyhow::bail!("wrong number of arguments to function like()");
+    }
+    let (Some(pattern), Some(text)) = (text_arg(args, 0), text_arg(args, 1)) else {
+        return Ok(Value::Null);
+    };
+    let escape = match args.get(2) {
+        Some(Value::Null) => return Ok(Value::Null),
+        Some(arg) => {
+            let escape = arg.to_string();
+            let mut chars = escape.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => anyhow::bail!("ESCAPE expression must be a single character"),
+            }
+        }
+        None => None,
+    };
+    let matched = like_match(&pattern, &text, escape, ctx.case_folding);
+    Ok(Value::I64(matched as i64))
+}
+
+// `%` matches any sequence of characters, `_` exactly one, everything else
+// compares after case folding
+pub fn like_match(pattern: &str, text: &str, escape: Option<char>, folding: CaseFolding) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let text = text.chars().collect::<Vec<char>>();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `%` if the current attempt fails
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() {
+            let escaped = Some(pattern[p]) == escape && p + 1 < pattern.len();
+            let (c, width) = if escaped {
+                (pattern[p + 1], 2)
+            } else {
+                (pattern[p], 1)
+            };
+            if !escaped && c == '%' {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            if (!escaped && c == '_') || folding.fold(c) == folding.fold(text[t]) {
+                p += width;
+                t += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((resume_p, resume_t)) => {
+                p = resume_p;
+                t = resume_t + 1;
+                backtrack = Some((resume_p, resume_t + 1));
+            }
+            None => return false,
+        }
+    }
+    while p < pattern.len() && pattern[p] == '%' && Some('%') != escape {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+fn abs(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function abs()");
+    }
+    Ok(match &args[0] {
+        Value::Null => Value::Null,
+        Value::I64(n) => match n.checked_abs() {
+            Some(n) => Value::I64(n),
+            None => anyhow::bail!("integer overflow"),
+        },
+        // Anything else is read as a REAL, 0.0 when it isn't a number
+        value => Value::Float(value.to_f64().abs()),
+    })
+}
+
+// round(X[, Y]): X rounded to Y digits after the decimal point, halfway away from zero.
+// Always a REAL
+fn round(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.is_empty() || args.len() > 2 {
+        anyhow::bail!("wrong number of arguments to function round()");
+    }
+    if args.iter().any(Value::is_null) {
+        return Ok(Value::Null);
+    }
+    let n = args[0].to_f64();
+    let digits = args.get(1).map_or(0, |digits| digits.to_i64().clamp(0, 30)) as usize;
+    if !n.is_finite() {
+        return Ok(Value::Float(n));
+    }
+    if digits == 0 {
+        // Like SQLite, through a 64-bit integer when the value fits one
+        if n.abs() < i64::MAX as f64 - 1.0 {
+            return Ok(Value::Float((n + 0.5f64.copysign(n)) as i64 as f64));
+        }
+        return Ok(Value::Float(n));
+    }
+    // Rounded on the exact decimal expansion, so 2.675, stored as 2.67499..., gives 2.67
+    let exact = format!("{:.1100}", n.abs());
+    let end = exact.find('.').unwrap() + 1 + digits;
+    let mut rounded = exact[..end].bytes().filter(|&b| b != b'.').collect::<Vec<_>>();
+    if exact.as_bytes()[end] >= b'5' {
+        match rounded.iter().rposition(|&b| b != b'9') {
+            Some(i) => {
+                rounded[i] += 1;
+                rounded[i + 1..].fill(b'0');
+            }
+            None => {
+                rounded.fill(b'0');
+                rounded.insert(0, b'1');
+            }
+        }
+    }
+    rounded.insert(rounded.len() - digits, b'.');
+    let rounded = String::from_utf8(rounded)?.parse::<f64>()?;
+    Ok(Value::Float(rounded.copysign(n)))
+}
+
+// -1, 0 or 1 by the sign of X, NULL when X isn't a number
+fn sign(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function sign()");
+    }
+    Ok(match args[0].with_numeric_affinity() {
+        Value::I64(n) => Value::I64(n.signum()),
+        Value::Float(n) if n > 0.0 => Value::I64(1),
+        Value::Float(n) if n < 0.0 => Value::I64(-1),
+        Value::Float(_) => Value::I64(0),
+        _ => Value::Null,
+    })
+}
+
+// The scalar min() and max() take two or more arguments and are NULL when any is; with
+// one they are the aggregates
+fn min(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    extreme(args, "min", Iterator::min)
+}
+
+fn max(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    extreme(args, "max", Iterator::max)
+}
+
+fn extreme<'a>(
+    args: &'a [Value],
+    name: &str,
+    pick: impl Fn(std::slice::Iter<'a, Value>) -> Option<&'a Value>,
+) -> anyhow::Result<Value> {
+    if args.is_empty() {
+        anyhow::bail!("wrong number of arguments to function {}()", name);
+    }
+    if args.iter().any(Value::is_null) {
+        return Ok(Value::Null);
+    }
+    Ok(pick(args.iter()).cloned().unwrap_or(Value::Null))
+}
+
+// nullif(X, Y): X, or NULL when it equals Y
+fn nullif(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 2 {
+        anyhow::bail!("wrong number of arguments to function nullif()");
+    }
+    Ok(match (&args[0], &args[1]) {
+        (x, y) if !x.is_null() && !y.is_null() && x == y => Value::Null,
+        (x, _) => x.clone(),
+    })
+}
+
+// The storage class of X
+fn type_of(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function typeof()");
+    }
+    Ok(Value::String(args[0].type_name().to_string()))
+}
+
+// A pseudo-random 64-bit integer
+fn random(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if !args.is_empty() {
+        anyhow::bail!("wrong number of arguments to function random()");
+    }
+    Ok(Value::I64(next_random() as i64))
+}
+
+// randomblob(N): N pseudo-random bytes, at least one
+fn randomblob(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function randomblob()");
+    }
+    let len = args[0].to_i64().max(1) as usize;
+    if len > MAX_LENGTH {
+        anyhow::bail!("string or blob too big");
+    }
+    let bytes = std::iter::repeat_with(|| next_random().to_le_bytes())
+        .flatten()
+        .take(len)
+        .collect();
+    Ok(Value::Blob(bytes))
+}
+
+// SplitMix64 over a counter seeded from the randomly keyed hasher std gives each process
+static RANDOM_STATE: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(RandomState::new().build_hasher().finish()));
+
+pub fn next_random() -> u64 {
+    let mut z = RANDOM_STATE
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// The code point of the first character
+fn unicode(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    if args.len() != 1 {
+        anyhow::bail!("wrong number of arguments to function unicode()");
+    }
+    Ok(match text_arg(args, 0).and_then(|text| text.chars().next()) {
+        Some(c) => Value::I64(c as i64),
+        None => Value::Null,
+    })
+}
+
+// A string made of the given code points
+fn char(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    let text = args
+        .iter()
+        .map(|arg| {
+            u32::try_from(arg.to_i64())
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER)
+        })
+        .collect();
+    Ok(Value::String(text))
+}
+
+// https://www.sqlite.org/printf.html
+fn printf(_: &FunctionContext, args: &[Value]) -> anyhow::Result<Value> {
+    let Some(format) = text_arg(args, 0) else {
+        return Ok(Value::Null);
+    };
+    let mut args = args[1..].iter();
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut spec = FormatSpec::default();
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => spec.left_align = true,
+                '+' => spec.plus_sign = true,
+                ' ' => spec.space_sign = true,
+                '0' => spec.zero_pad = true,
+                '#' => spec.alternate = true,
+                ',' => spec.thousands = true,
+                '!' => spec.keep_point = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        // Like SQLite, a width or precision from an argument is cut to an int, a negative
+        // one counts as its absolute value, and one written out keeps its low 31 bits
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            let width = args.next().map_or(0, Value::to_i64) as i32;
+            spec.left_align |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = printf_number(&mut chars);
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                let precision = args.next().map_or(0, Value::to_i64) as i32;
+                spec.precision = Some(precision.unsigned_abs() as usize);
+            } else {
+                spec.precision = Some(printf_number(&mut chars));
+            }
+        }
+        // A result longer than SQLITE_MAX_LENGTH is NULL
+        if spec.width > MAX_LENGTH {
+            return Ok(Value::Null);
+        }
+        // Length modifiers are accepted and ignored
+        while matches!(chars.peek(), Some('l' | 'h')) {
+            chars.next();
+        }
+
+        let Some(conversion) = chars.next() else {
+            break;
+        };
+        if conversion == '%' {
+            out.push('%');
+            continue;
+        }
+        let arg = args.next().cloned().unwrap_or(Value::Null);
+        let formatted = match conversion {
+            'd' | 'i' => spec.format_signed(arg.to_i64()),
+            'u' => spec.format_signed(arg.to_i64().max(0)),
+            'x' => spec.format_radix(arg.to_i64() as u64, 16, false),
+            'X' => spec.format_radix(arg.to_i64() as u64, 16, true),
+            'o' => spec.format_radix(arg.to_i64() as u64, 8, false),
+            'f' | 'F' => spec.format_float(arg.to_f64(), FloatStyle::Fixed),
+            'e' => spec.format_float(arg.to_f64(), FloatStyle::Exponent(false)),
+            'E' => spec.format_float(arg.to_f64(), FloatStyle::Exponent(true)),
+            'g' => spec.format_float(arg.to_f64(), FloatStyle::General(false)),
+            'G' => spec.format_float(arg.to_f64(), FloatStyle::General(true)),
+            'c' => spec.pad(arg.to_string().chars().next().map(String::from).unwrap_or_default()),
+            's' | 'z' => spec.format_text(match arg {
+                Value::Null => String::new(),
+                arg => arg.to_string(),
+            }),
+            'q' => spec.format_text(match arg {
+                Value::Null => "(NULL)".to_string(),
+                arg => arg.to_string().replace('\'', "''"),
+            }),
+            'Q' => spec.format_text(match arg {
+                Value::Null => "NULL".to_string(),
+                arg => format!("'{}'", arg.to_string().replace('\'', "''")),
+            }),
+            'w' => spec.format_text(match arg {
+                Value::Null => "(NULL)".to_string(),
+                arg => arg.to_string().replace('"', "\"\""),
+            }),
+            other => anyhow::bail!("unsupported printf conversion: %{}", other),
+        };
+        out.push_str(&formatted);
+        if out.len() > MAX_LENGTH {
+            return Ok(Value::Null);
+        }
+    }
+    Ok(Value::String(out))
+}
+
+// SQLITE_MAX_LENGTH, the longest text or blob a value can hold
+const MAX_LENGTH: usize = 1_000_000_000;
+// SQLITE_FP_PRECISION_LIMIT, the most digits after the point a float is formatted with
+const FLOAT_PRECISION_LIMIT: usize = 100_000_000;
+
+// The digits of a printf width or precision
+fn printf_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut n = 0u32;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = n.wrapping_mul(10).wrapping_add(digit);
+        chars.next();
+    }
+    (n & 0x7fff_ffff) as usize
+}
+
+#[derive(Debug, Default)]
+struct FormatSpec {
+    left_align: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    zero_pad: bool,
+    alternate: bool,
+    thousands: bool,
+    // `!`: a float keeps a digit after its point and up to 26 significant digits
+    keep_point: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+enum FloatStyle {
+    Fixed,
+    // The flag selects an upper case exponent marker
+    Exponent(bool),
+    General(bool),
+}
+
+impl FormatSpec {
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus_sign {
+            "+"
+        } else if self.space_sign {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn pad(&self, text: String) -> String {
+        let len = text.chars().count();
+        if len >= self.width {
+            return text;
+        }
+        let padding = " ".repeat(self.width - len);
+        if self.left_align {
+            text + &padding
+        } else {
+            padding + &text
+        }
+    }
+
+    // Pad a number, zeros go between the sign/prefix and the digits
+    fn pad_number(&self, prefix: &str, digits: String) -> String {
+        if self.zero_pad && !self.left_align {
+            let len = prefix.len() + digits.chars().count();
+            if len < self.width {
+                return format!("{}{}{}", prefix, "0".repeat(self.width - len), digits);
+            }
+        }
+        self.pad(format!("{}{}", prefix, digits))
+    }
+
+    fn format_signed(&self, n: i64) -> String {
+        let mut digits = n.unsigned_abs().to_string();
+        if let Some(precision) = self.precision {
+            if digits.len() < precision {
+                digits = "0".repeat(precision - digits.len()) + &digits;
+            }
+        }
+        if self.thousands {
+            digits = group_thousands(&digits);
+        }
+        self.pad_number(self.sign(n < 0), digits)
+    }
+
+    fn format_radix(&self, n: u64, radix: u32, upper: bool) -> String {
+        let mut digits = match (radix, upper) {
+            (16, false) => format!("{:x}", n),
+            (16, true) => format!("{:X}", n),
+            _ => format!("{:o}", n),
+        };
+        if let Some(precision) = self.precision {
+            if digits.len() < precision {
+                digits = "0".repeat(precision - digits.len()) + &digits;
+            }
+        }
+        let prefix = match (self.alternate && n != 0, radix, upper) {
+            (false, _, _) => "",
+            (true, 16, false) => "0x",
+            (true, 16, true) => "0X",
+            (true, _, _) => "0",
+        };
+        self.pad_number(prefix, digits)
+    }
+
+    fn format_float(&self, n: f64, style: FloatStyle) -> String {
+        if n.is_nan() {
+            return self.pad("NaN".to_string());
+        }
+        if n.is_infinite() {
+            return self.pad(format!("{}Inf", self.sign(n < 0.0)));
+        }
+        let precision = self.precision.unwrap_or(6).min(FLOAT_PRECISION_LIMIT);
+        let abs = n.abs();
+        let limit = if self.keep_point { 26 } else { 16 };
+        let mut digits = match style {
+            FloatStyle::Fixed => format_fixed(abs, precision, limit),
+            FloatStyle::Exponent(upper) => format_exponent(abs, precision, upper, limit),
+            FloatStyle::General(upper) => {
+                let precision = precision.max(1);
+                // The exponent the value has after rounding to `precision` digits
+                let (_, exponent) = decimal_digits(abs, Rounding::Significant(precision), limit);
+                if exponent < -4 || exponent >= precision as i64 {
+                    format_exponent(abs, precision - 1, upper, limit)
+                } else {
+                    format_fixed(abs, (precision as i64 - 1 - exponent) as usize, limit)
+                }
+            }
+        };
+        // Like SQLite, %g drops the zeros at the end unless `#` is given, and `!` drops
+        // them from %f and %e too, down to one digit after the point
+        let strip = match style {
+            FloatStyle::General(_) => !self.alternate,
+            _ => self.keep_point,
+        };
+        if strip {
+            digits = strip_trailing_zeros(&digits, self.keep_point);
+        }
+        if self.thousands {
+            let (integer, rest) = digits.split_at(digits.find(['.', 'e', 'E']).unwrap_or(digits.len()));
+            digits = group_thousands(integer) + rest;
+        }
+        self.pad_number(self.sign(n < 0.0), digits)
+    }
+
+    fn format_text(&self, text: String) -> String {
+        let text = match self.precision {
+            Some(precision) => text.chars().take(precision).collect(),
+            None => text,
+        };
+        self.pad(text)
+    }
+}
+
+// How SQLite renders a REAL, "%!.15g": always with a decimal point, e.g. 2.0 or 1.0e+20
+pub fn format_real(n: f64) -> String {
+    let spec = FormatSpec {
+        keep_point: true,
+        precision: Some(15),
+        ..FormatSpec::default()
+    };
+    spec.format_float(n, FloatStyle::General(false))
+}
+
+// Where decimal_digits rounds
+enum Rounding {
+    // To this many significant digits
+    Significant(usize),
+    // To this many digits after the point
+    Decimal(usize),
+}
+
+// The decimal digits of `n` rounded the way SQLite's printf does: on the decimal
+// expansion, half away from zero, and to no more than `limit` significant digits (16, or
+// 26 with `!`), so that 2.5 rounds to 3 and 0.1 to 20 places is 0.1 followed by zeros.
+// Returns the digits without the zeros past them, and the power of ten of the first one
+fn decimal_digits(n: f64, rounding: Rounding, limit: usize) -> (Vec<u8>, i64) {
+    // At least 19 significant digits, as many as SQLite takes from a double
+    let formatted = format!("{:.*e}", limit.max(19) - 1, n);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let mut exponent = if n == 0.0 { 0 } else { exponent.parse::<i64>().unwrap() };
+    let mut digits = mantissa.bytes().filter(u8::is_ascii_digit).map(|b| b - b'0').collect::<Vec<_>>();
+    let count = match rounding {
+        Rounding::Significant(count) => count as i64,
+        Rounding::Decimal(places) => exponent + 1 + places as i64,
+    };
+    // Every digit is rounded off, the first one can still round up to a 1 before it
+    if count < 0 {
+        return (Vec::new(), exponent);
+    }
+    let count = count.min(limit as i64) as usize;
+    let round_up = digits.get(count).is_some_and(|&digit| digit >= 5);
+    digits.truncate(count);
+    if round_up {
+        match digits.iter().rposition(|&digit| digit != 9) {
+            Some(i) => {
+                digits[i] += 1;
+                digits.truncate(i + 1);
+            }
+            // 9.96 to one place is 10.0
+            None => {
+                digits = vec![1];
+                exponent += 1;
+            }
+        }
+    }
+    (digits, exponent)
+}
+
+// `n` with `precision` digits after the point
+fn format_fixed(n: f64, precision: usize, limit: usize) -> String {
+    let (digits, exponent) = decimal_digits(n, Rounding::Decimal(precision), limit);
+    let digit = |power: i64| {
+        let i = exponent - power;
+        let digit = if i >= 0 { digits.get(i as usize).copied().unwrap_or(0) } else { 0 };
+        char::from(b'0' + digit)
+    };
+    let mut formatted = (0..=exponent.max(0)).rev().map(digit).collect::<String>();
+    if precision > 0 {
+        formatted.push('.');
+        formatted.extend((1..=precision as i64).map(|place| digit(-place)));
+    }
+    formatted
+}
+
+// C style exponent: at least two digits and an explicit sign, e.g. 1.500000e+01
+fn format_exponent(n: f64, precision: usize, upper: bool, limit: usize) -> String {
+    let (digits, exponent) = decimal_digits(n, Rounding::Significant(precision + 1), limit);
+    let digit = |i: usize| char::from(b'0' + digits.get(i).copied().unwrap_or(0));
+    let mut mantissa = digit(0).to_string();
+    if precision > 0 {
+        mantissa.push('.');
+        mantissa.extend((1..=precision).map(digit));
+    }
+    let marker = if upper { 'E' } else { 'e' };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}{}{}{:02}", mantissa, marker, sign, exponent.abs())
+}
+
+// The zeros at the end of the mantissa, and the point when nothing is left after it
+// unless `keep_point` asks for a zero there instead
+fn strip_trailing_zeros(formatted: &str, keep_point: bool) -> String {
+    let (mantissa, exponent) = match formatted.find(['e', 'E']) {
+        Some(pos) => formatted.split_at(pos),
+        None => (formatted, ""),
+    };
+    let mut mantissa = match mantissa.find('.') {
+        Some(_) => mantissa.trim_end_matches('0').trim_end_matches('.').to_string(),
+        None => mantissa.to_string(),
+    };
+    if keep_point && !mantissa.contains('.') {
+        mantissa.push_str(".0");
+    }
+    format!("{}{}", mantissa, exponent)
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(format: &str, args: &[Value]) -> Value {
+        let args = [&[Value::String(format.to_string())], args].concat();
+        printf(&FunctionContext::default(), &args).unwrap()
+    }
+
+    fn text(format: &str, arg: Value) -> String {
+        run(format, &[arg]).to_string()
+    }
+
+    #[test]
+    fn floats_round_half_away_from_zero_on_their_decimal_digits() {
+        assert_eq!(text("%.0f", Value::Float(2.5)), "3");
+        assert_eq!(text("%.0f", Value::Float(0.5)), "1");
+        assert_eq!(text("%.0f", Value::Float(-0.5)), "-1");
+        assert_eq!(text("%.1f", Value::Float(0.25)), "0.3");
+        // 0.15 and 1.005 are a little under, as doubles
+        assert_eq!(text("%.1f", Value::Float(0.15)), "0.1");
+        assert_eq!(text("%.2f", Value::Float(1.005)), "1.00");
+        assert_eq!(text("%.1f", Value::Float(9.96)), "10.0");
+        assert_eq!(text("%.0e", Value::Float(5.5)), "6e+00");
+        // No more than 16 significant digits
+        assert_eq!(text("%.20f", Value::Float(0.1)), "0.10000000000000000000");
+        assert_eq!(text("%.17g", Value::Float(0.1)), "0.1");
+    }
+
+    #[test]
+    fn randomblob_longer_than_max_length_is_too_big() {
+        let ctx = FunctionContext::default();
+        let blob = randomblob(&ctx, &[Value::I64(-5)]).unwrap();
+        assert!(matches!(blob, Value::Blob(bytes) if bytes.len() == 1));
+        let err = randomblob(&ctx, &[Value::I64(i64::MAX)]).unwrap_err();
+        assert_eq!(err.to_string(), "string or blob too big");
+    }
+
+    #[test]
+    fn bang_flag_keeps_a_digit_after_the_point() {
+        assert_eq!(text("%!.3g", Value::Float(1.0)), "1.0");
+        assert_eq!(text("%!.3g", Value::Float(1e10)), "1.0e+10");
+        assert_eq!(text("%!g", Value::Float(100000000.0)), "1.0e+08");
+        assert_eq!(text("%!.3f", Value::Float(1.0)), "1.0");
+        assert_eq!(text("%!.3f", Value::Float(1.25)), "1.25");
+        assert_eq!(text("%!.0f", Value::Float(1.0)), "1.0");
+        assert_eq!(text("%!.3e", Value::Float(1.0)), "1.0e+00");
+        assert_eq!(text("%!5.1f|", Value::Float(2.0)), "  2.0|");
+        assert_eq!(text("%!#.3g", Value::Float(1.0)), "1.00");
+        // Without it %f and %e keep their zeros
+        assert_eq!(text("%.3f", Value::Float(1.0)), "1.000");
+    }
+
+    #[test]
+    fn widths_and_precisions_are_capped_like_sqlite() {
+        let star = |format: &str, n: i64, arg: Value| run(format, &[Value::I64(n), arg]).to_string();
+        // Cut to an int, negative counts as its absolute value
+        assert_eq!(star("%*d", (1 << 32) + 3, Value::I64(7)), "  7");
+        assert_eq!(star("%*d|", -3, Value::I64(7)), "7  |");
+        assert_eq!(star("%.*f", -2, Value::Float(1.5)), "1.50");
+        assert_eq!(run("%*d", &[Value::I64(2_000_000_000), Value::I64(1)]), Value::Null);
+        // Written out, the low 31 bits
+        assert_eq!(text("%4294967299d", Value::I64(7)), "  7");
+    }
+}
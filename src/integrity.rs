@@ -0,0 +1,354 @@
+use crate::{
+    db::{Pager, HEADER_FREELIST_COUNT_OFFSET, HEADER_FREELIST_TRUNK_OFFSET, HEADER_SIZE},
+    page::{
+        index_max_local, is_pointer_map_page, local_payload_size, parse_cell_pointers, table_leaf_max_local,
+        PageHeader, PageType,
+    },
+    utils::{read_be_word_at, read_varint, read_varint_i64},
+};
+
+// The page holding the byte at 1 GiB is left for file locks, it is never used
+const PENDING_BYTE: usize = 0x4000_0000;
+
+// PRAGMA integrity_check: the freelist and every b-tree are walked with their overflow
+// chains, checking the layout of each page, the order of the rowids and that each page
+// of the file is used exactly once. The problems are worded the way SQLite words them
+// https://www.sqlite.org/pragma.html#pragma_integrity_check
+pub struct IntegrityCheck<'a> {
+    pager: &'a mut Pager,
+    usable_size: usize,
+    page_count: usize,
+    // Whether each page, by number, was reached yet
+    used: Vec<bool>,
+    errors: Vec<String>,
+    max_errors: usize,
+}
+
+// What a cell takes up on its page and where it leads
+struct CellInfo {
+    size: usize,
+    // The rowid of a table cell
+    key: Option<i64>,
+    left_child: Option<u32>,
+    // The first overflow page and the number of payload bytes on the chain
+    overflow: Option<(u32, usize)>,
+}
+
+impl<'a> IntegrityCheck<'a> {
+    pub fn new(pager: &'a mut Pager, auto_vacuum: bool, max_errors: usize) -> Self {
+        let usable_size = pager.usable_size();
+        let page_count = pager.page_count();
+        let mut used = vec![false; page_count + 1];
+        used[0] = true;
+        let lock_page = PENDING_BYTE / pager.page_size() + 1;
+        if lock_page <= page_count {
+            used[lock_page] = true;
+        }
+        if auto_vacuum {
+            for (page_num, used) in used.iter_mut().enumerate() {
+                *used |= is_pointer_map_page(page_num, usable_size);
+            }
+        }
+        IntegrityCheck {
+            pager,
+            usable_size,
+            page_count,
+            used,
+            errors: Vec::new(),
+            max_errors,
+        }
+    }
+
+    // "ok", or one row per problem with the first saying which database they are in
+    pub fn run(mut self, roots: &[usize]) -> anyhow::Result<Vec<String>> {
+        let header = self.pager.read_raw_page(1)?;
+        let trunk = u32::from_be_bytes(header[HEADER_FREELIST_TRUNK_OFFSET..][..4].try_into().unwrap());
+        let count = u32::from_be_bytes(header[HEADER_FREELIST_COUNT_OFFSET..][..4].try_into().unwrap());
+        self.check_freelist(trunk, count as usize);
+        for &root in roots {
+            self.check_page(root, root, &format!("Tree {} page {}: ", root, root), &mut None);
+        }
+        for page_num in 1..=self.page_count {
+            if !self.used[page_num] {
+                self.error("", format!("Page {}: never used", page_num));
+            }
+        }
+        if self.errors.is_empty() {
+            return Ok(vec!["ok".to_string()]);
+        }
+        self.errors[0].insert_str(0, "*** in database main ***\n");
+        Ok(self.errors)
+    }
+
+    fn full(&self) -> bool {
+        self.errors.len() >= self.max_errors
+    }
+
+    fn error(&mut self, prefix: &str, message: impl std::fmt::Display) {
+        if !self.full() {
+            self.errors.push(format!("{}{}", prefix, message));
+        }
+    }
+
+    // Mark a page as used, false when it can't be: its number is not a page of the file
+    // or something else uses it already
+    fn reference(&mut self, page_num: usize, prefix: &str) -> bool {
+        if page_num == 0 || page_num > self.page_count {
+            self.error(prefix, format!("invalid page number {}", page_num));
+            return false;
+        }
+        if self.used[page_num] {
+            self.error(prefix, format!("2nd reference to page {}", page_num));
+            return false;
+        }
+        self.used[page_num] = true;
+        true
+    }
+
+    // Trunk pages list up to usable_size / 4 - 2 leaf pages each, the header has the
+    // number of pages on the list, trunks included
+    // https://www.sqlite.org/fileformat.html#the_freelist
+    fn check_freelist(&mut self, first_trunk: u32, expected: usize) {
+        let prefix = "Freelist: ";
+        let mut found = 0;
+        let mut trunk = first_trunk as usize;
+        while trunk != 0 && !self.full() {
+            if !self.reference(trunk, prefix) {
+                break;
+            }
+            found += 1;
+            let buffer = match self.pager.read_raw_page(trunk) {
+                Ok(buffer) => buffer,
+                Err(error) => {
+                    self.error(prefix, error);
+                    break;
+                }
+            };
+            let leaf_count = u32::from_be_bytes(buffer[4..8].try_into().unwrap()) as usize;
+            if leaf_count > self.usable_size / 4 - 2 {
+                self.error(prefix, format!("freelist leaf count too big on page {}", trunk));
+                break;
+            }
+            for i in 0..leaf_count {
+                let leaf = u32::from_be_bytes(buffer[8 + i * 4..][..4].try_into().unwrap());
+                self.reference(leaf as usize, prefix);
+                found += 1;
+            }
+            trunk = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        }
+        if found != expected {
+            self.error(prefix, format!("size is {} but should be {}", found, expected));
+        }
+    }
+
+    // Check a page and the subtree under it, returns the depth of the subtree. The rowids
+    // of a table b-tree have to be at most `max_key`, which is then set to the smallest
+    fn check_page(&mut self, root: usize, page_num: usize, prefix: &str, max_key: &mut Option<i64>) -> Option<usize> {
+        if self.full() || !self.reference(page_num, prefix) {
+            return None;
+        }
+        let here = format!("Tree {} page {}: ", root, page_num);
+        let buffer = match self.pager.read_raw_page(page_num) {
+            Ok(buffer) => buffer,
+            Err(error) => {
+                self.error(&here, error);
+                return None;
+            }
+        };
+        let offset = if page_num == 1 { HEADER_SIZE } else { 0 };
+        let header = PageHeader::parse(&buffer, offset as u16)
+            .and_then(|header| Ok((parse_cell_pointers(&buffer, offset as u16, &header, self.usable_size)?, header)));
+        let (pointers, header) = match header {
+            Ok(header) => header,
+            Err(error) => {
+                self.error(&here, error);
+                return None;
+            }
+        };
+        let page_type = header.get_page_type().clone();
+        // The right child first, then the cells from right to left, each key below the
+        // one after it. Only the last key of a page may equal the bound from its parent
+        let mut extents = Vec::new();
+        let mut depth = None;
+        let mut key_can_be_equal = true;
+        if matches!(page_type, PageType::TableInterior | PageType::IndexInterior) {
+            let right_prefix = format!("Tree {} page {} right child: ", root, page_num);
+            let right = header.get_right_most_point() as usize;
+            depth = self.check_page(root, right, &right_prefix, max_key);
+            key_can_be_equal = false;
+        }
+        for (i, &pointer) in pointers.iter().enumerate().rev() {
+            let cell_prefix = format!("Tree {} page {} cell {}: ", root, page_num, i);
+            let cell = match parse_cell(&buffer, pointer as usize, &page_type, self.usable_size) {
+                Ok(cell) => cell,
+                Err(error) => {
+                    self.error(&cell_prefix, error);
+                    continue;
+                }
+            };
+            extents.push((pointer as usize, cell.size.max(4)));
+            if let Some(key) = cell.key {
+                let out_of_order = match *max_key {
+                    Some(max) if key_can_be_equal => key > max,
+                    Some(max) => key >= max,
+                    None => false,
+                };
+                if out_of_order {
+                    self.error(&cell_prefix, format!("Rowid {} out of order", key));
+                }
+                *max_key = Some(key);
+                key_can_be_equal = false;
+            }
+            if let Some((first_page, spilled)) = cell.overflow {
+                self.check_overflow(&cell_prefix, first_page, spilled);
+            }
+            if let Some(child) = cell.left_child {
+                let child_depth = self.check_page(root, child as usize, &cell_prefix, max_key);
+                key_can_be_equal = false;
+                self.check_depth(&cell_prefix, &mut depth, child_depth);
+            }
+        }
+        self.check_space(&buffer, page_num, &header, &here, extents);
+        Some(depth.map_or(0, |depth| depth + 1))
+    }
+
+    // Every child of an interior page is the root of a subtree of the same depth
+    fn check_depth(&mut self, prefix: &str, depth: &mut Option<usize>, child_depth: Option<usize>) {
+        let Some(child_depth) = child_depth else {
+            return;
+        };
+        match *depth {
+            Some(depth) if depth != child_depth => self.error(prefix, "Child page depth differs"),
+            _ => *depth = Some(child_depth),
+        }
+    }
+
+    // The chain has to have as many pages as the spilled payload fills, each starting
+    // with the number of the next and the last with 0
+    fn check_overflow(&mut self, prefix: &str, first_page: u32, spilled: usize) {
+        let expected = spilled.div_ceil(self.usable_size - 4);
+        let errors = self.errors.len();
+        let mut found = 0;
+        let mut next_page = first_page as usize;
+        while next_page != 0 && !self.full() {
+            if !self.reference(next_page, prefix) {
+                break;
+            }
+            found += 1;
+            match self.pager.read_raw_page(next_page) {
+                Ok(buffer) => next_page = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize,
+                Err(error) => {
+                    self.error(prefix, error);
+                    break;
+                }
+            }
+        }
+        if found != expected && self.errors.len() == errors {
+            let message = format!("overflow list length is {} but should be {}", found, expected);
+            self.error(prefix, message);
+        }
+    }
+
+    // The cell content area holds the cells, the freeblocks and the fragmented bytes the
+    // header counts, nothing may take up a byte twice
+    fn check_space(
+        &mut self,
+        buffer: &[u8],
+        page_num: usize,
+        header: &PageHeader,
+        here: &str,
+        mut extents: Vec<(usize, usize)>,
+    ) {
+        let content_start = header.get_cell_content_offset() as usize;
+        let cell_bytes: usize = extents.iter().map(|(_, size)| size).sum();
+        let mut freeblock = header.get_first_freeblock() as usize;
+        let mut free_bytes = 0;
+        while freeblock != 0 {
+            if freeblock < content_start || freeblock + 4 > self.usable_size {
+                self.error(here, "free space corruption");
+                return;
+            }
+            let next = read_be_word_at(buffer, freeblock) as usize;
+            let size = read_be_word_at(buffer, freeblock + 2) as usize;
+            if size < 4 || freeblock + size > self.usable_size || (next != 0 && next < freeblock + size) {
+                self.error(here, "free space corruption");
+                return;
+            }
+            extents.push((freeblock, size));
+            free_bytes += size;
+            freeblock = next;
+        }
+        extents.sort_unstable();
+        for pair in extents.windows(2) {
+            let ((start, size), (next_start, _)) = (pair[0], pair[1]);
+            if next_start < start + size {
+                self.error("", format!("Multiple uses for byte {} of page {}", next_start, page_num));
+                return;
+            }
+        }
+        let fragmented = (self.usable_size - content_start).checked_sub(cell_bytes + free_bytes);
+        let reported = header.get_fragmented_bytes_count() as usize;
+        if let Some(fragmented) = fragmented.filter(|fragmented| *fragmented != reported) {
+            let message = format!("Fragmentation of {} bytes reported as {} on page {}", fragmented, reported, page_num);
+            self.error("", message);
+        }
+    }
+}
+
+// Read the cell at `pointer` without its payload
+// https://www.sqlite.org/fileformat.html#b_tree_pages
+fn parse_cell(buffer: &[u8], pointer: usize, page_type: &PageType, usable_size: usize) -> anyhow::Result<CellInfo> {
+    let cell = &buffer[pointer..usable_size];
+    let mut size = 0;
+    let left_child = match page_type {
+        PageType::TableInterior | PageType::IndexInterior => {
+            size = 4;
+            Some(u32::from_be_bytes(cell[0..4].try_into().unwrap()))
+        }
+        PageType::TableLeaf | PageType::IndexLeaf => None,
+    };
+    if *page_type == PageType::TableInterior {
+        let (n, key) = read_varint_i64(&cell[size..])?;
+        return Ok(CellInfo {
+            size: size + n,
+            key: Some(key),
+            left_child,
+            overflow: None,
+        });
+    }
+    let (n, payload_size) = read_varint(&cell[size..])?;
+    size += n;
+    let mut key = None;
+    if *page_type == PageType::TableLeaf {
+        let (n, row_id) = read_varint_i64(&cell[size..])?;
+        size += n;
+        key = Some(row_id);
+    }
+    if payload_size > i32::MAX as u64 {
+        anyhow::bail!("payload size is too large: {}", payload_size);
+    }
+    let payload_size = payload_size as usize;
+    let max_local = match page_type {
+        PageType::TableLeaf => table_leaf_max_local(usable_size),
+        _ => index_max_local(usable_size),
+    };
+    let local = local_payload_size(payload_size, max_local, usable_size);
+    size += local;
+    let mut overflow = None;
+    if local < payload_size {
+        let Some(first_page) = cell.get(size..size + 4) else {
+            anyhow::bail!("Extends off end of page");
+        };
+        overflow = Some((u32::from_be_bytes(first_page.try_into().unwrap()), payload_size - local));
+        size += 4;
+    }
+    if size > cell.len() {
+        anyhow::bail!("Extends off end of page");
+    }
+    Ok(CellInfo {
+        size,
+        key,
+        left_child,
+        overflow,
+    })
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+// Rollback journal in the format SQLite uses, so either can roll back a commit the other
+// didn't finish: a header padded to a sector, then the page number, original content and
+// a checksum of every page the commit overwrites
+// https://www.sqlite.org/fileformat.html#the_rollback_journal
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const SECTOR_SIZE: usize = 512;
+
+pub fn journal_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-journal");
+    path.into()
+}
+
+// Write the original content of the pages a commit is about to overwrite and sync it,
+// before the database file is touched. `page_count` is the size of the database before
+pub fn write(
+    path: &Path,
+    page_size: usize,
+    page_count: usize,
+    pages: &[(usize, Vec<u8>)],
+) -> anyhow::Result<()> {
+    let nonce = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    let mut journal = vec![0; SECTOR_SIZE];
+    journal[0..8].copy_from_slice(&JOURNAL_MAGIC);
+    journal[8..12].copy_from_slice(&(pages.len() as u32).to_be_bytes());
+    journal[12..16].copy_from_slice(&nonce.to_be_bytes());
+    journal[16..20].copy_from_slice(&(page_count as u32).to_be_bytes());
+    journal[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+    journal[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+    for (page_num, data) in pages {
+        journal.extend_from_slice(&(*page_num as u32).to_be_bytes());
+        journal.extend_from_slice(data);
+        journal.extend_from_slice(&checksum(nonce, data).to_be_bytes());
+    }
+    let mut file = File::create(path).context("create journal")?;
+    file.write_all(&journal).context("write journal")?;
+    file.sync_all().context("sync journal")
+}
+
+pub fn remove(path: &Path) -> anyhow::Result<()> {
+    fs::remove_file(path).context("delete journal")
+}
+
+// A hot journal means a commit didn't finish: put the original pages back, cut the file
+// to its old size and delete the journal. The caller holds the exclusive lock, and `db`
+// is its own handle on the file, closing another one would drop the locks
+pub fn recover(db: &mut File, path: &Path) -> anyhow::Result<()> {
+    let Ok(mut journal) = File::open(path) else {
+        return Ok(());
+    };
+    let mut data = Vec::new();
+    journal.read_to_end(&mut data).context("read journal")?;
+    let read_u32 = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+    // The size of the database before the commit, from the first header
+    let mut original = None;
+    // SQLite starts a new header, at the next sector, each time it syncs the journal
+    // in the middle of a commit
+    let mut header = 0;
+    while data.len() >= header + 28 && data[header..header + 8] == JOURNAL_MAGIC {
+        let nonce = read_u32(header + 12);
+        let sector_size = (read_u32(header + 20) as usize).max(SECTOR_SIZE);
+        let page_size = read_u32(header + 24) as usize;
+        let page_count = original.get_or_insert((read_u32(header + 16) as usize, page_size)).0;
+        let record_size = page_size + 8;
+        // -1 records means the journal runs to the end of the file
+        let records = match read_u32(header + 8) {
+            u32::MAX => data.len().saturating_sub(header + sector_size) / record_size,
+            n => n as usize,
+        };
+        let mut torn = false;
+        for i in 0..records {
+            let at = header + sector_size + i * record_size;
+            let Some(record) = data.get(at..at + record_size) else {
+                torn = true;
+                break;
+            };
+            let page_num = u32::from_be_bytes(record[0..4].try_into().unwrap()) as usize;
+            let page = &record[4..4 + page_size];
+            let sum = u32::from_be_bytes(record[4 + page_size..].try_into().unwrap());
+            // A torn record ends the journal
+            if page_num == 0 || sum != checksum(nonce, page) {
+                torn = true;
+                break;
+            }
+            if page_num <= page_count {
+                db.seek(SeekFrom::Start(((page_num - 1) * page_size) as u64))?;
+                db.write_all(page).context("restore page")?;
+            }
+        }
+        if torn || records == 0 {
+            break;
+        }
+        let end = header + sector_size + records * record_size;
+        header = end.div_ceil(sector_size) * sector_size;
+    }
+    if let Some((page_count, page_size)) = original {
+        db.set_len((page_count * page_size) as u64).context("truncate db file")?;
+        db.sync_all().context("sync db file")?;
+    }
+    remove(path)
+}
+
+// The nonce plus every 200th byte, counting back from 200 bytes before the end
+fn checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut sum = nonce;
+    let mut i = page.len() as isize - 200;
+    while i > 0 {
+        sum = sum.wrapping_add(page[i as usize] as u32);
+        i -= 200;
+    }
+    sum
+}
@@ -0,0 +1,39 @@
+// The engine, for embedding in other programs. `Connection` is the way in, the CLI in
+// main.rs is built on it
+mod aggregate;
+mod btree;
+mod cache;
+mod collation;
+pub mod connection;
+mod cursor;
+mod db;
+mod ephemeral;
+pub mod error;
+mod eval;
+mod functions;
+mod integrity;
+mod journal;
+mod lock;
+mod page;
+mod page_cache;
+pub mod params;
+mod planner;
+mod record;
+pub mod row;
+mod sorter;
+mod sql;
+pub mod statement;
+mod stats;
+mod utils;
+mod wal;
+
+pub use connection::Connection;
+pub use db::{OpenOptions, QueryResult, ScriptError, StatementStats};
+pub use error::{Error, Result};
+pub use page_cache::CacheStats;
+pub use params::Params;
+pub use record::Value;
+pub use row::{Row, Rows};
+pub use sql::error::ParseError;
+pub use statement::Statement;
+pub use stats::BtreeUsage;
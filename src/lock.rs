@@ -0,0 +1,259 @@
+use std::{fs::File, io};
+
+// The POSIX advisory locks SQLite takes on a database file, on bytes at 1 GiB that no page
+// ever uses, so a writer here and a writer in sqlite3 keep out of each other's way:
+// RESERVED is held by the one connection that is writing a transaction, PENDING and the
+// SHARED range while it overwrites the file. A journal with no RESERVED holder is hot, the
+// writer that left it is gone
+// https://www.sqlite.org/lockingv3.html
+const PENDING_BYTE: i64 = 0x4000_0000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    None,
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+// The locks are held on a handle of its own. They belong to the process, not the handle,
+// so it is closed only when the pager is done with the file
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    level: Level,
+}
+
+impl FileLock {
+    pub fn new(file: &File) -> io::Result<Self> {
+        Ok(Self {
+            file: file.try_clone()?,
+            level: Level::None,
+        })
+    }
+    pub fn level(&self) -> Level {
+        self.level
+    }
+    // Each of these returns false when another process is in the way, leaving the locks
+    // as they were
+    pub fn shared(&mut self) -> io::Result<bool> {
+        if self.level >= Level::Shared {
+            return Ok(true);
+        }
+        // A writer waiting for readers to leave holds PENDING, new readers stay out
+        if !sys::set(&self.file, sys::READ, PENDING_BYTE, 1)? {
+            return Ok(false);
+        }
+        let locked = sys::set(&self.file, sys::READ, SHARED_FIRST, SHARED_SIZE)?;
+        sys::set(&self.file, sys::UNLOCK, PENDING_BYTE, 1)?;
+        if locked {
+            self.level = Level::Shared;
+        }
+        Ok(locked)
+    }
+    pub fn reserved(&mut self) -> io::Result<bool> {
+        if self.level >= Level::Reserved {
+            return Ok(true);
+        }
+        if !self.shared()? || !sys::set(&self.file, sys::WRITE, RESERVED_BYTE, 1)? {
+            return Ok(false);
+        }
+        self.level = Level::Reserved;
+        Ok(true)
+    }
+    pub fn exclusive(&mut self) -> io::Result<bool> {
+        if self.level == Level::Exclusive {
+            return Ok(true);
+        }
+        if !self.shared()? || !sys::set(&self.file, sys::WRITE, PENDING_BYTE, 1)? {
+            return Ok(false);
+        }
+        if !sys::set(&self.file, sys::WRITE, SHARED_FIRST, SHARED_SIZE)? {
+            sys::set(&self.file, sys::UNLOCK, PENDING_BYTE, 1)?;
+            return Ok(false);
+        }
+        self.level = Level::Exclusive;
+        Ok(true)
+    }
+    // Back to reading only, after a hot journal was rolled back
+    pub fn downgrade(&mut self) -> io::Result<()> {
+        if self.level <= Level::Shared {
+            return Ok(());
+        }
+        sys::set(&self.file, sys::READ, SHARED_FIRST, SHARED_SIZE)?;
+        sys::set(&self.file, sys::UNLOCK, PENDING_BYTE, 2)?;
+        self.level = Level::Shared;
+        Ok(())
+    }
+    pub fn unlock(&mut self) -> io::Result<()> {
+        if self.level == Level::None {
+            return Ok(());
+        }
+        sys::set(&self.file, sys::UNLOCK, PENDING_BYTE, 2 + SHARED_SIZE)?;
+        self.level = Level::None;
+        Ok(())
+    }
+    // Whether a writer in another process holds RESERVED, a process never sees its own locks
+    pub fn reserved_elsewhere(&self) -> io::Result<bool> {
+        sys::held(&self.file, RESERVED_BYTE, 1)
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.unlock();
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    // struct flock and the fcntl commands differ between systems, and between the
+    // architectures of some. These are the values of each one's headers, a system not
+    // listed would need its own
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")
+    ))]
+    mod consts {
+        pub const F_GETLK: i32 = 5;
+        pub const F_SETLK: i32 = 6;
+        pub const READ: i16 = 0;
+        pub const WRITE: i16 = 1;
+        pub const UNLOCK: i16 = 2;
+
+        #[repr(C)]
+        pub struct Flock {
+            pub l_type: i16,
+            pub l_whence: i16,
+            pub l_start: i64,
+            pub l_len: i64,
+            pub l_pid: i32,
+        }
+    }
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    mod consts {
+        pub const F_GETLK: i32 = 7;
+        pub const F_SETLK: i32 = 8;
+        pub const READ: i16 = 1;
+        pub const WRITE: i16 = 3;
+        pub const UNLOCK: i16 = 2;
+
+        #[repr(C)]
+        pub struct Flock {
+            pub l_start: i64,
+            pub l_len: i64,
+            pub l_pid: i32,
+            pub l_type: i16,
+            pub l_whence: i16,
+        }
+    }
+
+    // 7 and 8 are the commands of the struct before l_sysid was added
+    #[cfg(target_os = "freebsd")]
+    mod consts {
+        pub const F_GETLK: i32 = 11;
+        pub const F_SETLK: i32 = 12;
+        pub const READ: i16 = 1;
+        pub const WRITE: i16 = 3;
+        pub const UNLOCK: i16 = 2;
+
+        #[repr(C)]
+        pub struct Flock {
+            pub l_start: i64,
+            pub l_len: i64,
+            pub l_pid: i32,
+            pub l_type: i16,
+            pub l_whence: i16,
+            pub l_sysid: i32,
+        }
+    }
+
+    #[cfg(not(any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")
+        ),
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "freebsd"
+    )))]
+    compile_error!("file locks need the fcntl values and struct flock of this system in lock.rs");
+
+    pub use consts::{READ, UNLOCK, WRITE};
+    use consts::{Flock, F_GETLK, F_SETLK};
+
+    extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+
+    fn flock(kind: i16, start: i64, len: i64) -> Flock {
+        Flock {
+            l_type: kind,
+            l_whence: 0,
+            l_start: start,
+            l_len: len,
+            l_pid: 0,
+            #[cfg(target_os = "freebsd")]
+            l_sysid: 0,
+        }
+    }
+
+    // False when another process holds a conflicting lock
+    pub fn set(file: &File, kind: i16, start: i64, len: i64) -> io::Result<bool> {
+        let mut lock = flock(kind, start, len);
+        // SAFETY: the descriptor is open for as long as `file` is borrowed, and F_SETLK
+        // only reads the struct flock `lock` points to, laid out as this system's is
+        if unsafe { fcntl(file.as_raw_fd(), F_SETLK, &mut lock as *mut Flock) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::PermissionDenied => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+    // Whether another process holds any lock on the bytes
+    pub fn held(file: &File, start: i64, len: i64) -> io::Result<bool> {
+        let mut lock = flock(WRITE, start, len);
+        // SAFETY: the descriptor is open for as long as `file` is borrowed, and F_GETLK
+        // writes the conflicting lock, if any, back into the struct flock `lock` points to
+        if unsafe { fcntl(file.as_raw_fd(), F_GETLK, &mut lock as *mut Flock) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(lock.l_type != UNLOCK)
+    }
+}
+
+// Without advisory locks every lock is granted and nobody else is ever seen holding one
+#[cfg(not(unix))]
+mod sys {
+    use std::{fs::File, io};
+
+    pub const READ: i16 = 0;
+    pub const WRITE: i16 = 1;
+    pub const UNLOCK: i16 = 2;
+
+    pub fn set(_file: &File, _kind: i16, _start: i64, _len: i64) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    pub fn held(_file: &File, _start: i64, _len: i64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
@@ -1,74 +1,140 @@
-use anyhow::{bail, Result};
-use db::Db;
-use page::Page;
-use std::fs::File;
-use std::io::prelude::*;
+use anyhow::{bail, Context, Result};
+use codecrafters_sqlite::{Connection, OpenOptions, Params, Value};
+use format::{Format, Mode};
+use shell::{print_results, Shell};
+use std::io::{self, prelude::*, IsTerminal};
 
-mod db;
-mod page;
-mod utils;
-mod record;
-mod sql;
+mod editor;
+mod format;
+mod shell;
+mod terminal;
+mod watch;
 
 fn main() -> Result<()> {
     // Parse arguments
-    let args = std::env::args().collect::<Vec<_>>();
-    match args.len() {
-        0 | 1 => bail!("Missing <database path> and <command>"),
-        2 => bail!("Missing <command>"),
-        _ => {}
-    }
+    let mut args = std::env::args().collect::<Vec<_>>();
 
-    // Parse command and act accordingly
-    let command = &args[2];
-    match command.as_str() {
-        ".dbinfo" => {
-            let mut file = File::open(&args[1])?;
-            let mut header = [0; 100];
-            file.read_exact(&mut header)?;
+    // Options go between the program name and the database path, like sqlite3
+    let mut rowid_in_wildcard = false;
+    let mut format = Format::new(Mode::List, None);
+    let mut sort_memory_limit = None;
+    let mut watch = false;
+    let mut result_cache = false;
+    // Like sqlite3, a database that doesn't exist yet is created
+    let mut options = OpenOptions::new();
+    options.create(true);
+    let mut cache_stats = false;
+    let mut params = Params::new();
+    #[cfg(feature = "unicode-case")]
+    let mut unicode_case = false;
+    while args.len() > 1 && args[1].starts_with('-') {
+        match args.remove(1).as_str() {
+            "-rowid" => rowid_in_wildcard = true,
+            "-header" => format.header = Some(true),
+            "-noheader" => format.header = Some(false),
+            "-mode" | "--mode" if args.len() > 1 => format.mode = args.remove(1).parse()?,
+            "-nullvalue" if args.len() > 1 => format.null_value = args.remove(1),
+            "-sortmem" if args.len() > 1 => sort_memory_limit = Some(args.remove(1).parse()?),
+            "-watch" | "--watch" => watch = true,
+            "-cache" => result_cache = true,
+            "-cachesize" if args.len() > 1 => {
+                options.cache_size(args.remove(1).parse()?);
+            }
+            "-readonly" => {
+                options.read_only();
+            }
+            "-cachestats" => cache_stats = true,
+            // -param :name value, or ?N for a numbered parameter
+            "-param" if args.len() > 2 => {
+                let name = args.remove(1);
+                let value = param_value(&args.remove(1));
+                match name.strip_prefix('?') {
+                    Some(index) => params.bind(index.parse().context("bad parameter index")?, value)?,
+                    None => params.bind_named(&name, value)?,
+                }
+            }
+            #[cfg(feature = "unicode-case")]
+            "-unicode" => unicode_case = true,
+            option => bail!("Unknown option: {}", option),
+        }
+    }
 
-            // The page size is stored at the 16th byte offset, using 2 bytes in big-endian order
-            #[allow(unused_variables)]
-            let page_size = u16::from_be_bytes([header[16], header[17]]);
+    if args.len() < 2 {
+        bail!("Missing <database path> and <command>");
+    }
+    let open = || -> Result<Connection> {
+        let mut connection = Connection::open_with(&args[1], &options)?;
+        connection.set_rowid_in_wildcard(rowid_in_wildcard);
+        connection.set_result_cache(result_cache);
+        if let Some(bytes) = sort_memory_limit {
+            connection.set_sort_memory_limit(bytes);
+        }
+        #[cfg(feature = "unicode-case")]
+        connection.set_unicode_case(unicode_case);
+        Ok(connection)
+    };
+    if args.len() == 2 {
+        let mut shell = Shell::new(&args[1], open()?, params, format);
+        if io::stdin().is_terminal() {
+            return shell.repl();
+        }
+        // SQL piped on stdin runs as a script, like `.read`
+        let mut script = String::new();
+        io::stdin().read_to_string(&mut script)?;
+        if !shell.run_script(&script) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-            println!("database page size: {}", page_size);
-            let mut page_header = [0; 12];
-            file.read_exact(&mut page_header)?;
-            let cells = u16::from_be_bytes([page_header[3], page_header[4]]);
-            println!("number of tables: {}", cells);
+    let command = &args[2];
+    if !watch || command.starts_with('.') {
+        let mut shell = Shell::new(&args[1], open()?, params.clone(), format.clone());
+        let succeeded = shell.run_command(command)?;
+        if cache_stats && !command.starts_with('.') {
+            let stats = shell.connection().cache_stats();
+            eprintln!(
+                "page cache: {} hits, {} misses, {} evictions",
+                stats.hits, stats.misses, stats.evictions
+            );
         }
-        ".tables" => {
-            let mut db = Db::from_file(&args[1])?;
-            let page = db.pager.read_page(1).unwrap();
-            match page {
-                Page::TableLeaf(leaf) => {
-                    let mut table_names = Vec::new();
-                    for cell in &leaf.cells {
-                        if let Some(name) = cell.record.body.get(2) {
-                            if let crate::record::Value::String(table_name) = &name.value {
-                                table_names.push(table_name.clone());
-                            }
-                        }
-                    }
-                    table_names.sort();
-                    println!("{}", table_names.join(" "));
-                }
-                _ => bail!("Invalid page type"),
-            }
+        if !succeeded {
+            std::process::exit(1);
         }
-        // https://saveriomiroddi.github.io/SQLIte-database-file-format-diagrams/
-        sql => {
-            let mut db = Db::from_file(&args[1])?;
-            let results = db.execute_sql(sql)?;
-            for rows in results {
-                for row in &rows {
-                   println!("{}", row.join("|")); 
-                }
-                
+        return Ok(());
+    }
+
+    // Re-run the query on every change until interrupted. The database is opened
+    // afresh each time so no stale pages are cached
+    let mut watcher = watch::FileWatcher::new(&args[1]);
+    loop {
+        match open() {
+            // Paging would hold up the refreshes
+            Ok(mut connection) => {
+                print_results(connection.execute_script(command, &params), &format, false, None);
             }
+            Err(err) => eprintln!("Error: {}", err),
         }
-        _ => bail!("Missing or invalid command passed: {}", command),
+        watcher.wait_for_change();
+        // A blank line sets each refresh apart
+        println!();
     }
+}
 
-    Ok(())
+// The value of a -param option: a number, NULL or a quoted string, any other text as is
+fn param_value(text: &str) -> Value {
+    if let Ok(n) = text.parse() {
+        return Value::I64(n);
+    }
+    // Not "inf" or "nan", which Rust reads as numbers but SQL doesn't
+    if let Some(n) = text.parse::<f64>().ok().filter(|n| n.is_finite()) {
+        return Value::Float(n);
+    }
+    if text.eq_ignore_ascii_case("null") {
+        return Value::Null;
+    }
+    match text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        Some(quoted) => Value::String(quoted.replace("''", "'")),
+        None => Value::String(text.to_string()),
+    }
 }
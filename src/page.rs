@@ -1,9 +1,12 @@
+use std::{collections::HashSet, sync::Arc};
+
 use anyhow::Ok;
 
 use crate::{
     db::HEADER_SIZE,
+    error::Error,
     record::Record,
-    utils::{read_be_word_at, read_varint},
+    utils::{read_be_word_at, read_varint, read_varint_i64},
 };
 
 pub const TABLE_LEAF_PAGE_ID: u8 = 0x0d;
@@ -11,17 +14,20 @@ pub const TABLE_INTERIOR_PAGE_ID: u8 = 0x05;
 pub const INDEX_LEAF_PAGE_ID: u8 = 0x0a;
 pub const INDEX_INTERIOR_PAGE_ID: u8 = 0x02;
 
-const PAGE_LEAF_HEADER_SIZE: usize = 8;
-const PAGE_INTERIOR_HEADER_SIZE: usize = 12;
+pub const PAGE_LEAF_HEADER_SIZE: usize = 8;
+pub const PAGE_INTERIOR_HEADER_SIZE: usize = 12;
 
 const PAGE_FIRST_FREEBLOCK_OFFSET: usize = 1;
-const PAGE_CELL_COUNT_OFFSET: usize = 3;
-const PAGE_CELL_CONTENT_OFFSET: usize = 5;
+pub const PAGE_CELL_COUNT_OFFSET: usize = 3;
+pub const PAGE_CELL_CONTENT_OFFSET: usize = 5;
 const PAGE_FRAGMENTED_BYTES_COUNT_OFFSET: usize = 7;
-const PAGE_RIGHT_MOST_POINTER_OFFSET: usize = 8;
+pub const PAGE_RIGHT_MOST_POINTER_OFFSET: usize = 8;
+
+// Reads a page of the file by number, to follow the overflow chains of cells
+pub type ReadPage<'a> = dyn FnMut(usize) -> anyhow::Result<Vec<u8>> + 'a;
 
 
-  /**
+  /*
 
                     [Interior Table Page (Root)]
                     +---------------------------+
@@ -51,7 +57,12 @@ pub enum Page {
 }
 
 impl Page {
-    pub fn parse(buffer: &[u8], page_num: usize) -> anyhow::Result<Self> {
+    pub fn parse(
+        buffer: &Arc<[u8]>,
+        page_num: usize,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
         // https://www.sqlite.org/fileformat.html#b_tree_pages
         // The 100-byte database file header (found on page 1 only)
         // The 8 or 12 byte b-tree page header
@@ -60,64 +71,75 @@ impl Page {
         // The cell content area
         // The reserved region
         let ptr_offset = if page_num == 1 { HEADER_SIZE as u16 } else { 0 };
-        let page_type = buffer[ptr_offset as usize];
+        let page_type = page_bytes(buffer, ptr_offset as usize, 1, usable_size)?[0];
        
         match page_type {
             TABLE_LEAF_PAGE_ID => {
-                let page = TableLeafPage::parse(buffer, ptr_offset)?;
+                let page = TableLeafPage::parse(buffer, ptr_offset, usable_size, read_page)?;
                 Ok(Self::TableLeaf(page))
             }
             TABLE_INTERIOR_PAGE_ID => {
-                let page = TableInteriorPage::parse(buffer, ptr_offset)?;
+                let page = TableInteriorPage::parse(buffer, ptr_offset, usable_size)?;
                 Ok(Self::TableInterior(page))
             }
             INDEX_LEAF_PAGE_ID => {
-                let page = IndexLeafPage::parse(buffer, ptr_offset)?;
+                let page = IndexLeafPage::parse(buffer, ptr_offset, usable_size, read_page)?;
                 Ok(Self::IndexLeaf(page))
             }
             INDEX_INTERIOR_PAGE_ID => {
-                let page = IndexInteriorPage::parse(buffer, ptr_offset)?;
+                let page = IndexInteriorPage::parse(buffer, ptr_offset, usable_size, read_page)?;
                 Ok(Self::IndexInterior(page))
             }
             _ => {
-                anyhow::bail!("Unknown page type in page parse: {}", page_type)
+                anyhow::bail!(Error::CorruptPage(format!("Unknown page type in page parse: {}", page_type)))
             }
         }
     }
 
     pub fn get_page_type(&self) -> &PageType {
+        self.get_header().get_page_type()
+    }
+
+    pub fn get_header(&self) -> &PageHeader {
         match self {
-            Page::TableLeaf(page) => page.header.get_page_type(),
-            Page::TableInterior(page) => page.header.get_page_type(),
-            Page::IndexLeaf(page) => page.header.get_page_type(),
-            Page::IndexInterior(page) => page.header.get_page_type(),
+            Page::TableLeaf(page) => &page.header,
+            Page::TableInterior(page) => &page.header,
+            Page::IndexLeaf(page) => &page.header,
+            Page::IndexInterior(page) => &page.header,
         }
     }
 }
 
+// With auto_vacuum every page but page 1 has an entry in a pointer-map page, the type of
+// page and its parent, so pages can be moved. Pointer-map pages are not b-tree pages:
+// the first is page 2 and each is followed by the usable_size / 5 pages it describes
+// https://www.sqlite.org/fileformat.html#pointer_map_or_ptrmap_pages
+pub fn is_pointer_map_page(page_num: usize, usable_size: usize) -> bool {
+    let entries = usable_size / 5;
+    page_num >= 2 && (page_num - 2) % (entries + 1) == 0
+}
+
 #[derive(Debug, Clone)]
 pub struct TableLeafPage {
     pub header: PageHeader,
     pub cells: Vec<TableLeafCell>,
 }
 impl TableLeafPage {
-    pub fn parse(buffer: &[u8], ptr_offset: u16) -> anyhow::Result<Self> {
+    pub fn parse(
+        buffer: &Arc<[u8]>,
+        ptr_offset: u16,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
         // all buffer starts db header
         let header = PageHeader::parse(buffer, ptr_offset)?;
 
-        // 计算单元格指针区域的起始位置（紧跟在页面头部之后）
-        let cell_pointer_area_start = ptr_offset as usize + PAGE_LEAF_HEADER_SIZE;
-
         // 解析单元格指针数组
-        let cell_pointers = parse_cell_pointers(
-            &buffer[cell_pointer_area_start..],
-            header.cell_count as usize,
-            ptr_offset,
-        );
+        let cell_pointers = parse_cell_pointers(buffer, ptr_offset, &header, usable_size)?;
         // 解析每个单元格
         let cells = cell_pointers
             .iter()
-            .map(|ptr| TableLeafCell::parse(&buffer[*ptr as usize..]))
+            .map(|ptr| TableLeafCell::parse(buffer, *ptr as usize, usable_size, read_page))
             .collect::<anyhow::Result<Vec<TableLeafCell>>>()?;
         Ok(TableLeafPage {
             header,
@@ -137,13 +159,16 @@ pub struct PageHeader {
 }
 impl PageHeader {
     pub fn parse(buffer: &[u8], ptr_offset: u16) -> anyhow::Result<Self> {
+        if buffer.len() < ptr_offset as usize + PAGE_LEAF_HEADER_SIZE {
+            anyhow::bail!(Error::CorruptPage("page header runs past the end of the page".to_string()));
+        }
         // 验证页面类型
         let page_type = match buffer[ptr_offset as usize] {
             TABLE_LEAF_PAGE_ID => PageType::TableLeaf,
             TABLE_INTERIOR_PAGE_ID => PageType::TableInterior,
             INDEX_LEAF_PAGE_ID => PageType::IndexLeaf,
             INDEX_INTERIOR_PAGE_ID => PageType::IndexInterior,
-            other => anyhow::bail!("Unsupported page type: {}", other),
+            other => anyhow::bail!(Error::CorruptPage(format!("Unsupported page type: {}", other))),
         };
 
         // 读取页面头部的各个字段
@@ -160,9 +185,12 @@ impl PageHeader {
         let right_most_point = if page_type == PageType::TableLeaf || page_type == PageType::IndexLeaf {
             0
         } else {
+            if buffer.len() < ptr_offset as usize + PAGE_INTERIOR_HEADER_SIZE {
+                anyhow::bail!(Error::CorruptPage("page header runs past the end of the page".to_string()));
+            }
             u32::from_be_bytes(
                 buffer[ptr_offset as usize + PAGE_RIGHT_MOST_POINTER_OFFSET
-                    ..PAGE_INTERIOR_HEADER_SIZE]
+                    ..ptr_offset as usize + PAGE_INTERIOR_HEADER_SIZE]
                     .try_into()
                     .unwrap(),
             )
@@ -186,6 +214,30 @@ impl PageHeader {
         self.cell_count
     }
 
+    pub fn get_first_freeblock(&self) -> u16 {
+        self.first_freeblock
+    }
+
+    pub fn get_cell_content_offset(&self) -> u32 {
+        // A zero value is interpreted as 65536
+        if self.cell_content_offset == 0 {
+            65_536
+        } else {
+            self.cell_content_offset
+        }
+    }
+
+    pub fn get_fragmented_bytes_count(&self) -> u8 {
+        self.fragmented_bytes_count
+    }
+
+    pub fn get_header_size(&self) -> usize {
+        match self.page_type {
+            PageType::TableLeaf | PageType::IndexLeaf => PAGE_LEAF_HEADER_SIZE,
+            PageType::TableInterior | PageType::IndexInterior => PAGE_INTERIOR_HEADER_SIZE,
+        }
+    }
+
     pub fn get_page_type(&self) -> &PageType {
         &self.page_type
     }
@@ -202,7 +254,9 @@ pub enum PageType {
 #[derive(Debug, Clone)]
 pub struct TableLeafCell {
     pub size: u64,
-    pub row_id: u64,
+    pub row_id: i64,
+    pub local_size: usize,
+    pub overflow_page: Option<u32>,
     pub record: Record,
 }
 
@@ -213,30 +267,193 @@ impl TableLeafCell {
     // A varint which is the integer key, a.k.a. "rowid"
     // The initial portion of the payload that does not spill to overflow pages.
     // A 4-byte big-endian integer page number for the first page of the overflow page list - omitted if all payload fits on the b-tree page.
-    pub fn parse(cell_buffer: &[u8]) -> anyhow::Result<Self> {
-        let (n, payload_size) = read_varint(cell_buffer)?;
-        let buffer = &cell_buffer[n as usize..];
-
-        let (n, row_id) = read_varint(buffer)?;
-        let buffer = &buffer[n as usize..]; //  start of payload
-
-        let payload = buffer[..payload_size as usize].to_vec();
-        let record = Record::parse(&payload, row_id)?;
+    // The cell starts at `offset` in the page, its record borrows from the page
+    pub fn parse(
+        page: &Arc<[u8]>,
+        offset: usize,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
+        let (n, payload_size) = read_varint(page_rest(page, offset, usable_size)?)?;
+        let (m, row_id) = read_varint_i64(page_rest(page, offset + n, usable_size)?)?;
+        let start = offset + n + m; //  start of payload
+
+        let max_local = table_leaf_max_local(usable_size);
+        let (payload, overflow_page) = split_payload(page, start, payload_size, max_local, usable_size)?;
+        let record = parse_record(
+            page,
+            start,
+            payload.len(),
+            payload_size as usize,
+            overflow_page,
+            usable_size,
+            read_page,
+        )?;
         Ok(Self {
-            size: payload_size as u64,
+            size: payload_size,
             row_id,
+            local_size: payload.len(),
+            overflow_page,
             record,
         })
     }
 }
 
-fn parse_cell_pointers(buffer: &[u8], cell_count: usize, ptr_offset: u16) -> Vec<u16> {
+// https://www.sqlite.org/fileformat.html#cell_payload
+// U: usable size, P: payload size
+// X: the most payload a cell keeps on the b-tree page, U-35 for table leaves and
+//    ((U-12)*64/255)-23 for index pages (the 64/255 max embedded payload fraction)
+// M: ((U-12)*32/255)-23, the least payload kept on the page once it spills (32/255 min fraction)
+// K: M+((P-M)%(U-4)), used as the local size when it does not exceed X, so that the
+//    overflow pages end up completely full
+pub fn table_leaf_max_local(usable_size: usize) -> usize {
+    usable_size - 35
+}
+
+pub fn index_max_local(usable_size: usize) -> usize {
+    ((usable_size - 12) * 64 / 255) - 23
+}
+
+pub fn min_local(usable_size: usize) -> usize {
+    ((usable_size - 12) * 32 / 255) - 23
+}
+
+// Number of payload bytes stored inline on the b-tree page
+pub fn local_payload_size(payload_size: usize, max_local: usize, usable_size: usize) -> usize {
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let min_local = min_local(usable_size);
+    let local = min_local + (payload_size - min_local) % (usable_size - 4);
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+// Split the payload of a cell, which starts at `start` in the page, into the inline bytes
+// and the first overflow page, if any
+fn split_payload(
+    page: &[u8],
+    start: usize,
+    payload_size: u64,
+    max_local: usize,
+    usable_size: usize,
+) -> anyhow::Result<(&[u8], Option<u32>)> {
+    // SQLite never stores a value longer than this, a bigger size is garbage
+    if payload_size > i32::MAX as u64 {
+        anyhow::bail!(Error::CorruptPage(format!("payload size is too large: {}", payload_size)));
+    }
+    let payload_size = payload_size as usize;
+    let local_size = local_payload_size(payload_size, max_local, usable_size);
+    let local = page_bytes(page, start, local_size, usable_size)?;
+    if local_size == payload_size {
+        return Ok((local, None));
+    }
+    let overflow_page = page_bytes(page, start + local_size, 4, usable_size)?;
+    Ok((local, Some(u32::from_be_bytes(overflow_page.try_into().unwrap()))))
+}
+
+// `len` bytes of the page from `start`, which have to end before the reserved region
+fn page_bytes(page: &[u8], start: usize, len: usize, usable_size: usize) -> anyhow::Result<&[u8]> {
+    match start.checked_add(len) {
+        Some(end) if end <= usable_size.min(page.len()) => Ok(&page[start..end]),
+        _ => anyhow::bail!(Error::CorruptPage(format!(
+            "cell at offset {} runs past the end of the page",
+            start
+        ))),
+    }
+}
+
+// The rest of the page from `start`, up to the reserved region
+fn page_rest(page: &[u8], start: usize, usable_size: usize) -> anyhow::Result<&[u8]> {
+    page_bytes(page, start, usable_size.saturating_sub(start), usable_size)
+}
+
+// Parse the record of a cell whose payload starts at `start` in the page. A payload that
+// fits on the page is borrowed from it, one that spilled to overflow pages is gathered first
+fn parse_record(
+    page: &Arc<[u8]>,
+    start: usize,
+    local_size: usize,
+    payload_size: usize,
+    overflow_page: Option<u32>,
+    usable_size: usize,
+    read_page: &mut ReadPage,
+) -> anyhow::Result<Record> {
+    let local = &page[start..start + local_size];
+    match overflow_page {
+        None => Record::parse_shared(page.clone(), start, start + local_size),
+        Some(first_page) => {
+            let payload = read_overflow(local, first_page, payload_size, usable_size, read_page)?;
+            Record::parse_shared(payload.into(), 0, payload_size)
+        }
+    }
+}
+
+// Follow an overflow chain, appending to the local bytes until the payload is whole.
+// Each overflow page starts with the number of the next one, 0 on the last
+pub fn read_overflow(
+    local: &[u8],
+    first_page: u32,
+    payload_size: usize,
+    usable_size: usize,
+    read_page: &mut ReadPage,
+) -> anyhow::Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(payload_size);
+    payload.extend_from_slice(local);
+    let mut next_page = first_page;
+    let mut seen = HashSet::new();
+    while payload.len() < payload_size {
+        if next_page == 0 {
+            anyhow::bail!(Error::CorruptPage("overflow chain ends before the payload does".to_string()));
+        }
+        if !seen.insert(next_page) {
+            anyhow::bail!(Error::CorruptPage(format!("overflow chain loops back to page {}", next_page)));
+        }
+        let buffer = read_page(next_page as usize)?;
+        next_page = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
+        let take = (payload_size - payload.len()).min(usable_size - 4);
+        payload.extend_from_slice(&buffer[4..4 + take]);
+    }
+    Ok(payload)
+}
+
+// The cell pointer array follows the page header. The array has to end before the cell
+// content area starts, and each cell has to start in that area with room for at least 4
+// bytes before the reserved region
+pub fn parse_cell_pointers(
+    buffer: &[u8],
+    ptr_offset: u16,
+    header: &PageHeader,
+    usable_size: usize,
+) -> anyhow::Result<Vec<u16>> {
+    let cell_count = header.cell_count as usize;
+    let array_start = ptr_offset as usize + header.get_header_size();
+    let array_end = array_start + cell_count * 2;
+    let content_start = header.get_cell_content_offset() as usize;
+    if content_start > usable_size {
+        anyhow::bail!(Error::CorruptPage(format!(
+            "cell content area starts past the end of the page: {}",
+            content_start
+        )));
+    }
+    if array_end > content_start {
+        anyhow::bail!(Error::CorruptPage(format!(
+            "{} cell pointers run into the cell content area",
+            cell_count
+        )));
+    }
     let mut pointers = Vec::with_capacity(cell_count);
     for i in 0..cell_count {
-        let ptr = read_be_word_at(buffer, i * 2);
+        let ptr = read_be_word_at(buffer, array_start + i * 2);
+        if (ptr as usize) < content_start || ptr as usize > usable_size - 4 {
+            anyhow::bail!(Error::CorruptPage(format!("cell pointer {} is out of range: {}", i, ptr)));
+        }
         pointers.push(ptr);
     }
-    pointers
+    Ok(pointers)
 }
 
 #[derive(Debug, Clone)]
@@ -246,20 +463,13 @@ pub struct TableInteriorPage {
 }
 
 impl TableInteriorPage {
-    pub fn parse(buffer: &[u8], ptr_offset: u16) -> anyhow::Result<Self> {
+    pub fn parse(buffer: &[u8], ptr_offset: u16, usable_size: usize) -> anyhow::Result<Self> {
         let header = PageHeader::parse(buffer, ptr_offset)?;
-        // 计算单元格指针区域的起始位置（紧跟在页面头部之后）
-        let cell_pointer_area_start = ptr_offset as usize + PAGE_INTERIOR_HEADER_SIZE;
-
-        let cell_pointers = parse_cell_pointers(
-            &buffer[cell_pointer_area_start..],
-            header.cell_count as usize,
-            ptr_offset,
-        );
+        let cell_pointers = parse_cell_pointers(buffer, ptr_offset, &header, usable_size)?;
 
         let cells = cell_pointers
             .iter()
-            .map(|ptr| TableInteriorCell::parse(&buffer[*ptr as usize..]))
+            .map(|ptr| TableInteriorCell::parse(page_rest(buffer, *ptr as usize, usable_size)?))
             .collect::<anyhow::Result<Vec<TableInteriorCell>>>()?;
 
         Ok(TableInteriorPage {
@@ -271,15 +481,17 @@ impl TableInteriorPage {
 
 #[derive(Debug, Clone)]
 pub struct TableInteriorCell {
-    pub row_id: u64,
+    pub row_id: i64,
     pub left_child: u32,
 }
 
 impl TableInteriorCell {
     pub fn parse(cell_buffer: &[u8]) -> anyhow::Result<Self> {
-        let left_child = u32::from_be_bytes(cell_buffer[0..4].try_into().unwrap());
-        let buffer = &cell_buffer[4..];
-        let (n, row_id) = read_varint(buffer)?;
+        let Some((left_child, buffer)) = cell_buffer.split_first_chunk::<4>() else {
+            anyhow::bail!(Error::CorruptPage("cell runs past the end of the page".to_string()));
+        };
+        let left_child = u32::from_be_bytes(*left_child);
+        let (_, row_id) = read_varint_i64(buffer)?;
         Ok(TableInteriorCell { row_id, left_child })
     }
 }
@@ -292,17 +504,17 @@ pub struct IndexLeafPage {
 }
 
 impl IndexLeafPage {
-    pub fn parse(buffer: &[u8], ptr_offset: u16) -> anyhow::Result<Self> {
+    pub fn parse(
+        buffer: &Arc<[u8]>,
+        ptr_offset: u16,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
         let header = PageHeader::parse(buffer, ptr_offset)?;
-        let cell_pointer_area_start = ptr_offset as usize + PAGE_LEAF_HEADER_SIZE;
-        let cell_pointers = parse_cell_pointers(
-            &buffer[cell_pointer_area_start..],
-            header.cell_count as usize,
-            ptr_offset,
-        );
+        let cell_pointers = parse_cell_pointers(buffer, ptr_offset, &header, usable_size)?;
         let cells = cell_pointers
             .iter()
-            .map(|ptr| IndexLeafCell::parse(&buffer[*ptr as usize..]))
+            .map(|ptr| IndexLeafCell::parse(buffer, *ptr as usize, usable_size, read_page))
             .collect::<anyhow::Result<Vec<IndexLeafCell>>>()?;
         Ok(IndexLeafPage {
             header,
@@ -314,17 +526,36 @@ impl IndexLeafPage {
 #[derive(Debug, Clone)]
 pub struct IndexLeafCell {
     pub size: usize,
+    pub local_size: usize,
+    pub overflow_page: Option<u32>,
     pub record: Record,
 }
 
 impl IndexLeafCell {
-    pub fn parse(cell_buffer: &[u8]) -> anyhow::Result<Self> {
-        let (n, payload_size) = read_varint(cell_buffer)?;
-        let buffer = &cell_buffer[n as usize..];
-
-        let record = Record::parse(buffer, 0)?;
+    pub fn parse(
+        page: &Arc<[u8]>,
+        offset: usize,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
+        let (n, payload_size) = read_varint(page_rest(page, offset, usable_size)?)?;
+        let start = offset + n;
+
+        let max_local = index_max_local(usable_size);
+        let (payload, overflow_page) = split_payload(page, start, payload_size, max_local, usable_size)?;
+        let record = parse_record(
+            page,
+            start,
+            payload.len(),
+            payload_size as usize,
+            overflow_page,
+            usable_size,
+            read_page,
+        )?;
         Ok(Self {
             size: payload_size as usize,
+            local_size: payload.len(),
+            overflow_page,
             record,
         })
     }
@@ -337,17 +568,17 @@ pub struct IndexInteriorPage {
 }
 
 impl IndexInteriorPage {
-    pub fn parse(buffer: &[u8], ptr_offset: u16) -> anyhow::Result<Self> {
+    pub fn parse(
+        buffer: &Arc<[u8]>,
+        ptr_offset: u16,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
         let header = PageHeader::parse(buffer, ptr_offset)?;
-        let cell_pointer_area_start = ptr_offset as usize + PAGE_INTERIOR_HEADER_SIZE;
-        let cell_pointers = parse_cell_pointers(
-            &buffer[cell_pointer_area_start..],
-            header.cell_count as usize,
-            ptr_offset,
-        );
+        let cell_pointers = parse_cell_pointers(buffer, ptr_offset, &header, usable_size)?;
         let cells = cell_pointers
             .iter()
-            .map(|ptr| IndexInteriorCell::parse(&buffer[*ptr as usize..]))
+            .map(|ptr| IndexInteriorCell::parse(buffer, *ptr as usize, usable_size, read_page))
             .collect::<anyhow::Result<Vec<IndexInteriorCell>>>()?;
 
         Ok(IndexInteriorPage {
@@ -361,19 +592,38 @@ impl IndexInteriorPage {
 pub struct IndexInteriorCell {
     pub size: usize,
     pub left_child: u32,
+    pub local_size: usize,
+    pub overflow_page: Option<u32>,
     pub record: Record,
 }
 
 impl IndexInteriorCell {
-    pub fn parse(buffer: &[u8]) -> anyhow::Result<Self> {
-        let left_child = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-        let buffer = &buffer[4..];
-        let (n, payload_size) = read_varint(buffer)?;
-        let buffer = &buffer[n as usize..];
-        let record = Record::parse(buffer, 0)?;
+    pub fn parse(
+        page: &Arc<[u8]>,
+        offset: usize,
+        usable_size: usize,
+        read_page: &mut ReadPage,
+    ) -> anyhow::Result<Self> {
+        let left_child = page_bytes(page, offset, 4, usable_size)?;
+        let left_child = u32::from_be_bytes(left_child.try_into().unwrap());
+        let (n, payload_size) = read_varint(page_rest(page, offset + 4, usable_size)?)?;
+        let start = offset + 4 + n;
+        let max_local = index_max_local(usable_size);
+        let (payload, overflow_page) = split_payload(page, start, payload_size, max_local, usable_size)?;
+        let record = parse_record(
+            page,
+            start,
+            payload.len(),
+            payload_size as usize,
+            overflow_page,
+            usable_size,
+            read_page,
+        )?;
         Ok(Self {
             size: payload_size as usize,
             left_child,
+            local_size: payload.len(),
+            overflow_page,
             record,
         })
     }
@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use crate::page::Page;
+
+// Pages kept when no limit is set
+pub const DEFAULT_CAPACITY: usize = 2000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+// Parsed pages by page number, limited to `capacity` pages. Once full the page used least
+// recently makes room, so scanning a large database reads through a fixed amount of memory
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: usize,
+    // Each page with the tick of its last use
+    pages: HashMap<usize, (Arc<Page>, u64)>,
+    // Page numbers by the tick of their last use, least recent first
+    recency: BTreeMap<u64, usize>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.pages.len() > self.capacity {
+            self.evict();
+        }
+    }
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+    // A hit, the page becomes the most recently used
+    pub fn get(&mut self, page_num: usize) -> Option<Arc<Page>> {
+        let tick = self.next_tick();
+        let (page, used) = self.pages.get_mut(&page_num)?;
+        self.recency.remove(used);
+        self.recency.insert(tick, page_num);
+        *used = tick;
+        self.stats.hits += 1;
+        Some(page.clone())
+    }
+    // A miss, the page was read from the file
+    pub fn insert(&mut self, page_num: usize, page: Arc<Page>) {
+        self.remove(page_num);
+        while self.pages.len() >= self.capacity {
+            self.evict();
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, page_num);
+        self.stats.misses += 1;
+        self.pages.insert(page_num, (page, tick));
+    }
+    pub fn remove(&mut self, page_num: usize) {
+        if let Some((_, used)) = self.pages.remove(&page_num) {
+            self.recency.remove(&used);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.recency.clear();
+    }
+    fn evict(&mut self) {
+        if let Some((_, page_num)) = self.recency.pop_first() {
+            self.pages.remove(&page_num);
+            self.stats.evictions += 1;
+        }
+    }
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::TABLE_LEAF_PAGE_ID;
+
+    // An empty table leaf, which page 2 and later parse to
+    fn page() -> Arc<Page> {
+        let mut buffer = vec![0; 4096];
+        buffer[0] = TABLE_LEAF_PAGE_ID;
+        buffer[5..7].copy_from_slice(&4096u16.to_be_bytes());
+        let buffer: Arc<[u8]> = buffer.into();
+        Arc::new(Page::parse(&buffer, 2, 4096, &mut |_| unreachable!()).unwrap())
+    }
+
+    fn cached(cache: &mut PageCache, pages: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        pages.into_iter().filter(|&page_num| cache.get(page_num).is_some()).collect()
+    }
+
+    #[test]
+    fn the_least_recently_used_page_makes_room() {
+        let mut cache = PageCache::new(3);
+        for page_num in 1..=3 {
+            cache.insert(page_num, page());
+        }
+        // Using page 1 leaves page 2 the least recent
+        assert!(cache.get(1).is_some());
+        cache.insert(4, page());
+        assert_eq!(cached(&mut cache, [2]), []);
+        // Then 3 and 1 are the least recent
+        cache.insert(5, page());
+        cache.insert(6, page());
+        assert_eq!(cached(&mut cache, 1..=6), [4, 5, 6]);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (4, 6, 3));
+    }
+
+    #[test]
+    fn a_reinserted_page_replaces_the_cached_one() {
+        let mut cache = PageCache::new(2);
+        cache.insert(1, page());
+        cache.insert(2, page());
+        cache.insert(1, page());
+        assert_eq!(cache.stats().evictions, 0);
+        // Without taking a second slot, and it is now the most recent
+        cache.insert(3, page());
+        assert_eq!(cached(&mut cache, 1..=3), [1, 3]);
+        cache.remove(1);
+        assert_eq!(cached(&mut cache, [1]), []);
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn lowering_the_capacity_evicts_down_to_it() {
+        let mut cache = PageCache::new(0);
+        cache.insert(1, page());
+        cache.insert(2, page());
+        // A capacity of 0 still keeps one page
+        assert_eq!(cached(&mut cache, 1..=2), [2]);
+
+        cache.set_capacity(4);
+        for page_num in [1, 3, 4] {
+            cache.insert(page_num, page());
+        }
+        assert!(cache.get(2).is_some());
+        cache.set_capacity(2);
+        assert_eq!(cached(&mut cache, 1..=4), [2, 4]);
+        assert_eq!(cache.stats().evictions, 3);
+        cache.clear();
+        assert_eq!(cached(&mut cache, 1..=4), []);
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::{Error, Result},
+    record::Value,
+};
+
+// Values for the parameters of a statement, kept apart from its text so they are never
+// spliced into it. A parameter left unbound is NULL
+// https://www.sqlite.org/c3ref/bind_blob.html
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    // By index, from 1
+    positional: Vec<Value>,
+    // By name, with the prefix: ":id", "@id" or "$id"
+    named: HashMap<String, Value>,
+}
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ?1, ?2 and so on, in order
+    pub fn from_values(values: Vec<Value>) -> Self {
+        Self {
+            positional: values,
+            named: HashMap::new(),
+        }
+    }
+
+    // The parameter at `index`, from 1. A named parameter has an index too, the one
+    // of its first use in the statement
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        if index == 0 {
+            return Err(Error::Misuse("parameter index out of range: 0".to_string()));
+        }
+        if index > self.positional.len() {
+            self.positional.resize(index, Value::Null);
+        }
+        self.positional[index - 1] = value;
+        Ok(())
+    }
+
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        if !name.starts_with([':', '@', '$']) {
+            let message = format!("parameter name must start with ':', '@' or '$': {}", name);
+            return Err(Error::Misuse(message));
+        }
+        self.named.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positional.is_empty() && self.named.is_empty()
+    }
+
+    // The value of the parameter at `index`, or of the one called `name` when it is
+    // bound by name
+    pub fn get(&self, index: usize, name: Option<&str>) -> Value {
+        name.and_then(|name| self.named.get(name))
+            .or_else(|| self.positional.get(index - 1))
+            .cloned()
+            .unwrap_or(Value::Null)
+    }
+}
@@ -0,0 +1,546 @@
+use std::{cmp::Ordering, collections::HashMap, ops::Bound};
+
+use crate::{
+    collation::Collation,
+    db::Schema,
+    eval::literal_value,
+    record::{Record, Value, ValueRef},
+    sql::{parser::Expr, token::TokenType},
+};
+
+// Chooses how a query reaches the rows of a table: a full scan, point lookups by rowid,
+// or index range scans, whichever is estimated to read the fewest pages. Without
+// statistics from ANALYZE the estimates go by the size of the table and fixed guesses at
+// how many rows a term keeps, the way SQLite's own planner does
+
+// Rows assumed to share one value of the first column of an index
+const ROWS_PER_KEY: f64 = 10.0;
+// The fraction of rows one bound of a range is assumed to keep
+const RANGE_SELECTIVITY: f64 = 0.25;
+
+// What ANALYZE wrote to sqlite_stat1 for each index of a table, by the index's name in
+// lowercase: the average number of rows that share a value of its first column, of its
+// first two columns and so on
+pub type IndexStats = HashMap<String, Vec<f64>>;
+
+// How the rows of a table are reached
+#[derive(Debug)]
+pub enum Plan {
+    // Every row, in rowid order
+    FullScan,
+    // The rows found by each lookup, one per OR term
+    Lookups(Vec<Lookup>),
+}
+
+#[derive(Debug)]
+pub enum Lookup {
+    // Seeks in the table b-tree
+    RowIds(Vec<i64>),
+    // Index entries in these ranges, which end with the rowid of their row
+    IndexRanges { root_page: usize, ranges: Vec<KeyRange> },
+}
+
+// What the terms ANDed together ask of each column, by column name
+pub type Constraints = HashMap<String, ColumnConstraint>;
+
+// The constraints each OR term of a WHERE clause puts on the columns of one table, named
+// `qualifier` in the query. Unqualified columns are only taken to be this table's when
+// it is the only one, and terms that constrain something else are left to the WHERE
+// check of each row
+pub fn where_constraints(where_expr: &Expr, qualifier: &str, is_only_table: bool) -> Vec<Constraints> {
+    let mut terms = Vec::new();
+    collect_disjuncts(where_expr, &mut terms);
+    terms
+        .into_iter()
+        .map(|term| {
+            let mut conjuncts = Vec::new();
+            collect_conjuncts(term, &mut conjuncts);
+            term_constraints(&conjuncts, qualifier, is_only_table)
+        })
+        .collect()
+}
+
+// What the terms ANDed together say about the columns of one table
+pub fn term_constraints(conjuncts: &[&Expr], qualifier: &str, is_only_table: bool) -> Constraints {
+    let mut constraints = Constraints::new();
+    for conjunct in conjuncts {
+        let Some(((table, column), ranges)) = probe_term(conjunct) else {
+            continue;
+        };
+        let is_this_table = match table {
+            Some(table) => table.eq_ignore_ascii_case(qualifier),
+            None => is_only_table,
+        };
+        if is_this_table {
+            constraints
+                .entry(column.to_lowercase())
+                .or_insert_with(ColumnConstraint::new)
+                .add(ranges);
+        }
+    }
+    constraints
+}
+
+// The cheapest way to the rows that satisfy any of `terms`. Every term needs a lookup
+// for the union of them to beat a full scan, which costs a read of each of the
+// `row_count` rows. A lookup costs a descent of the b-tree per range and a seek in the
+// table per row it finds
+pub fn plan(
+    table: &Schema,
+    indexes: &[Schema],
+    row_count: f64,
+    stats: &IndexStats,
+    terms: &[Constraints],
+) -> Plan {
+    if terms.is_empty() {
+        return Plan::FullScan;
+    }
+    let row_count = row_count.max(1.0);
+    let mut lookups = Vec::new();
+    let mut cost = 0.0;
+    for constraints in terms {
+        let Some((lookup, lookup_cost)) = best_lookup(table, indexes, row_count, stats, constraints) else {
+            return Plan::FullScan;
+        };
+        lookups.push(lookup);
+        cost += lookup_cost;
+    }
+    match cost <= row_count {
+        true => Plan::Lookups(lookups),
+        false => Plan::FullScan,
+    }
+}
+
+// The rowid, or else the index with the cheapest estimate: the more leading columns it
+// has pinned down with equalities, then a range on the column after them, the fewer
+// rows it finds. A composite index is used when only some of its leading columns are
+// constrained
+fn best_lookup(
+    table: &Schema,
+    indexes: &[Schema],
+    row_count: f64,
+    stats: &IndexStats,
+    constraints: &Constraints,
+) -> Option<(Lookup, f64)> {
+    let seek_cost = row_count.log2().max(1.0);
+    // `rowid = 42` goes straight down the table b-tree to the leaf holding the row
+    for (column, constraint) in constraints {
+        if let (true, Some(values)) = (table.is_rowid(column), &constraint.values) {
+            let row_ids = values.iter().filter_map(rowid_key).collect::<Vec<_>>();
+            let cost = row_ids.len() as f64 * seek_cost;
+            return Some((Lookup::RowIds(row_ids), cost));
+        }
+    }
+
+    let mut best: Option<(&Schema, usize, bool, f64)> = None;
+    for index in indexes {
+        let columns = &index.columns[..seekable_columns(table, index).min(index.columns.len())];
+        let equalities = columns
+            .iter()
+            .take_while(|column| constraints.get(&column.name).is_some_and(|c| c.values.is_some()))
+            .count();
+        let range = columns
+            .get(equalities)
+            .and_then(|column| constraints.get(&column.name))
+            .filter(|constraint| constraint.has_range());
+        if equalities == 0 && range.is_none() {
+            continue;
+        }
+        let ranges = columns[..equalities]
+            .iter()
+            .map(|column| constraints[&column.name].values.as_ref().map_or(1, Vec::len))
+            .product::<usize>();
+        // Each further equality column is assumed to halve the rows, unless ANALYZE
+        // counted them
+        let counted = stats.get(&index.name().to_lowercase()).and_then(|rows| rows.get(equalities.checked_sub(1)?));
+        let mut rows = match (equalities, counted) {
+            (0, _) => row_count,
+            (_, Some(rows)) => rows.max(1.0),
+            (n, None) => (ROWS_PER_KEY / 2f64.powi(n as i32 - 1)).max(1.0),
+        };
+        if let Some(range) = range {
+            let bounds = [&range.lower, &range.upper]
+                .into_iter()
+                .filter(|bound| !matches!(bound, Bound::Unbounded))
+                .count();
+            // Like SQLite, a range closed at both ends (`BETWEEN`) is assumed to keep
+            // a further quarter, 1/64 of the rows in all
+            rows *= RANGE_SELECTIVITY.powi(if bounds == 2 { 3 } else { bounds as i32 });
+        }
+        let cost = ranges as f64 * (seek_cost + rows.min(row_count) * seek_cost);
+        if best.as_ref().map_or(true, |best| cost < best.3) {
+            best = Some((index, equalities, range.is_some(), cost));
+        }
+    }
+    let (index, equalities, has_range, cost) = best?;
+
+    // One range per combination of the values of the equality columns
+    let mut prefixes = vec![Vec::new()];
+    for column in &index.columns[..equalities] {
+        let values = constraints[&column.name].values.as_deref().unwrap_or_default();
+        prefixes = prefixes
+            .iter()
+            .flat_map(|prefix| {
+                values.iter().map(move |value| {
+                    let mut prefix = prefix.clone();
+                    prefix.push(value.clone());
+                    prefix
+                })
+            })
+            .collect();
+    }
+    let (lower, upper) = match has_range {
+        true => {
+            let constraint = &constraints[&index.columns[equalities].name];
+            (constraint.lower.clone(), constraint.upper.clone())
+        }
+        false => (Bound::Unbounded, Bound::Unbounded),
+    };
+    let ranges = prefixes
+        .into_iter()
+        .filter_map(|prefix| KeyRange::new(prefix, lower.clone(), upper.clone()))
+        .collect();
+    let lookup = Lookup::IndexRanges {
+        root_page: index.root_page as usize,
+        ranges,
+    };
+    Some((lookup, cost))
+}
+
+// Flatten `a AND b AND c` into its terms
+pub fn collect_conjuncts<'a>(expr: &'a Expr, terms: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::BinaryOp(left, op, right) if op.token_type == TokenType::And => {
+            collect_conjuncts(left, terms);
+            collect_conjuncts(right, terms);
+        }
+        _ => terms.push(expr),
+    }
+}
+
+// Flatten `a OR b OR c` into its terms
+fn collect_disjuncts<'a>(expr: &'a Expr, terms: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::BinaryOp(left, op, right) if op.token_type == TokenType::Or => {
+            collect_disjuncts(left, terms);
+            collect_disjuncts(right, terms);
+        }
+        _ => terms.push(expr),
+    }
+}
+
+// `column = literal`, `literal = column`, `column IN (literal, ...)` or `column IN (SELECT
+// ...)` once the subquery has run, as the column and the values it is compared with. NULL
+// equals nothing, so it is left out
+fn equality_term(expr: &Expr) -> Option<(ColumnRef<'_>, Vec<Value>)> {
+    let (column, values) = match expr {
+        Expr::BinaryOp(left, op, right) if op.token_type == TokenType::Equal => {
+            match (left.as_ref(), right.as_ref()) {
+                (column, Expr::Literal(literal)) | (Expr::Literal(literal), column) => {
+                    (column_ref(column)?, vec![literal_value(literal)])
+                }
+                _ => return None,
+            }
+        }
+        Expr::InList(left, list) => {
+            let column = column_ref(left)?;
+            let values = list
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(literal) => Some(literal_value(literal)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<Value>>>()?;
+            (column, values)
+        }
+        Expr::InRows(left, rows) => (column_ref(left)?, rows.entries().ok()?.into_iter().flatten().collect()),
+        _ => return None,
+    };
+    Some((column, values.into_iter().filter(|value| !value.is_null()).collect()))
+}
+
+// What a term of a WHERE clause asks of a column: to be one of some values, or to fall
+// between two bounds
+pub enum ProbeRanges {
+    Values(Vec<Value>),
+    Range(Bound<Value>, Bound<Value>),
+}
+
+// What the terms ANDed together ask of one column
+pub struct ColumnConstraint {
+    values: Option<Vec<Value>>,
+    lower: Bound<Value>,
+    upper: Bound<Value>,
+}
+
+impl ColumnConstraint {
+    pub fn new() -> Self {
+        Self {
+            values: None,
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        }
+    }
+    // The first term to set something wins, the WHERE clause is checked on every row
+    // the probe finds anyway
+    pub fn add(&mut self, ranges: ProbeRanges) {
+        match ranges {
+            ProbeRanges::Values(values) => {
+                self.values.get_or_insert(values);
+            }
+            ProbeRanges::Range(lower, upper) => {
+                if matches!(self.lower, Bound::Unbounded) {
+                    self.lower = lower;
+                }
+                if matches!(self.upper, Bound::Unbounded) {
+                    self.upper = upper;
+                }
+            }
+        }
+    }
+    fn has_range(&self) -> bool {
+        !matches!((&self.lower, &self.upper), (Bound::Unbounded, Bound::Unbounded))
+    }
+}
+
+fn probe_term(expr: &Expr) -> Option<(ColumnRef<'_>, ProbeRanges)> {
+    if let Some((column, values)) = equality_term(expr) {
+        return Some((column, ProbeRanges::Values(values)));
+    }
+    let (column, lower, upper) = range_term(expr)?;
+    Some((column, ProbeRanges::Range(lower, upper)))
+}
+
+// `column < literal` and the like, either way around, or `column BETWEEN literal AND
+// literal`, as the column and the bounds of its values
+fn range_term(expr: &Expr) -> Option<(ColumnRef<'_>, Bound<Value>, Bound<Value>)> {
+    match expr {
+        Expr::BinaryOp(left, op, right) => {
+            let (column, literal, flipped) = match (left.as_ref(), right.as_ref()) {
+                (column, Expr::Literal(literal)) => (column, literal, false),
+                (Expr::Literal(literal), column) => (column, literal, true),
+                _ => return None,
+            };
+            let column = column_ref(column)?;
+            let value = literal_value(literal);
+            // `5 < a` is `a > 5`
+            let (lower, upper) = match (&op.token_type, flipped) {
+                (TokenType::Less, false) | (TokenType::Greater, true) => {
+                    (Bound::Unbounded, Bound::Excluded(value))
+                }
+                (TokenType::LessEqual, false) | (TokenType::GreaterEqual, true) => {
+                    (Bound::Unbounded, Bound::Included(value))
+                }
+                (TokenType::Greater, false) | (TokenType::Less, true) => {
+                    (Bound::Excluded(value), Bound::Unbounded)
+                }
+                (TokenType::GreaterEqual, false) | (TokenType::LessEqual, true) => {
+                    (Bound::Included(value), Bound::Unbounded)
+                }
+                _ => return None,
+            };
+            Some((column, lower, upper))
+        }
+        Expr::Between(value, low, high) => match (low.as_ref(), high.as_ref()) {
+            (Expr::Literal(low), Expr::Literal(high)) => Some((
+                column_ref(value)?,
+                Bound::Included(literal_value(low)),
+                Bound::Included(literal_value(high)),
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The index entries a lookup wants, by comparing their leading columns with the bounds.
+// Only as many columns as a bound has take part, so `[1]` bounds every entry starting
+// with 1 whatever follows
+#[derive(Debug)]
+pub struct KeyRange {
+    lower: Bound<Vec<Value>>,
+    upper: Bound<Vec<Value>>,
+}
+
+impl KeyRange {
+    // Entries equal to `prefix`, with the column after it between `lower` and `upper`.
+    // None when nothing can match, as with a NULL bound. NULL keys sort first and are
+    // never in range, so an open lower bound starts just after them
+    fn new(prefix: Vec<Value>, lower: Bound<Value>, upper: Bound<Value>) -> Option<Self> {
+        let is_null = |bound: &Bound<Value>| match bound {
+            Bound::Included(value) | Bound::Excluded(value) => value.is_null(),
+            Bound::Unbounded => false,
+        };
+        if prefix.iter().any(Value::is_null) || is_null(&lower) || is_null(&upper) {
+            return None;
+        }
+        let extend = |value: Value| {
+            let mut key = prefix.clone();
+            key.push(value);
+            key
+        };
+        if let (Bound::Unbounded, Bound::Unbounded) = (&lower, &upper) {
+            return Some(Self {
+                lower: Bound::Included(prefix.clone()),
+                upper: Bound::Included(prefix),
+            });
+        }
+        let lower = match lower {
+            Bound::Included(value) => Bound::Included(extend(value)),
+            Bound::Excluded(value) => Bound::Excluded(extend(value)),
+            Bound::Unbounded => Bound::Excluded(extend(Value::Null)),
+        };
+        let upper = match upper {
+            Bound::Included(value) => Bound::Included(extend(value)),
+            Bound::Excluded(value) => Bound::Excluded(extend(value)),
+            Bound::Unbounded => Bound::Included(prefix),
+        };
+        Some(Self { lower, upper })
+    }
+    pub fn above_lower(&self, record: &Record) -> bool {
+        match &self.lower {
+            Bound::Included(key) => compare_key(record, key).is_ge(),
+            Bound::Excluded(key) => compare_key(record, key).is_gt(),
+            Bound::Unbounded => true,
+        }
+    }
+    pub fn below_upper(&self, record: &Record) -> bool {
+        match &self.upper {
+            Bound::Included(key) => compare_key(record, key).is_le(),
+            Bound::Excluded(key) => compare_key(record, key).is_lt(),
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+// An index entry against the first `key.len()` columns
+fn compare_key(record: &Record, key: &[Value]) -> Ordering {
+    key.iter()
+        .enumerate()
+        .map(|(i, value)| record.value_ref(i).unwrap_or(ValueRef::Null).cmp(&value.as_ref()))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+// The leading columns of an index whose entries sort in plain ascending order, which a
+// lookup can compare keys on. A DESC or COLLATE column, or one whose table column has a
+// collation other than BINARY, and the ones after it can't be
+fn seekable_columns(table: &Schema, index: &Schema) -> usize {
+    let sql = index.sql.to_lowercase();
+    let (Some(start), Some(end)) = (sql.find('('), sql.rfind(')')) else {
+        return 0;
+    };
+    sql[start + 1..end]
+        .split(',')
+        .take_while(|column_def| match column_def.split_whitespace().collect::<Vec<_>>()[..] {
+            [name] | [name, "asc"] => table.collation(name) == Collation::Binary,
+            _ => false,
+        })
+        .count()
+}
+
+// The rowid a value is equal to, if any. The rowid has integer affinity, so text that
+// reads as a number is compared as that number, and only a whole number can match
+fn rowid_key(value: &Value) -> Option<i64> {
+    match value.with_numeric_affinity() {
+        Value::I64(row_id) => Some(row_id),
+        // `as` would saturate a float past the i64 range into one
+        Value::Float(n) if n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) => {
+            Some(n as i64)
+        }
+        _ => None,
+    }
+}
+
+// A column reference, with its table qualifier if any
+type ColumnRef<'a> = (Option<&'a str>, &'a str);
+
+fn column_ref(expr: &Expr) -> Option<ColumnRef<'_>> {
+    match expr {
+        Expr::Identifier(column) => Some((None, column)),
+        Expr::QualifiedIdentifier(table, column) => Some((Some(table), column)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{
+        parser::{Parser, Stmt},
+        scanner::Scanner,
+    };
+
+    fn schema(schema_type: &str, name: &str, root_page: u32, sql: &str) -> Schema {
+        Schema::parse(schema_type, name.to_string(), "t".to_string(), sql.to_string(), root_page).unwrap()
+    }
+
+    fn indexes() -> Vec<Schema> {
+        vec![
+            schema("index", "t_a", 3, "CREATE INDEX t_a ON t(a)"),
+            schema("index", "t_ab", 4, "CREATE INDEX t_ab ON t(a, b)"),
+            schema("index", "t_c", 5, "CREATE INDEX t_c ON t(c)"),
+        ]
+    }
+
+    // The plan for a WHERE clause on `t`, as how each OR term reaches its rows
+    fn plan_for(where_clause: &str, row_count: f64, stats: &IndexStats) -> Vec<String> {
+        let sql = format!("SELECT * FROM t WHERE {}", where_clause);
+        let tokens = Scanner::new(sql.clone()).scan_tokens().clone();
+        let Stmt::Select(select) = Parser::new(&sql, tokens).parse().unwrap().remove(0) else {
+            unreachable!()
+        };
+        let table = schema("table", "t", 2, "CREATE TABLE t(id INTEGER PRIMARY KEY, a, b, c)");
+        let indexes = indexes();
+        let terms = where_constraints(select.where_clause.as_ref().unwrap(), "t", true);
+        match plan(&table, &indexes, row_count, stats, &terms) {
+            Plan::FullScan => vec!["scan".to_string()],
+            Plan::Lookups(lookups) => lookups
+                .iter()
+                .map(|lookup| match lookup {
+                    Lookup::RowIds(row_ids) => format!("rowids {:?}", row_ids),
+                    Lookup::IndexRanges { root_page, ranges } => {
+                        let index = indexes.iter().find(|index| index.root_page as usize == *root_page).unwrap();
+                        format!("{} x{}", index.name(), ranges.len())
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn without_stats_the_guesses_decide() {
+        let none = IndexStats::new();
+        assert_eq!(plan_for("id = 42 AND a = 1", 1000.0, &none), ["rowids [42]"]);
+        assert_eq!(plan_for("a = 1", 1000.0, &none), ["t_a x1"]);
+        assert_eq!(plan_for("a IN (1, 2) AND b = 3", 1000.0, &none), ["t_ab x2"]);
+        assert_eq!(plan_for("a = 1 OR c = 2", 1000.0, &none), ["t_a x1", "t_c x1"]);
+        // A quarter of the rows is more than scanning, a BETWEEN keeps few enough
+        assert_eq!(plan_for("c > 5", 1000.0, &none), ["scan"]);
+        assert_eq!(plan_for("c BETWEEN 5 AND 9", 1000.0, &none), ["t_c x1"]);
+        // A term with no index leaves the whole OR to a scan
+        assert_eq!(plan_for("a = 1 OR b = 2", 1000.0, &none), ["scan"]);
+        // The ten rows one key is guessed to have are the whole of a small table
+        assert_eq!(plan_for("a = 1", 5.0, &none), ["scan"]);
+    }
+
+    #[test]
+    fn sqlite_stat1_rows_replace_the_guesses() {
+        let none = IndexStats::new();
+        let stats = IndexStats::from([
+            ("t_a".to_string(), vec![400.0]),
+            ("t_ab".to_string(), vec![400.0, 1.0]),
+            ("t_c".to_string(), vec![2.0]),
+        ]);
+        // Without stats the first of the equally good indexes is taken
+        assert_eq!(plan_for("a = 1 AND c = 2", 1000.0, &none), ["t_a x1"]);
+        assert_eq!(plan_for("a = 1 AND c = 2", 1000.0, &stats), ["t_c x1"]);
+        // Too many rows share a value of `a` to be worth a lookup
+        assert_eq!(plan_for("a = 1", 1000.0, &stats), ["scan"]);
+        assert_eq!(plan_for("a = 1 AND b = 2", 1000.0, &stats), ["t_ab x1"]);
+        // An index with no row of its own still goes by the guesses
+        let partial = IndexStats::from([("t_c".to_string(), vec![900.0])]);
+        assert_eq!(plan_for("a = 1 AND c = 2", 1000.0, &partial), ["t_a x1"]);
+        assert_eq!(plan_for("c = 2", 1000.0, &partial), ["scan"]);
+    }
+}
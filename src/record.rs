@@ -1,7 +1,10 @@
-use core::str;
-use std::fmt::format;
+use std::{cmp::Ordering, fmt, sync::Arc};
 
-use crate::utils::read_varint;
+use crate::{
+    error::Error,
+    functions,
+    utils::{read_varint, write_varint},
+};
 
 #[derive(Debug, Clone)]
 pub enum RecordFieldType {
@@ -17,12 +20,12 @@ pub enum RecordFieldType {
     One,
     String,
     Blob,
-    Variable,
 }
 
 #[derive(Debug, Clone)]
 pub struct RecordField {
-    // pub offset: usize,
+    // Offset of the value from the start of the record
+    pub offset: usize,
     pub field_size: usize,
     pub field_type: RecordFieldType,
 }
@@ -35,10 +38,13 @@ pub struct RecordHeader {
 impl RecordHeader {
     pub fn parse(payload: &[u8]) -> anyhow::Result<(Self, usize)> {
         let (varint_size, header_length) = read_varint(payload)?;
-        
-        let mut buffer = &payload[varint_size..header_length as usize]; // header_length
+        let Some(mut buffer) = payload.get(varint_size..header_length as usize) else {
+            anyhow::bail!(Error::CorruptPage(format!("record header size is out of range: {}", header_length)));
+        };
         let mut current_offset = varint_size;
         let mut fields = Vec::new();
+        // The values follow the header in the order of their types
+        let mut value_offset = header_length as usize;
         
         while !buffer.is_empty() && current_offset < header_length as usize {
             let (byte_read, field_type) = read_varint(buffer)?;
@@ -53,7 +59,7 @@ impl RecordHeader {
                 7 => (RecordFieldType::Float, 8),
                 8 => (RecordFieldType::Zero, 0),
                 9 => (RecordFieldType::One, 0),
-                n if n > 12 && n % 2 == 0 => {
+                n if n >= 12 && n % 2 == 0 => {
                     let size = ((n - 12) / 2) as usize;
                     (RecordFieldType::Blob, size)
                 }
@@ -61,86 +67,155 @@ impl RecordHeader {
                     let size = ((n - 13) / 2) as usize;
                     (RecordFieldType::String, size)
                 }
-                n => todo!("unsupported field type: {}", n),
+                n => anyhow::bail!(Error::CorruptPage(format!("unsupported field type: {}", n))),
             };
             
             fields.push(RecordField {
+                offset: value_offset,
                 field_size,
                 field_type,
             });
+            value_offset = value_offset.saturating_add(field_size);
             buffer = &buffer[byte_read..];
             current_offset += byte_read;
         }
         
-        Ok((RecordHeader { fields }, current_offset as usize ))
+        Ok((RecordHeader { fields }, current_offset))
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct RecordBody {
-    pub value: Value,
-}
-
+// A record decoded lazily from the buffer it was read from: parsing only reads the header,
+// a value is decoded when it is asked for and text and blobs are borrowed from the buffer.
+// The records of a page share the page's buffer
 #[derive(Debug, Clone)]
 pub struct Record {
     pub header: RecordHeader,
-    pub body: Vec<RecordBody>,
+    data: Arc<[u8]>,
+    // Where the record's payload starts in `data`
+    start: usize,
 }
 
 impl Record {
-    pub fn parse(payload: &[u8], row_id: u64) -> anyhow::Result<Self> {
-        let (header, header_length) = RecordHeader::parse(payload)?;
-        let mut body = Vec::new();
-        let mut offset = header_length;
-        for field in header.fields.iter() {
-            let value = match field.field_type {
-                RecordFieldType::Null => Value::I64(row_id as i64),
-                RecordFieldType::I8 => {
-                    let val = read_i8_at(payload, offset);
-                    Value::I64(val as i64)
-                },
-                RecordFieldType::I16 => {
-                    let val = read_i16_at(payload, offset);
-                    Value::I64(val as i64)
-                },
-                RecordFieldType::I24 => {
-                    let val = read_i24_at(payload, offset);
-                    Value::I64(val as i64)
-                },
-                RecordFieldType::I32 => {
-                    let val = read_i32_at(payload, offset);
-                    Value::I64(val as i64)
-                },
-                RecordFieldType::I48 => {
-                    let val = read_i48_at(payload, offset);
-                    Value::I64(val)
-                },
-                RecordFieldType::I64 => {
-                    let val = read_i64_at(payload, offset);
-                    Value::I64(val)
-                },
-                RecordFieldType::Float => Value::Float(read_f64_at(payload, offset)),
-                RecordFieldType::Zero => Value::I64(0),
-                RecordFieldType::One => Value::I64(1),
-                RecordFieldType::String => {
-                    let value = String::from_utf8(payload[offset..offset + field.field_size].to_vec())?;
-                    Value::String(value)
+    pub fn parse(payload: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_shared(Arc::from(payload), 0, payload.len())
+    }
+
+    // The record in `data[start..end]`, without copying it
+    pub fn parse_shared(data: Arc<[u8]>, start: usize, end: usize) -> anyhow::Result<Self> {
+        let payload = &data[start..end];
+        let (header, _) = RecordHeader::parse(payload)?;
+        for field in &header.fields {
+            let Some(bytes) = payload.get(field.offset..field.offset.saturating_add(field.field_size)) else {
+                anyhow::bail!(Error::CorruptPage("record field runs past the end of the payload".to_string()));
+            };
+            // Text is checked once here so reading it later can't fail
+            if let RecordFieldType::String = field.field_type {
+                std::str::from_utf8(bytes)?;
+            }
+        }
+        Ok(Record {
+            header,
+            data,
+            start,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.fields.is_empty()
+    }
+
+    // The i-th value, borrowing text and blobs from the page
+    pub fn value_ref(&self, i: usize) -> Option<ValueRef<'_>> {
+        let field = self.header.fields.get(i)?;
+        let payload = &self.data[self.start + field.offset..];
+        let value = match field.field_type {
+            RecordFieldType::Null => ValueRef::Null,
+            RecordFieldType::I8 => ValueRef::I64(read_i8_at(payload, 0) as i64),
+            RecordFieldType::I16 => ValueRef::I64(read_i16_at(payload, 0) as i64),
+            RecordFieldType::I24 => ValueRef::I64(read_i24_at(payload, 0) as i64),
+            RecordFieldType::I32 => ValueRef::I64(read_i32_at(payload, 0) as i64),
+            RecordFieldType::I48 => ValueRef::I64(read_i48_at(payload, 0)),
+            RecordFieldType::I64 => ValueRef::I64(read_i64_at(payload, 0)),
+            RecordFieldType::Float => ValueRef::Float(read_f64_at(payload, 0)),
+            RecordFieldType::Zero => ValueRef::I64(0),
+            RecordFieldType::One => ValueRef::I64(1),
+            RecordFieldType::String => {
+                let text = std::str::from_utf8(&payload[..field.field_size]);
+                ValueRef::String(text.expect("text is checked when the record is parsed"))
+            }
+            RecordFieldType::Blob => ValueRef::Blob(&payload[..field.field_size]),
+        };
+        Some(value)
+    }
+
+    pub fn value(&self, i: usize) -> Option<Value> {
+        self.value_ref(i).map(ValueRef::to_owned)
+    }
+
+    // Encode values in the record format: a header of serial types followed by the data
+    // https://www.sqlite.org/fileformat.html#record_format
+    pub fn serialize(values: &[Value]) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut data = Vec::new();
+        for value in values {
+            let serial_type = match value {
+                Value::Null => 0,
+                Value::I64(0) => 8,
+                Value::I64(1) => 9,
+                Value::I64(n) => {
+                    let (serial_type, size) = match *n {
+                        -128..=127 => (1, 1),
+                        -32_768..=32_767 => (2, 2),
+                        -8_388_608..=8_388_607 => (3, 3),
+                        -2_147_483_648..=2_147_483_647 => (4, 4),
+                        -140_737_488_355_328..=140_737_488_355_327 => (5, 6),
+                        _ => (6, 8),
+                    };
+                    data.extend_from_slice(&n.to_be_bytes()[8 - size..]);
+                    serial_type
                 }
-                RecordFieldType::Blob => {
-                    let value = payload[offset..offset + field.field_size].to_vec();
-                    Value::Blob(value)
+                Value::Float(n) => {
+                    data.extend_from_slice(&n.to_be_bytes());
+                    7
+                }
+                Value::String(s) => {
+                    data.extend_from_slice(s.as_bytes());
+                    s.len() as u64 * 2 + 13
+                }
+                Value::Blob(b) => {
+                    data.extend_from_slice(b);
+                    b.len() as u64 * 2 + 12
                 }
-                RecordFieldType::Variable => Value::Null,
             };
-            body.push(RecordBody { value });
-            offset += field.field_size;
+            write_varint(serial_type, &mut types);
+        }
+        // The header size varint counts itself
+        let mut header_size = types.len() + 1;
+        let mut size_varint = Vec::new();
+        loop {
+            size_varint.clear();
+            write_varint(header_size as u64, &mut size_varint);
+            if size_varint.len() + types.len() == header_size {
+                break;
+            }
+            header_size = size_varint.len() + types.len();
         }
-        // println!("body: {:#?}", body);
-        Ok(Record { header, body })
+        let mut payload = size_varint;
+        payload.extend(types);
+        payload.extend(data);
+        payload
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.len()).filter_map(|i| self.value(i)).collect()
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Null,
     I64(i64),
@@ -149,37 +224,353 @@ pub enum Value {
     Blob(Vec<u8>),
 }
 
-impl ToString for Value {
-    fn to_string(&self) -> String {
+// A value borrowing its text or blob from a record
+#[derive(Debug, Clone, Copy)]
+pub enum ValueRef<'a> {
+    Null,
+    I64(i64),
+    Float(f64),
+    String(&'a str),
+    Blob(&'a [u8]),
+}
+
+impl ValueRef<'_> {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    pub fn to_owned(self) -> Value {
+        match self {
+            Self::Null => Value::Null,
+            Self::I64(n) => Value::I64(n),
+            Self::Float(n) => Value::Float(n),
+            Self::String(s) => Value::String(s.to_string()),
+            Self::Blob(b) => Value::Blob(b.to_vec()),
+        }
+    }
+
+    // Storage class rank: NULL < INTEGER/REAL < TEXT < BLOB
+    fn class_rank(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::I64(_) | Self::Float(_) => 1,
+            Self::String(_) => 2,
+            Self::Blob(_) => 3,
+        }
+    }
+}
+
+// https://www.sqlite.org/datatype3.html#sort_order
+impl Ord for ValueRef<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::I64(a), Self::I64(b)) => a.cmp(b),
+            (Self::I64(a), Self::Float(b)) => compare_int_float(*a, *b),
+            (Self::Float(a), Self::I64(b)) => compare_int_float(*b, *a).reverse(),
+            // 0.0 and -0.0 are equal, a NaN, which SQLite never stores, goes last
+            (Self::Float(a), Self::Float(b)) => a.partial_cmp(b).unwrap_or_else(|| a.is_nan().cmp(&b.is_nan())),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (Self::Blob(a), Self::Blob(b)) => a.cmp(b),
+            _ => self.class_rank().cmp(&other.class_rank()),
+        }
+    }
+}
+
+// Exactly, like sqlite3IntFloatCompare: an i64 past 2^53 has no f64 of its own, so
+// converting it would make it equal to its neighbours
+fn compare_int_float(i: i64, r: f64) -> Ordering {
+    // -2^63 is an f64, and so is 2^63 which is past i64::MAX
+    if r.is_nan() || r >= 9223372036854775808.0 {
+        return Ordering::Less;
+    }
+    if r < -9223372036854775808.0 {
+        return Ordering::Greater;
+    }
+    // In range, so the whole part is an i64, and the fraction decides a tie
+    i.cmp(&(r as i64)).then_with(|| 0.0.partial_cmp(&r.fract()).unwrap())
+}
+
+impl PartialOrd for ValueRef<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ValueRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ValueRef<'_> {}
+
+// The affinity a declared type gives a column, or a CAST its result
+// https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    // The first rule that matches wins, a type with none of the words is NUMERIC
+    pub fn from_type_name(type_name: &str) -> Self {
+        let name = type_name.to_uppercase();
+        if name.contains("INT") {
+            Self::Integer
+        } else if ["CHAR", "CLOB", "TEXT"].iter().any(|word| name.contains(word)) {
+            Self::Text
+        } else if name.contains("BLOB") || name.trim().is_empty() {
+            Self::Blob
+        } else if ["REAL", "FLOA", "DOUB"].iter().any(|word| name.contains(word)) {
+            Self::Real
+        } else {
+            Self::Numeric
+        }
+    }
+}
+
+impl Value {
+    pub fn as_ref(&self) -> ValueRef<'_> {
+        match self {
+            Self::Null => ValueRef::Null,
+            Self::I64(n) => ValueRef::I64(*n),
+            Self::Float(n) => ValueRef::Float(*n),
+            Self::String(s) => ValueRef::String(s),
+            Self::Blob(b) => ValueRef::Blob(b),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    // sqlite3_value_int64(): text is read up to the first character that can't be part of an integer
+    pub fn to_i64(&self) -> i64 {
+        match self {
+            Self::Null => 0,
+            Self::I64(n) => *n,
+            Self::Float(n) => *n as i64,
+            Self::String(s) => text_to_i64(s),
+            Self::Blob(v) => text_to_i64(&String::from_utf8_lossy(v)),
+        }
+    }
+
+    // sqlite3_value_double()
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Self::Null => 0.0,
+            Self::I64(n) => *n as f64,
+            Self::Float(n) => *n,
+            Self::String(s) => text_to_f64(s),
+            Self::Blob(v) => text_to_f64(&String::from_utf8_lossy(v)),
+        }
+    }
+
+    // NUMERIC affinity: text that is a well-formed number, give or take surrounding
+    // spaces, becomes that number and anything else is left as it is
+    pub fn with_numeric_affinity(&self) -> Value {
+        let Self::String(s) = self else {
+            return self.clone();
+        };
+        let text = s.trim();
+        if text.is_empty() || numeric_prefix(text).len() != text.len() {
+            return self.clone();
+        }
+        match Self::String(text.to_string()).to_numeric() {
+            Self::Float(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Self::I64(n as i64),
+            value => value,
+        }
+    }
+
+    // The value stored in a column of the given affinity: TEXT turns numbers into text,
+    // NUMERIC and INTEGER turn text that reads as a number and REALs without a fractional
+    // part into INTEGERs, REAL makes any number a REAL, and BLOB leaves everything as it is
+    // https://www.sqlite.org/datatype3.html#type_affinity
+    pub fn with_affinity(&self, affinity: Affinity) -> Value {
+        match (affinity, self) {
+            (_, Self::Null | Self::Blob(_)) | (Affinity::Blob, _) => self.clone(),
+            (Affinity::Text, Self::I64(_) | Self::Float(_)) => Self::String(self.to_text()),
+            (Affinity::Text, _) => self.clone(),
+            (Affinity::Real, _) => match self.with_numeric_affinity() {
+                Self::I64(n) => Self::Float(n as f64),
+                value => value,
+            },
+            (Affinity::Integer | Affinity::Numeric, Self::Float(n)) if n.fract() == 0.0 && n.abs() < 9.2e18 => {
+                Self::I64(*n as i64)
+            }
+            (Affinity::Integer | Affinity::Numeric, _) => self.with_numeric_affinity(),
+        }
+    }
+
+    // sqlite3_value_text(): a REAL keeps its decimal point, a blob is read as UTF-8
+    // The storage class, as typeof() names it
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::I64(_) => "integer",
+            Self::Float(_) => "real",
+            Self::String(_) => "text",
+            Self::Blob(_) => "blob",
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::Float(n) => functions::format_real(*n),
+            value => value.to_string(),
+        }
+    }
+
+    // A SQL literal that reads back as the same value, the way .dump writes it
+    pub fn to_sql_literal(&self) -> String {
         match self {
-            Self::Null => format!("NULL"),
-            Self::I64(n) => format!("{n}"),
-            Self::Float(n) => format!("{n}"),
+            Self::Null => "NULL".to_string(),
+            Self::I64(n) => n.to_string(),
+            Self::Float(n) if n.is_nan() => "NULL".to_string(),
+            // Too big to be finite, so it reads back as infinity
+            Self::Float(n) if n.is_infinite() => if *n > 0.0 { "9.0e+999" } else { "-9.0e+999" }.to_string(),
+            // The shortest text that reads back as the same double
+            Self::Float(n) => format!("{:?}", n),
+            Self::String(text) => format!("'{}'", text.replace('\'', "''")),
+            Self::Blob(bytes) => format!("X'{}'", bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+        }
+    }
+
+    // CAST(value AS type). NULL stays NULL, text cast to a number is read up to the first
+    // character that can't be part of one
+    pub fn cast(&self, affinity: Affinity) -> Value {
+        match (self, affinity) {
+            (Self::Null, _) => Self::Null,
+            (_, Affinity::Integer) => Self::I64(self.to_i64()),
+            (_, Affinity::Real) => Self::Float(self.to_f64()),
+            (_, Affinity::Text) => Self::String(self.to_text()),
+            (Self::Blob(_), Affinity::Blob) => self.clone(),
+            (_, Affinity::Blob) => Self::Blob(self.to_text().into_bytes()),
+            (Self::I64(_) | Self::Float(_), Affinity::Numeric) => self.clone(),
+            // Text that reads as a whole number is an INTEGER even when written like a REAL
+            (_, Affinity::Numeric) => match self.to_numeric() {
+                Self::Float(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Self::I64(n as i64),
+                value => value,
+            },
+        }
+    }
+
+    // The INTEGER or REAL an arithmetic operator sees, text is read up to the first
+    // character that can't be part of a number, NULL stays NULL
+    pub fn to_numeric(&self) -> Value {
+        let text = match self {
+            Self::Null | Self::I64(_) | Self::Float(_) => return self.clone(),
             Self::String(s) => s.clone(),
-            Self::Blob(v) => std::str::from_utf8(v).unwrap().to_string(),
-        }
-    }
-}
-
-// impl PartialOrd for Value {
-//     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-//         match (self, other) {
-//             (Self::I64(a), Self::I64(b)) => a.partial_cmp(b),
-//             (Self::String(a), Self::String(b)) => a.partial_cmp(b),
-//             _ => None,
-//         }
-//     }
-// }
-// impl PartialEq for Value {
-//     fn eq(&self, other: &Self) -> bool {
-//         match (self, other) {
-//             (Self::I64(a), Self::I64(b)) => a == b,
-//             (Self::String(a), Self::String(b)) => a == b,
-//             _ => false,
-//         }
-//     }
-    
-// }
+            Self::Blob(v) => String::from_utf8_lossy(v).into_owned(),
+        };
+        let prefix = numeric_prefix(&text);
+        if prefix.is_empty() {
+            return Self::I64(0);
+        }
+        if !prefix.contains(['.', 'e', 'E']) {
+            if let Ok(n) = prefix.parse() {
+                return Self::I64(n);
+            }
+        }
+        Self::Float(text_to_f64(&text))
+    }
+}
+
+// Parse the leading integer of `text`, saturating on overflow
+fn text_to_i64(text: &str) -> i64 {
+    let text = text.trim_start();
+    let sign_len = usize::from(text.starts_with(['+', '-']));
+    let digits = text[sign_len..]
+        .bytes()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    let integer = &text[..sign_len + digits];
+    match integer.parse::<i64>() {
+        Ok(n) => n,
+        Err(_) if digits > 0 => text_to_f64(integer) as i64,
+        Err(_) => 0,
+    }
+}
+
+// Parse the longest prefix of `text` that looks like a number, 0.0 if there is none
+fn text_to_f64(text: &str) -> f64 {
+    numeric_prefix(text).parse().unwrap_or(0.0)
+}
+
+// The longest prefix of `text` that looks like a number, without leading whitespace
+fn numeric_prefix(text: &str) -> &str {
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let mut end = 0;
+    if end < bytes.len() && (bytes[end] == b'+' || bytes[end] == b'-') {
+        end += 1;
+    }
+    let digits_start = end;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        end += 1;
+    }
+    if end < bytes.len() && bytes[end] == b'.' {
+        end += 1;
+        while end < bytes.len() && bytes[end].is_ascii_digit() {
+            end += 1;
+        }
+    }
+    if end == digits_start || &text[digits_start..end] == "." {
+        return "";
+    }
+    // Only take the exponent if it is complete
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exponent_end = end + 1;
+        if exponent_end < bytes.len() && (bytes[exponent_end] == b'+' || bytes[exponent_end] == b'-') {
+            exponent_end += 1;
+        }
+        if exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+            while exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+                exponent_end += 1;
+            }
+            end = exponent_end;
+        }
+    }
+    &text[..end]
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "NULL"),
+            Self::I64(n) => write!(f, "{n}"),
+            Self::Float(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Blob(v) => write!(f, "{}", String::from_utf8_lossy(v)),
+        }
+    }
+}
+
+// Compared like the borrowed values
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_ref().cmp(&other.as_ref())
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
 pub fn read_i8_at(input: &[u8], offset: usize) -> i8 {
     input[offset] as i8
 }
@@ -189,7 +580,8 @@ pub fn read_i16_at(input: &[u8], offset: usize) -> i16 {
 }
 
 pub fn read_i24_at(input: &[u8], offset: usize) -> i32 {
-    i32::from_be_bytes([0, input[offset], input[offset + 1], input[offset + 2]])
+    // Shift into the top bytes and back to sign-extend
+    i32::from_be_bytes([input[offset], input[offset + 1], input[offset + 2], 0]) >> 8
 }
 
 pub fn read_i32_at(input: &[u8], offset: usize) -> i32 {
@@ -197,7 +589,9 @@ pub fn read_i32_at(input: &[u8], offset: usize) -> i32 {
 }
 
 pub fn read_i48_at(input: &[u8], offset: usize) -> i64 {
-    i64::from_be_bytes(input[offset..offset + 6].try_into().unwrap())
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&input[offset..offset + 6]);
+    i64::from_be_bytes(bytes) >> 16
 }
 
 pub fn read_i64_at(input: &[u8], offset: usize) -> i64 {
@@ -207,3 +601,47 @@ pub fn read_i64_at(input: &[u8], offset: usize) -> i64 {
 pub fn read_f64_at(input: &[u8], offset: usize) -> f64 {
     f64::from_be_bytes(input[offset..offset + 8].try_into().unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_and_floats_compare_exactly() {
+        let cases = [
+            (9007199254740993, 9007199254740992.0, Ordering::Greater),
+            (9007199254740992, 9007199254740992.0, Ordering::Equal),
+            (i64::MAX, 9223372036854775808.0, Ordering::Less),
+            (i64::MIN, -9223372036854775808.0, Ordering::Equal),
+            (i64::MIN, -1e19, Ordering::Greater),
+            (2, 2.5, Ordering::Less),
+            (-2, -2.5, Ordering::Greater),
+            (0, -0.0, Ordering::Equal),
+        ];
+        for (i, f, ordering) in cases {
+            assert_eq!(Value::I64(i).cmp(&Value::Float(f)), ordering, "{} {}", i, f);
+            assert_eq!(Value::Float(f).cmp(&Value::I64(i)), ordering.reverse(), "{} {}", f, i);
+        }
+    }
+
+    #[test]
+    fn storage_classes_order_null_numbers_text_blobs() {
+        let values = [
+            Value::Null,
+            Value::I64(i64::MIN),
+            Value::Float(1e300),
+            Value::String(String::new()),
+            Value::String("a".to_string()),
+            Value::Blob(Vec::new()),
+        ];
+        for pair in values.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn negative_zero_equals_zero() {
+        assert_eq!(Value::Float(-0.0), Value::Float(0.0));
+        assert!(Value::Float(-0.5) < Value::Float(-0.0));
+    }
+}
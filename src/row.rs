@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use crate::{
+    connection::Connection,
+    db::RowStream,
+    error::{Error, Result},
+    record::Value,
+};
+
+// A result row, read by column index or name as the Rust type it holds:
+// `row.get::<i64>(0)`, `row.get::<&str>("name")`, `row.get::<Option<f64>>("score")`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    // Shared by every row of the result
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    pub fn new(columns: Arc<[String]>, values: Vec<Value>) -> Self {
+        Self { columns, values }
+    }
+
+    pub fn get<'a, T: FromValue<'a>>(&'a self, index: impl RowIndex) -> Result<T> {
+        let i = index.position(self)?;
+        T::from_value(&self.values[i]).map_err(|expected| {
+            let actual = self.values[i].type_name();
+            Error::TypeMismatch(format!("column {} is {}, not {}", self.columns[i], actual, expected))
+        })
+    }
+
+    pub fn value(&self, index: impl RowIndex) -> Result<&Value> {
+        Ok(&self.values[index.position(self)?])
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    // The first column called `name`, in any case
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.eq_ignore_ascii_case(name))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The values the way sqlite3 prints them, NULL as an empty string
+    pub fn texts(&self) -> Vec<String> {
+        self.values
+            .iter()
+            .map(|value| match value {
+                Value::Null => String::new(),
+                value => value.to_text(),
+            })
+            .collect()
+    }
+}
+
+// The rows of a statement, read off the database as they are iterated over so that
+// stopping early skips the rest. The connection is borrowed until they are dropped
+pub struct Rows<'a> {
+    connection: &'a mut Connection,
+    stream: RowStream,
+    // After the last row or an error
+    done: bool,
+}
+
+impl<'a> Rows<'a> {
+    pub(crate) fn new(connection: &'a mut Connection, stream: RowStream) -> Self {
+        Self {
+            connection,
+            stream,
+            done: false,
+        }
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.stream.columns()
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.connection.db.next_row(&mut self.stream).map_err(Error::from).transpose();
+        self.done = !matches!(row, Some(Ok(_)));
+        row
+    }
+}
+
+impl Drop for Rows<'_> {
+    fn drop(&mut self) {
+        self.connection.db.end_read();
+    }
+}
+
+// A column of a row, by its position from 0 or its name
+pub trait RowIndex {
+    fn position(&self, row: &Row) -> Result<usize>;
+}
+
+impl RowIndex for usize {
+    fn position(&self, row: &Row) -> Result<usize> {
+        if *self >= row.len() {
+            return Err(Error::Misuse(format!("column index out of range: {}", self)));
+        }
+        Ok(*self)
+    }
+}
+
+impl RowIndex for &str {
+    fn position(&self, row: &Row) -> Result<usize> {
+        match row.column_index(self) {
+            Some(i) => Ok(i),
+            None => Err(Error::NoSuchColumn(self.to_string())),
+        }
+    }
+}
+
+// A Rust type a value of a row reads as. There are no conversions between storage
+// classes, except that an INTEGER reads as an f64, NULL only reads as an Option or a
+// Value. Err has the name of the type the value should have had
+pub trait FromValue<'a>: Sized {
+    fn from_value(value: &'a Value) -> std::result::Result<Self, &'static str>;
+}
+
+impl<'a> FromValue<'a> for &'a Value {
+    fn from_value(value: &'a Value) -> std::result::Result<Self, &'static str> {
+        Ok(value)
+    }
+}
+
+impl FromValue<'_> for Value {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue<'_> for i64 {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::I64(n) => Ok(*n),
+            _ => Err("integer"),
+        }
+    }
+}
+
+impl FromValue<'_> for i32 {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::I64(n) => i32::try_from(*n).map_err(|_| "a 32-bit integer"),
+            _ => Err("integer"),
+        }
+    }
+}
+
+impl FromValue<'_> for bool {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::I64(n) => Ok(*n != 0),
+            _ => Err("integer"),
+        }
+    }
+}
+
+impl FromValue<'_> for f64 {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::Float(n) => Ok(*n),
+            Value::I64(n) => Ok(*n as f64),
+            _ => Err("real"),
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    fn from_value(value: &'a Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::String(text) => Ok(text),
+            _ => Err("text"),
+        }
+    }
+}
+
+impl FromValue<'_> for String {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        <&str>::from_value(value).map(str::to_string)
+    }
+}
+
+impl<'a> FromValue<'a> for &'a [u8] {
+    fn from_value(value: &'a Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::Blob(bytes) => Ok(bytes),
+            _ => Err("blob"),
+        }
+    }
+}
+
+impl FromValue<'_> for Vec<u8> {
+    fn from_value(value: &Value) -> std::result::Result<Self, &'static str> {
+        <&[u8]>::from_value(value).map(<[u8]>::to_vec)
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for Option<T> {
+    fn from_value(value: &'a Value) -> std::result::Result<Self, &'static str> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
@@ -0,0 +1,546 @@
+use anyhow::{bail, Context, Result};
+use codecrafters_sqlite::{Connection, Params, QueryResult, Row};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use crate::{editor::LineEditor, format::Format, terminal};
+
+const HELP: &str = "\
+.dbinfo                  Show the page size and the number of tables
+.dump                    Show the database as SQL that recreates it
+.exit                    Exit this program
+.headers on|off          Turn the header row on or off
+.help                    Show this message
+.history                 Show the statements entered so far, !N runs number N again
+.indexes ?TABLE?         Show the names of the indexes, of TABLE only if given
+.mode ?MODE?             Set the output mode: column, csv, json, list, markdown or table
+.nullvalue STRING        Show NULL as STRING, which is empty to begin with
+.once FILE               Write the output of the next command to FILE
+.output ?FILE?           Write output to FILE, or back to stdout without it
+.quit                    Exit this program
+.read FILE               Run the SQL in FILE as a unit
+.stats                   Show the pages and bytes used by each table and index
+.tables                  Show the names of the tables
+.timer on|off            Show the time and page reads of each statement
+.width NUM1 NUM2 ...     Set the widths of the columns, negative to right-align";
+
+// A database opened for dot commands and SQL, from the command line or typed at the prompt
+pub struct Shell {
+    path: String,
+    connection: Connection,
+    params: Params,
+    format: Format,
+    // Where output goes instead of stdout, from .output or .once
+    redirect: Option<Redirect>,
+}
+
+struct Redirect {
+    file: File,
+    // Only for the next command, from .once
+    once: bool,
+}
+
+impl Shell {
+    pub fn new(path: &str, connection: Connection, params: Params, format: Format) -> Self {
+        Shell {
+            path: path.to_string(),
+            connection,
+            params,
+            format,
+            redirect: None,
+        }
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    // A dot command or SQL, returns false if a statement failed. Its error has already
+    // been printed, a failed dot command is an Err
+    pub fn run_command(&mut self, command: &str) -> Result<bool> {
+        let once = self.redirect.as_ref().is_some_and(|redirect| redirect.once);
+        let result = self.dispatch(command);
+        // Unless the command replaced it
+        let name = command.split_whitespace().next();
+        if once && !matches!(name, Some(".once" | ".output")) {
+            self.redirect = None;
+        }
+        result
+    }
+
+    fn dispatch(&mut self, command: &str) -> Result<bool> {
+        if !command.trim_start().starts_with('.') {
+            return Ok(self.run_sql(command));
+        }
+        let mut words = arguments(command).into_iter();
+        let name = words.next().unwrap_or_default();
+        let mut out = self.writer()?;
+        match name.as_str() {
+            ".dbinfo" => {
+                let mut file = File::open(&self.path)?;
+                let mut header = [0; 100];
+                file.read_exact(&mut header)?;
+
+                // The page size is stored at the 16th byte offset, using 2 bytes in big-endian order
+                let page_size = u16::from_be_bytes([header[16], header[17]]);
+
+                writeln!(out, "database page size: {}", page_size)?;
+                let mut page_header = [0; 12];
+                file.read_exact(&mut page_header)?;
+                let cells = u16::from_be_bytes([page_header[3], page_header[4]]);
+                writeln!(out, "number of tables: {}", cells)?;
+            }
+            ".tables" => writeln!(out, "{}", self.connection.table_names()?.join(" "))?,
+            ".dump" => self.connection.dump(&mut out)?,
+            // .indexes [table]
+            ".indexes" => {
+                let names = self.connection.index_names(words.next().as_deref())?;
+                if !names.is_empty() {
+                    writeln!(out, "{}", names.join(" "))?;
+                }
+            }
+            ".stats" => {
+                writeln!(out, "name|type|pages|leaf|interior|overflow|cells|payload|unused")?;
+                for usage in self.connection.space_usage()? {
+                    writeln!(
+                        out,
+                        "{}|{}|{}|{}|{}|{}|{}|{}|{}",
+                        usage.name,
+                        if usage.is_index { "index" } else { "table" },
+                        usage.total_pages(),
+                        usage.leaf_pages,
+                        usage.interior_pages,
+                        usage.overflow_pages,
+                        usage.cells,
+                        usage.payload_bytes,
+                        usage.unused_bytes,
+                    )?;
+                }
+            }
+            ".read" => {
+                let path = command.trim_start()[".read".len()..].trim();
+                // A path with spaces in it can be quoted, like in sqlite3
+                let path = ['"', '\''].iter().find_map(|&quote| path.strip_prefix(quote)?.strip_suffix(quote)).unwrap_or(path);
+                if path.is_empty() {
+                    bail!("usage: .read FILE");
+                }
+                let script = fs::read_to_string(path).with_context(|| format!("cannot open \"{}\"", path))?;
+                return Ok(self.run_script(&script));
+            }
+            ".headers" => match words.next() {
+                Some(value) => self.format.header = Some(boolean_value(&value)?),
+                None => bail!("usage: .headers on|off"),
+            },
+            ".timer" => match words.next() {
+                Some(value) => self.connection.set_timer(boolean_value(&value)?),
+                None => bail!("usage: .timer on|off"),
+            },
+            // .mode [name]
+            ".mode" => match words.next() {
+                Some(name) => self.format.mode = name.parse()?,
+                None => writeln!(out, "current output mode: {}", self.format.mode.name())?,
+            },
+            // .output [file], back to stdout without one
+            ".output" => {
+                self.redirect = match words.next().filter(|path| path != "stdout") {
+                    Some(path) => Some(Redirect { file: create(&path)?, once: false }),
+                    None => None,
+                };
+            }
+            ".once" => match words.next() {
+                Some(path) => self.redirect = Some(Redirect { file: create(&path)?, once: true }),
+                None => bail!("usage: .once FILE"),
+            },
+            ".nullvalue" => match (words.next(), words.next()) {
+                (Some(text), None) => self.format.null_value = text,
+                _ => bail!("usage: .nullvalue STRING"),
+            },
+            // .width [num ...], the ones left out fit their columns to the values
+            ".width" => {
+                let widths = words.map(|width| width.parse()).collect::<std::result::Result<_, _>>();
+                self.format.widths = widths.map_err(|_| anyhow::anyhow!("usage: .width NUM1 NUM2 ..."))?;
+            }
+            ".help" => writeln!(out, "{}", HELP)?,
+            name => bail!("unknown command or invalid arguments: \"{}\". Enter \".help\" for help", &name[1..]),
+        }
+        out.flush()?;
+        Ok(true)
+    }
+
+    // Each statement runs on its own, a failed one doesn't stop the rest
+    fn run_sql(&mut self, sql: &str) -> bool {
+        let mut printer = match self.redirect_file() {
+            Ok(file) => Printer::new(&self.format, true, file),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return false;
+            }
+        };
+        self.connection.execute_script_rows(sql, &self.params, |rows| match rows {
+            Ok(rows) => {
+                let columns = rows.columns().to_vec();
+                printer.print(&columns, rows)
+            }
+            Err(err) => printer.fail(err),
+        });
+        printer.finish() & self.print_timer()
+    }
+
+    // Run a script as a unit, with its dot commands on lines of their own like at the
+    // prompt. The first failure stops the script and rolls its writes back
+    pub fn run_script(&mut self, script: &str) -> bool {
+        let opened = self.connection.begin_script();
+        let succeeded = self.run_script_lines(script);
+        match self.connection.finish_script(opened, succeeded) {
+            Ok(()) => succeeded,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                false
+            }
+        }
+    }
+
+    fn run_script_lines(&mut self, script: &str) -> bool {
+        let mut sql = String::new();
+        // The line of the script `sql` starts on
+        let mut first_line = 1;
+        for (i, line) in script.lines().enumerate() {
+            if sql.is_empty() && line.trim_start().starts_with('.') {
+                let command = line.trim();
+                if matches!(command.split_whitespace().next(), Some(".quit" | ".exit")) {
+                    return true;
+                }
+                match self.run_command(command) {
+                    Ok(true) => continue,
+                    // The error of a statement in a script it read is printed already
+                    Ok(false) => return false,
+                    Err(err) => {
+                        eprintln!("Error: near line {}: {}", i + 1, err);
+                        return false;
+                    }
+                }
+            }
+            // Like sqlite3, lines of only a comment don't hold a dot command after them back
+            if sql.is_empty() && (line.trim().is_empty() || line.trim_start().starts_with("--")) {
+                continue;
+            }
+            if sql.is_empty() {
+                first_line = i + 1;
+            }
+            sql.push_str(line);
+            sql.push('\n');
+            if is_complete(&sql) {
+                if !self.run_script_part(&sql, first_line) {
+                    return false;
+                }
+                sql.clear();
+            }
+        }
+        // The last statement doesn't need its `;`
+        sql.trim().is_empty() || self.run_script_part(&sql, first_line)
+    }
+
+    // The statements of a script up to its next dot command, `first_line` is the line of
+    // the script they start on. The rows of each are printed once it is done, those of the
+    // statements before a failed one stay printed
+    fn run_script_part(&mut self, sql: &str, first_line: usize) -> bool {
+        let mut printer = match self.redirect_file() {
+            Ok(file) => Printer::new(&self.format, true, file),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return false;
+            }
+        };
+        let executed =
+            self.connection.execute_script_part(sql, &self.params, |result| printer.print_result(Ok(result)));
+        let printed = printer.finish();
+        let succeeded = match executed {
+            Ok(()) => printed & self.print_timer(),
+            Err(mut err) => {
+                self.print_timer();
+                err.line += first_line - 1;
+                eprintln!("Error: {}", err);
+                false
+            }
+        };
+        // Like `run_command`, .once only holds for the statements after it
+        if self.redirect.as_ref().is_some_and(|redirect| redirect.once) {
+            self.redirect = None;
+        }
+        succeeded
+    }
+
+    // A line for each statement run since the last time, while .timer is on
+    fn print_timer(&mut self) -> bool {
+        let stats = self.connection.take_statement_stats();
+        let printed = self.writer().and_then(|mut out| {
+            for stats in stats {
+                writeln!(
+                    out,
+                    "Run Time: real {:.3} sec, {} pages read, {} written, {} cache hits, {} misses",
+                    stats.elapsed.as_secs_f64(),
+                    stats.pages_read,
+                    stats.pages_written,
+                    stats.cache_hits,
+                    stats.cache_misses,
+                )?;
+            }
+            Ok(out.flush()?)
+        });
+        match printed {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                false
+            }
+        }
+    }
+
+    // The file of .output or .once, None for stdout
+    fn redirect_file(&self) -> io::Result<Option<File>> {
+        self.redirect.as_ref().map(|redirect| redirect.file.try_clone()).transpose()
+    }
+
+    // The file of .output or .once, or else stdout
+    fn writer(&self) -> Result<io::BufWriter<Box<dyn Write>>> {
+        let out: Box<dyn Write> = match &self.redirect {
+            Some(redirect) => Box::new(redirect.file.try_clone()?),
+            None => Box::new(io::stdout()),
+        };
+        Ok(io::BufWriter::new(out))
+    }
+
+    // Read commands at a prompt until .quit or the end of the input. SQL goes on over
+    // as many lines as it takes to end it with `;`, a dot command is a line of its own
+    pub fn repl(&mut self) -> Result<()> {
+        println!("Enter \".help\" for usage hints.");
+        let mut editor = LineEditor::new(history_path());
+        let mut sql = String::new();
+        loop {
+            let prompt = if sql.is_empty() { "sqlite> " } else { "   ...> " };
+            let line = match editor.read_line(prompt) {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                // Ctrl-C drops the statement typed so far
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                    sql.clear();
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let line = match line.trim().strip_prefix('!') {
+                // !N runs entry N of the history again
+                Some(number) if sql.is_empty() => {
+                    let entry = number.parse::<usize>().ok().and_then(|n| editor.history().get(n.wrapping_sub(1)));
+                    match entry {
+                        Some(entry) => {
+                            println!("{}", entry);
+                            entry.clone()
+                        }
+                        None => {
+                            eprintln!("Error: no such history entry: {}", number);
+                            continue;
+                        }
+                    }
+                }
+                _ => line,
+            };
+            if sql.is_empty() && line.trim_start().starts_with('.') {
+                let command = line.trim();
+                editor.add_history(command);
+                match command.split_whitespace().next() {
+                    Some(".quit" | ".exit") => break,
+                    Some(".history") => {
+                        for (i, entry) in editor.history().iter().enumerate() {
+                            println!("{:5}  {}", i + 1, entry);
+                        }
+                    }
+                    _ => {
+                        if let Err(err) = self.run_command(command) {
+                            eprintln!("Error: {}", err);
+                        }
+                    }
+                }
+                continue;
+            }
+            if sql.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            sql.push_str(&line);
+            sql.push('\n');
+            if is_complete(&sql) {
+                editor.add_history(&sql);
+                if let Err(err) = self.run_command(&sql) {
+                    eprintln!("Error: {}", err);
+                }
+                sql.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+// The words of a dot command, a word in quotes can have spaces in it
+fn arguments(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = command.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let word = if c == '\'' || c == '"' {
+            chars.next();
+            chars.by_ref().take_while(|&next| next != c).collect()
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            word
+        };
+        words.push(word);
+    }
+    words
+}
+
+fn create(path: &str) -> Result<File> {
+    File::create(path).with_context(|| format!("cannot open \"{}\"", path))
+}
+
+// on/off, yes/no, true/false or a number, like sqlite3
+fn boolean_value(text: &str) -> Result<bool> {
+    if let Ok(n) = text.parse::<i64>() {
+        return Ok(n != 0);
+    }
+    match text.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => bail!("not a boolean value: \"{}\"", text),
+    }
+}
+
+// $SQLITE_HISTORY, or ~/.sqlite_history like sqlite3
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("SQLITE_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".sqlite_history"))
+}
+
+// Whether `sql` ends with a `;` outside of any string, quoted name or comment, so the
+// statement can run. Like sqlite3_complete, without triggers
+pub fn is_complete(sql: &str) -> bool {
+    let mut chars = sql.chars().peekable();
+    let mut complete = false;
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => complete = true,
+            c if c.is_whitespace() => {}
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                // A doubled quote is part of the string, the loop picks the second up
+                // as the start of another one
+                if !chars.by_ref().any(|c| c == close) {
+                    return false;
+                }
+                complete = false;
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                if !chars.by_ref().any(|c| c == '\n') {
+                    // A comment to the end of the input leaves the statement as it was
+                    return complete;
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                let closed = chars.by_ref().any(|c| {
+                    let end = star && c == '/';
+                    star = c == '*';
+                    end
+                });
+                if !closed {
+                    return false;
+                }
+            }
+            _ => complete = false,
+        }
+    }
+    complete
+}
+
+// Print the result of each statement to `file`, or else stdout, returns false if any of
+// them failed. Output that would scroll off the terminal goes through a pager unless
+// `paged` is off
+pub fn print_results(results: Vec<codecrafters_sqlite::Result<QueryResult>>, format: &Format, paged: bool, file: Option<File>) -> bool {
+    let mut printer = Printer::new(format, paged, file);
+    results.into_iter().for_each(|result| printer.print_result(result));
+    printer.finish()
+}
+
+// The results of statements on their way out, each row printed as it is read
+pub struct Printer<'a> {
+    format: &'a Format,
+    output: terminal::Output,
+    succeeded: bool,
+    // Until the reader quits the pager, the rows after that aren't read
+    reading: bool,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(format: &'a Format, paged: bool, file: Option<File>) -> Self {
+        let output = match file {
+            Some(file) => terminal::Output::to_file(file),
+            None => terminal::Output::new(paged),
+        };
+        Printer { format, output, succeeded: true, reading: true }
+    }
+
+    pub fn print(&mut self, columns: &[String], rows: impl Iterator<Item = codecrafters_sqlite::Result<Row>>) {
+        let mut lines = self.format.lines_for(columns);
+        for row in rows {
+            if !self.reading {
+                return;
+            }
+            match row {
+                Ok(row) => self.write_lines(lines.row(row.values())),
+                // The lines of the rows before it stay printed
+                Err(err) => return self.fail(err),
+            }
+        }
+        self.write_lines(lines.finish());
+    }
+
+    pub fn print_result(&mut self, result: codecrafters_sqlite::Result<QueryResult>) {
+        match result {
+            Ok(result) => self.print(&result.columns, result.rows.into_iter().map(Ok)),
+            Err(err) => self.fail(err),
+        }
+    }
+
+    // Keep going after a failed statement, like sqlite3 running a script
+    pub fn fail(&mut self, err: codecrafters_sqlite::Error) {
+        eprintln!("Error: {}", err);
+        self.succeeded = false;
+    }
+
+    fn write_lines(&mut self, lines: Vec<String>) {
+        for line in &lines {
+            if !self.reading {
+                break;
+            }
+            self.reading = self.output.write_line(line);
+        }
+    }
+
+    // Returns false if any statement failed
+    pub fn finish(self) -> bool {
+        self.output.finish();
+        self.succeeded
+    }
+}
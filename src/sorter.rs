@@ -0,0 +1,325 @@
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    mem,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+};
+
+use anyhow::Context;
+
+use crate::{
+    collation::Collation,
+    record::{Record, Value},
+    utils::write_varint,
+};
+
+// Rows are buffered until their estimated size reaches the limit
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+// At most this many runs are merged at once, each holds a file open while merging. Like
+// SORTER_MAX_MERGE_COUNT in SQLite, more runs than that are merged in passes
+const MAX_MERGE_WIDTH: usize = 16;
+
+// Makes temp file names unique within the process
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// External merge sort in the spirit of SQLite's VdbeSorter
+// https://www.sqlite.org/tempfiles.html#transient_indices
+// Buffered rows are sorted and written out as a run whenever they exceed the memory
+// limit, the runs are then merged back together while reading
+pub struct Sorter {
+    // One flag and collation per sort key
+    descending: Vec<bool>,
+    collations: Vec<Collation>,
+    memory_limit: usize,
+    buffered: Vec<SortEntry>,
+    buffered_bytes: usize,
+    runs: Vec<Run>,
+}
+
+struct SortEntry {
+    keys: Vec<Value>,
+    row: Vec<Value>,
+}
+
+impl Sorter {
+    pub fn new(descending: Vec<bool>, collations: Vec<Collation>, memory_limit: usize) -> Self {
+        Sorter {
+            descending,
+            collations,
+            memory_limit,
+            buffered: Vec::new(),
+            buffered_bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, keys: Vec<Value>, row: Vec<Value>) -> anyhow::Result<()> {
+        self.buffered_bytes += keys.iter().chain(row.iter()).map(value_size).sum::<usize>();
+        self.buffered.push(SortEntry { keys, row });
+        if self.buffered_bytes > self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    // Sorted rows, merging the spilled runs with whatever is still buffered
+    pub fn finish(mut self) -> anyhow::Result<SortedRows> {
+        self.sort_buffered();
+        // Each pass merges neighbouring runs, so runs stay in the order they were written
+        while self.runs.len() >= MAX_MERGE_WIDTH {
+            let mut runs = mem::take(&mut self.runs).into_iter().peekable();
+            while runs.peek().is_some() {
+                let batch = runs.by_ref().take(MAX_MERGE_WIDTH).collect();
+                let mut merged = self.merge(batch, Vec::new())?;
+                self.runs.push(write_run(std::iter::from_fn(|| merged.next_entry()))?);
+            }
+        }
+        let buffered = mem::take(&mut self.buffered);
+        let runs = mem::take(&mut self.runs);
+        self.merge(runs, buffered)
+    }
+
+    // Runs come first so rows with equal keys keep their insertion order
+    fn merge(&self, runs: Vec<Run>, buffered: Vec<SortEntry>) -> anyhow::Result<SortedRows> {
+        let mut sources = Vec::new();
+        for run in runs {
+            sources.push(RunSource::File(run.open()?));
+        }
+        sources.push(RunSource::Memory(buffered.into_iter()));
+        let mut heads = Vec::new();
+        for source in &mut sources {
+            heads.push(source.next_entry(self.descending.len())?);
+        }
+        Ok(SortedRows {
+            descending: self.descending.clone(),
+            collations: self.collations.clone(),
+            sources,
+            heads,
+        })
+    }
+
+    fn sort_buffered(&mut self) {
+        let (descending, collations) = (&self.descending, &self.collations);
+        self.buffered
+            .sort_by(|a, b| compare_keys(&a.keys, &b.keys, descending, collations));
+    }
+
+    // Write the buffered rows out as one sorted run
+    fn spill(&mut self) -> anyhow::Result<()> {
+        self.sort_buffered();
+        let run = write_run(self.buffered.drain(..).map(Ok))?;
+        self.buffered_bytes = 0;
+        self.runs.push(run);
+        Ok(())
+    }
+}
+
+// Each entry is a varint length followed by a record of the keys and the row
+fn write_run(entries: impl Iterator<Item = anyhow::Result<SortEntry>>) -> anyhow::Result<Run> {
+    let run = Run::create();
+    let file = File::create(&run.path).context("create sort run")?;
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        let entry = entry?;
+        let mut values = entry.keys;
+        values.extend(entry.row);
+        let payload = Record::serialize(&values);
+        let mut size = Vec::new();
+        write_varint(payload.len() as u64, &mut size);
+        writer.write_all(&size).context("write sort run")?;
+        writer.write_all(&payload).context("write sort run")?;
+    }
+    writer.flush().context("write sort run")?;
+    Ok(run)
+}
+
+pub struct SortedRows {
+    descending: Vec<bool>,
+    collations: Vec<Collation>,
+    sources: Vec<RunSource>,
+    // The next entry of each source
+    heads: Vec<Option<SortEntry>>,
+}
+
+impl Iterator for SortedRows {
+    type Item = anyhow::Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|entry| entry.map(|entry| entry.row))
+    }
+}
+
+impl SortedRows {
+    fn next_entry(&mut self) -> Option<anyhow::Result<SortEntry>> {
+        // There are only a handful of runs, a linear scan for the smallest head is enough
+        let mut smallest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(entry) = head else { continue };
+            let is_smaller = match smallest {
+                Some(j) => {
+                    let current = self.heads[j].as_ref().unwrap();
+                    compare_keys(&entry.keys, &current.keys, &self.descending, &self.collations) == Ordering::Less
+                }
+                None => true,
+            };
+            if is_smaller {
+                smallest = Some(i);
+            }
+        }
+        let i = smallest?;
+        let next = match self.sources[i].next_entry(self.descending.len()) {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
+        let entry = mem::replace(&mut self.heads[i], next)?;
+        Some(Ok(entry))
+    }
+}
+
+enum RunSource {
+    Memory(std::vec::IntoIter<SortEntry>),
+    File(RunReader),
+}
+
+impl RunSource {
+    fn next_entry(&mut self, key_count: usize) -> anyhow::Result<Option<SortEntry>> {
+        match self {
+            RunSource::Memory(entries) => Ok(entries.next()),
+            RunSource::File(reader) => reader.next_entry(key_count),
+        }
+    }
+}
+
+// A temp file holding one sorted run, removed on drop
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    fn create() -> Self {
+        let id = RUN_COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+        Run { path: run_path(id) }
+    }
+
+    fn open(self) -> anyhow::Result<RunReader> {
+        let file = File::open(&self.path).context("open sort run")?;
+        Ok(RunReader {
+            reader: BufReader::new(file),
+            _run: self,
+        })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn run_path(id: usize) -> PathBuf {
+    let name = format!("sqlite-sorter-{}-{}.tmp", std::process::id(), id);
+    std::env::temp_dir().join(name)
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    // Keeps the file around until the reader is done with it
+    _run: Run,
+}
+
+impl RunReader {
+    // As write_run wrote it
+    fn next_entry(&mut self, key_count: usize) -> anyhow::Result<Option<SortEntry>> {
+        let Some(size) = self.read_size()? else {
+            return Ok(None);
+        };
+        let mut payload = vec![0; size as usize];
+        self.reader
+            .read_exact(&mut payload)
+            .context("read sort run")?;
+        let mut values = Record::parse(&payload)?.values();
+        let row = values.split_off(key_count);
+        Ok(Some(SortEntry { keys: values, row }))
+    }
+
+    fn read_size(&mut self) -> anyhow::Result<Option<u64>> {
+        let mut size = 0;
+        for i in 0..9 {
+            let mut byte = [0];
+            if self.reader.read(&mut byte).context("read sort run")? == 0 {
+                if i == 0 {
+                    return Ok(None);
+                }
+                anyhow::bail!("sort run truncated");
+            }
+            if i == 8 {
+                return Ok(Some((size << 8) | byte[0] as u64));
+            }
+            size = (size << 7) | (byte[0] & 0x7F) as u64;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(Some(size))
+    }
+}
+
+fn compare_keys(a: &[Value], b: &[Value], descending: &[bool], collations: &[Collation]) -> Ordering {
+    for (((a, b), descending), collation) in a.iter().zip(b).zip(descending).zip(collations) {
+        let ordering = collation.compare(a, b);
+        if ordering != Ordering::Equal {
+            return if *descending { ordering.reverse() } else { ordering };
+        }
+    }
+    Ordering::Equal
+}
+
+// Rough in-memory footprint of a value
+fn value_size(value: &Value) -> usize {
+    mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Blob(b) => b.len(),
+            _ => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The runs written since `first` whose files are still around
+    fn runs_left(first: usize) -> usize {
+        let last = RUN_COUNTER.load(AtomicOrdering::Relaxed);
+        (first..last).filter(|&id| run_path(id).exists()).count()
+    }
+
+    #[test]
+    fn many_runs_are_merged_in_passes() {
+        let first = RUN_COUNTER.load(AtomicOrdering::Relaxed);
+        // Every row goes past a one byte budget, so each is spilled as a run of its own
+        let mut sorter = Sorter::new(vec![false, true], vec![Collation::Binary; 2], 1);
+        for i in 0..600 {
+            let keys = vec![Value::I64(i % 25), Value::String(format!("{}", i % 2))];
+            sorter.push(keys, vec![Value::I64(i)]).unwrap();
+        }
+        assert_eq!(runs_left(first), 600);
+        // 600 runs merge into 38, then 3, which are read back as the rows come out
+        let rows = sorter.finish().unwrap();
+        assert_eq!(RUN_COUNTER.load(AtomicOrdering::Relaxed) - first, 641);
+        assert_eq!(runs_left(first), 3);
+
+        let rows = rows.map(|row| match row.unwrap()[..] {
+            [Value::I64(i)] => i,
+            ref row => panic!("{:?}", row),
+        });
+        // Odd rows first within each key, rows with equal keys in the order they came
+        let mut expected = (0..600).collect::<Vec<_>>();
+        expected.sort_by_key(|i| (i % 25, -(i % 2)));
+        assert_eq!(rows.collect::<Vec<_>>(), expected);
+        assert_eq!(runs_left(first), 0);
+    }
+}
@@ -1,5 +1,5 @@
-
-pub mod token;
+pub mod error;
+pub mod keywords;
+pub mod parser;
 pub mod scanner;
-mod keywords;
-pub mod parser;
\ No newline at end of file
+pub mod token;
\ No newline at end of file
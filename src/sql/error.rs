@@ -0,0 +1,40 @@
+use thiserror::Error;
+
+// A syntax error, located at the token the parser gave up on
+#[derive(Debug, Error)]
+#[error("{message} at line {line}, column {column}\n{snippet}")]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    // The offending line of SQL with a caret under the token
+    pub snippet: String,
+}
+
+impl ParseError {
+    // `offset` is the character offset of the token in `source`
+    pub fn new(message: impl Into<String>, source: &[char], offset: usize) -> Self {
+        let offset = offset.min(source.len());
+        let line_start = source[..offset]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(source.len(), |i| offset + i);
+        let line = source[..line_start].iter().filter(|&&c| c == '\n').count() + 1;
+        let text = source[line_start..line_end].iter().collect::<String>();
+        // Keep tabs so the caret lines up with the text above it
+        let indent = source[line_start..offset]
+            .iter()
+            .map(|&c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        ParseError {
+            message: message.into(),
+            line,
+            column: offset - line_start + 1,
+            snippet: format!("  {}\n  {}^", text.trim_end(), indent),
+        }
+    }
+}
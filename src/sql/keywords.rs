@@ -4,12 +4,13 @@ use super::token::TokenType;
 
 
 static KEYWORDS: LazyLock<HashMap<String, TokenType>> = LazyLock::new(|| {
-    let map = HashMap::from([
+    HashMap::from([
         ("SELECT".to_string(), TokenType::Select),
         ("FROM".to_string(), TokenType::From),
         ("WHERE".to_string(), TokenType::Where),
         ("AND".to_string(), TokenType::And),
         ("OR".to_string(), TokenType::Or),
+        ("NOT".to_string(), TokenType::Not),
         ("INSERT".to_string(), TokenType::Insert),
         ("INTO".to_string(), TokenType::Into),
         ("VALUES".to_string(), TokenType::Values),
@@ -18,8 +19,38 @@ static KEYWORDS: LazyLock<HashMap<String, TokenType>> = LazyLock::new(|| {
         ("DELETE".to_string(), TokenType::Delete),
         ("UPDATE".to_string(), TokenType::Update),
         ("SET".to_string(), TokenType::Set),
-    ]);
-    map
+        ("AS".to_string(), TokenType::As),
+        ("IN".to_string(), TokenType::In),
+        ("BETWEEN".to_string(), TokenType::Between),
+        ("LIKE".to_string(), TokenType::Like),
+        ("IS".to_string(), TokenType::Is),
+        ("ORDER".to_string(), TokenType::Order),
+        ("BY".to_string(), TokenType::By),
+        ("ASC".to_string(), TokenType::Asc),
+        ("DESC".to_string(), TokenType::Desc),
+        ("DISTINCT".to_string(), TokenType::Distinct),
+        ("GROUP".to_string(), TokenType::Group),
+        ("HAVING".to_string(), TokenType::Having),
+        ("LIMIT".to_string(), TokenType::Limit),
+        ("JOIN".to_string(), TokenType::Join),
+        ("INNER".to_string(), TokenType::Inner),
+        ("LEFT".to_string(), TokenType::Left),
+        ("RIGHT".to_string(), TokenType::Right),
+        ("FULL".to_string(), TokenType::Full),
+        ("NATURAL".to_string(), TokenType::Natural),
+        ("USING".to_string(), TokenType::Using),
+        ("OUTER".to_string(), TokenType::Outer),
+        ("CROSS".to_string(), TokenType::Cross),
+        ("ON".to_string(), TokenType::On),
+        ("UNION".to_string(), TokenType::Union),
+        ("INTERSECT".to_string(), TokenType::Intersect),
+        ("EXCEPT".to_string(), TokenType::Except),
+        ("CASE".to_string(), TokenType::Case),
+        ("WHEN".to_string(), TokenType::When),
+        ("THEN".to_string(), TokenType::Then),
+        ("ELSE".to_string(), TokenType::Else),
+        ("END".to_string(), TokenType::End),
+    ])
 });
 
 pub fn get(text: &str) -> Option<TokenType> {
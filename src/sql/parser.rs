@@ -1,95 +1,998 @@
-use super::token::{Token, TokenType};
+use std::sync::Arc;
 
-#[derive(Debug)]
+use super::{
+    error::ParseError,
+    keywords,
+    token::{Token, TokenType},
+};
+use crate::{ephemeral::EphemeralBtree, record::Affinity};
+
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Stmt {
-    // columns, from, where
-    Select(Vec<Expr>, Option<TableReference>, Option<Expr>),
+    Select(SelectStmt),
+    // VALUES (expr, ...), ...
+    Values(Vec<Vec<Expr>>),
+    Insert(InsertStmt),
+    Update(UpdateStmt),
+    Delete(DeleteStmt),
+    CreateTable(CreateTableStmt),
+    CreateIndex(CreateIndexStmt),
+    // DROP TABLE [IF EXISTS] table
+    DropTable { name: String, if_exists: bool },
+    // DROP INDEX [IF EXISTS] index
+    DropIndex { name: String, if_exists: bool },
+    AlterTable(AlterTableStmt),
+    // BEGIN [DEFERRED | IMMEDIATE | EXCLUSIVE] [TRANSACTION]
+    Begin,
+    // COMMIT | END [TRANSACTION]
+    Commit,
+    // ROLLBACK [TRANSACTION] [TO [SAVEPOINT] savepoint]
+    Rollback { savepoint: Option<String> },
+    // SAVEPOINT savepoint
+    Savepoint(String),
+    // RELEASE [SAVEPOINT] savepoint
+    Release(String),
+    // PRAGMA [main.]name [= value | (value)]
+    Pragma { name: String, value: Option<String> },
+    // ANALYZE [[main.]table | index]
+    Analyze(Option<String>),
+    // EXPLAIN stmt, the program the statement compiles to instead of its result
+    Explain(Box<Stmt>),
+}
+
+impl Stmt {
+    // Whether running the statement leaves the database as it was
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Stmt::Select(_) | Stmt::Values(_) | Stmt::Pragma { .. } | Stmt::Explain(_))
+    }
+    pub fn is_transaction_control(&self) -> bool {
+        matches!(
+            self,
+            Stmt::Begin
+                | Stmt::Commit
+                | Stmt::Rollback { .. }
+                | Stmt::Savepoint(_)
+                | Stmt::Release(_)
+        )
+    }
+}
+
+// INSERT INTO table [(column, ...)] VALUES (expr, ...), ...
+#[derive(Debug, Clone)]
+pub struct InsertStmt {
+    pub table: String,
+    // Empty when the values are for every column in order
+    pub columns: Vec<String>,
+    pub values: Vec<Vec<Expr>>,
+}
+
+// UPDATE table SET column = expr, ... [WHERE expr]
+#[derive(Debug, Clone)]
+pub struct UpdateStmt {
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub where_clause: Option<Expr>,
+}
+
+// DELETE FROM table [WHERE expr]
+#[derive(Debug, Clone)]
+pub struct DeleteStmt {
+    pub table: String,
+    pub where_clause: Option<Expr>,
+}
+
+// CREATE TABLE [IF NOT EXISTS] table (column [type] [constraint ...], ...)
+#[derive(Debug, Clone)]
+pub struct CreateTableStmt {
+    pub table: String,
+    pub if_not_exists: bool,
+    pub columns: Vec<String>,
+    // A column defined by [GENERATED ALWAYS] AS (expr)
+    pub has_generated_column: bool,
+    // The text that goes in sqlite_schema, which is what the columns are read back from
+    pub sql: String,
+}
+
+// CREATE [UNIQUE] INDEX [IF NOT EXISTS] index ON table (column [ASC | DESC], ...)
+#[derive(Debug, Clone)]
+pub struct CreateIndexStmt {
+    pub index: String,
+    pub table: String,
+    pub unique: bool,
+    pub if_not_exists: bool,
+    // Each indexed column, and whether it is in descending order
+    pub columns: Vec<(String, bool)>,
+    pub sql: String,
+}
+
+// ALTER TABLE table ADD [COLUMN] column [type] [constraint ...]
+#[derive(Debug, Clone)]
+pub struct AlterTableStmt {
+    pub table: String,
+    pub column: String,
+    // The column definition as written, which goes into the stored CREATE TABLE
+    pub definition: String,
+    // Defined by [GENERATED ALWAYS] AS (expr)
+    pub generated: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SelectStmt {
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn>,
+    pub from: Option<TableReference>,
+    pub joins: Vec<Join>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+    // The SELECTs combined with this one, left to right. ORDER BY and LIMIT above
+    // apply to the combined rows
+    pub compound: Vec<CompoundSelect>,
+    // Tables of the WITH clause in front of it
+    pub with: Vec<CommonTableExpr>,
+}
+
+// WITH name [(column, ...)] AS (SELECT ...)
+#[derive(Debug, PartialEq, Clone)]
+pub struct CommonTableExpr {
+    pub name: String,
+    // Named after the result columns of the SELECT when empty
+    pub columns: Vec<String>,
+    pub select: SelectStmt,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompoundSelect {
+    pub op: CompoundOp,
+    pub select: SelectStmt,
 }
 
-// #[derive(Debug)]
-// pub struct SelectStmt {
-//     pub columns: Vec<Expr>,
-//     pub from: Option<TableReference>,
-//     pub where_clause: Option<Expr>,
-// }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompoundOp {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+// A select list item, `expr` is `Expr::Aliased` when it has an AS clause
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResultColumn {
+    pub expr: Expr,
+    // Source text, which names the result column when there is no alias
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+}
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct TableReference {
     pub name: String,
     pub alias: Option<String>,
+    // FROM (VALUES ...), the rows stand in for a table
+    pub values: Option<Vec<Vec<Expr>>>,
+}
+
+impl TableReference {
+    // The name columns are qualified with
+    pub fn qualifier(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableReference,
+    pub on: Option<Expr>,
+    // Join on every column name the two sides have in common
+    pub natural: bool,
+    // USING (column, ...)
+    pub using: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    // Also CROSS JOIN and the comma join
+    Inner,
+    Left,
+    Right,
+    Full,
+}
+
+impl JoinKind {
+    // Rows of the tables before the join are kept even without a match
+    pub fn keeps_left(self) -> bool {
+        matches!(self, JoinKind::Left | JoinKind::Full)
+    }
+    // Rows of the joined table are kept even without a match
+    pub fn keeps_right(self) -> bool {
+        matches!(self, JoinKind::Right | JoinKind::Full)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
     Identifier(String),
+    // table.column
+    QualifiedIdentifier(String, String),
     Literal(Literal),
     BinaryOp(Box<Expr>, Token, Box<Expr>),
+    UnaryOp(Token, Box<Expr>),
     FunctionCall(Box<Expr>, Vec<Expr>),
+    InList(Box<Expr>, Vec<Expr>),
+    // expr BETWEEN low AND high
+    Between(Box<Expr>, Box<Expr>, Box<Expr>),
+    // expr IN (SELECT ...)
+    InSelect(Box<Expr>, Box<SelectStmt>),
+    // expr IN the rows a subquery returned, keyed by its value. Never parsed but planted
+    // in place of an InSelect once the subquery has run
+    InRows(Box<Expr>, Arc<EphemeralBtree>),
+    // EXISTS (SELECT ...)
+    Exists(Box<SelectStmt>),
+    // CASE [operand] WHEN ... THEN ... [ELSE ...] END, the WHEN and THEN pairs in order
+    Case(Option<Box<Expr>>, Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    // CAST(expr AS type), the type name in lowercase
+    Cast(Box<Expr>, String),
+    // expr COLLATE name, the collation name in lowercase
+    Collate(Box<Expr>, String),
+    // An operand of a comparison that takes the other side's affinity first, never
+    // parsed but planted by the planner
+    Affinity(Box<Expr>, Affinity),
     Wildcard,
+    // table.*, every column of the one table
+    TableWildcard(String),
     Aliased(Box<Expr>, String),
+    // ?, ?NNN or a named parameter, by its index from 1 and, when named, its name with
+    // the prefix
+    Parameter(usize, Option<String>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Literal {
     String(String),
-    Number(f64),
-    Boolean(bool),
+    Integer(i64),
+    Real(f64),
+    // x'0A1B', or a value a subquery returned
+    Blob(Vec<u8>),
     Null,
 }
 
+const TABLE_CONSTRAINTS: [&str; 5] = ["constraint", "primary", "unique", "check", "foreign"];
+
+// The largest ?NNN, SQLITE_MAX_VARIABLE_NUMBER
+const MAX_PARAMETER: usize = 32766;
+// The deepest an expression tree goes, a subquery in it counting as a level, like SQLite
+const MAX_EXPR_DEPTH: usize = 1000;
+// How deep a statement's expressions nest before parsing and running it needs more than
+// the 2 MB of stack a thread gets by default. Both recurse for each level, and a debug
+// build takes tens of kilobytes for one
+pub const SHALLOW_DEPTH: usize = 64;
+// The stack of the thread a deeper statement runs on, only reserved until it is used
+const DEEP_STACK_SIZE: usize = 256 << 20;
+
+// `f` run on a thread with the stack a statement nesting up to MAX_EXPR_DEPTH needs
+pub fn on_deep_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(DEEP_STACK_SIZE).spawn_scoped(scope, f);
+        match thread.expect("a thread for a deep statement").join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
 pub struct Parser {
+    source: Vec<char>,
     tokens: Vec<Token>,
     current: usize,
+    // The parameters of the statement being parsed so far, by index from 1. Named ones
+    // share the index of their first use
+    parameters: Vec<Option<String>>,
+    // Of the expression being parsed, at the token about to be consumed
+    depth: usize,
+    // The deepest the statement being parsed went so far, and that of each one before it
+    deepest: usize,
+    depths: Vec<usize>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, current: 0 }
+    pub fn new(source: &str, tokens: Vec<Token>) -> Self {
+        Parser {
+            source: source.chars().collect(),
+            tokens,
+            current: 0,
+            parameters: Vec::new(),
+            depth: 0,
+            deepest: 0,
+            depths: Vec::new(),
+        }
     }
+    // Stops at the first syntax error
     pub fn parse(&mut self) -> anyhow::Result<Vec<Stmt>> {
+        self.parse_script().into_iter().collect()
+    }
+    // Every statement of a script, in order. A statement with a syntax error is skipped
+    // up to the next `;` so the statements after it still parse
+    pub fn parse_script(&mut self) -> Vec<anyhow::Result<Stmt>> {
+        self.parse_script_lines()
+            .into_iter()
+            .map(|(_, stmt)| stmt)
+            .collect()
+    }
+    // The name of each parameter of the statement parsed last, by index from 1. None
+    // for ? and ?NNN
+    pub fn parameters(&self) -> &[Option<String>] {
+        &self.parameters
+    }
+    // How deep the expressions of each statement parsed so far nest, in order, see
+    // `SHALLOW_DEPTH`
+    pub fn depths(&self) -> &[usize] {
+        &self.depths
+    }
+    // Like `parse_script`, along with the line each statement starts on
+    pub fn parse_script_lines(&mut self) -> Vec<(usize, anyhow::Result<Stmt>)> {
+        // A level takes at least a token, so only this many can nest too deep to parse
+        // on the thread's own stack
+        if self.tokens.len() > SHALLOW_DEPTH {
+            return on_deep_stack(|| self.parse_lines());
+        }
+        self.parse_lines()
+    }
+    fn parse_lines(&mut self) -> Vec<(usize, anyhow::Result<Stmt>)> {
         let mut stmts = Vec::new();
+        loop {
+            while self.matches(&[TokenType::Semicolon]) {}
+            if self.is_at_end() {
+                break;
+            }
+            let line = self.peek().line;
+            // Each statement numbers its parameters from 1
+            self.parameters.clear();
+            (self.depth, self.deepest) = (0, 0);
+            let stmt = self.check_tokens().and_then(|_| self.parse_stmt()).and_then(|stmt| {
+                if self.is_at_end() || self.check(&TokenType::Semicolon) {
+                    Ok(stmt)
+                } else {
+                    Err(self.error("Expected ';' after statement"))
+                }
+            });
+            if stmt.is_err() {
+                self.synchronize();
+            }
+            self.depths.push(self.deepest);
+            stmts.push((line, stmt));
+        }
+        stmts
+    }
+    // A statement with text the scanner didn't make a token of fails as a whole, before
+    // any of it is parsed
+    fn check_tokens(&self) -> anyhow::Result<()> {
+        let illegal = self.tokens[self.current..]
+            .iter()
+            .take_while(|token| !matches!(token.token_type, TokenType::Semicolon | TokenType::Eof))
+            .find(|token| token.token_type == TokenType::Illegal);
+        match illegal {
+            Some(token) => Err(self.error_at(token.offset, &format!("unrecognized token: \"{}\"", token.lexeme))),
+            None => Ok(()),
+        }
+    }
+    // Skip past the next `;`
+    fn synchronize(&mut self) {
         while !self.is_at_end() {
-            stmts.push(self.parse_stmt()?);
+            if self.advance().token_type == TokenType::Semicolon {
+                return;
+            }
         }
-        Ok(stmts)
     }
     fn parse_stmt(&mut self) -> anyhow::Result<Stmt> {
+        if self.matches_word("explain") {
+            return Ok(Stmt::Explain(Box::new(self.parse_stmt()?)));
+        }
         if self.matches(&[TokenType::Select]) {
-            return Ok(self.select_stmt()?);
+            return Ok(Stmt::Select(self.select_stmt()?));
+        }
+        if self.check_word("with") {
+            return Ok(Stmt::Select(self.with_select()?));
+        }
+        if self.matches(&[TokenType::Values]) {
+            return Ok(Stmt::Values(self.values_rows()?));
+        }
+        if self.matches(&[TokenType::Insert]) {
+            return self.insert_stmt();
+        }
+        if self.matches(&[TokenType::Update]) {
+            return self.update_stmt();
+        }
+        if self.matches(&[TokenType::Delete]) {
+            return self.delete_stmt();
+        }
+        if self.matches(&[TokenType::Create]) {
+            if self.matches(&[TokenType::Table]) {
+                return self.create_table_stmt();
+            }
+            let unique = self.matches_word("unique");
+            if self.matches_word("index") {
+                return self.create_index_stmt(unique);
+            }
+            return Err(self.error("Expected 'TABLE' or 'INDEX' after 'CREATE'"));
+        }
+        if self.matches_word("drop") {
+            return self.drop_stmt();
+        }
+        if self.matches_word("alter") {
+            return self.alter_table_stmt();
+        }
+        if self.matches_word("begin") {
+            // A single connection has no one to take locks from, the kinds behave the same
+            let _ = self.matches_word("deferred")
+                || self.matches_word("immediate")
+                || self.matches_word("exclusive");
+            self.matches_word("transaction");
+            return Ok(Stmt::Begin);
+        }
+        if self.matches_word("commit") || self.matches(&[TokenType::End]) {
+            self.matches_word("transaction");
+            return Ok(Stmt::Commit);
+        }
+        if self.matches_word("rollback") {
+            self.matches_word("transaction");
+            let mut savepoint = None;
+            if self.matches_word("to") {
+                self.matches_word("savepoint");
+                savepoint = Some(self.savepoint_name()?);
+            }
+            return Ok(Stmt::Rollback { savepoint });
+        }
+        if self.matches_word("savepoint") {
+            return Ok(Stmt::Savepoint(self.savepoint_name()?));
+        }
+        if self.matches_word("release") {
+            self.matches_word("savepoint");
+            return Ok(Stmt::Release(self.savepoint_name()?));
+        }
+        if self.matches_word("pragma") {
+            return self.pragma_stmt();
         }
-        todo!()
+        if self.matches_word("analyze") {
+            return self.analyze_stmt();
+        }
+        Err(self.error("Expected a statement"))
+    }
+    fn insert_stmt(&mut self) -> anyhow::Result<Stmt> {
+        self.consume(TokenType::Into, "Expected 'INTO' after 'INSERT'")?;
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        let mut columns = Vec::new();
+        if self.matches(&[TokenType::LeftParen]) {
+            loop {
+                let column = self.consume(TokenType::Identifier, "Expected column name")?;
+                columns.push(column.lexeme.clone());
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightParen, "Expected ')' after column names")?;
+        }
+        self.consume(TokenType::Values, "Expected 'VALUES'")?;
+        let values = self.values_rows()?;
+        Ok(Stmt::Insert(InsertStmt {
+            table,
+            columns,
+            values,
+        }))
+    }
+    fn update_stmt(&mut self) -> anyhow::Result<Stmt> {
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        self.consume(TokenType::Set, "Expected 'SET' after table name")?;
+        let mut assignments = Vec::new();
+        loop {
+            let column = self
+                .consume(TokenType::Identifier, "Expected column name")?
+                .lexeme
+                .clone();
+            self.consume(TokenType::Equal, "Expected '=' after column name")?;
+            assignments.push((column, self.expression()?));
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        let where_clause = if self.matches(&[TokenType::Where]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        Ok(Stmt::Update(UpdateStmt {
+            table,
+            assignments,
+            where_clause,
+        }))
+    }
+    fn delete_stmt(&mut self) -> anyhow::Result<Stmt> {
+        self.consume(TokenType::From, "Expected 'FROM' after 'DELETE'")?;
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        let where_clause = if self.matches(&[TokenType::Where]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        Ok(Stmt::Delete(DeleteStmt {
+            table,
+            where_clause,
+        }))
+    }
+    fn create_table_stmt(&mut self) -> anyhow::Result<Stmt> {
+        let if_not_exists = self.if_not_exists()?;
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        let start = self.previous().offset;
+        self.consume(TokenType::LeftParen, "Expected '(' after table name")?;
+        let mut columns = Vec::new();
+        let mut has_generated_column = false;
+        loop {
+            // Table constraints start with a word a column can't be named
+            let is_constraint = self.check(&TokenType::Identifier)
+                && TABLE_CONSTRAINTS
+                    .iter()
+                    .any(|word| self.peek().lexeme.eq_ignore_ascii_case(word));
+            if !is_constraint {
+                let column = match self.peek().token_type {
+                    TokenType::Identifier => self.advance().lexeme.clone(),
+                    // A quoted name
+                    TokenType::String => self.advance().literal.clone().unwrap(),
+                    _ => return Err(self.error("Expected column name")),
+                };
+                columns.push(column);
+            }
+            // The type and constraints are read back from the stored text
+            let words = self.skip_definition();
+            has_generated_column |= !is_constraint && words.iter().any(|word| word == "as");
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after column definitions")?;
+        let end = self.previous().offset + 1;
+        // Like SQLite, the stored text starts over from the table name, without IF NOT EXISTS
+        let sql = format!("CREATE TABLE {}", self.source[start..end].iter().collect::<String>());
+        Ok(Stmt::CreateTable(CreateTableStmt {
+            table,
+            if_not_exists,
+            columns,
+            has_generated_column,
+            sql,
+        }))
+    }
+    fn create_index_stmt(&mut self, unique: bool) -> anyhow::Result<Stmt> {
+        let if_not_exists = self.if_not_exists()?;
+        let index = self
+            .consume(TokenType::Identifier, "Expected index name")?
+            .lexeme
+            .clone();
+        let start = self.previous().offset;
+        self.consume(TokenType::On, "Expected 'ON' after index name")?;
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        self.consume(TokenType::LeftParen, "Expected '(' after table name")?;
+        let mut columns = Vec::new();
+        loop {
+            let column = self
+                .consume(TokenType::Identifier, "Expected column name")?
+                .lexeme
+                .clone();
+            // The collation is read back from the SQL
+            if self.matches_word("collate") {
+                self.consume(TokenType::Identifier, "Expected collation name")?;
+            }
+            let descending = match self.matches(&[TokenType::Asc, TokenType::Desc]) {
+                true => self.previous().token_type == TokenType::Desc,
+                false => false,
+            };
+            columns.push((column, descending));
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after indexed columns")?;
+        let end = self.previous().offset + 1;
+        let sql = format!(
+            "CREATE{} INDEX {}",
+            if unique { " UNIQUE" } else { "" },
+            self.source[start..end].iter().collect::<String>()
+        );
+        Ok(Stmt::CreateIndex(CreateIndexStmt {
+            index,
+            table,
+            unique,
+            if_not_exists,
+            columns,
+            sql,
+        }))
     }
-    fn select_stmt(&mut self) -> anyhow::Result<Stmt> {
+    fn drop_stmt(&mut self) -> anyhow::Result<Stmt> {
+        let is_table = self.matches(&[TokenType::Table]);
+        if !is_table && !self.matches_word("index") {
+            return Err(self.error("Expected 'TABLE' or 'INDEX' after 'DROP'"));
+        }
+        let if_exists = self.matches_word("if");
+        if if_exists && !self.matches_word("exists") {
+            return Err(self.error("Expected 'EXISTS' after 'IF'"));
+        }
+        let name = self
+            .consume(TokenType::Identifier, "Expected name after 'DROP'")?
+            .lexeme
+            .clone();
+        Ok(match is_table {
+            true => Stmt::DropTable { name, if_exists },
+            false => Stmt::DropIndex { name, if_exists },
+        })
+    }
+    fn analyze_stmt(&mut self) -> anyhow::Result<Stmt> {
+        if !self.check(&TokenType::Identifier) {
+            return Ok(Stmt::Analyze(None));
+        }
+        if self.check_word("main") && self.peek_next().token_type == TokenType::Dot {
+            self.advance();
+            self.advance();
+        }
+        let name = self
+            .consume(TokenType::Identifier, "Expected table or index name")?
+            .lexeme
+            .clone();
+        Ok(Stmt::Analyze(Some(name)))
+    }
+    fn pragma_stmt(&mut self) -> anyhow::Result<Stmt> {
+        if self.check_word("main") && self.peek_next().token_type == TokenType::Dot {
+            self.advance();
+            self.advance();
+        }
+        let name = self
+            .consume(TokenType::Identifier, "Expected pragma name")?
+            .lexeme
+            .to_lowercase();
+        let value = if self.matches(&[TokenType::Equal]) {
+            Some(self.pragma_value()?)
+        } else if self.matches(&[TokenType::LeftParen]) {
+            let value = self.pragma_value()?;
+            self.consume(TokenType::RightParen, "Expected ')' after pragma value")?;
+            Some(value)
+        } else {
+            None
+        };
+        Ok(Stmt::Pragma { name, value })
+    }
+    // A number, a name or a string. A keyword is a name too, like the ON of foreign_keys=ON
+    fn pragma_value(&mut self) -> anyhow::Result<String> {
+        let sign = if self.matches(&[TokenType::Minus]) { "-" } else { "" };
+        match self.peek().token_type {
+            TokenType::Number => Ok(format!("{}{}", sign, self.advance().lexeme)),
+            TokenType::Identifier if sign.is_empty() => Ok(self.advance().lexeme.clone()),
+            TokenType::String if sign.is_empty() => Ok(self.advance().literal.clone().unwrap()),
+            _ if sign.is_empty() && keywords::get(&self.peek().lexeme).is_some() => {
+                Ok(self.advance().lexeme.clone())
+            }
+            _ => Err(self.error("Expected pragma value")),
+        }
+    }
+    fn alter_table_stmt(&mut self) -> anyhow::Result<Stmt> {
+        self.consume(TokenType::Table, "Expected 'TABLE' after 'ALTER'")?;
+        let table = self
+            .consume(TokenType::Identifier, "Expected table name")?
+            .lexeme
+            .clone();
+        if !self.matches_word("add") {
+            return Err(self.error("Expected 'ADD' after table name"));
+        }
+        self.matches_word("column");
+        let start = self.peek().offset;
+        let column = match self.peek().token_type {
+            TokenType::Identifier => self.advance().lexeme.clone(),
+            TokenType::String => self.advance().literal.clone().unwrap(),
+            _ => return Err(self.error("Expected column name")),
+        };
+        // The definition runs to the end of the statement
+        let mut end = self.previous().offset + self.previous().lexeme.chars().count();
+        let mut depth = 0;
+        let mut generated = false;
+        while !self.is_at_end() && !self.check(&TokenType::Semicolon) {
+            let token = self.advance();
+            end = token.offset + token.lexeme.chars().count();
+            match token.token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::As if depth == 0 => generated = true,
+                _ => (),
+            }
+        }
+        Ok(Stmt::AlterTable(AlterTableStmt {
+            table,
+            column,
+            definition: self.source[start..end].iter().collect(),
+            generated,
+        }))
+    }
+    fn savepoint_name(&mut self) -> anyhow::Result<String> {
+        match self.peek().token_type {
+            TokenType::Identifier => Ok(self.advance().lexeme.clone()),
+            TokenType::String => Ok(self.advance().literal.clone().unwrap()),
+            _ => Err(self.error("Expected savepoint name")),
+        }
+    }
+    // IF NOT EXISTS, IF and EXISTS are plain words to the scanner
+    fn if_not_exists(&mut self) -> anyhow::Result<bool> {
+        if !self.matches_word("if") {
+            return Ok(false);
+        }
+        if !self.matches(&[TokenType::Not]) || !self.matches_word("exists") {
+            return Err(self.error("Expected 'NOT EXISTS' after 'IF'"));
+        }
+        Ok(true)
+    }
+    // Skip to the `,` or `)` that ends a column or constraint definition, returns the
+    // words on the way in lowercase. AS is only a word outside parentheses, where it
+    // starts a generated column's expression rather than being part of a CAST
+    fn skip_definition(&mut self) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut depth = 0;
+        while !self.is_at_end() {
+            match self.peek().token_type {
+                TokenType::Comma | TokenType::RightParen if depth == 0 => break,
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::Identifier => words.push(self.peek().lexeme.to_lowercase()),
+                TokenType::As if depth == 0 => words.push("as".to_string()),
+                _ => (),
+            }
+            self.advance();
+        }
+        words
+    }
+    fn select_stmt(&mut self) -> anyhow::Result<SelectStmt> {
+        let mut select = self.select_core()?;
+        while self.matches(&[TokenType::Union, TokenType::Intersect, TokenType::Except]) {
+            let op = match self.previous().token_type.clone() {
+                TokenType::Union if self.matches_word("all") => CompoundOp::UnionAll,
+                TokenType::Union => CompoundOp::Union,
+                TokenType::Intersect => CompoundOp::Intersect,
+                _ => CompoundOp::Except,
+            };
+            self.consume(TokenType::Select, "Expected SELECT after compound operator")?;
+            let right = self.select_core()?;
+            select.compound.push(CompoundSelect { op, select: right });
+        }
+        if self.matches(&[TokenType::Order]) {
+            self.consume(TokenType::By, "Expected 'BY' after 'ORDER'")?;
+            select.order_by = self.ordering_terms()?;
+        }
+        (select.limit, select.offset) = self.limit_clause()?;
+        let compound_op = self.peek().lexeme.to_uppercase();
+        if matches!(
+            self.peek().token_type,
+            TokenType::Union | TokenType::Intersect | TokenType::Except
+        ) {
+            let clause = match select.limit {
+                Some(_) => "LIMIT",
+                None => "ORDER BY",
+            };
+            let message = format!("{} clause should come after {} not before", clause, compound_op);
+            return Err(self.error(&message));
+        }
+        Ok(select)
+    }
+    // A SELECT up to where a compound operator, ORDER BY or LIMIT can follow
+    fn select_core(&mut self) -> anyhow::Result<SelectStmt> {
+        let distinct = self.matches(&[TokenType::Distinct]);
         let columns = self.select_list()?;
 
         self.consume(TokenType::From, "Expected 'FROM' after select columns")?;
 
         let from = Some(self.table_reference()?);
+        let joins = self.joins()?;
 
         let where_clause = if self.matches(&[TokenType::Where]) {
             Some(self.expression()?)
         } else {
             None
         };
-        // println!("select {:?} from {:?} where {:?}", columns, from, where_clause);
-        Ok(Stmt::Select(columns, from, where_clause))
+        let mut group_by = Vec::new();
+        if self.matches(&[TokenType::Group]) {
+            self.consume(TokenType::By, "Expected 'BY' after 'GROUP'")?;
+            loop {
+                group_by.push(self.expression()?);
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let having = match self.matches(&[TokenType::Having]) {
+            true => Some(self.expression()?),
+            false => None,
+        };
+        Ok(SelectStmt {
+            distinct,
+            columns,
+            from,
+            joins,
+            where_clause,
+            group_by,
+            having,
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            compound: Vec::new(),
+            with: Vec::new(),
+        })
+    }
+    // [WITH name [(column, ...)] AS (SELECT ...), ...] SELECT ...
+    fn with_select(&mut self) -> anyhow::Result<SelectStmt> {
+        let mut with = Vec::new();
+        if self.matches_word("with") {
+            // Tables can't refer to themselves, so RECURSIVE changes nothing
+            self.matches_word("recursive");
+            loop {
+                let name = self.consume(TokenType::Identifier, "Expected table name after WITH")?;
+                let name = name.lexeme.clone();
+                let mut columns = Vec::new();
+                if self.matches(&[TokenType::LeftParen]) {
+                    loop {
+                        let column = self.consume(TokenType::Identifier, "Expected column name")?;
+                        columns.push(column.lexeme.clone());
+                        if !self.matches(&[TokenType::Comma]) {
+                            break;
+                        }
+                    }
+                    self.consume(TokenType::RightParen, "Expected ')' after column names")?;
+                }
+                self.consume(TokenType::As, "Expected 'AS' after WITH table name")?;
+                let select = self.subquery()?;
+                with.push(CommonTableExpr {
+                    name,
+                    columns,
+                    select,
+                });
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::Select, "Expected SELECT")?;
+        let mut select = self.select_stmt()?;
+        select.with = with;
+        Ok(select)
+    }
+    // (SELECT ...) inside an expression, or a WITH clause table
+    fn subquery(&mut self) -> anyhow::Result<SelectStmt> {
+        let depth = self.depth;
+        self.deeper()?;
+        self.consume(TokenType::LeftParen, "Expected '(' before subquery")?;
+        let select = self.with_select()?;
+        self.consume(TokenType::RightParen, "Expected ')' after subquery")?;
+        self.depth = depth;
+        Ok(select)
+    }
+    // LIMIT count [OFFSET skip], or LIMIT skip, count
+    fn limit_clause(&mut self) -> anyhow::Result<(Option<Expr>, Option<Expr>)> {
+        if !self.matches(&[TokenType::Limit]) {
+            return Ok((None, None));
+        }
+        let limit = self.expression()?;
+        if self.matches(&[TokenType::Comma]) {
+            let count = self.expression()?;
+            return Ok((Some(count), Some(limit)));
+        }
+        let offset = match self.matches_word("offset") {
+            true => Some(self.expression()?),
+            false => None,
+        };
+        Ok((Some(limit), offset))
+    }
+    fn ordering_terms(&mut self) -> anyhow::Result<Vec<OrderingTerm>> {
+        let mut terms = Vec::new();
+        loop {
+            let expr = self.expression()?;
+            let descending = if self.matches(&[TokenType::Desc]) {
+                true
+            } else {
+                self.matches(&[TokenType::Asc]);
+                false
+            };
+            terms.push(OrderingTerm { expr, descending });
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        Ok(terms)
     }
-    fn select_list(&mut self) -> anyhow::Result<Vec<Expr>> {
+    fn select_list(&mut self) -> anyhow::Result<Vec<ResultColumn>> {
         let mut columns = Vec::new();
         loop {
-            columns.push(self.expression()?);
+            let start = self.peek().offset;
+            let mut expr = self.expression()?;
+            let last = self.previous();
+            let text = self.source[start..last.offset + last.lexeme.chars().count()]
+                .iter()
+                .collect();
+            // The AS is optional
+            let has_alias = self.matches(&[TokenType::As])
+                || self.check(&TokenType::Identifier)
+                || self.check(&TokenType::String);
+            if has_alias {
+                let alias = self.alias()?;
+                expr = Expr::Aliased(Box::new(expr), alias);
+            }
+            columns.push(ResultColumn { expr, text });
             if !self.matches(&[TokenType::Comma]) {
                 break;
             }
         }
         Ok(columns)
     }
-    fn table_reference(&mut self) -> anyhow::Result<TableReference> {
-        let name = self
-            .consume(TokenType::Identifier, "Expected table name")?
+    fn alias(&mut self) -> anyhow::Result<String> {
+        if self.matches(&[TokenType::String]) {
+            return Ok(self.previous().literal.clone().unwrap());
+        }
+        Ok(self
+            .consume(TokenType::Identifier, "Expected alias")?
             .lexeme
-            .clone();
-        let alias = if self.matches(&[TokenType::As]) {
+            .clone())
+    }
+    fn values_rows(&mut self) -> anyhow::Result<Vec<Vec<Expr>>> {
+        let mut rows = Vec::new();
+        loop {
+            let start = self.peek().offset;
+            self.consume(TokenType::LeftParen, "Expected '(' before VALUES row")?;
+            let mut row = Vec::new();
+            loop {
+                row.push(self.expression()?);
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightParen, "Expected ')' after VALUES row")?;
+            if rows.first().is_some_and(|first: &Vec<Expr>| first.len() != row.len()) {
+                return Err(self.error_at(start, "all VALUES must have the same number of terms"));
+            }
+            rows.push(row);
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        Ok(rows)
+    }
+    fn table_reference(&mut self) -> anyhow::Result<TableReference> {
+        let mut values = None;
+        let name = if self.matches(&[TokenType::LeftParen]) {
+            self.consume(TokenType::Values, "Expected VALUES after '('")?;
+            values = Some(self.values_rows()?);
+            self.consume(TokenType::RightParen, "Expected ')' after VALUES")?;
+            String::new()
+        } else {
+            self.consume(TokenType::Identifier, "Expected table name")?
+                .lexeme
+                .clone()
+        };
+        // The AS is optional
+        let alias = if self.matches(&[TokenType::As]) || self.check(&TokenType::Identifier) {
             Some(
                 self.consume(TokenType::Identifier, "Expected table alias")?
                     .lexeme
@@ -98,20 +1001,254 @@ impl Parser {
         } else {
             None
         };
-        Ok(TableReference { name, alias })
+        Ok(TableReference {
+            name,
+            alias,
+            values,
+        })
+    }
+    fn joins(&mut self) -> anyhow::Result<Vec<Join>> {
+        let mut joins = Vec::new();
+        loop {
+            if self.matches(&[TokenType::Comma]) {
+                joins.push(Join {
+                    kind: JoinKind::Inner,
+                    table: self.table_reference()?,
+                    on: None,
+                    natural: false,
+                    using: Vec::new(),
+                });
+                continue;
+            }
+            let natural = self.matches(&[TokenType::Natural]);
+            let kind = if self.matches(&[TokenType::Left, TokenType::Right, TokenType::Full]) {
+                let kind = match self.previous().token_type {
+                    TokenType::Left => JoinKind::Left,
+                    TokenType::Right => JoinKind::Right,
+                    _ => JoinKind::Full,
+                };
+                self.matches(&[TokenType::Outer]);
+                kind
+            } else if self.matches(&[TokenType::Inner, TokenType::Cross])
+                || self.check(&TokenType::Join)
+                || natural
+            {
+                JoinKind::Inner
+            } else {
+                break;
+            };
+            self.consume(TokenType::Join, "Expected 'JOIN'")?;
+            let table = self.table_reference()?;
+            let mut on = None;
+            let mut using = Vec::new();
+            let clause_start = self.peek().offset;
+            if self.matches(&[TokenType::On]) {
+                on = Some(self.expression()?);
+            } else if self.matches(&[TokenType::Using]) {
+                self.consume(TokenType::LeftParen, "Expected '(' after USING")?;
+                loop {
+                    let column = self.consume(TokenType::Identifier, "Expected column name")?;
+                    using.push(column.lexeme.clone());
+                    if !self.matches(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::RightParen, "Expected ')' after USING columns")?;
+            }
+            if natural && (on.is_some() || !using.is_empty()) {
+                let message = "a NATURAL join may not have an ON or USING clause";
+                return Err(self.error_at(clause_start, message));
+            }
+            joins.push(Join {
+                kind,
+                table,
+                on,
+                natural,
+                using,
+            });
+        }
+        Ok(joins)
     }
     fn expression(&mut self) -> anyhow::Result<Expr> {
-        // function call
-        if self.check(&TokenType::Identifier) {
-            if self.peek_next().token_type == TokenType::LeftParen {
-                return self.function_call();
+        let depth = self.depth;
+        self.deeper()?;
+        let mut expr = self.conjunction()?;
+        while self.matches(&[TokenType::Or]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.conjunction()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    // AND binds tighter than OR
+    fn conjunction(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.negation()?;
+        while self.matches(&[TokenType::And]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.negation()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    // NOT binds looser than the comparisons and tighter than AND
+    fn negation(&mut self) -> anyhow::Result<Expr> {
+        if self.matches(&[TokenType::Not]) {
+            let op = self.previous().clone();
+            self.deeper()?;
+            let operand = self.negation()?;
+            self.depth -= 1;
+            return Ok(Expr::UnaryOp(op, Box::new(operand)));
+        }
+        self.comparison()
+    }
+    // =, !=, IS, IN, BETWEEN and LIKE share a precedence level below <, <=, > and >=.
+    // IS NOT, NOT IN, NOT BETWEEN and NOT LIKE are the negation of the operator without NOT
+    fn comparison(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.relational()?;
+        loop {
+            let not = match self.check(&TokenType::Not)
+                && matches!(
+                    self.peek_next().token_type,
+                    TokenType::In | TokenType::Between | TokenType::Like
+                )
+            {
+                true => Some(self.advance().clone()),
+                false => None,
+            };
+            if self.matches(&[TokenType::Equal, TokenType::NotEqual]) {
+                let op = self.previous().clone();
+                let right = self.relational()?;
+                expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+            } else if self.matches(&[TokenType::Is]) {
+                let op = self.previous().clone();
+                let not = self.matches(&[TokenType::Not]).then(|| self.previous().clone());
+                let right = self.relational()?;
+                expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+                if let Some(not) = not {
+                    expr = Expr::UnaryOp(not, Box::new(expr));
+                }
+            } else if self.check(&TokenType::In) {
+                expr = self.in_list(expr)?;
+            } else if self.matches(&[TokenType::Between]) {
+                let low = self.relational()?;
+                self.consume(TokenType::And, "Expected 'AND' after BETWEEN")?;
+                let high = self.relational()?;
+                expr = Expr::Between(Box::new(expr), Box::new(low), Box::new(high));
+            } else if self.matches(&[TokenType::Like]) {
+                // `X LIKE Y ESCAPE Z` is the function call like(Y, X, Z)
+                let mut args = vec![self.relational()?, expr];
+                if self.matches_word("escape") {
+                    args.push(self.relational()?);
+                }
+                expr = Expr::FunctionCall(Box::new(Expr::Identifier("like".to_string())), args);
+            } else {
+                self.depth = depth;
+                return Ok(expr);
             }
-
-            if self.peek_next().token_type == TokenType::Equal {
-                return self.binary();
+            if let Some(not) = not {
+                expr = Expr::UnaryOp(not, Box::new(expr));
+            }
+            self.deeper()?;
+        }
+    }
+    fn relational(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.bitwise()?;
+        while self.matches(&[
+            TokenType::Less,
+            TokenType::LessEqual,
+            TokenType::Greater,
+            TokenType::GreaterEqual,
+        ]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.bitwise()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    // &, |, << and >> share one precedence level
+    fn bitwise(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.term()?;
+        while self.matches(&[
+            TokenType::Ampersand,
+            TokenType::Pipe,
+            TokenType::ShiftLeft,
+            TokenType::ShiftRight,
+        ]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.term()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    // + and - bind looser than *, / and % but tighter than the bitwise operators
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.factor()?;
+        while self.matches(&[TokenType::Plus, TokenType::Minus]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.factor()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    fn factor(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.unary()?;
+        while self.matches(&[TokenType::Star, TokenType::Slash, TokenType::Percent]) {
+            self.deeper()?;
+            let op = self.previous().clone();
+            let right = self.unary()?;
+            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+    // ~, - and + bind tighter than any binary operator. A minus before a number is part
+    // of the literal, -9223372036854775808 is then the smallest INTEGER rather than a REAL
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.matches(&[TokenType::Tilde, TokenType::Minus, TokenType::Plus]) {
+            let op = self.previous().clone();
+            if op.token_type == TokenType::Minus && self.check(&TokenType::Number) {
+                if self.peek().lexeme == "9223372036854775808" {
+                    self.advance();
+                    return Ok(Expr::Literal(Literal::Integer(i64::MIN)));
+                }
+                let negated = match self.collate()? {
+                    Expr::Literal(Literal::Integer(n)) => Expr::Literal(Literal::Integer(-n)),
+                    Expr::Literal(Literal::Real(n)) => Expr::Literal(Literal::Real(-n)),
+                    operand => Expr::UnaryOp(op, Box::new(operand)),
+                };
+                return Ok(negated);
             }
+            self.deeper()?;
+            let operand = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::UnaryOp(op, Box::new(operand)));
+        }
+        self.collate()
+    }
+    // COLLATE binds tighter than any operator
+    fn collate(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.primary()?;
+        while self.matches_word("collate") {
+            let name = self.consume(TokenType::Identifier, "Expected collation name")?.lexeme.to_lowercase();
+            expr = Expr::Collate(Box::new(expr), name);
         }
-        self.primary()
+        Ok(expr)
     }
     fn function_call(&mut self) -> anyhow::Result<Expr> {
         let name = self.advance().lexeme.clone();
@@ -120,7 +1257,7 @@ impl Parser {
 
         if self.matches(&[TokenType::Star]) {
             args.push(Expr::Wildcard);
-        } else {
+        } else if !self.check(&TokenType::RightParen) {
             loop {
                 args.push(self.expression()?);
                 if !self.matches(&[TokenType::Comma]) {
@@ -135,15 +1272,99 @@ impl Parser {
         Ok(Expr::FunctionCall(Box::new(Expr::Identifier(name)), args))
     }
 
-    fn binary(&mut self) -> anyhow::Result<Expr> {
-        let left = self.primary()?.clone();
-        let op = self.advance().clone();
-        let right = self.primary()?;
-        Ok(Expr::BinaryOp(Box::new(left), op, Box::new(right)))
+    // After CASE. Without an operand each WHEN is a condition, with one it is a value
+    // compared with the operand
+    fn case(&mut self) -> anyhow::Result<Expr> {
+        let operand = match self.check(&TokenType::When) {
+            true => None,
+            false => Some(Box::new(self.expression()?)),
+        };
+        let mut branches = Vec::new();
+        while self.matches(&[TokenType::When]) {
+            let when = self.expression()?;
+            self.consume(TokenType::Then, "Expected 'THEN' after WHEN")?;
+            branches.push((when, self.expression()?));
+        }
+        if branches.is_empty() {
+            return Err(self.error("Expected 'WHEN' in CASE"));
+        }
+        let else_result = match self.matches(&[TokenType::Else]) {
+            true => Some(Box::new(self.expression()?)),
+            false => None,
+        };
+        self.consume(TokenType::End, "Expected 'END' after CASE")?;
+        Ok(Expr::Case(operand, branches, else_result))
     }
+
+    // After CAST, the type name is read like a column's type, e.g. VARCHAR(10)
+    fn cast(&mut self) -> anyhow::Result<Expr> {
+        self.consume(TokenType::LeftParen, "Expected '(' after CAST")?;
+        let expr = self.expression()?;
+        self.consume(TokenType::As, "Expected 'AS' in CAST")?;
+        let words = self.skip_definition();
+        if words.is_empty() {
+            return Err(self.error("Expected a type name in CAST"));
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after CAST")?;
+        Ok(Expr::Cast(Box::new(expr), words.join(" ")))
+    }
+
+    fn in_list(&mut self, left: Expr) -> anyhow::Result<Expr> {
+        self.consume(TokenType::In, "Expected 'IN'")?;
+        let next = self.peek_next();
+        let is_subquery = next.token_type == TokenType::Select || next.lexeme.eq_ignore_ascii_case("with");
+        if self.check(&TokenType::LeftParen) && is_subquery {
+            return Ok(Expr::InSelect(Box::new(left), Box::new(self.subquery()?)));
+        }
+        self.consume(TokenType::LeftParen, "Expected '(' after IN")?;
+        let mut list = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                list.push(self.expression()?);
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after IN list")?;
+        Ok(Expr::InList(Box::new(left), list))
+    }
+
     fn primary(&mut self) -> anyhow::Result<Expr> {
+        // EXISTS is a plain word to the scanner, for IF NOT EXISTS
+        if self.peek_next().token_type == TokenType::LeftParen && self.matches_word("exists") {
+            return Ok(Expr::Exists(Box::new(self.subquery()?)));
+        }
+        if self.peek_next().token_type == TokenType::LeftParen && self.matches_word("cast") {
+            return self.cast();
+        }
+        if self.check(&TokenType::Identifier) && self.peek_next().token_type == TokenType::LeftParen {
+            return self.function_call();
+        }
+        if self.matches(&[TokenType::Case]) {
+            return self.case();
+        }
+        if self.matches_word("null") {
+            return Ok(Expr::Literal(Literal::Null));
+        }
+        if self.matches(&[TokenType::LeftParen]) {
+            let expr = self.expression()?;
+            self.consume(TokenType::RightParen, "Expected ')' after expression")?;
+            return Ok(expr);
+        }
         if self.matches(&[TokenType::Identifier]) {
-            return Ok(Expr::Identifier(self.previous().lexeme.clone()));
+            let name = self.previous().lexeme.clone();
+            if self.matches(&[TokenType::Dot]) {
+                if self.matches(&[TokenType::Star]) {
+                    return Ok(Expr::TableWildcard(name));
+                }
+                let column = self
+                    .consume(TokenType::Identifier, "Expected column name after '.'")?
+                    .lexeme
+                    .clone();
+                return Ok(Expr::QualifiedIdentifier(name, column));
+            }
+            return Ok(Expr::Identifier(name));
         }
         if self.matches(&[TokenType::String]) {
             return Ok(Expr::Literal(Literal::String(
@@ -151,17 +1372,86 @@ impl Parser {
             )));
         }
         if self.matches(&[TokenType::Number]) {
-            let num_str = self.previous().literal.clone().unwrap();
-            let number = match num_str.parse::<f64>() {
-                Ok(n) => n,
-                Err(e) => anyhow::bail!("Invalid number".to_string()),
-            };
-            return Ok(Expr::Literal(Literal::Number(number)));
+            let text = self.previous().literal.clone().unwrap();
+            return Ok(Expr::Literal(self.number(&text)?));
+        }
+        if self.matches(&[TokenType::Blob]) {
+            let digits = self.previous().literal.clone().unwrap();
+            return Ok(Expr::Literal(self.blob(&digits)?));
         }
         if self.matches(&[TokenType::Star]) {
             return Ok(Expr::Wildcard);
         }
-        todo!();
+        if self.matches(&[TokenType::Variable]) {
+            return self.parameter();
+        }
+        Err(self.error("Expected an expression"))
+    }
+    // ? takes the index after the largest so far, ?NNN the one it names and a named
+    // parameter the index of its first use or the next one
+    // https://www.sqlite.org/lang_expr.html#parameters
+    fn parameter(&mut self) -> anyhow::Result<Expr> {
+        let token = self.previous().clone();
+        let text = token.lexeme.as_str();
+        if text == "?" {
+            self.parameters.push(None);
+            return Ok(Expr::Parameter(self.parameters.len(), None));
+        }
+        if let Some(digits) = text.strip_prefix('?') {
+            let index = match digits.parse() {
+                Ok(index @ 1..=MAX_PARAMETER) => index,
+                _ => {
+                    let message = format!("variable number must be between ?1 and ?{}", MAX_PARAMETER);
+                    return Err(self.error_at(token.offset, &message));
+                }
+            };
+            if index > self.parameters.len() {
+                self.parameters.resize(index, None);
+            }
+            // The same parameter as a named one at that index
+            return Ok(Expr::Parameter(index, self.parameters[index - 1].clone()));
+        }
+        let name = Some(text.to_string());
+        let index = match self.parameters.iter().position(|parameter| *parameter == name) {
+            Some(i) => i + 1,
+            None => {
+                self.parameters.push(name.clone());
+                self.parameters.len()
+            }
+        };
+        Ok(Expr::Parameter(index, name))
+    }
+    fn number(&self, text: &str) -> anyhow::Result<Literal> {
+        let offset = self.previous().offset;
+        if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            // Up to 16 digits, taken as the bits of a 64-bit integer
+            return match u64::from_str_radix(hex, 16) {
+                Ok(n) => Ok(Literal::Integer(n as i64)),
+                Err(_) => Err(self.error_at(offset, &format!("hex literal too big: {}", text))),
+            };
+        }
+        if !text.contains(['.', 'e', 'E']) {
+            // Integers too large for 64 bits are REAL, like in SQLite
+            if let Ok(n) = text.parse() {
+                return Ok(Literal::Integer(n));
+            }
+        }
+        match text.parse() {
+            Ok(n) => Ok(Literal::Real(n)),
+            Err(_) => Err(self.error_at(offset, "Invalid number")),
+        }
+    }
+    // An even number of hex digits, two to a byte
+    fn blob(&self, digits: &str) -> anyhow::Result<Literal> {
+        let token = self.previous();
+        if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(self.error_at(token.offset, &format!("unrecognized token: \"{}\"", token.lexeme)));
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+        Ok(Literal::Blob(bytes))
     }
     fn matches(&mut self, types: &[TokenType]) -> bool {
         for t in types {
@@ -172,6 +1462,17 @@ impl Parser {
         }
         false
     }
+    // A word that is not a keyword to the scanner, in any case
+    fn matches_word(&mut self, word: &str) -> bool {
+        if self.check_word(word) {
+            self.advance();
+            return true;
+        }
+        false
+    }
+    fn check_word(&mut self, word: &str) -> bool {
+        self.check(&TokenType::Identifier) && self.peek().lexeme.eq_ignore_ascii_case(word)
+    }
     fn check(&mut self, token_type: &TokenType) -> bool {
         if self.is_at_end() {
             return false;
@@ -182,7 +1483,23 @@ impl Parser {
         if self.check(&token_type) {
             return Ok(self.advance());
         }
-        anyhow::bail!(message.to_string());
+        Err(self.error(message))
+    }
+    // One level further down the expression tree
+    fn deeper(&mut self) -> anyhow::Result<()> {
+        self.depth += 1;
+        self.deepest = self.deepest.max(self.depth);
+        if self.depth > MAX_EXPR_DEPTH {
+            return Err(self.error(&format!("Expression tree is too large (maximum depth {})", MAX_EXPR_DEPTH)));
+        }
+        Ok(())
+    }
+    // An error at the token about to be consumed
+    fn error(&self, message: &str) -> anyhow::Error {
+        self.error_at(self.peek().offset, message)
+    }
+    fn error_at(&self, offset: usize, message: &str) -> anyhow::Error {
+        ParseError::new(message, &self.source, offset).into()
     }
     fn peek(&self) -> &Token {
         &self.tokens[self.current]
@@ -203,6 +1520,67 @@ impl Parser {
         &self.tokens[self.current - 1]
     }
     fn is_at_end(&self) -> bool {
-        self.peek().token_type == TokenType::EOF
+        self.peek().token_type == TokenType::Eof
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::scanner::Scanner;
+
+    fn parse(sql: &str) -> anyhow::Result<Vec<Stmt>> {
+        let tokens = Scanner::new(sql.to_string()).scan_tokens().clone();
+        Parser::new(sql, tokens).parse()
+    }
+
+    // On the test thread, which has the stack any thread gets by default
+    #[test]
+    fn expressions_nest_up_to_the_depth_limit() {
+        let nested = |n: usize| format!("SELECT {}1{} FROM t", "(".repeat(n), ")".repeat(n));
+        assert!(parse(&nested(MAX_EXPR_DEPTH - 1)).is_ok());
+        for n in [MAX_EXPR_DEPTH + 1, 100_000] {
+            let error = parse(&nested(n)).unwrap_err().to_string();
+            assert!(error.starts_with("Expression tree is too large (maximum depth 1000)"), "{}", error);
+        }
+        let chain = |n: usize| format!("SELECT 1{} FROM t", " + 1".repeat(n));
+        assert!(parse(&chain(MAX_EXPR_DEPTH - 1)).is_ok());
+        assert!(parse(&chain(MAX_EXPR_DEPTH + 1)).is_err());
+        assert!(parse(&format!("SELECT {}1 FROM t", "NOT ".repeat(100_000))).is_err());
+        let subqueries = |n: usize| {
+            let select = "x IN (SELECT x FROM t WHERE ".repeat(n);
+            format!("SELECT 1 FROM t WHERE {}1{}", select, ")".repeat(n))
+        };
+        assert!(parse(&subqueries(MAX_EXPR_DEPTH / 2 - 1)).is_ok());
+        assert!(parse(&subqueries(MAX_EXPR_DEPTH / 2 + 1)).is_err());
+        assert!(parse(&subqueries(10_000)).is_err());
+    }
+
+    #[test]
+    fn pragma_values_can_be_keywords() {
+        for (sql, expected) in [
+            ("PRAGMA foreign_keys=ON", "ON"),
+            ("PRAGMA writable_schema = on", "on"),
+            ("PRAGMA foreign_keys=OFF", "OFF"),
+            ("PRAGMA main.cache_size(-2000)", "-2000"),
+        ] {
+            match &parse(sql).unwrap()[..] {
+                [Stmt::Pragma { value, .. }] => assert_eq!(value.as_deref(), Some(expected), "{}", sql),
+                stmts => panic!("{:?}", stmts),
+            }
+        }
+        assert!(parse("PRAGMA foreign_keys = -ON").is_err());
+    }
+
+    #[test]
+    fn unrecognized_tokens_fail_their_statement() {
+        for sql in ["SELECT 1 # 2 FROM t", "SELECT 'abc FROM t", "SELECT x'12"] {
+            let error = parse(sql).unwrap_err().to_string();
+            assert!(error.starts_with("unrecognized token: "), "{}", error);
+        }
+        let sql = "SELECT 1 FROM t; SELECT # FROM t; SELECT 2 FROM t";
+        let tokens = Scanner::new(sql.to_string()).scan_tokens().clone();
+        let stmts = Parser::new(sql, tokens).parse_script();
+        assert_eq!(stmts.iter().map(Result::is_ok).collect::<Vec<_>>(), [true, false, true]);
     }
 }
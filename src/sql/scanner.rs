@@ -1,7 +1,8 @@
 use super::{keywords, token::{Token, TokenType}};
 
 pub struct Scanner {
-    source: String,
+    // Indexed by character so non-ASCII text doesn't split a UTF-8 sequence
+    source: Vec<char>,
     tokens: Vec<Token>,
     start: usize,
     current: usize,
@@ -11,7 +12,7 @@ pub struct Scanner {
 impl Scanner {
     pub fn new(source: String) -> Self {
         Scanner {
-            source,
+            source: source.chars().collect(),
             tokens: Vec::new(),
             start: 0,
             current: 0,
@@ -25,8 +26,13 @@ impl Scanner {
             self.scan_token();
         }
 
-        self.tokens
-            .push(Token::new(TokenType::EOF, String::new(), None, self.line));
+        self.tokens.push(Token::new(
+            TokenType::Eof,
+            String::new(),
+            None,
+            self.line,
+            self.current,
+        ));
         &self.tokens
     }
 
@@ -36,74 +42,169 @@ impl Scanner {
             '(' => self.add_token(TokenType::LeftParen, None),
             ')' => self.add_token(TokenType::RightParen, None),
             ',' => self.add_token(TokenType::Comma, None),
+            // .5 is a number
+            '.' if self.peek().is_ascii_digit() => self.number(),
             '.' => self.add_token(TokenType::Dot, None),
             ';' => self.add_token(TokenType::Semicolon, None),
+            '+' => self.add_token(TokenType::Plus, None),
+            // -- to the end of the line
+            '-' if self.match_char('-') => {
+                while !self.is_at_end() && self.peek() != '\n' {
+                    self.advance();
+                }
+            }
+            '-' => self.add_token(TokenType::Minus, None),
             '*' => self.add_token(TokenType::Star, None),
-            '=' => self.add_token(TokenType::Equal, None),
+            '/' if self.match_char('*') => self.block_comment(),
+            '/' => self.add_token(TokenType::Slash, None),
+            '%' => self.add_token(TokenType::Percent, None),
+            '&' => self.add_token(TokenType::Ampersand, None),
+            '|' => self.add_token(TokenType::Pipe, None),
+            '~' => self.add_token(TokenType::Tilde, None),
+            '<' if self.match_char('<') => self.add_token(TokenType::ShiftLeft, None),
+            '>' if self.match_char('>') => self.add_token(TokenType::ShiftRight, None),
+            '<' if self.match_char('=') => self.add_token(TokenType::LessEqual, None),
+            '<' if self.match_char('>') => self.add_token(TokenType::NotEqual, None),
+            '!' if self.match_char('=') => self.add_token(TokenType::NotEqual, None),
+            '>' if self.match_char('=') => self.add_token(TokenType::GreaterEqual, None),
+            '<' => self.add_token(TokenType::Less, None),
+            '>' => self.add_token(TokenType::Greater, None),
+            // == is another way to write =
+            '=' => {
+                self.match_char('=');
+                self.add_token(TokenType::Equal, None)
+            }
             ' ' | '\r' | '\t' => (),
             '\n' => self.line += 1,
             '"' => self.string('"'),
             '\'' => self.string('\''),
             '0'..='9' => self.number(),
-            _ => {
-                if c.is_alphabetic() {
-                    self.identifier();
-                }
+            'x' | 'X' if self.match_char('\'') => self.blob(),
+            '?' => self.variable(|c| c.is_ascii_digit()),
+            ':' | '@' | '$' if self.peek().is_alphanumeric() || self.peek() == '_' => {
+                self.variable(|c| c.is_alphanumeric() || c == '_')
             }
+            _ if c.is_alphabetic() || c == '_' => self.identifier(),
+            _ => self.add_token(TokenType::Illegal, None),
         }
     }
 
     fn string(&mut self, quote: char) {
-        while !self.is_at_end() && self.peek() != quote {
-            if self.peek() == '\n' {
+        let mut value = String::new();
+        loop {
+            if self.is_at_end() {
+                // Unterminated string
+                return self.add_token(TokenType::Illegal, None);
+            }
+            let c = self.advance();
+            if c == quote {
+                // A doubled quote stands for one quote inside the string: 'O''Brien'
+                if !self.match_char(quote) {
+                    break;
+                }
+            } else if c == '\n' {
                 self.line += 1;
             }
-            self.advance();
+            value.push(c);
         }
+        self.add_token(TokenType::String, Some(value));
+    }
 
+    // The hex digits between the quotes, checked by the parser
+    fn blob(&mut self) {
+        while !self.is_at_end() && self.peek() != '\'' {
+            self.advance();
+        }
         if self.is_at_end() {
-            // Unterminated string
-            return;
+            // Unterminated blob
+            return self.add_token(TokenType::Illegal, None);
         }
-
-        // The closing quote
         self.advance();
-
-        // Trim the surrounding quotes
-        let value = self.source[self.start + 1..self.current - 1].to_string();
-        self.add_token(TokenType::String, Some(value));
+        let value = self.text(self.start + 2, self.current - 1);
+        self.add_token(TokenType::Blob, Some(value));
     }
 
-    fn number(&mut self) {
-        while self.peek().is_digit(10) {
-            self.advance();
+    // /* up to */, or the end of the input when it isn't closed, like SQLite
+    fn block_comment(&mut self) {
+        while !self.is_at_end() {
+            match self.advance() {
+                '*' if self.match_char('/') => return,
+                '\n' => self.line += 1,
+                _ => (),
+            }
         }
+    }
 
-        // Look for a decimal part
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
-            // Consume the "."
+    // Like SQLite: 1, 1.5, 1., .5, 1e10, 1.e2, 2.5E-3 or 0x1F. A number that runs into
+    // letters, like 12abc, 0x or 1e, is no token at all
+    fn number(&mut self) {
+        // Hexadecimal integer, 0x1F
+        if self.source[self.start] == '0'
+            && matches!(self.peek(), 'x' | 'X')
+            && self.peek_next().is_ascii_hexdigit()
+        {
             self.advance();
+            while self.peek().is_ascii_hexdigit() {
+                self.advance();
+            }
+        } else {
+            // The integer part, unless the number starts at its "."
+            if self.source[self.start] != '.' {
+                while self.peek().is_ascii_digit() {
+                    self.advance();
+                }
+                self.match_char('.');
+            }
+            // The decimal part
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
 
-            while self.peek().is_digit(10) {
+            // Look for an exponent, 1e10 or 2.5E-3
+            if matches!(self.peek(), 'e' | 'E') {
+                let sign_len = usize::from(matches!(self.peek_next(), '+' | '-'));
+                let has_digits = self
+                    .source
+                    .get(self.current + 1 + sign_len)
+                    .is_some_and(|c| c.is_ascii_digit());
+                if has_digits {
+                    for _ in 0..=sign_len {
+                        self.advance();
+                    }
+                    while self.peek().is_ascii_digit() {
+                        self.advance();
+                    }
+                }
+            }
+        }
+        if self.peek().is_alphanumeric() || self.peek() == '_' {
+            while self.peek().is_alphanumeric() || self.peek() == '_' {
                 self.advance();
             }
+            return self.add_token(TokenType::Illegal, None);
+        }
+        let literal = self.text(self.start, self.current);
+        self.add_token(TokenType::Number, Some(literal));
+    }
+
+    // A parameter, numbered after the ? or named after the prefix
+    fn variable(&mut self, is_part: fn(char) -> bool) {
+        while is_part(self.peek()) {
+            self.advance();
         }
-        let literal = &self.source[self.start..self.current];
-        self.add_token(TokenType::Number, Some(literal.to_string()));
+        self.add_token(TokenType::Variable, None);
     }
 
     fn identifier(&mut self) {
         let mut c = self.peek();
-        while c.is_alphabetic() || c == '_' {
+        while c.is_alphanumeric() || c == '_' {
             self.advance();
             c = self.peek();
         }
 
-        let text = self.source[self.start..self.current].to_string();
+        let text = self.text(self.start, self.current);
         let token_type = keywords::get(&text)
             .unwrap_or(TokenType::Identifier);
-        // println!("{token_type:?},   {text}");
-
         self.add_token(token_type, None);
     }
 
@@ -113,14 +214,23 @@ impl Scanner {
 
     fn advance(&mut self) -> char {
         self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        self.source[self.current - 1]
+    }
+
+    // Consume the next character if it is `expected`
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.peek() != expected {
+            return false;
+        }
+        self.current += 1;
+        true
     }
 
     fn peek(&self) -> char {
         if self.is_at_end() {
             '\0'
         } else {
-            self.source.chars().nth(self.current).unwrap()
+            self.source[self.current]
         }
     }
 
@@ -128,12 +238,77 @@ impl Scanner {
         if self.current + 1 >= self.source.len() {
             '\0'
         } else {
-            self.source.chars().nth(self.current + 1).unwrap()
+            self.source[self.current + 1]
         }
     }
 
+    fn text(&self, start: usize, end: usize) -> String {
+        self.source[start..end].iter().collect()
+    }
+
     fn add_token(&mut self, token_type: TokenType, literal: Option<String>) {
-        let text = self.source[self.start..self.current].to_string();
-        self.tokens.push(Token::new(token_type, text, literal, self.line));
+        let text = self.text(self.start, self.current);
+        self.tokens
+            .push(Token::new(token_type, text, literal, self.line, self.start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literals(source: &str) -> Vec<Option<String>> {
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens();
+        tokens[..tokens.len() - 1].iter().map(|token| token.literal.clone()).collect()
+    }
+
+    #[test]
+    fn doubled_quotes_are_escaped_quotes() {
+        assert_eq!(literals("'O''Brien'"), [Some("O'Brien".to_string())]);
+        assert_eq!(literals("'it''s', ''''"), [Some("it's".to_string()), None, Some("'".to_string())]);
+        assert_eq!(literals("\"a\"\"b\""), [Some("a\"b".to_string())]);
+        assert_eq!(literals("''"), [Some(String::new())]);
+    }
+
+    fn types(source: &str) -> Vec<TokenType> {
+        let mut scanner = Scanner::new(source.to_string());
+        scanner.scan_tokens().iter().map(|token| token.token_type.clone()).collect()
+    }
+
+    #[test]
+    fn block_comments_are_skipped_wherever_they_are() {
+        use TokenType::{Eof, Number, Plus, Slash, Star};
+        assert_eq!(types("1 /* a */ + /**/2"), [Number, Plus, Number, Eof]);
+        assert_eq!(types("1/*a*/*/*b*//2"), [Number, Star, Slash, Number, Eof]);
+        // They don't nest, and one left open runs to the end of the input
+        assert_eq!(types("1 /* a /* b */ + 2 */"), [Number, Plus, Number, Star, Slash, Eof]);
+        assert_eq!(types("1 /* + 2"), [Number, Eof]);
+        assert_eq!(types("1 -- /* a\n+ 2"), [Number, Plus, Number, Eof]);
+        assert_eq!(literals("'/* a */'"), [Some("/* a */".to_string())]);
+        // The lines a comment spans still count
+        let mut scanner = Scanner::new("/* a\n b\n */ 1".to_string());
+        assert_eq!(scanner.scan_tokens()[0].line, 3);
+    }
+
+    #[test]
+    fn text_that_is_no_token_is_kept_as_illegal() {
+        assert_eq!(types("1 # 2"), [TokenType::Number, TokenType::Illegal, TokenType::Number, TokenType::Eof]);
+        assert_eq!(types("'abc; 1"), [TokenType::Illegal, TokenType::Eof]);
+        assert_eq!(types("\"abc"), [TokenType::Illegal, TokenType::Eof]);
+        assert_eq!(types("x'12"), [TokenType::Illegal, TokenType::Eof]);
+    }
+
+    #[test]
+    fn numbers_are_read_like_sqlite() {
+        for (source, literal) in [("1.", "1."), (".5", ".5"), ("1.e2", "1.e2"), ("2.5E-3", "2.5E-3"), ("0x1F", "0x1F")] {
+            assert_eq!(literals(source), [Some(literal.to_string())], "{}", source);
+        }
+        // The exponent needs digits, the sign alone isn't part of it
+        assert_eq!(types("1e+"), [TokenType::Illegal, TokenType::Plus, TokenType::Eof]);
+        for source in ["0x", "1e", "12abc", "0x1g", "1.x"] {
+            assert_eq!(types(source), [TokenType::Illegal, TokenType::Eof], "{}", source);
+        }
+        assert_eq!(types("t.a"), [TokenType::Identifier, TokenType::Dot, TokenType::Identifier, TokenType::Eof]);
     }
 }
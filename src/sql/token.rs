@@ -1,18 +1,31 @@
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     // Single-character tokens
-    LeftParen, RightParen, Comma, Dot, Semicolon, Star, Equal,
+    LeftParen, RightParen, Comma, Dot, Semicolon, Plus, Minus, Star, Slash, Percent, Equal,
+    Ampersand, Pipe, Tilde, Less, Greater,
+
+    // Two-character tokens
+    ShiftLeft, ShiftRight, LessEqual, GreaterEqual, NotEqual,
     
     // Literals
     Identifier, String, Number,
+    // x'0A1B', the hex digits are the literal
+    Blob,
+    // ?, ?NNN, :name, @name and $name
+    Variable,
     
     // Keywords
-    Select, From, Where, And, Or,
+    Select, From, Where, And, Or, Not,
     Insert, Into, Values,
     Create, Table,
-    Delete, Update, Set, As,
-    
-    EOF
+    Delete, Update, Set, As, In, Between, Like, Is,
+    Order, By, Asc, Desc, Distinct, Group, Having, Limit,
+    Join, Inner, Left, Right, Full, Outer, Cross, On, Natural, Using,
+    Union, Intersect, Except, Case, When, Then, Else, End,
+
+    // Text that isn't a token, like # or a string without its closing quote
+    Illegal,
+    Eof
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -21,15 +34,24 @@ pub struct Token {
     pub lexeme: String,
     pub literal: Option<String>,
     pub line: usize,
+    // Character offset of the lexeme in the source
+    pub offset: usize,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, literal: Option<String>, line: usize) -> Self {
+    pub fn new(
+        token_type: TokenType,
+        lexeme: String,
+        literal: Option<String>,
+        line: usize,
+        offset: usize,
+    ) -> Self {
         Token {
             token_type,
             lexeme,
             literal,
             line,
+            offset,
         }
     }
 }
\ No newline at end of file
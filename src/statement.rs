@@ -0,0 +1,155 @@
+use crate::{
+    connection::Connection,
+    db::{QueryResult, RowStream},
+    error::{Error, Result},
+    params::Params,
+    record::Value,
+    row::{Row, Rows},
+    sql::{parser::{self, Stmt}, scanner},
+};
+
+// A statement parsed once and run any number of times, with other values bound to its
+// parameters between runs: prepare, bind, step until there are no more rows, reset
+// https://www.sqlite.org/cintro.html
+pub struct Statement {
+    sql: String,
+    stmt: Stmt,
+    // The name of each parameter by index from 1, None for ? and ?NNN
+    parameters: Vec<Option<String>>,
+    params: Params,
+    // The current run, None before the first step, with the change counter of the
+    // database when it started and the connection it runs on
+    rows: Option<(RowStream, Option<u32>, u64)>,
+    columns: Vec<String>,
+    // Nests too deep to run on the stack of the caller's thread, see `parser::SHALLOW_DEPTH`
+    deep: bool,
+}
+
+impl Statement {
+    // `sql` is a single statement, a trailing `;` is allowed
+    pub fn prepare(sql: &str) -> Result<Self> {
+        let mut scanner = scanner::Scanner::new(sql.to_string());
+        let tokens = scanner.scan_tokens().clone();
+        let mut parser = parser::Parser::new(sql, tokens);
+        let mut stmts = parser.parse()?;
+        if stmts.len() != 1 {
+            let message = format!("expected a single statement, got {}", stmts.len());
+            return Err(Error::Misuse(message));
+        }
+        Ok(Self {
+            sql: sql.to_string(),
+            stmt: stmts.remove(0),
+            parameters: parser.parameters().to_vec(),
+            params: Params::new(),
+            rows: None,
+            columns: Vec::new(),
+            deep: parser.depths()[0] > parser::SHALLOW_DEPTH,
+        })
+    }
+
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    // The largest parameter index, named parameters included
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    // The index of the parameter called `name`, prefix included, if the statement has one
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters
+            .iter()
+            .position(|parameter| parameter.as_deref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.parameters.get(index.checked_sub(1)?)?.as_deref()
+    }
+
+    // The values take effect from the next run, they stay bound across `reset`
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        if index == 0 || index > self.parameters.len() {
+            return Err(Error::Misuse(format!("parameter index out of range: {}", index)));
+        }
+        self.params.bind(index, value)
+    }
+
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        match self.parameter_index(name) {
+            Some(index) => self.bind(index, value),
+            None => Err(Error::Misuse(format!("no such parameter: {}", name))),
+        }
+    }
+
+    // Bind every value of `params`, in place of the values bound so far
+    pub fn bind_params(&mut self, params: Params) {
+        self.params = params;
+    }
+
+    // Every parameter back to NULL
+    pub fn clear_bindings(&mut self) {
+        self.params = Params::new();
+    }
+
+    // The next row of the result, None once there are no more until `reset`. The first
+    // step runs the statement, a write has done all of its work by the time it returns.
+    // The statement doesn't hold on to the connection between steps, nor to its read lock
+    // on the file, so a statement reset or dropped part way leaves other processes free to
+    // write: when the database was written to in between, by this connection or another,
+    // the rows read so far may be gone, and the step fails and resets the statement
+    // instead. A run stays on the connection it started on, stepping it on another one fails
+    pub fn step(&mut self, connection: &mut Connection) -> Result<Option<Row>> {
+        let db = &mut connection.db;
+        let (rows, version) = match &mut self.rows {
+            Some((_, _, id)) if *id != connection.id => {
+                let message = "the statement was stepped on another connection than it started on";
+                return Err(Error::Misuse(message.to_string()));
+            }
+            Some((rows, version, _)) => {
+                db.resume_read()?;
+                (rows, *version)
+            }
+            None => {
+                let rows = db.start_rows(&self.stmt, &self.params, self.deep)?;
+                self.columns = rows.columns().to_vec();
+                let version = db.change_counter();
+                let (rows, _, _) = self.rows.insert((rows, version, connection.id));
+                (rows, version)
+            }
+        };
+        if db.change_counter() != version {
+            db.end_read();
+            self.reset();
+            return Err(Error::Misuse("the database changed between steps of a statement".to_string()));
+        }
+        let row = db.next_row(rows);
+        db.end_read();
+        Ok(row?)
+    }
+
+    // Run the statement from the start, its rows are read as they are iterated over
+    pub fn query<'a>(&mut self, connection: &'a mut Connection) -> Result<Rows<'a>> {
+        self.reset();
+        let rows = connection.db.start_rows(&self.stmt, &self.params, self.deep)?;
+        self.columns = rows.columns().to_vec();
+        Ok(Rows::new(connection, rows))
+    }
+
+    // Ready to run again from the start, with the same bindings
+    pub fn reset(&mut self) {
+        self.rows = None;
+    }
+
+    // Run the statement from the start and return all of its rows
+    pub fn execute(&mut self, connection: &mut Connection) -> Result<QueryResult> {
+        self.reset();
+        Ok(connection.db.execute_prepared(&self.stmt, &self.params, self.deep)?)
+    }
+
+    // The names of the result columns, known once the statement has run
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use crate::{
+    db::{Pager, HEADER_SIZE},
+    error::Error,
+    page::Page,
+    utils::read_be_word_at,
+};
+
+// Space usage of a single b-tree, in the spirit of SQLite's `dbstat` virtual table
+// https://www.sqlite.org/dbstat.html
+#[derive(Debug, Clone, Default)]
+pub struct BtreeUsage {
+    pub name: String,
+    pub is_index: bool,
+    pub leaf_pages: usize,
+    pub interior_pages: usize,
+    pub overflow_pages: usize,
+    pub cells: usize,
+    pub payload_bytes: u64,
+    pub unused_bytes: u64,
+}
+
+impl BtreeUsage {
+    pub fn new(name: &str, is_index: bool) -> Self {
+        BtreeUsage {
+            name: name.to_string(),
+            is_index,
+            ..Default::default()
+        }
+    }
+
+    pub fn total_pages(&self) -> usize {
+        self.leaf_pages + self.interior_pages + self.overflow_pages
+    }
+
+    // Walk every page of the b-tree rooted at `root_page`
+    pub fn collect(
+        &mut self,
+        pager: &mut Pager,
+        root_page: usize,
+        usable_size: usize,
+    ) -> anyhow::Result<()> {
+        let mut stack = vec![root_page];
+        while let Some(page_num) = stack.pop() {
+            let buffer = Arc::from(pager.read_raw_page(page_num)?);
+            let page = Page::parse(&buffer, page_num, usable_size, &mut |overflow_page| {
+                pager.read_raw_page(overflow_page)
+            })?;
+            self.unused_bytes += free_bytes(&buffer, &page, page_num, usable_size) as u64;
+
+            // (payload size, inline size, first overflow page) of every cell
+            let payloads = match &page {
+                Page::TableLeaf(leaf) => {
+                    self.leaf_pages += 1;
+                    leaf.cells
+                        .iter()
+                        .map(|cell| (cell.size as usize, cell.local_size, cell.overflow_page))
+                        .collect()
+                }
+                Page::TableInterior(interior) => {
+                    self.interior_pages += 1;
+                    stack.push(interior.header.get_right_most_point() as usize);
+                    stack.extend(interior.cells.iter().map(|cell| cell.left_child as usize));
+                    // Table interior cells only hold a rowid key, there is no payload
+                    Vec::new()
+                }
+                Page::IndexLeaf(leaf) => {
+                    self.leaf_pages += 1;
+                    leaf.cells
+                        .iter()
+                        .map(|cell| (cell.size, cell.local_size, cell.overflow_page))
+                        .collect()
+                }
+                Page::IndexInterior(interior) => {
+                    self.interior_pages += 1;
+                    stack.push(interior.header.get_right_most_point() as usize);
+                    stack.extend(interior.cells.iter().map(|cell| cell.left_child as usize));
+                    interior
+                        .cells
+                        .iter()
+                        .map(|cell| (cell.size, cell.local_size, cell.overflow_page))
+                        .collect::<Vec<_>>()
+                }
+            };
+            self.cells += page.get_header().get_cell_count() as usize;
+
+            for (payload_size, local_size, overflow_page) in payloads {
+                self.payload_bytes += payload_size as u64;
+                if let Some(first_page) = overflow_page {
+                    // Each overflow page starts with a 4-byte pointer to the next one,
+                    // whatever the last page does not fill is wasted
+                    let overflow_pages = count_overflow_chain(pager, first_page)?;
+                    let spilled = payload_size - local_size;
+                    self.overflow_pages += overflow_pages;
+                    self.unused_bytes +=
+                        (overflow_pages * (usable_size - 4)).saturating_sub(spilled) as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn count_overflow_chain(pager: &mut Pager, first_page: u32) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut next_page = first_page;
+    while next_page != 0 {
+        count += 1;
+        // A chain longer than the file loops
+        if count > pager.page_count() {
+            anyhow::bail!(Error::CorruptPage(format!("overflow chain loops back to page {}", next_page)));
+        }
+        let buffer = pager.read_raw_page(next_page as usize)?;
+        next_page = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
+    }
+    Ok(count)
+}
+
+// Unallocated gap + freeblocks + fragmented bytes
+fn free_bytes(buffer: &[u8], page: &Page, page_num: usize, usable_size: usize) -> usize {
+    let header = page.get_header();
+    let header_start = if page_num == 1 { HEADER_SIZE } else { 0 };
+    let cell_pointers_end =
+        header_start + header.get_header_size() + header.get_cell_count() as usize * 2;
+    let content_start = (header.get_cell_content_offset() as usize).min(usable_size);
+    let mut free = content_start.saturating_sub(cell_pointers_end);
+
+    // Each freeblock starts with the offset of the next one and its own size
+    let mut freeblock = header.get_first_freeblock() as usize;
+    while freeblock != 0 && freeblock + 4 <= buffer.len() {
+        free += read_be_word_at(buffer, freeblock + 2) as usize;
+        let next = read_be_word_at(buffer, freeblock) as usize;
+        // Freeblocks are kept in increasing order, anything else is a broken chain
+        if next <= freeblock {
+            break;
+        }
+        freeblock = next;
+    }
+
+    free + header.get_fragmented_bytes_count() as usize
+}
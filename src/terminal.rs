@@ -0,0 +1,168 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, IsTerminal, Write},
+    process::{Child, Command, Stdio},
+    sync::OnceLock,
+};
+
+// Where result lines go: straight to stdout, or through a pager when stdout is a
+// terminal and the lines wouldn't fit on one screen
+pub struct Output {
+    sink: Sink,
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    // From .output or .once
+    File(io::BufWriter<File>),
+    // $PAGER, or less
+    External(Child),
+    // A screenful at a time with a --More-- prompt, when no pager program can be started
+    Internal { height: usize, shown: usize },
+    // The lines so far, until they fill the screen and go to a pager, or else to stdout
+    Pending { height: usize, lines: Vec<String> },
+}
+
+impl Output {
+    // Without a height for the terminal nothing is paged
+    pub fn new(paged: bool) -> Self {
+        let stdout = io::stdout();
+        let height = match paged && stdout.is_terminal() {
+            true => terminal_height(),
+            false => None,
+        };
+        let sink = match height {
+            Some(height) => Sink::Pending { height, lines: Vec::new() },
+            None => Sink::Stdout(stdout),
+        };
+        Output { sink }
+    }
+
+    pub fn to_file(file: File) -> Self {
+        Output { sink: Sink::File(io::BufWriter::new(file)) }
+    }
+
+    // Returns false once the reader has quit the pager, the rest can be skipped
+    pub fn write_line(&mut self, line: &str) -> bool {
+        match &mut self.sink {
+            Sink::Stdout(stdout) => writeln!(stdout, "{}", line).is_ok(),
+            Sink::File(file) => writeln!(file, "{}", line).is_ok(),
+            Sink::External(child) => {
+                let stdin = child.stdin.as_mut().unwrap();
+                writeln!(stdin, "{}", line).is_ok()
+            }
+            Sink::Internal { height, shown } => {
+                println!("{}", line);
+                *shown += 1;
+                // One line of the screen goes to the prompt
+                if *shown % (*height - 1).max(1) != 0 {
+                    return true;
+                }
+                print!("--More-- (Enter for more, q to quit)");
+                let _ = io::stdout().flush();
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer).is_ok() && !answer.trim().starts_with('q')
+            }
+            Sink::Pending { height, lines } => {
+                lines.push(line.to_string());
+                if lines.len() < *height {
+                    return true;
+                }
+                let (height, lines) = (*height, std::mem::take(lines));
+                self.sink = match spawn_pager() {
+                    Some(child) => Sink::External(child),
+                    None => Sink::Internal { height, shown: 0 },
+                };
+                lines.iter().all(|line| self.write_line(line))
+            }
+        }
+    }
+
+    // Wait for the pager to exit
+    pub fn finish(self) {
+        match self.sink {
+            Sink::External(mut child) => {
+                // Closing stdin tells the pager there is no more input
+                drop(child.stdin.take());
+                let _ = child.wait();
+            }
+            Sink::File(mut file) => {
+                let _ = file.flush();
+            }
+            // They fit on the screen
+            Sink::Pending { lines, .. } => {
+                let mut stdout = io::stdout().lock();
+                for line in lines {
+                    let _ = writeln!(stdout, "{}", line);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// $PAGER is a command line of the shell, like `less -R` or one with quotes in it. The
+// shell starts even when the program doesn't exist and the lines piped to it would be
+// lost, so that is checked first
+fn spawn_pager() -> Option<Child> {
+    let command = env::var("PAGER").unwrap_or_else(|_| "less -FRX".to_string());
+    if !pager_exists(&command) {
+        return None;
+    }
+    Command::new("sh").arg("-c").arg(command).stdin(Stdio::piped()).spawn().ok()
+}
+
+// Whether the program a command line starts can be found, split into words by the shell
+// itself so quotes in it work the same as when it runs
+fn pager_exists(command: &str) -> bool {
+    Command::new("sh")
+        .arg("-c")
+        .arg(r#"eval "set -- $1" && [ $# -gt 0 ] && command -v "$1""#)
+        .arg("sh")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// None when neither $LINES nor the terminal tell us, or they say 0 as stty does for a
+// terminal that never set its size. Asked once, a resize during the session isn't seen
+fn terminal_height() -> Option<usize> {
+    static HEIGHT: OnceLock<Option<usize>> = OnceLock::new();
+    *HEIGHT.get_or_init(|| {
+        let lines = env::var("LINES").ok().and_then(|lines| lines.parse().ok());
+        if let Some(lines) = lines.filter(|&lines| lines > 0) {
+            return Some(lines);
+        }
+        // `stty size` prints "rows columns" for the terminal on its stdin
+        let size = File::open("/dev/tty").ok().and_then(|tty| {
+            Command::new("stty")
+                .arg("size")
+                .stdin(tty)
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+        });
+        size.and_then(|output| {
+            let text = String::from_utf8(output.stdout).ok()?;
+            text.split_whitespace().next()?.parse().ok()
+        })
+        .filter(|&height| height > 0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_pagers_are_found_out_before_they_start() {
+        assert!(pager_exists("cat"));
+        assert!(pager_exists("'cat' -u"));
+        assert!(!pager_exists("no-such-pager-program -R"));
+        assert!(!pager_exists(""));
+    }
+}
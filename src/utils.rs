@@ -1,22 +1,109 @@
+use crate::error::Error;
+
 pub fn read_be_word_at(buf: &[u8], offset: usize) -> u16 {
     u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
 }
 
 pub fn read_varint(buffer: &[u8]) -> anyhow::Result<(usize, u64)> {
     let mut result = 0u64;
-    let mut n = 0;
-    loop {
-        let byte = buffer[n];
+    for n in 0..8 {
+        let Some(&byte) = buffer.get(n) else {
+            anyhow::bail!(Error::CorruptPage("varint truncated".to_string()));
+        };
         // b & 0x7F 获取下7bits有效数据
         result = (result << 7) | ((byte & 0x7F) as u64);
-        n += 1;
         if byte & 0x80 == 0 { // 取高位1继续，0终止
+            return Ok((n + 1, result));
+        }
+    }
+    // 第9个字节的8bits全部有效，组成完整的64位
+    let Some(&byte) = buffer.get(8) else {
+        anyhow::bail!(Error::CorruptPage("varint truncated".to_string()));
+    };
+    Ok((9, (result << 8) | byte as u64))
+}
+
+pub fn write_varint(value: u64, out: &mut Vec<u8>) {
+    // 超过56位时第9个字节保存完整的8bits
+    if value > 0x00ff_ffff_ffff_ffff {
+        let mut bytes = [0u8; 9];
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for i in (0..8).rev() {
+            bytes[i] = (rest & 0x7F) as u8 | 0x80;
+            rest >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+    let mut bytes = Vec::new();
+    let mut rest = value;
+    loop {
+        bytes.push((rest & 0x7F) as u8);
+        rest >>= 7;
+        if rest == 0 {
             break;
         }
-        if n >= 9 {
-            anyhow::bail!("varint too long"); 
-        } 
     }
-    Ok((n, result))
+    // 除最后一个字节外高位置1
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        out.push(if i + 1 < bytes.len() { byte | 0x80 } else { *byte });
+    }
 }
 
+// Rowids are signed 64-bit integers stored as the two's complement bits of a varint
+pub fn read_varint_i64(buffer: &[u8]) -> anyhow::Result<(usize, i64)> {
+    let (n, value) = read_varint(buffer)?;
+    Ok((n, value as i64))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: i64) -> usize {
+        let mut out = Vec::new();
+        write_varint(value as u64, &mut out);
+        let (n, read) = read_varint_i64(&out).unwrap();
+        assert_eq!(read, value);
+        assert_eq!(n, out.len());
+        n
+    }
+
+    #[test]
+    fn negative_rowids_take_nine_bytes() {
+        for value in [-1, -2, -128, -(1 << 56), i64::MIN] {
+            assert_eq!(round_trip(value), 9, "{}", value);
+        }
+    }
+
+    #[test]
+    fn extremes_round_trip() {
+        assert_eq!(round_trip(i64::MAX), 9);
+        assert_eq!(round_trip(i64::MIN), 9);
+        assert_eq!(round_trip(0), 1);
+    }
+
+    #[test]
+    fn nine_byte_boundary() {
+        // 8 bytes of 7 bits hold 56 bits, one more needs the ninth byte and its full 8 bits
+        assert_eq!(round_trip(0x00ff_ffff_ffff_ffff), 8);
+        assert_eq!(round_trip(0x0100_0000_0000_0000), 9);
+        assert_eq!(round_trip(127), 1);
+        assert_eq!(round_trip(128), 2);
+    }
+
+    #[test]
+    fn minus_one_is_all_ones() {
+        let mut out = Vec::new();
+        write_varint(-1i64 as u64, &mut out);
+        assert_eq!(out, [0xff; 9]);
+    }
+
+    #[test]
+    fn truncated_varint_is_an_error() {
+        assert!(read_varint(&[0x81]).is_err());
+        assert!(read_varint(&[0xff; 8]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+// Write-ahead log of a database in WAL mode: a 32 byte header, then frames of a 24 byte
+// header and a page. The newest frame of a page up to the last commit frame is the current
+// content of the page, the main file only catches up at checkpoints
+// https://www.sqlite.org/fileformat.html#the_write_ahead_log
+const WAL_HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+// The low bit says whether checksums read the data as big-endian words
+const WAL_MAGIC: u32 = 0x377f0682;
+
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: Option<File>,
+    // The header the frames were read under, a checkpoint that restarts the log writes a
+    // new salt into it
+    header: Option<[u8; WAL_HEADER_SIZE]>,
+    page_size: usize,
+    big_endian: bool,
+    // Offset of the page in the newest committed frame of each page
+    frames: HashMap<usize, u64>,
+    // Database size in pages after the last commit, None before the first one
+    page_count: Option<usize>,
+    // Where the frames after the last commit start, and the checksum up to there
+    end: u64,
+    sum: (u32, u32),
+}
+
+pub fn wal_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-wal");
+    path.into()
+}
+
+impl Wal {
+    // The log of the database at `db_path`, read up to its last commit. There may be no
+    // log yet, `refresh` picks it up once there is
+    pub fn open(db_path: &Path) -> anyhow::Result<Self> {
+        let mut wal = Self {
+            path: wal_path(db_path),
+            file: None,
+            header: None,
+            page_size: 0,
+            big_endian: false,
+            frames: HashMap::new(),
+            page_count: None,
+            end: WAL_HEADER_SIZE as u64,
+            sum: (0, 0),
+        };
+        wal.refresh()?;
+        Ok(wal)
+    }
+    // Catch up with the commits other connections made since the last call, at the start of
+    // each read. True when the committed content changed
+    pub fn refresh(&mut self) -> anyhow::Result<bool> {
+        // Opened again each time, the log may have been deleted and made anew since
+        self.file = File::open(&self.path).ok();
+        let header = match self.file.as_mut() {
+            Some(file) => read_header(file)?,
+            None => None,
+        };
+        let Some(header) = header else {
+            return Ok(self.reset(None));
+        };
+        let mut changed = false;
+        if self.header != Some(header) {
+            changed = self.reset(Some(header));
+        }
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(self.end)).context("seek to wal frame")?;
+        let salt = &header[16..24];
+        let mut pending = HashMap::new();
+        let mut sum = self.sum;
+        let mut at = self.end;
+        let mut frame = vec![0; FRAME_HEADER_SIZE + self.page_size];
+        // Frames after the first one with a stale salt or a bad checksum are left over from
+        // before the log was restarted, or were never finished
+        while read_full(file, &mut frame)? {
+            let read_u32 = |at: usize| u32::from_be_bytes(frame[at..at + 4].try_into().unwrap());
+            if &frame[8..16] != salt {
+                break;
+            }
+            sum = checksum(sum, &frame[..8], self.big_endian);
+            sum = checksum(sum, &frame[FRAME_HEADER_SIZE..], self.big_endian);
+            if sum != (read_u32(16), read_u32(20)) {
+                break;
+            }
+            pending.insert(read_u32(0) as usize, at + FRAME_HEADER_SIZE as u64);
+            at += frame.len() as u64;
+            // Only a commit frame, which carries the new database size, makes the frames
+            // of its transaction count
+            let size = read_u32(4) as usize;
+            if size > 0 {
+                self.frames.extend(pending.drain());
+                self.page_count = Some(size);
+                self.end = at;
+                self.sum = sum;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+    // Start over with the log that has `header`, or without a log. True when committed
+    // frames were dropped
+    fn reset(&mut self, header: Option<[u8; WAL_HEADER_SIZE]>) -> bool {
+        let changed = self.page_count.is_some();
+        self.header = header;
+        if let Some(header) = header {
+            let read_u32 = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+            self.big_endian = read_u32(0) & 1 == 1;
+            self.page_size = match read_u32(8) {
+                1 => 65536,
+                size => size as usize,
+            };
+            self.sum = (read_u32(24), read_u32(28));
+        }
+        self.frames.clear();
+        self.page_count = None;
+        self.end = WAL_HEADER_SIZE as u64;
+        changed
+    }
+    // The database size after the last commit in the log, None when it has none
+    pub fn page_count(&self) -> Option<usize> {
+        self.page_count
+    }
+    // The committed content of a page, if the log has one
+    pub fn read_page(&mut self, page_num: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let (Some(&offset), Some(file)) = (self.frames.get(&page_num), self.file.as_mut()) else {
+            return Ok(None);
+        };
+        file.seek(SeekFrom::Start(offset)).context("seek to wal frame")?;
+        let mut buffer = vec![0; self.page_size];
+        file.read_exact(&mut buffer).context("read wal frame")?;
+        Ok(Some(buffer))
+    }
+}
+
+// The header of the log, None when it is too short or its magic or checksum is off
+fn read_header(file: &mut File) -> anyhow::Result<Option<[u8; WAL_HEADER_SIZE]>> {
+    file.seek(SeekFrom::Start(0)).context("seek to wal header")?;
+    let mut header = [0; WAL_HEADER_SIZE];
+    if !read_full(file, &mut header)? {
+        return Ok(None);
+    }
+    let read_u32 = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+    let magic = read_u32(0);
+    if magic & !1 != WAL_MAGIC || checksum((0, 0), &header[..24], magic & 1 == 1) != (read_u32(24), read_u32(28)) {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+// Fill `buffer`, false when the file ends first
+fn read_full(file: &mut File, buffer: &mut [u8]) -> anyhow::Result<bool> {
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err).context("read wal"),
+    }
+}
+
+// Running checksum over pairs of 32-bit words, chained from frame to frame
+fn checksum((mut s0, mut s1): (u32, u32), data: &[u8], big_endian: bool) -> (u32, u32) {
+    let word = |bytes: &[u8]| {
+        let bytes = bytes.try_into().unwrap();
+        match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    };
+    for pair in data.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
@@ -0,0 +1,41 @@
+use std::{
+    fs,
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Polls the database file and its write-ahead log, so a query can be re-run whenever
+// another process writes to the database
+pub struct FileWatcher {
+    paths: Vec<PathBuf>,
+    // Modification time and size of each file, None while it doesn't exist
+    states: Vec<Option<(SystemTime, u64)>>,
+}
+
+impl FileWatcher {
+    pub fn new(db_path: &str) -> Self {
+        let paths = vec![PathBuf::from(db_path), PathBuf::from(format!("{}-wal", db_path))];
+        let states = paths.iter().map(file_state).collect();
+        FileWatcher { paths, states }
+    }
+
+    // Blocks until one of the files is created, modified or removed
+    pub fn wait_for_change(&mut self) {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let states = self.paths.iter().map(file_state).collect::<Vec<_>>();
+            if states != self.states {
+                self.states = states;
+                return;
+            }
+        }
+    }
+}
+
+fn file_state(path: &PathBuf) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
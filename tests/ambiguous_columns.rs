@@ -0,0 +1,34 @@
+// A column name more than one table of a query has must be qualified
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+#[test]
+fn column_names_more_than_one_table_has_are_ambiguous() {
+    let db = TempDb::new("ambiguous_columns");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(id INTEGER PRIMARY KEY, x); CREATE TABLE b(id INTEGER PRIMARY KEY, y)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 'p'), (2, 'q'); INSERT INTO b VALUES (1, 'r')");
+    let ambiguous = [
+        ("SELECT id FROM a, b", "id"),
+        ("SELECT rowid FROM a, b", "rowid"),
+        ("SELECT a.id FROM a, b ORDER BY id", "id"),
+        ("SELECT count(*) FROM a JOIN b ON id = 1", "id"),
+        ("SELECT max(id) FROM a, b", "id"),
+        ("SELECT t.x FROM a t JOIN a t", "t.x"),
+    ];
+    for (sql, name) in ambiguous {
+        let err = connection.query(sql, &Params::new()).err().unwrap();
+        assert_eq!(err.to_string(), format!("ambiguous column name: {}", name), "{}", sql);
+    }
+    let resolved = [
+        "SELECT a.id AS id, x FROM a, b ORDER BY id",
+        "SELECT id, x, y FROM a JOIN b USING (id) WHERE id = 1",
+        "SELECT x, y FROM a, b WHERE a.id = b.id",
+        "SELECT count(*) FROM a t JOIN a u",
+    ];
+    for sql in resolved {
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+}
@@ -0,0 +1,32 @@
+// Files SQLite wrote with auto_vacuum, whose pointer-map pages are no b-tree pages
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+#[test]
+fn auto_vacuum_files_read_past_their_pointer_map_pages() {
+    if !has_sqlite3() {
+        return;
+    }
+    for mode in ["FULL", "INCREMENTAL"] {
+        let db = TempDb::new(&format!("auto_vacuum_{}", mode));
+        // Small pages, so that there are several pointer-map pages between the others
+        db.sqlite3(&format!(
+            "PRAGMA page_size = 1024; PRAGMA auto_vacuum = {};
+             CREATE TABLE t(n INTEGER, s TEXT); CREATE INDEX t_s ON t(s);
+             INSERT INTO t SELECT value, printf('%.*c', 100 + value % 50, 'x') FROM generate_series(1, 3000);
+             DELETE FROM t WHERE n % 3 = 0",
+            mode
+        ));
+        let mut connection = db.open();
+        assert_eq!(texts(&mut connection, "PRAGMA integrity_check"), ["ok"]);
+        for sql in ["SELECT count(*), sum(n) FROM t", "SELECT count(*) FROM t WHERE s > 'xxxxx'", "SELECT n FROM t WHERE n > 2990"] {
+            assert_reads_like_sqlite3(&db, &mut connection, sql);
+        }
+        // Writes would have to keep the pointer map up to date
+        let err = connection.execute_batch("INSERT INTO t VALUES (1, 'a')", &Params::new()).err().unwrap();
+        assert_eq!(err.to_string(), "writing to an auto_vacuum database is not supported");
+        assert_intact(&db, &mut connection);
+    }
+}
@@ -0,0 +1,38 @@
+// COLLATE and the collations of columns decide comparisons the way SQLite's do
+mod common;
+use common::*;
+
+#[test]
+fn collate_applies_to_operands_that_take_an_affinity() {
+    let db = TempDb::new("collate_affinity");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE emp(name TEXT, n INTEGER); INSERT INTO emp VALUES ('alice', 1), ('bob', 2)");
+    for sql in [
+        "SELECT name FROM emp WHERE name = 'ALICE' COLLATE NOCASE",
+        "SELECT name FROM emp WHERE 'ALICE' COLLATE NOCASE = name",
+        "SELECT name FROM emp WHERE name COLLATE NOCASE IN ('ALICE')",
+        "SELECT name FROM emp WHERE n = '1' COLLATE NOCASE",
+    ] {
+        assert_eq!(texts(&mut connection, sql), ["alice"], "{}", sql);
+    }
+}
+
+#[test]
+fn the_left_column_decides_the_collation_even_when_binary() {
+    let db = TempDb::new("collate_binary");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE c2(a TEXT COLLATE NOCASE, b TEXT); INSERT INTO c2 VALUES ('x', 'X')");
+    run(&mut connection, "CREATE TABLE j1(k TEXT); CREATE TABLE j2(t TEXT COLLATE NOCASE)");
+    run(&mut connection, "INSERT INTO j1 VALUES ('a'), ('A'), ('b'), ('B'); INSERT INTO j2 VALUES ('a'), ('B')");
+    for sql in [
+        "SELECT count(*) FROM c2 WHERE b = a",
+        "SELECT count(*) FROM c2 WHERE a = b",
+        "SELECT count(*) FROM c2 WHERE b IN (a)",
+        "SELECT * FROM j1 JOIN j2 ON j2.t = j1.k ORDER BY 1, 2",
+        "SELECT * FROM j1 JOIN j2 ON j1.k = j2.t ORDER BY 1, 2",
+        "SELECT * FROM j2 JOIN j1 ON j1.k = j2.t ORDER BY 1, 2",
+        "SELECT * FROM j1, j2 WHERE j1.k = j2.t ORDER BY 1, 2",
+    ] {
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+}
@@ -0,0 +1,96 @@
+// What the integration tests share. Each file uses only some of it
+#![allow(dead_code)]
+
+use std::{env, fs, path::PathBuf, process, sync::OnceLock};
+
+use codecrafters_sqlite::{Connection, OpenOptions, Params};
+
+// A database of its own for each test, removed when it is done
+pub struct TempDb {
+    pub path: PathBuf,
+}
+
+impl TempDb {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("sqlite-test-{}-{}.db", name, process::id()));
+        let _ = fs::remove_file(&path);
+        Self { path }
+    }
+
+    pub fn open(&self) -> Connection {
+        Connection::open_with(&self.path, OpenOptions::new().create(true)).unwrap()
+    }
+
+    // What the sqlite3 program prints for `sql` run on the file, for a test that writes
+    // its file with it or checks on it from another process. Such a test returns first
+    // when there is no sqlite3, see `has_sqlite3`
+    pub fn sqlite3(&self, sql: &str) -> String {
+        let output = process::Command::new("sqlite3").arg(&self.path).arg(sql).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim_end().to_string()
+    }
+
+    // That sqlite3 prints `expected` for `sql` too, to check our reads and writes against
+    // SQLite itself. Skipped without sqlite3. Like `sqlite3`, trailing empty lines aren't
+    // compared
+    pub fn assert_sqlite3(&self, sql: &str, expected: &str) {
+        if has_sqlite3() {
+            assert_eq!(expected.trim_end(), self.sqlite3(sql), "{}", sql);
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Whether the sqlite3 program is on the PATH
+pub fn has_sqlite3() -> bool {
+    static FOUND: OnceLock<bool> = OnceLock::new();
+    *FOUND.get_or_init(|| {
+        let found = process::Command::new("sqlite3").arg("-version").output().is_ok_and(|output| output.status.success());
+        if !found {
+            eprintln!("sqlite3 is not on the PATH, the checks against it are skipped");
+        }
+        found
+    })
+}
+
+pub fn run(connection: &mut Connection, sql: &str) {
+    connection.execute_batch(sql, &Params::new()).unwrap();
+}
+
+pub fn texts(connection: &mut Connection, sql: &str) -> Vec<String> {
+    let result = connection.query(sql, &Params::new()).unwrap();
+    result.rows.iter().map(|row| row.texts().join("|")).collect()
+}
+
+// That `sql` reads the rows sqlite3 reads from the file
+pub fn assert_reads_like_sqlite3(db: &TempDb, connection: &mut Connection, sql: &str) {
+    db.assert_sqlite3(sql, &texts(connection, sql).join("\n"));
+}
+
+// Each statement reads the rows it is paired with, a line each, here and in sqlite3
+pub fn assert_reads(db: &TempDb, cases: &[(&str, &str)]) {
+    let mut connection = db.open();
+    for &(sql, expected) in cases {
+        assert_eq!(texts(&mut connection, sql).join("\n"), expected, "{}", sql);
+        db.assert_sqlite3(sql, expected);
+    }
+}
+
+// Both by our own check and by SQLite's
+pub fn assert_intact(db: &TempDb, connection: &mut Connection) {
+    assert_eq!(texts(connection, "PRAGMA integrity_check"), ["ok"]);
+    db.assert_sqlite3("PRAGMA integrity_check", "ok");
+}
+
+// INSERT ... VALUES of rows `from..to`, each a number and a text long enough to fill pages
+pub fn insert_rows(connection: &mut Connection, table: &str, from: i64, to: i64) {
+    let values = (from..to)
+        .map(|i| format!("({}, '{}')", i, "x".repeat(50 + (i % 7) as usize * 30)))
+        .collect::<Vec<_>>();
+    run(connection, &format!("INSERT INTO {} VALUES {}", table, values.join(", ")));
+}
@@ -0,0 +1,66 @@
+// SELECTs run as register-machine programs, which EXPLAIN lists
+use codecrafters_sqlite::{Connection, Params};
+
+mod common;
+use common::*;
+
+#[test]
+fn explain_lists_the_program_a_select_runs() {
+    let db = TempDb::new("explain");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(id INTEGER PRIMARY KEY, k); CREATE TABLE b(k, v)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 1), (2, 2), (3, 2)");
+    run(&mut connection, "INSERT INTO b VALUES (2, 'x'), (3, 'y'), (2, 'z')");
+    let opcodes = |connection: &mut Connection, sql: &str| {
+        let rows = texts(connection, &format!("EXPLAIN {}", sql));
+        rows.iter().map(|row| row.split('|').nth(1).unwrap().to_string()).collect::<Vec<_>>()
+    };
+    let join = "SELECT a.id, b.v FROM a JOIN b ON b.k = a.k ORDER BY 2";
+    let programs = [
+        (join, &["OpenRead", "HashLookup", "SorterInsert", "SorterSort", "ResultRow"][..]),
+        ("SELECT k, count(*) FROM a GROUP BY k", &["OpenEphemeral", "Rewind", "AggStep", "AggFinal", "ResultRow", "Next"]),
+        ("SELECT k FROM a UNION SELECT k FROM b", &["OpenEphemeral", "IdxInsert", "Column", "ResultRow"]),
+        ("SELECT k FROM a EXCEPT SELECT k FROM b", &["IdxInsert", "Found", "ResultRow"]),
+    ];
+    for (sql, expected) in programs {
+        let ops = opcodes(&mut connection, sql);
+        assert_eq!((ops.first().unwrap().as_str(), ops.last().unwrap().as_str()), ("Init", "Goto"), "{}", sql);
+        for opcode in expected {
+            assert!(ops.iter().any(|op| op == opcode), "{} has no {}: {:?}", sql, opcode, ops);
+        }
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+    let err = connection.query("EXPLAIN DELETE FROM a", &Params::new()).err().unwrap();
+    assert_eq!(err.to_string(), "EXPLAIN is only supported for SELECT");
+}
+
+#[test]
+fn each_kind_of_program_reads_the_rows_sqlite_does() {
+    let db = TempDb::new("programs");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(id INTEGER PRIMARY KEY, k); CREATE TABLE b(k, v)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 1), (2, 2), (3, 2), (4, NULL)");
+    run(&mut connection, "INSERT INTO b VALUES (2, 'x'), (3, 'y'), (2, 'z'), (NULL, 'n')");
+    // Each with the opcode of the step it takes
+    let programs = [
+        ("SELECT k FROM a WHERE k > 1", "Rewind"),
+        ("SELECT k FROM a WHERE id = 2", "Lookup"),
+        ("SELECT count(*) FROM a", "Count"),
+        ("SELECT a.id, b.v FROM a LEFT JOIN b ON b.k = a.k + 10 ORDER BY 1, 2", "NullRow"),
+        ("SELECT a.id, b.v FROM a RIGHT JOIN b ON b.k = a.k ORDER BY 1, 2", "MarkMatched"),
+        ("SELECT a.id, b.v FROM a FULL JOIN b ON b.k = a.k ORDER BY 1, 2", "IfMatched"),
+        ("SELECT id, v FROM a JOIN b USING (k) ORDER BY 1, 2", "Using"),
+        ("SELECT max(k), sum(id) FROM a", "AggInit"),
+        ("SELECT k, count(*) FROM a GROUP BY k HAVING count(*) > 1", "AggStep"),
+        ("SELECT DISTINCT k FROM a", "Found"),
+        ("SELECT id FROM a LIMIT 2 OFFSET 1", "DecrJumpZero"),
+        ("SELECT id FROM a ORDER BY k DESC, id", "SorterSort"),
+        ("SELECT id FROM a WHERE k IN (SELECT k FROM b) ORDER BY 1", "Rewind"),
+        ("SELECT k FROM a INTERSECT SELECT k FROM b", "IdxInsert"),
+    ];
+    for (sql, opcode) in programs {
+        let explained = texts(&mut connection, &format!("EXPLAIN {}", sql));
+        assert!(explained.iter().any(|row| row.split('|').nth(1) == Some(opcode)), "{} has no {}", sql, opcode);
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+}
@@ -0,0 +1,142 @@
+// Functions give the values SQLite's do, NULLs and mixed types included
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+#[test]
+fn avg_and_total_skip_nulls_and_read_text_as_numbers() {
+    let db = TempDb::new("avg_total");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n, g); CREATE TABLE e(n)");
+    run(&mut connection, "INSERT INTO t VALUES (1, 'a'), (2, 'a'), (NULL, 'a'), ('4', 'b'), ('x', 'b'), (2.5, 'c')");
+    drop(connection);
+    assert_reads(
+        &db,
+        &[
+            ("SELECT avg(n), total(n), sum(n) FROM t", "1.9|9.5|9.5"),
+            ("SELECT g, avg(n), total(n) FROM t GROUP BY g", "a|1.5|3.0\nb|2.0|4.0\nc|2.5|2.5"),
+            // Over no rows, or only NULLs, total() is 0.0 where avg() and sum() are NULL
+            ("SELECT avg(n), total(n), sum(n) FROM e", "|0.0|"),
+            ("SELECT avg(n), total(n) FROM t WHERE n IS NULL", "|0.0"),
+            ("SELECT typeof(total(n)), typeof(avg(n)), typeof(sum(n)) FROM t WHERE g = 'a'", "real|real|integer"),
+            // Both add up as floats, past where sum() overflows
+            ("SELECT total(9223372036854775807), avg(9223372036854775807) FROM t", "5.53402322211287e+19|9.22337203685478e+18"),
+        ],
+    );
+}
+
+#[test]
+fn coalesce_and_ifnull_stop_at_the_first_value_that_is_not_null() {
+    let db = TempDb::new("coalesce_ifnull");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n); INSERT INTO t VALUES (1), (NULL), ('x')");
+    for sql in ["SELECT ifnull(1, 2, 3) FROM t", "SELECT coalesce(1) FROM t"] {
+        let name = if sql.contains("ifnull") { "ifnull" } else { "coalesce" };
+        let err = connection.query(sql, &Params::new()).err().unwrap();
+        assert_eq!(err.to_string(), format!("wrong number of arguments to function {}()", name));
+    }
+    drop(connection);
+    // abs() of the smallest integer overflows, so reaching it would fail the statement
+    let overflow = "abs(-9223372036854775807 - 1)";
+    let lazy = format!("SELECT coalesce(NULL, 1, {}), ifnull(2, {}) FROM t LIMIT 1", overflow, overflow);
+    assert_reads(
+        &db,
+        &[
+            ("SELECT coalesce(NULL, n, 'd'), ifnull(n, 'none') FROM t", "1|1\nd|none\nx|x"),
+            ("SELECT coalesce(NULL, NULL), ifnull(NULL, NULL) FROM t LIMIT 1", "|"),
+            (&lazy, "1|2"),
+            ("SELECT typeof(coalesce(NULL, '1')), typeof(ifnull(NULL, 1.0)) FROM t LIMIT 1", "text|real"),
+        ],
+    );
+}
+
+#[test]
+fn substr_and_ltrim_count_characters_from_either_end() {
+    let db = TempDb::new("substr_ltrim");
+    run(&mut db.open(), "CREATE TABLE t(n); INSERT INTO t VALUES (1)");
+    assert_reads(
+        &db,
+        &[
+            // Negative starts count from the end, a negative length takes the characters before
+            (
+                "SELECT substr('hello', 2), substr('hello', 2, 3), substr('hello', -3, 2), substr('hello', 0, 2), substr('hello', 3, -2) FROM t",
+                "ello|ell|ll|h|he",
+            ),
+            ("SELECT substr(NULL, 1), substr('héllo', 2, 2), substr(x'0102030405', 2, 2) = x'0203', substr(12345, 2, 2) FROM t", "|él|1|23"),
+            ("SELECT ltrim('  ab  '), ltrim('xxaxb', 'x'), ltrim('abcba', 'ab'), ltrim(NULL), ltrim('', 'a'), ltrim(12300, '1') FROM t", "ab  |axb|cba|||2300"),
+        ],
+    );
+}
+
+#[test]
+fn round_and_abs_follow_sqlite_on_halves_text_and_overflow() {
+    let db = TempDb::new("round_abs");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n); INSERT INTO t VALUES (1)");
+    let err = connection.query("SELECT abs(-9223372036854775807 - 1) FROM t", &Params::new()).err().unwrap();
+    assert_eq!(err.to_string(), "integer overflow");
+    drop(connection);
+    assert_reads(
+        &db,
+        &[
+            // Halves away from zero, on the double's own digits: 1.005 is a little under
+            (
+                "SELECT round(2.5), round(-2.5), round(0.5), round(1.005, 2), round(1234.5678, -2), round(NULL) FROM t",
+                "3.0|-3.0|1.0|1.0|1235.0|",
+            ),
+            ("SELECT round('3.7'), round(5), typeof(round(5)) FROM t", "4.0|5.0|real"),
+            ("SELECT abs(-3), abs(3.5), abs(-0.0), abs(NULL), abs('-4'), abs('x'), typeof(abs('-4')) FROM t", "3|3.5|0.0||4.0|0.0|real"),
+        ],
+    );
+}
+
+#[test]
+fn unicode_and_char_convert_between_characters_and_code_points() {
+    let db = TempDb::new("unicode_char");
+    run(&mut db.open(), "CREATE TABLE t(n); INSERT INTO t VALUES (1)");
+    assert_reads(
+        &db,
+        &[
+            // The first character only, NULL for an empty string
+            (
+                "SELECT unicode('A'), unicode('é'), unicode('😀x'), unicode(''), unicode(NULL), unicode(65), typeof(unicode('')) FROM t",
+                "65|233|128512|||54|null",
+            ),
+            // Code points out of range are U+FFFD
+            (
+                "SELECT char(72, 105), char(233, 128512), char(), char('66'), length(char(1114111)), char(-1) = char(65533) FROM t",
+                "Hi|é😀||B|1|1",
+            ),
+        ],
+    );
+}
+
+#[test]
+fn typeof_names_the_storage_class_column_affinity_gave_a_value() {
+    let db = TempDb::new("typeof");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(i INTEGER, r REAL, x TEXT, b BLOB, n NUMERIC, none)");
+    run(
+        &mut connection,
+        "INSERT INTO t VALUES ('12', '3', 45, x'00', '1.0', '7'), ('a', 'b', 1.5, 'c', '2.5', 3.0), (NULL, 4, NULL, 5, 'x', x'01')",
+    );
+    let err = connection.query("SELECT typeof() FROM t", &Params::new()).err().unwrap();
+    assert_eq!(err.to_string(), "wrong number of arguments to function typeof()");
+    drop(connection);
+    // The values as written here, read back by both
+    assert_reads(
+        &db,
+        &[
+            (
+                "SELECT typeof(i), typeof(r), typeof(x), typeof(b), typeof(n), typeof(none) FROM t",
+                "integer|real|text|blob|integer|text\ntext|text|text|text|real|real\nnull|real|null|integer|text|blob",
+            ),
+            ("SELECT i, r, n FROM t", "12|3.0|1\na|b|2.5\n|4.0|x"),
+            (
+                "SELECT typeof(1), typeof(1.0), typeof('1'), typeof(x'01'), typeof(NULL), typeof(1 + 1.0), typeof('1' + 1) FROM t LIMIT 1",
+                "integer|real|text|blob|null|real|integer",
+            ),
+        ],
+    );
+}
@@ -0,0 +1,40 @@
+// Equi-joins run as hash joins find the rows the nested loop does
+mod common;
+use common::*;
+
+#[test]
+fn hash_joins_find_the_rows_the_nested_loop_does() {
+    let db = TempDb::new("hash_join");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(k, v); CREATE TABLE b(k, w); CREATE TABLE c(k TEXT, x)");
+    // Duplicate keys on both sides, NULLs, and values that are equal across types
+    let keys = ["1", "1", "1.0", "'1'", "2", "NULL", "NULL", "X'01'", "'x'", "3"];
+    for (i, key) in keys.iter().enumerate() {
+        run(&mut connection, &format!("INSERT INTO a VALUES ({}, {})", key, i));
+    }
+    for i in 0..40 {
+        let key = keys[i % keys.len()];
+        run(&mut connection, &format!("INSERT INTO b VALUES ({}, {}); INSERT INTO c VALUES ({}, {})", key, i, key, i));
+    }
+    // `OR 0` keeps the same condition from being a key, leaving it to the nested loop
+    for (hashed, looped) in [
+        ("SELECT v, w FROM a JOIN b ON a.k = b.k", "SELECT v, w FROM a JOIN b ON a.k = b.k OR 0"),
+        ("SELECT v, w FROM b JOIN a ON a.k = b.k", "SELECT v, w FROM b JOIN a ON a.k = b.k OR 0"),
+        ("SELECT v, w FROM a LEFT JOIN b ON b.k = a.k", "SELECT v, w FROM a LEFT JOIN b ON b.k = a.k OR 0"),
+        ("SELECT v, x FROM a JOIN c ON a.k = c.k", "SELECT v, x FROM a JOIN c ON a.k = c.k OR 0"),
+        ("SELECT v, x FROM c JOIN a ON c.k = a.k", "SELECT v, x FROM c JOIN a ON c.k = a.k OR 0"),
+        ("SELECT v, w FROM a JOIN b USING (k)", "SELECT v, w FROM a JOIN b ON a.k = b.k OR 0"),
+        ("SELECT v, w FROM a, b WHERE a.k = b.k AND w < 20", "SELECT v, w FROM a, b WHERE (a.k = b.k OR 0) AND w < 20"),
+    ] {
+        let sorted = |mut rows: Vec<String>| {
+            rows.sort();
+            rows
+        };
+        let rows = sorted(texts(&mut connection, hashed));
+        assert_eq!(rows, sorted(texts(&mut connection, looped)), "{}", hashed);
+        if has_sqlite3() {
+            let expected = db.sqlite3(hashed).lines().map(String::from).collect();
+            assert_eq!(rows, sorted(expected), "{}", hashed);
+        }
+    }
+}
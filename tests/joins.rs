@@ -0,0 +1,52 @@
+// Outer, NATURAL and USING joins pair and pad the rows SQLite's do
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+// a.k and b.k each have a value the other doesn't, and a NULL
+fn join_tables(name: &str) -> TempDb {
+    let db = TempDb::new(name);
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(id, k, x); CREATE TABLE b(id, k, y)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 1, 'a1'), (2, 2, 'a2'), (3, NULL, 'a3')");
+    run(&mut connection, "INSERT INTO b VALUES (1, 2, 'b1'), (2, 3, 'b2'), (3, NULL, 'b3'), (1, 1, 'b4')");
+    db
+}
+
+#[test]
+fn right_and_full_joins_pad_the_rows_nothing_matched() {
+    let db = join_tables("right_join");
+    assert_reads(
+        &db,
+        &[
+            ("SELECT a.x, b.y FROM a RIGHT JOIN b ON a.k = b.k ORDER BY 2", "a2|b1\n|b2\n|b3\na1|b4"),
+            // A row ON rejects is padded, WHERE then sees the NULLs
+            (
+                "SELECT a.x, b.y FROM a RIGHT JOIN b ON a.k = b.k AND b.y <> 'b1' WHERE a.x IS NULL ORDER BY 2",
+                "|b1\n|b2\n|b3",
+            ),
+            ("SELECT a.x, b.y FROM a FULL JOIN b ON a.k = b.k ORDER BY 1, 2", "|b2\n|b3\na1|b4\na2|b1\na3|"),
+        ],
+    );
+}
+
+#[test]
+fn natural_joins_and_using_match_on_the_shared_columns_once() {
+    let db = join_tables("natural_join");
+    let mut connection = db.open();
+    let result = connection.query("SELECT * FROM a NATURAL JOIN b", &Params::new()).unwrap();
+    assert_eq!(result.columns, ["id", "k", "x", "y"]);
+    drop(connection);
+    assert_reads(
+        &db,
+        &[
+            ("SELECT * FROM a NATURAL JOIN b ORDER BY 1", "1|1|a1|b4"),
+            ("SELECT * FROM a NATURAL LEFT JOIN b ORDER BY 1", "1|1|a1|b4\n2|2|a2|\n3||a3|"),
+            ("SELECT id, k, x, y FROM a JOIN b USING (id, k)", "1|1|a1|b4"),
+            ("SELECT * FROM a JOIN b USING (k) ORDER BY 1, 2", "1|1|a1|1|b4\n2|2|a2|1|b1"),
+            // The column of the right table, when only it has the row
+            ("SELECT k FROM a NATURAL RIGHT JOIN b ORDER BY 1", "\n1\n2\n3"),
+        ],
+    );
+}
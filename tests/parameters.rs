@@ -0,0 +1,48 @@
+// Values bound to the parameters of a statement, and parameters left unbound
+use codecrafters_sqlite::{Params, Value};
+
+mod common;
+use common::*;
+
+#[test]
+fn unbound_parameters_are_null_wherever_they_are() {
+    let db = TempDb::new("unbound_parameters");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(x INTEGER, y TEXT); CREATE TABLE b(x INTEGER)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 'p'), (2, NULL); INSERT INTO b VALUES (1)");
+    let mut params = Params::new();
+    params.bind(1, Value::I64(1)).unwrap();
+    for (sql, expected) in [
+        ("SELECT ?2 IS NULL, :name IS NULL, ?1 FROM a WHERE x = 1", &["1|1|1"][..]),
+        ("SELECT x FROM a WHERE y IS ?2", &["2"]),
+        ("SELECT x FROM a WHERE EXISTS (SELECT 1 FROM b WHERE b.x = a.x AND ?3 IS NULL)", &["1"]),
+        ("SELECT a.x FROM a JOIN b ON b.x = a.x OR ?4 ORDER BY 1", &["1"]),
+        ("SELECT count(*) FROM a GROUP BY ?5 HAVING ?6 IS NULL", &["2"]),
+    ] {
+        let result = connection.query(sql, &params).unwrap();
+        let rows = result.rows.iter().map(|row| row.texts().join("|")).collect::<Vec<_>>();
+        assert_eq!(rows, expected, "{}", sql);
+    }
+}
+
+#[test]
+fn statements_take_the_values_bound_for_each_run() {
+    let db = TempDb::new("bound_parameters");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    let mut insert = connection.prepare("INSERT INTO t VALUES (?, :s)").unwrap();
+    for (n, s) in [(1, "one"), (2, "two")] {
+        insert.bind(1, Value::I64(n)).unwrap();
+        insert.bind_named(":s", Value::String(s.to_string())).unwrap();
+        insert.execute(&mut connection).unwrap();
+    }
+    insert.clear_bindings();
+    insert.execute(&mut connection).unwrap();
+    assert_eq!(texts(&mut connection, "SELECT n, s FROM t"), ["1|one", "2|two", "|"]);
+    let mut select = connection.prepare("SELECT s FROM t WHERE n = ?1 OR n = ?1 + 1").unwrap();
+    select.bind(1, Value::I64(1)).unwrap();
+    assert_eq!(select.parameter_count(), 1);
+    let rows = select.execute(&mut connection).unwrap().rows;
+    assert_eq!(rows.iter().map(|row| row.texts().join("|")).collect::<Vec<_>>(), ["one", "two"]);
+    assert!(select.bind(2, Value::Null).is_err());
+}
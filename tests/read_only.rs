@@ -0,0 +1,58 @@
+// Files opened read-only, which no statement may change
+use std::{fs, path::PathBuf};
+
+use codecrafters_sqlite::{Connection, OpenOptions, Params};
+
+mod common;
+use common::*;
+
+fn open_read_only(db: &TempDb) -> Connection {
+    Connection::open_with(&db.path, OpenOptions::new().read_only()).unwrap()
+}
+
+#[test]
+fn read_only_connections_read_but_never_write() {
+    let db = TempDb::new("read_only");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT); CREATE INDEX t_n ON t(n)");
+    insert_rows(&mut connection, "t", 0, 300);
+    drop(connection);
+    let before = fs::read(&db.path).unwrap();
+
+    let mut connection = open_read_only(&db);
+    assert_eq!(texts(&mut connection, "SELECT count(*), sum(n) FROM t WHERE n >= 100"), ["200|39900"]);
+    for sql in [
+        "INSERT INTO t VALUES (1, 'x')",
+        "UPDATE t SET n = 0",
+        "DELETE FROM t",
+        "CREATE TABLE u(a)",
+        "DROP INDEX t_n",
+        "BEGIN; INSERT INTO t VALUES (1, 'x'); COMMIT",
+    ] {
+        let err = connection.execute_batch(sql, &Params::new()).err().unwrap();
+        assert_eq!(err.to_string(), "attempt to write a readonly database", "{}", sql);
+    }
+    // Nor did a failed statement leave a journal behind
+    assert_eq!(texts(&mut connection, "PRAGMA integrity_check"), ["ok"]);
+    drop(connection);
+    assert_eq!(fs::read(&db.path).unwrap(), before);
+    assert!(!PathBuf::from(format!("{}-journal", db.path.display())).exists());
+}
+
+#[test]
+fn read_only_opens_neither_create_a_file_nor_roll_back_a_journal() {
+    let db = TempDb::new("read_only_missing");
+    let opened = Connection::open_with(&db.path, OpenOptions::new().read_only().create(true));
+    assert!(opened.is_err());
+    assert!(!db.path.exists());
+
+    // Rolling a hot journal back writes to the file, which only a writer may do
+    let db = TempDb::new("read_only_hot_journal");
+    run(&mut db.open(), "CREATE TABLE t(n)");
+    let journal = PathBuf::from(format!("{}-journal", db.path.display()));
+    fs::write(&journal, [0; 512]).unwrap();
+    let err = Connection::open_with(&db.path, OpenOptions::new().read_only()).err().unwrap();
+    assert!(err.to_string().contains("cannot open a database with a hot journal read-only"), "{}", err);
+    fs::remove_file(&journal).unwrap();
+    assert!(texts(&mut open_read_only(&db), "SELECT n FROM t").is_empty());
+}
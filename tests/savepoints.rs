@@ -0,0 +1,46 @@
+// SAVEPOINT, RELEASE and ROLLBACK TO, nested in each other and in BEGIN
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+#[test]
+fn savepoints_nest_and_roll_back_part_of_a_transaction() {
+    let db = TempDb::new("savepoints");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER)");
+    run(&mut connection, "SAVEPOINT outer_sp; INSERT INTO t VALUES (1)");
+    run(&mut connection, "SAVEPOINT inner_sp; INSERT INTO t VALUES (2)");
+    // ROLLBACK TO undoes the writes since the savepoint and keeps it, to roll back to again
+    run(&mut connection, "ROLLBACK TO inner_sp");
+    assert_eq!(texts(&mut connection, "SELECT n FROM t"), ["1"]);
+    run(&mut connection, "INSERT INTO t VALUES (3); ROLLBACK TO SAVEPOINT inner_sp; INSERT INTO t VALUES (4)");
+    assert_eq!(texts(&mut connection, "SELECT n FROM t"), ["1", "4"]);
+    // Releasing the outer one releases the inner one too, and commits the transaction it began
+    run(&mut connection, "RELEASE outer_sp");
+    for name in ["inner_sp", "outer_sp"] {
+        let err = connection.execute_batch(&format!("ROLLBACK TO {}", name), &Params::new()).err().unwrap();
+        assert_eq!(err.to_string(), format!("no such savepoint: {}", name));
+    }
+    drop(connection);
+    let mut connection = db.open();
+    assert_eq!(texts(&mut connection, "SELECT n FROM t"), ["1", "4"]);
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn savepoints_inside_begin_leave_the_transaction_open() {
+    let db = TempDb::new("savepoints_in_begin");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER)");
+    run(&mut connection, "BEGIN; INSERT INTO t VALUES (1); SAVEPOINT a; INSERT INTO t VALUES (2)");
+    run(&mut connection, "SAVEPOINT b; INSERT INTO t VALUES (3); RELEASE b");
+    // b was released into a, so rolling a back undoes b's write as well
+    run(&mut connection, "ROLLBACK TO a");
+    assert_eq!(texts(&mut connection, "SELECT n FROM t"), ["1"]);
+    run(&mut connection, "RELEASE a");
+    let err = connection.execute_batch("RELEASE a", &Params::new()).err().unwrap();
+    assert_eq!(err.to_string(), "no such savepoint: a");
+    run(&mut connection, "ROLLBACK");
+    assert!(texts(&mut connection, "SELECT n FROM t").is_empty());
+}
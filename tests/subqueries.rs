@@ -0,0 +1,44 @@
+// Subqueries read the rows SQLite does, wherever in a statement they are
+mod common;
+use common::*;
+
+#[test]
+fn subqueries_run_outside_where() {
+    let db = TempDb::new("subqueries");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE a(x INTEGER, y TEXT); CREATE TABLE b(x INTEGER, z TEXT)");
+    run(&mut connection, "INSERT INTO a VALUES (1, 'p'), (2, 'q'), (3, 'r'); INSERT INTO b VALUES (1, 'u'), (1, 'v'), (3, 'w')");
+    for sql in [
+        "SELECT x, EXISTS (SELECT 1 FROM b WHERE b.x = a.x), x IN (SELECT x FROM b) FROM a",
+        "SELECT x FROM a ORDER BY EXISTS (SELECT 1 FROM b WHERE b.x = a.x), x DESC",
+        "SELECT a.x, b.z FROM a JOIN b ON a.x = b.x AND EXISTS (SELECT 1 FROM b b2 WHERE b2.z = 'w' AND b2.x = a.x)",
+        "SELECT a.x, b.z FROM a LEFT JOIN b ON b.x IN (SELECT x FROM a WHERE y = 'p') ORDER BY 1, 2",
+        "SELECT count(*), sum(EXISTS (SELECT 1 FROM b WHERE b.x = a.x)) FROM a",
+        "SELECT y, count(*) FROM a GROUP BY EXISTS (SELECT 1 FROM b WHERE b.x = a.x) ORDER BY 2",
+        "SELECT count(*) FROM a HAVING EXISTS (SELECT 1 FROM b)",
+    ] {
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+}
+
+#[test]
+fn in_subqueries_compare_with_the_affinity_of_equals() {
+    let db = TempDb::new("in_subquery_affinity");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(a TEXT, i INT, b, c TEXT COLLATE NOCASE)");
+    run(&mut connection, "CREATE TABLE u(id INTEGER PRIMARY KEY, n INT, s TEXT, v)");
+    run(&mut connection, "INSERT INTO t VALUES ('1', 1, '1', 'X'), ('2', 2, 2, 'y'), (NULL, NULL, NULL, NULL)");
+    run(&mut connection, "INSERT INTO u VALUES (1, 1, '1', NULL), (3, 2, 'x', 1)");
+    for sql in [
+        "SELECT a FROM t WHERE a IN (SELECT n FROM u)",
+        "SELECT count(*) FROM t WHERE '1' IN (SELECT id FROM u)",
+        "SELECT count(*) FROM t WHERE 1 IN (SELECT s FROM u)",
+        "SELECT id FROM u WHERE id IN (SELECT a FROM t)",
+        "SELECT b, b IN (SELECT s FROM u), b IN (SELECT n FROM u), i IN (SELECT v FROM u) FROM t",
+        "SELECT c FROM t WHERE c IN (SELECT s FROM u)",
+        "SELECT a FROM t WHERE a IN (SELECT id FROM u WHERE u.n = t.i)",
+        "SELECT i, i IN (SELECT id FROM u WHERE 0), NULL IN (SELECT id FROM u) FROM t",
+    ] {
+        assert_reads_like_sqlite3(&db, &mut connection, sql);
+    }
+}
@@ -0,0 +1,64 @@
+// .timer and the stats of each statement behind it
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use codecrafters_sqlite::Params;
+
+mod common;
+use common::*;
+
+#[test]
+fn each_statement_records_its_page_reads_while_the_timer_is_on() {
+    let db = TempDb::new("timer_stats");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 500);
+    assert!(connection.take_statement_stats().is_empty());
+    drop(connection);
+
+    let mut connection = db.open();
+    connection.set_timer(true);
+    let results = connection.execute_script("SELECT sum(n) FROM t; SELECT max(n) FROM t", &Params::new());
+    assert!(results.iter().all(Result::is_ok));
+    let stats = connection.take_statement_stats();
+    assert_eq!(stats.len(), 2);
+    // The first reads the table into the cache, the second finds it there
+    assert!(stats[0].pages_read > 1 && stats[0].cache_misses > 1);
+    assert!(stats[1].cache_hits > 0 && stats[1].pages_read < stats[0].pages_read);
+    assert!(connection.take_statement_stats().is_empty());
+    // A statement whose rows are read as they are asked for counts once they run out
+    let rows = connection.query_rows("SELECT n FROM t", &Params::new()).unwrap();
+    assert_eq!(rows.count(), 500);
+    assert_eq!(connection.take_statement_stats().len(), 1);
+    run(&mut connection, "INSERT INTO t VALUES (1, 'x')");
+    assert!(connection.take_statement_stats()[0].pages_written > 0);
+    connection.set_timer(false);
+    run(&mut connection, "SELECT n FROM t");
+    assert!(connection.take_statement_stats().is_empty());
+}
+
+#[test]
+fn the_shell_prints_a_line_for_each_statement_until_the_timer_is_off() {
+    let db = TempDb::new("timer_shell");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER); INSERT INTO t VALUES (1), (2)");
+    drop(connection);
+    let mut shell = Command::new(env!("CARGO_BIN_EXE_codecrafters-sqlite"))
+        .arg(&db.path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let script = ".timer on\nSELECT count(*) FROM t;\nSELECT n FROM t WHERE n > 1;\n.timer off\nSELECT n FROM t;\n";
+    shell.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = shell.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let lines = String::from_utf8(output.stdout).unwrap().lines().map(String::from).collect::<Vec<_>>();
+    let timed = lines.iter().filter(|line| line.starts_with("Run Time: real ")).count();
+    assert_eq!(timed, 2, "{:?}", lines);
+    assert!(lines[1].ends_with(" misses"), "{}", lines[1]);
+    let rows = lines.iter().filter(|line| !line.starts_with("Run Time: ")).collect::<Vec<_>>();
+    assert_eq!(rows, ["2", "2", "1", "2"]);
+}
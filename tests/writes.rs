@@ -0,0 +1,603 @@
+// Each write leaves a file PRAGMA integrity_check passes, read back the way it was written
+use std::{fs, path::PathBuf, process};
+
+use codecrafters_sqlite::{Error, Params, Statement, Value};
+
+mod common;
+use common::*;
+
+#[test]
+fn inserts_split_pages() {
+    let db = TempDb::new("inserts");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 3000);
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT count(*), sum(n) FROM t"), ["3000|4498500"]);
+    assert_eq!(texts(&mut connection, "SELECT max(rowid) FROM t"), ["3000"]);
+}
+
+#[test]
+fn rowids_cover_the_full_range() {
+    let db = TempDb::new("rowids");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(id INTEGER PRIMARY KEY, v)");
+    for id in [i64::MAX, -1, 0, i64::MIN, 1 << 56, -(1 << 56)] {
+        let params = Params::new();
+        connection.execute(&format!("INSERT INTO t VALUES ({}, 'v')", id), &params).unwrap();
+    }
+    assert_intact(&db, &mut connection);
+    let ids = texts(&mut connection, "SELECT id FROM t");
+    let expected = [i64::MIN, -(1 << 56), -1, 0, 1 << 56, i64::MAX].map(|id| id.to_string());
+    assert_eq!(ids, expected);
+    assert_eq!(texts(&mut connection, &format!("SELECT v FROM t WHERE id = {}", i64::MIN)), ["v"]);
+    // The largest rowid is taken, so a free one is picked at random
+    run(&mut connection, "INSERT INTO t(v) VALUES ('w')");
+    let picked = texts(&mut connection, "SELECT id FROM t WHERE v = 'w'");
+    let picked = picked[0].parse::<i64>().unwrap();
+    assert!((1..=1 << 62).contains(&picked) && picked != 1 << 56, "{}", picked);
+    assert_intact(&db, &mut connection);
+    // Unless the table is AUTOINCREMENT, which never goes back
+    run(&mut connection, "CREATE TABLE a(id INTEGER PRIMARY KEY AUTOINCREMENT, v)");
+    run(&mut connection, &format!("INSERT INTO a VALUES ({}, 'v')", i64::MAX));
+    let err = connection.execute("INSERT INTO a(v) VALUES ('w')", &Params::new()).unwrap_err();
+    assert!(err.to_string().contains("database or disk is full"), "{}", err);
+}
+
+#[test]
+fn updates_keep_indexes_in_step() {
+    let db = TempDb::new("updates");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT); CREATE INDEX t_n ON t(n)");
+    insert_rows(&mut connection, "t", 0, 1000);
+    run(&mut connection, "UPDATE t SET n = n + 5000 WHERE n % 3 = 0");
+    run(&mut connection, "UPDATE t SET rowid = rowid + 10000 WHERE n < 100");
+    run(&mut connection, "UPDATE t SET s = upper(s) WHERE n % 5 = 0");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t WHERE n >= 5000"), ["334"]);
+    assert_eq!(texts(&mut connection, "SELECT rowid FROM t WHERE n = 1"), ["10002"]);
+}
+
+#[test]
+fn unique_and_descending_indexes_are_built_and_kept() {
+    let db = TempDb::new("unique_desc_indexes");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT COLLATE NOCASE)");
+    insert_rows(&mut connection, "t", 0, 500);
+    run(&mut connection, "CREATE UNIQUE INDEX t_n ON t(n); CREATE INDEX t_s ON t(s DESC, n DESC)");
+    insert_rows(&mut connection, "t", 500, 1000);
+    run(&mut connection, "UPDATE t SET n = n + 5000 WHERE n % 3 = 0; DELETE FROM t WHERE n % 7 = 0");
+    run(&mut connection, "INSERT INTO t VALUES (NULL, 'a'), (NULL, 'A')");
+    assert_intact(&db, &mut connection);
+    // sqlite3 reads the rows in the order of the DESC index
+    let sql = "SELECT n FROM t WHERE s > '' ORDER BY s DESC, n DESC LIMIT 5";
+    db.assert_sqlite3(&sql.replace("FROM t", "FROM t INDEXED BY t_s"), &texts(&mut connection, sql).join("\n"));
+
+    for sql in ["INSERT INTO t VALUES (1, 'b'), (1, 'c')", "INSERT INTO t VALUES (1.0, 'b')", "UPDATE t SET n = 1 WHERE n = 2"] {
+        let err = connection.execute(sql, &Params::new()).unwrap_err();
+        assert!(matches!(err, Error::Constraint(_)), "{}", sql);
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: t.n");
+    }
+    let err = connection.execute("CREATE UNIQUE INDEX t_s2 ON t(s)", &Params::new()).unwrap_err();
+    assert_eq!(err.to_string(), "UNIQUE constraint failed: t.s");
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t WHERE n = 1"), ["1"]);
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn unique_and_primary_key_constraints_get_their_indexes() {
+    let db = TempDb::new("autoindexes");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(s TEXT PRIMARY KEY DESC, n INTEGER UNIQUE, m, UNIQUE(n, m DESC), UNIQUE(n))");
+    run(&mut connection, "CREATE TABLE u(id INTEGER PRIMARY KEY, e TEXT UNIQUE COLLATE NOCASE)");
+    let sql = "SELECT name, tbl_name, sql IS NULL FROM sqlite_schema ORDER BY rootpage";
+    db.assert_sqlite3(
+        sql,
+        "t|t|0\nsqlite_autoindex_t_1|t|1\nsqlite_autoindex_t_2|t|1\nsqlite_autoindex_t_3|t|1\nu|u|0\nsqlite_autoindex_u_1|u|1",
+    );
+    let values = (0..1000).map(|i| format!("('{}', {}, {})", "k".repeat(i % 40 + 1) + &i.to_string(), i, i % 3));
+    run(&mut connection, &format!("INSERT INTO t VALUES {}", values.collect::<Vec<_>>().join(", ")));
+    run(&mut connection, "INSERT INTO t VALUES (NULL, NULL, 1), (NULL, NULL, 1)");
+    run(&mut connection, "UPDATE t SET n = n + 5000 WHERE n % 3 = 0; DELETE FROM t WHERE n % 7 = 0");
+    run(&mut connection, "INSERT INTO u(e) VALUES ('x'), ('y')");
+    assert_intact(&db, &mut connection);
+
+    for (sql, column) in [
+        ("INSERT INTO t VALUES ('kk1', -1, 0)", "t.s"),
+        ("INSERT INTO t VALUES ('new', 1, 0)", "t.n"),
+        ("UPDATE t SET n = 2 WHERE n = 4", "t.n"),
+        ("INSERT INTO u(e) VALUES ('X')", "u.e"),
+    ] {
+        let err = connection.execute(sql, &Params::new()).unwrap_err();
+        assert!(matches!(err, Error::Constraint(_)), "{}", sql);
+        assert_eq!(err.to_string(), format!("UNIQUE constraint failed: {}", column));
+    }
+    // A row sqlite3 writes is found through the indexes both sides keep
+    if !has_sqlite3() {
+        return;
+    }
+    db.sqlite3("INSERT INTO t VALUES ('k2', -2, 2)");
+    assert_eq!(texts(&mut connection, "SELECT n FROM t WHERE s = 'k2'"), ["-2"]);
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn deletes_free_their_pages() {
+    let db = TempDb::new("deletes");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT); CREATE INDEX t_s ON t(s)");
+    insert_rows(&mut connection, "t", 0, 2000);
+    run(&mut connection, "DELETE FROM t WHERE n % 10 <> 0");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t"), ["200"]);
+    let free = texts(&mut connection, "PRAGMA freelist_count");
+    assert_ne!(free, ["0"]);
+    // The free pages are used again
+    insert_rows(&mut connection, "t", 2000, 3000);
+    assert_intact(&db, &mut connection);
+    run(&mut connection, "DELETE FROM t");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t"), ["0"]);
+}
+
+#[test]
+fn create_index_over_existing_rows() {
+    let db = TempDb::new("create_index");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT COLLATE NOCASE)");
+    insert_rows(&mut connection, "t", 0, 1500);
+    run(&mut connection, "INSERT INTO t VALUES (-1, 'ABC'), (-2, 'abc')");
+    run(&mut connection, "CREATE INDEX t_n ON t(n); CREATE INDEX t_s_n ON t(s, n)");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT n FROM t WHERE s = 'abc' ORDER BY n"), ["-2", "-1"]);
+    assert_eq!(texts(&mut connection, "SELECT s FROM t WHERE n = 700"), ["x".repeat(50)]);
+}
+
+#[test]
+fn drops_put_pages_on_the_freelist() {
+    let db = TempDb::new("drops");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT); CREATE TABLE u(n); CREATE INDEX t_n ON t(n)");
+    insert_rows(&mut connection, "t", 0, 1000);
+    run(&mut connection, "INSERT INTO u VALUES (1)");
+    run(&mut connection, "DROP INDEX t_n");
+    assert_intact(&db, &mut connection);
+    run(&mut connection, "DROP TABLE t");
+    assert_intact(&db, &mut connection);
+    assert!(connection.query("SELECT * FROM t", &Params::new()).is_err());
+    assert_ne!(texts(&mut connection, "PRAGMA freelist_count"), ["0"]);
+    assert_eq!(texts(&mut connection, "SELECT n FROM u"), ["1"]);
+}
+
+#[test]
+fn a_corrupt_freelist_trunk_fails_the_write() {
+    let db = TempDb::new("corrupt_freelist");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT); CREATE TABLE u(n)");
+    insert_rows(&mut connection, "t", 0, 200);
+    run(&mut connection, "DROP TABLE t");
+    drop(connection);
+    let file = fs::read(&db.path).unwrap();
+    let trunk = u32::from_be_bytes(file[32..36].try_into().unwrap()) as usize;
+    let page_size = u16::from_be_bytes(file[16..18].try_into().unwrap()) as usize;
+    let leaf_count = u32::from_be_bytes(file[(trunk - 1) * page_size + 4..][..4].try_into().unwrap());
+    assert!(leaf_count > 1);
+    // Too many leaves to fit on the page, then a last leaf past the end of the file
+    let at = (trunk - 1) * page_size + 4;
+    for (at, value) in [(at, 100_000), (at + 4 * leaf_count as usize, 9999)] {
+        let mut corrupt = file.clone();
+        corrupt[at..at + 4].copy_from_slice(&u32::to_be_bytes(value));
+        fs::write(&db.path, &corrupt).unwrap();
+        let mut connection = db.open();
+        // Its root is the first page it takes
+        let err = connection.execute_batch("CREATE TABLE v(n)", &Params::new()).err().unwrap();
+        assert!(matches!(err, Error::CorruptPage(_)), "{:?}", err);
+        assert!(connection.query("SELECT n FROM v", &Params::new()).is_err());
+    }
+}
+
+#[test]
+fn added_columns_read_their_default() {
+    let db = TempDb::new("alter");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER)");
+    run(&mut connection, "INSERT INTO t VALUES (1), (2)");
+    run(&mut connection, "ALTER TABLE t ADD COLUMN s TEXT DEFAULT 'none'");
+    run(&mut connection, "INSERT INTO t VALUES (3, 'three')");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT n, s FROM t"), ["1|none", "2|none", "3|three"]);
+}
+
+#[test]
+fn generated_columns_are_rejected() {
+    let db = TempDb::new("generated");
+    let mut connection = db.open();
+    let params = Params::new();
+    let creates = [
+        "CREATE TABLE g(a INT, b AS (a * 2))",
+        "CREATE TABLE g(a INT, b INT GENERATED ALWAYS AS (a * 2) STORED)",
+    ];
+    for sql in creates {
+        let err = connection.execute(sql, &params).unwrap_err();
+        assert!(err.to_string().contains("generated columns are not supported"), "{}", err);
+    }
+    // AS inside parentheses is a CAST
+    run(&mut connection, "CREATE TABLE t(a INT CHECK (CAST(a AS INT) > 0))");
+    let err = connection.execute("ALTER TABLE t ADD COLUMN b AS (a * 2)", &params).unwrap_err();
+    assert!(err.to_string().contains("generated columns are not supported"), "{}", err);
+    db.assert_sqlite3("SELECT name FROM sqlite_schema", "t");
+}
+
+#[test]
+fn autoincrement_never_reuses_rowids() {
+    let db = TempDb::new("autoincrement");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, v NOT NULL)");
+    run(&mut connection, "INSERT INTO t(v) VALUES (1), (2), (3); DELETE FROM t WHERE id = 3");
+    run(&mut connection, "INSERT INTO t(v) VALUES (4)");
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT id FROM t"), ["1", "2", "4"]);
+    assert_eq!(texts(&mut connection, "SELECT name, seq FROM sqlite_sequence"), ["t|4"]);
+    let error = connection.execute("INSERT INTO t(v) VALUES (NULL)", &Params::new()).unwrap_err();
+    assert_eq!(error.to_string(), "NOT NULL constraint failed: t.v");
+    // The failed statement changed nothing
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t"), ["3"]);
+}
+
+#[test]
+fn sqlite3_dumps_of_autoincrement_tables_replay() {
+    if !has_sqlite3() {
+        return;
+    }
+    let source = TempDb::new("dump-source");
+    source.sqlite3("CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, v); INSERT INTO t(v) VALUES ('a'), ('b')");
+    // The dump turns writable_schema ON to fill sqlite_sequence
+    let dump = source.sqlite3(".dump");
+    assert!(dump.contains("PRAGMA writable_schema=ON;"), "{}", dump);
+    let db = TempDb::new("dump");
+    let mut connection = db.open();
+    run(&mut connection, &dump);
+    assert_intact(&db, &mut connection);
+    assert_eq!(texts(&mut connection, "SELECT id, v FROM t"), ["1|a", "2|b"]);
+    assert_eq!(texts(&mut connection, "SELECT name, seq FROM sqlite_sequence"), ["t|2"]);
+}
+
+#[test]
+fn written_values_read_back() {
+    let db = TempDb::new("values");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(a, b REAL, c TEXT, d BLOB)");
+    let long = "y".repeat(10_000);
+    let sql = format!("INSERT INTO t VALUES (NULL, 1, 2.5, x'00ff'), (-9223372036854775808, '3', '{}', 'b')", long);
+    run(&mut connection, &sql);
+    assert_intact(&db, &mut connection);
+    let result = connection.query("SELECT a, b, c, d FROM t", &Params::new()).unwrap();
+    let values = result.rows.iter().map(|row| row.values().to_vec()).collect::<Vec<_>>();
+    assert_eq!(
+        values,
+        [
+            vec![Value::Null, Value::Float(1.0), Value::String("2.5".to_string()), Value::Blob(vec![0, 255])],
+            vec![Value::I64(i64::MIN), Value::Float(3.0), Value::String(long), Value::String("b".to_string())],
+        ]
+    );
+}
+
+#[test]
+fn another_connection_sees_the_writes() {
+    let db = TempDb::new("connections");
+    let mut reader = db.open();
+    let mut writer = db.open();
+    run(&mut writer, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut writer, "t", 0, 10);
+    assert_eq!(texts(&mut reader, "SELECT count(*) FROM t"), ["10"]);
+    // The pages the reader cached change, and the file grows past the size it read
+    insert_rows(&mut writer, "t", 10, 2000);
+    run(&mut writer, "UPDATE t SET n = n * 2");
+    assert_eq!(texts(&mut reader, "SELECT count(*), sum(n) FROM t"), ["2000|3998000"]);
+}
+
+#[test]
+fn a_write_between_steps_resets_the_statement() {
+    let db = TempDb::new("steps");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 1000);
+    let mut statement = connection.prepare("SELECT n FROM t").unwrap();
+    assert!(statement.step(&mut connection).unwrap().is_some());
+    run(&mut connection, "DELETE FROM t WHERE n > 10");
+    assert!(statement.step(&mut connection).is_err());
+    // It runs again from the start
+    let mut count = 0;
+    while statement.step(&mut connection).unwrap().is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 11);
+}
+
+#[test]
+fn statements_stopped_part_way_let_others_write() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("steps_lock");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 1000);
+    let mut statement = connection.prepare("SELECT n FROM t").unwrap();
+    assert!(statement.step(&mut connection).unwrap().is_some());
+    statement.reset();
+    drop(statement);
+    db.sqlite3("INSERT INTO t VALUES (1000, 'x')");
+    // Nor is the file locked between steps, the next step sees the write
+    let mut statement = connection.prepare("SELECT n FROM t").unwrap();
+    assert!(statement.step(&mut connection).unwrap().is_some());
+    db.sqlite3("INSERT INTO t VALUES (1001, 'x')");
+    assert!(statement.step(&mut connection).is_err());
+    assert_eq!(statement.step(&mut connection).unwrap().unwrap().texts(), ["0"]);
+    drop(statement);
+    db.sqlite3("DELETE FROM t WHERE n >= 1000");
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t"), ["1000"]);
+}
+
+#[test]
+fn steps_stay_on_the_connection_they_started_on() {
+    let db = TempDb::new("steps_connection");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 10);
+    let mut other = db.open();
+    let mut statement = connection.prepare("SELECT n FROM t").unwrap();
+    assert!(statement.step(&mut connection).unwrap().is_some());
+    assert!(statement.step(&mut other).is_err());
+    // The run goes on where it was on its own connection
+    assert_eq!(statement.step(&mut connection).unwrap().unwrap().texts(), ["1"]);
+    // After a reset it can start on the other one
+    statement.reset();
+    assert_eq!(statement.step(&mut other).unwrap().unwrap().texts(), ["0"]);
+}
+
+#[test]
+fn script_rows_left_unread_dont_stop_the_script() {
+    let db = TempDb::new("script_rows");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 1000);
+    let mut firsts = Vec::new();
+    let sql = "SELECT n FROM t; DELETE FROM t WHERE n >= 10; SELECT count(*) FROM t";
+    connection.execute_script_rows(sql, &Params::new(), |rows| {
+        let first = rows.unwrap().next().map(|row| row.unwrap().get::<i64>(0).unwrap());
+        firsts.push(first);
+    });
+    assert_eq!(firsts, vec![Some(0), None, Some(10)]);
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn random_results_are_not_cached() {
+    let db = TempDb::new("random_cache");
+    let mut connection = db.open();
+    connection.set_result_cache(true);
+    run(&mut connection, "CREATE TABLE t(n INTEGER, s TEXT)");
+    insert_rows(&mut connection, "t", 0, 1);
+    let mut values = Vec::new();
+    for _ in 0..2 {
+        let results = connection.execute_script("SELECT random() FROM t; SELECT n FROM t", &Params::new());
+        let rows = results.into_iter().map(|result| result.unwrap().rows).collect::<Vec<_>>();
+        values.push(rows.concat());
+    }
+    assert_ne!(values[0][0], values[1][0]);
+    assert_eq!(values[0][1], values[1][1]);
+}
+
+#[test]
+fn cached_results_keep_the_case_of_their_column_names() {
+    let db = TempDb::new("cache_case");
+    let mut connection = db.open();
+    connection.set_result_cache(true);
+    run(&mut connection, "CREATE TABLE t(id INTEGER)");
+    run(&mut connection, "INSERT INTO t VALUES (1)");
+    for (sql, column) in [("SELECT id AS X FROM t", "X"), ("SELECT id AS x FROM t", "x"), ("select  id  as X from t", "X")] {
+        for _ in 0..2 {
+            let results = connection.execute_script(sql, &Params::new());
+            let result = results.into_iter().next().unwrap().unwrap();
+            assert_eq!(result.columns, [column], "{}", sql);
+        }
+    }
+}
+
+#[test]
+fn tables_past_root_page_127_read_back() {
+    let db = TempDb::new("many_tables");
+    let mut connection = db.open();
+    // Enough tables for root pages past what an i8 holds, and for sqlite_schema to split
+    // so that page 1, with the file header before its own, becomes an interior page
+    for i in 0..200 {
+        run(&mut connection, &format!("CREATE TABLE t{}(n INTEGER, s TEXT)", i));
+        run(&mut connection, &format!("INSERT INTO t{} VALUES ({}, 'row')", i, i));
+    }
+    drop(connection);
+    let mut connection = db.open();
+    assert_eq!(texts(&mut connection, "SELECT n FROM t0"), ["0"]);
+    assert_eq!(texts(&mut connection, "SELECT n FROM t199"), ["199"]);
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn drop_table_takes_its_autoindexes() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("drop_autoindex");
+    db.sqlite3("CREATE TABLE dept(name TEXT PRIMARY KEY, code TEXT UNIQUE); INSERT INTO dept VALUES ('a', 'b')");
+    let mut connection = db.open();
+    assert_eq!(connection.index_names(Some("dept")).unwrap(), ["sqlite_autoindex_dept_1", "sqlite_autoindex_dept_2"]);
+    run(&mut connection, "DROP TABLE dept");
+    drop(connection);
+    assert_eq!(db.sqlite3("PRAGMA integrity_check"), "ok");
+    assert_eq!(db.sqlite3("SELECT count(*) FROM sqlite_schema"), "0");
+}
+
+#[test]
+fn analyze_writes_stats_for_autoindexes() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("analyze_autoindex");
+    db.sqlite3(
+        "CREATE TABLE dept(id INTEGER PRIMARY KEY, name TEXT UNIQUE, code TEXT); CREATE INDEX dept_code ON dept(code);
+         INSERT INTO dept VALUES (1, 'a', 'x'), (2, 'b', 'y'), (3, 'c', 'x')",
+    );
+    let mut connection = db.open();
+    run(&mut connection, "ANALYZE");
+    drop(connection);
+    assert_eq!(db.sqlite3("PRAGMA integrity_check"), "ok");
+    let stats = db.sqlite3("SELECT tbl, idx, stat FROM sqlite_stat1 ORDER BY idx");
+    assert_eq!(stats, "dept|dept_code|3 2\ndept|sqlite_autoindex_dept_1|3 1");
+}
+
+#[test]
+fn space_usage_counts_autoindexes() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("space_autoindex");
+    db.sqlite3("CREATE TABLE dept(name TEXT PRIMARY KEY, code TEXT UNIQUE); INSERT INTO dept VALUES ('a', 'b')");
+    let mut connection = db.open();
+    let usage = connection.space_usage().unwrap();
+    let pages = usage
+        .iter()
+        .map(|btree| format!("{}|{}", btree.name, btree.total_pages()))
+        .collect::<Vec<_>>();
+    let expected = db.sqlite3("SELECT name, count(*) FROM dbstat GROUP BY name ORDER BY name != 'sqlite_schema', name");
+    assert_eq!(pages.join("\n"), expected);
+}
+
+#[test]
+fn reads_roll_back_a_hot_journal() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("hot_journal");
+    db.sqlite3("CREATE TABLE t(a, b); INSERT INTO t SELECT value, randomblob(500) FROM generate_series(1, 2000)");
+    // Keep a copy of the file and journal halfway through an update too big for the
+    // page cache, as a writer that crashed there would leave them
+    let crashed = TempDb::new("hot_journal_crashed");
+    let journal = |db: &TempDb| PathBuf::from(format!("{}-journal", db.path.display()));
+    let copy = format!(
+        ".shell cp '{}' '{}' && cp '{}' '{}'",
+        db.path.display(),
+        crashed.path.display(),
+        journal(&db).display(),
+        journal(&crashed).display()
+    );
+    let output = process::Command::new("sqlite3")
+        .arg(&db.path)
+        .args(["PRAGMA cache_size = 1", "BEGIN", "UPDATE t SET a = -a", &copy])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(journal(&crashed).exists());
+
+    let mut connection = crashed.open();
+    assert_eq!(texts(&mut connection, "SELECT count(*), sum(a) FROM t"), ["2000|2001000"]);
+    assert!(!journal(&crashed).exists());
+    // SHARED is held while the rows are read, so no other process can write meanwhile
+    let mut rows = connection.query_rows("SELECT a FROM t", &Params::new()).unwrap();
+    rows.next().unwrap().unwrap();
+    let output = process::Command::new("sqlite3").arg(&crashed.path).arg("DELETE FROM t").output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("database is locked"));
+    drop(rows);
+    drop(connection);
+    assert_eq!(crashed.sqlite3("PRAGMA integrity_check; SELECT count(*), sum(a) FROM t"), "ok\n2000|2001000");
+}
+
+#[test]
+fn integrity_check_walks_autoindexes() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("integrity_autoindex");
+    db.sqlite3("CREATE TABLE dept(name TEXT PRIMARY KEY, code TEXT UNIQUE); INSERT INTO dept VALUES ('a', 'b'), ('c', 'd')");
+    let mut connection = db.open();
+    // Each autoindex has a b-tree of its own, which would otherwise count as never used
+    assert_intact(&db, &mut connection);
+}
+
+#[test]
+fn reads_see_commits_made_to_the_log_since_the_last_read() {
+    if !has_sqlite3() {
+        return;
+    }
+    let db = TempDb::new("wal_refresh");
+    let (base, first, second) = (TempDb::new("wal_base"), TempDb::new("wal_first"), TempDb::new("wal_second"));
+    let (restarted_base, restarted) = (TempDb::new("wal_restarted_base"), TempDb::new("wal_restarted"));
+    let wal = PathBuf::from(format!("{}-wal", db.path.display()));
+    let copy = |from: &PathBuf, to: &TempDb| format!(".shell cp '{}' '{}'", from.display(), to.path.display());
+    // Keep the file and the log as they were after each commit, sqlite3 checkpoints the
+    // log into the file when it exits
+    let output = process::Command::new("sqlite3")
+        .arg(&db.path)
+        .args([
+            "PRAGMA journal_mode = WAL",
+            "PRAGMA wal_autocheckpoint = 0",
+            "CREATE TABLE t(a)",
+            "INSERT INTO t VALUES (1)",
+        ])
+        .arg(copy(&db.path, &base))
+        .arg(copy(&wal, &first))
+        .arg("INSERT INTO t SELECT value FROM generate_series(2, 500)")
+        .arg(copy(&wal, &second))
+        .args(["PRAGMA wal_checkpoint(RESTART)", "DELETE FROM t WHERE a > 10"])
+        .arg(copy(&db.path, &restarted_base))
+        .arg(copy(&wal, &restarted))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    fs::copy(&base.path, &db.path).unwrap();
+    fs::copy(&first.path, &wal).unwrap();
+    let mut connection = db.open();
+    assert_eq!(texts(&mut connection, "SELECT count(*) FROM t"), ["1"]);
+    fs::copy(&second.path, &wal).unwrap();
+    assert_eq!(texts(&mut connection, "SELECT count(*), sum(a) FROM t"), ["500|125250"]);
+    // A restarted log has a new salt, the frames from before are stale
+    fs::copy(&restarted_base.path, &db.path).unwrap();
+    fs::copy(&restarted.path, &wal).unwrap();
+    assert_eq!(texts(&mut connection, "SELECT count(*), sum(a) FROM t"), ["10|55"]);
+    fs::remove_file(&wal).unwrap();
+}
+
+#[test]
+fn batches_stop_at_the_first_failure() {
+    let db = TempDb::new("batch_stops");
+    let mut connection = db.open();
+    let sql = "CREATE TABLE t(a); INSERT INTO t VALUES (1); INSERT INTO nowhere VALUES (2); INSERT INTO t VALUES (3)";
+    assert!(connection.execute_batch(sql, &Params::new()).is_err());
+    assert_eq!(texts(&mut connection, "SELECT a FROM t"), ["1"]);
+}
+
+#[test]
+fn expressions_as_deep_as_sqlite_allows_run_on_a_default_thread() {
+    let db = TempDb::new("deep_expressions");
+    let mut connection = db.open();
+    run(&mut connection, "CREATE TABLE t(x); INSERT INTO t VALUES (1)");
+    let nested = format!("SELECT {}x{} FROM t", "(".repeat(999), ")".repeat(999));
+    let sums = format!("SELECT {}x{} FROM t", "x + (".repeat(499), ")".repeat(499));
+    let ors = format!("SELECT count(*) FROM t WHERE x = 0{}", " OR x = 1".repeat(998));
+    let subqueries = format!("SELECT count(*) FROM t WHERE {}1{}", "x IN (SELECT x FROM t WHERE ".repeat(499), ")".repeat(499));
+    // Past the old limit of 64, which these went over
+    let ids = (1..=79).map(|id| format!("x = {}", id)).collect::<Vec<_>>();
+    let few_ors = format!("SELECT count(*) FROM t WHERE {}", ids.join(" OR "));
+    let others = (2..=70).map(|id| format!("x <> {}", id)).collect::<Vec<_>>();
+    let ands = format!("SELECT count(*) FROM t WHERE {}", others.join(" AND "));
+    let cases = [(nested, "1"), (sums, "500"), (ors, "1"), (subqueries, "1"), (few_ors, "1"), (ands, "1")];
+    for (sql, expected) in cases {
+        assert_eq!(texts(&mut connection, &sql), [expected], "{}", &sql[..80]);
+        let mut statement = Statement::prepare(&sql).unwrap();
+        assert_eq!(statement.execute(&mut connection).unwrap().rows.len(), 1);
+        assert!(statement.step(&mut connection).unwrap().is_some());
+    }
+    let too_deep = format!("SELECT {}x{} FROM t", "x + (".repeat(500), ")".repeat(500));
+    let err = connection.query(&too_deep, &Params::new()).err().unwrap();
+    assert!(err.to_string().starts_with("Expression tree is too large (maximum depth 1000)"), "{}", err);
+}